@@ -0,0 +1,55 @@
+// Times the VM on a few programs. Run with `cargo bench`
+#![allow(non_snake_case)]
+#![allow(non_upper_case_globals)]
+use std::io;
+use std::time::{Duration, Instant};
+
+use ToastLang::codegen::{ASTConverter, VMCore};
+use ToastLang::module::Module;
+use ToastLang::output::Sink;
+use ToastLang::parser::Parser;
+
+// Each step of a loop is a call, so loops recurse this deep. Well under VMCore::maxCallDepth
+const loopDepth: usize = 5000;
+// Number of times each loop is run by a program
+const loopCount: usize = 20;
+
+fn compile(source: &str) -> Module {
+    let mut parser = Parser::new(source);
+    let mut ast_converter = ASTConverter::new();
+    for ast in parser.parse().expect("Could not parse benchmark") {
+        ast_converter.ConvertExprToByteCode(ast);
+    }
+    return Module::FromConverter(&ast_converter, None);
+}
+
+fn run(module: &Module) -> u64 {
+    let mut toast_vm = VMCore::new();
+    toast_vm.output = Sink::new(io::sink());
+    toast_vm.processProgram(module).expect("Benchmark failed");
+    return toast_vm.instructionCount;
+}
+
+// Runs the program until about a second has passed and prints the time per run and the instructions run per second
+fn bench(name: &str, source: &str) {
+    let module = compile(source);
+    let instructions = run(&module);
+    let (mut runs, started) = (0u32, Instant::now());
+    while runs == 0 || started.elapsed() < Duration::from_secs(1) {
+        run(&module);
+        runs += 1;
+    }
+    let perRun = started.elapsed() / runs;
+    println!("{:<20} {:>10.3} ms/run {:>12} instructions/run {:>8.1} M instructions/s", name, perRun.as_secs_f64() * 1000.0,
+        instructions, instructions as f64 / perRun.as_secs_f64() / 1e6);
+}
+
+fn main() {
+    let calls = |call: &str| vec![call; loopCount].join("\n");
+    bench("arithmetic loop", &format!("def count(i: number, acc: number) -> number:\nmatch i with\n| 0 -> acc\n| _ -> count(i - 1, acc + i * 2 - 1)\nendmatch\nend\n{}",
+        calls(&format!("count({}, 0)", loopDepth))));
+    bench("recursive fib", "def fib(n: number) -> number:\nmatch n with\n| 0 -> 0\n| 1 -> 1\n| _ -> fib(n - 1) + fib(n - 2)\nendmatch\nend\nfib(25)");
+    let elements: Vec<String> = (1..=loopDepth).map(|x| x.to_string()).collect();
+    bench("array sums", &format!("def total(xs: number[], i: number) -> number:\nmatch i with\n| 0 -> xs[0]\n| _ -> xs[i] + total(xs, i - 1)\nendmatch\nend\nlet nums: number[] = [{}]\n{}",
+        elements.join(", "), calls(&format!("total(nums, {})", loopDepth - 1))));
+}
@@ -0,0 +1,457 @@
+#![allow(non_snake_case)]
+use std::collections::HashMap;
+
+use crate::bytecode::Instruction;
+use crate::codegen::{OpCodes, VarTypes};
+use crate::module::{Constant, FunctionEntry, Module};
+
+/// Target of a jump or a call that is worked out once every line is read
+enum PendingRef {
+    Label(String),
+    Function(String)
+}
+
+/// Removes a comment (starting with ';') from a line. Semicolons in char and string literals are kept
+fn StripComment(line: &str) -> &str {
+    let mut quote: Option<char> = None;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, ';') => return &line[..i],
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            _ => {}
+        }
+    }
+    return line;
+}
+
+/// Splits operands on commas that are not inside brackets, parentheses or quotes
+fn SplitOperands(operands: &str) -> Vec<String> {
+    let mut parts = Vec::<String>::new();
+    let mut cur = String::new();
+    let mut depth = 0;
+    let mut quote: Option<char> = None;
+    for c in operands.chars() {
+        match (quote, c) {
+            (None, ',') if depth == 0 => {
+                parts.push(cur.trim().to_string());
+                cur = String::new();
+                continue;
+            },
+            (None, '[' | '(') => depth += 1,
+            (None, ']' | ')') => depth -= 1,
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            _ => {}
+        }
+        cur.push(c);
+    }
+    if !cur.trim().is_empty() {
+        parts.push(cur.trim().to_string());
+    }
+    return parts;
+}
+
+/// Finds the OpCode of a mnemonic. Mnemonics are not case sensitive and the 'Op' prefix is optional (e.g loadscalar or OpLoadScalar)
+fn ParseOpCode(mnemonic: &str) -> Result<OpCodes, String> {
+    let mnemonic = mnemonic.to_lowercase();
+    for byte in 0..=u8::MAX {
+        let Some(opCode) = num::FromPrimitive::from_u8(byte) else { break };
+        let opCode: OpCodes = opCode;
+        let name = format!("{:?}", opCode).to_lowercase();
+        if name == mnemonic || name[2..] == mnemonic {
+            return Ok(opCode);
+        }
+    }
+    return Err(format!("Unknown instruction '{}'", mnemonic));
+}
+
+/// Parses a Var Type. Accepts the VarTypes names (e.g FloatType) and the short names float, number, char, string, list, array, arrayref, variant and null
+fn ParseVarType(text: &str) -> Result<VarTypes, String> {
+    let name = text.trim().to_lowercase();
+    let name = name.strip_suffix("type").unwrap_or(&name);
+    match name {
+        "null" => Ok(VarTypes::NullType),
+        "float" | "number" => Ok(VarTypes::FloatType),
+        "char" => Ok(VarTypes::CharType),
+        "string" => Ok(VarTypes::StringType),
+        "array" | "list" => Ok(VarTypes::ArrayType),
+        "arrayref" => Ok(VarTypes::ArrayRef),
+        "variant" => Ok(VarTypes::VariantType),
+        _ => Err(format!("Unknown type '{}'", text.trim()))
+    }
+}
+
+/// Parses a register operand (e.g r3)
+fn ParseReg(text: &str) -> Result<u8, String> {
+    let text = text.trim();
+    return text.strip_prefix('r').and_then(|x| x.parse::<u8>().ok()).ok_or(format!("Expected a register, found '{}'", text));
+}
+
+/// Parses a whole number. A leading word naming the operand is skipped (e.g var 3, tag 1, slot 0)
+fn ParseInt<T: std::str::FromStr>(text: &str) -> Result<T, String> {
+    let value = text.split_whitespace().last().unwrap_or("");
+    return value.parse::<T>().map_err(|_| format!("Expected a number, found '{}'", text.trim()));
+}
+
+/// Parses a number or char constant into the bits held by a register
+fn ParseConstant(text: &str, varType: VarTypes) -> Result<u64, String> {
+    let text = text.trim();
+    match varType {
+        VarTypes::CharType => {
+            let mut chars = text.strip_prefix('\'').and_then(|x| x.strip_suffix('\'')).ok_or(format!("Expected a char, found '{}'", text))?.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if c.is_ascii() => Ok(c as u64),
+                _ => Err(format!("Expected a char, found '{}'", text))
+            }
+        },
+        VarTypes::FloatType => text.parse::<f64>().map(f64::to_bits).map_err(|_| format!("Expected a number, found '{}'", text)),
+        _ => Err(format!("Can not load a constant of type {:?}", varType))
+    }
+}
+
+/// Parses a bracketed list (e.g [1, 2, 3]) with the given parser for each item
+fn ParseList<T>(text: &str, parseItem: impl Fn(&str) -> Result<T, String>) -> Result<Vec<T>, String> {
+    let inner = text.trim().strip_prefix('[').and_then(|x| x.strip_suffix(']')).ok_or(format!("Expected a list, found '{}'", text.trim()))?;
+    return SplitOperands(inner).iter().map(|x| parseItem(x)).collect();
+}
+
+/// Parses an element operand (e.g r1[2]). Returns the list register and the text of the index
+fn ParseElement(text: &str) -> Result<(u8, String), String> {
+    let text = text.trim();
+    let (listReg, index) = text.strip_suffix(']').and_then(|x| x.split_once('[')).ok_or(format!("Expected an element, found '{}'", text))?;
+    return Ok((ParseReg(listReg)?, index.to_string()));
+}
+
+/// Parses the operands of StartFunc (e.g foo(float, char) -> float). The function name is optional
+fn ParseFunctionHeader(text: &str) -> Result<(Option<String>, Vec<VarTypes>, VarTypes), String> {
+    let (name, rest) = text.split_once('(').ok_or(format!("Expected parameter types, found '{}'", text.trim()))?;
+    let (params, returnType) = rest.split_once(')').ok_or(format!("Expected ')' in '{}'", text.trim()))?;
+    let returnType = returnType.trim().strip_prefix("->").ok_or(format!("Expected '->' and a return type in '{}'", text.trim()))?;
+    let paramTypes = SplitOperands(params).iter().map(|x| ParseVarType(x)).collect::<Result<Vec<VarTypes>, String>>()?;
+    let name = Some(name.trim().to_string()).filter(|x| !x.is_empty());
+    return Ok((name, paramTypes, ParseVarType(returnType)?));
+}
+
+/// Parses a constant pool value: a number or a quoted string
+fn ParsePoolConstant(text: &str) -> Result<Constant, String> {
+    let text = text.trim();
+    if let Some(value) = text.strip_prefix('"').and_then(|x| x.strip_suffix('"')) {
+        return Ok(Constant::String(value.bytes().collect()));
+    }
+    return text.parse::<f64>().map(Constant::Number).map_err(|_| format!("Expected a number or a string, found '{}'", text));
+}
+
+/// Parses one instruction. Jump targets and function ids given by name are returned to be worked out later.
+/// Constants are added to the constant pool if they are not in it yet
+fn ParseInstruction(opCode: OpCodes, operands: &str, constants: &mut Vec<Constant>) -> Result<(Instruction, Option<PendingRef>, Option<String>), String> {
+    let ops = SplitOperands(operands);
+    let expected = match opCode {
+        OpCodes::OpStartFunc | OpCodes::OpCallFunc | OpCodes::OpJump | OpCodes::OpJumpIfFalse | OpCodes::OpAddFuncParameter
+            | OpCodes::OpAccessElementEnd | OpCodes::OpDropVars | OpCodes::OpLoadArrayDimensions => 1,
+        OpCodes::OpLoadReg | OpCodes::OpAdd | OpCodes::OpSub | OpCodes::OpMul | OpCodes::OpDiv | OpCodes::OpNewVar | OpCodes::OpCopyVarToNewMemoryBlock
+            | OpCodes::OpReturn | OpCodes::OpTestTag | OpCodes::OpTestLength | OpCodes::OpSpill | OpCodes::OpReload | OpCodes::OpLoadConstant => 2,
+        OpCodes::OpLoadScalar | OpCodes::OpLoadVar | OpCodes::OpAccessArray | OpCodes::OpLoadElement | OpCodes::OpTestEqual
+            | OpCodes::OpLoadIndexed | OpCodes::OpUnwrap => 3,
+        OpCodes::OpLoadArray | OpCodes::OpMakeVariant => 4,
+        _ => 0
+    };
+    // StartFunc keeps its commas inside the parameter list
+    if opCode != OpCodes::OpStartFunc && ops.len() != expected {
+        return Err(format!("{:?} takes {} operands, found {}", opCode, expected, ops.len()));
+    }
+    let mut pendingRef = None;
+    let mut funcName = None;
+    let instruction = match opCode {
+        OpCodes::OpLoadReg => Instruction::LoadReg { src: ParseReg(&ops[0])?, dst: ParseReg(&ops[1])? },
+        OpCodes::OpLoadScalar => {
+            let varType = ParseVarType(&ops[1])?;
+            Instruction::LoadScalar { reg: ParseReg(&ops[0])?, varType, value: ParseConstant(&ops[2], varType)? }
+        },
+        OpCodes::OpAdd => Instruction::Add { reg1: ParseReg(&ops[0])?, reg2: ParseReg(&ops[1])? },
+        OpCodes::OpSub => Instruction::Sub { reg1: ParseReg(&ops[0])?, reg2: ParseReg(&ops[1])? },
+        OpCodes::OpMul => Instruction::Mul { reg1: ParseReg(&ops[0])?, reg2: ParseReg(&ops[1])? },
+        OpCodes::OpDiv => Instruction::Div { reg1: ParseReg(&ops[0])?, reg2: ParseReg(&ops[1])? },
+        OpCodes::OpNewVar => Instruction::NewVar { reg: ParseReg(&ops[0])?, varType: ParseVarType(&ops[1])? },
+        OpCodes::OpLoadVar => Instruction::LoadVar { reg: ParseReg(&ops[0])?, varType: ParseVarType(&ops[1])?, varId: ParseInt(&ops[2])? },
+        OpCodes::OpStartFunc => {
+            let (name, paramTypes, returnType) = ParseFunctionHeader(operands)?;
+            funcName = name;
+            Instruction::StartFunc { paramTypes, returnType }
+        },
+        OpCodes::OpAddFuncParameter => Instruction::AddFuncParameter { varType: ParseVarType(&ops[0])? },
+        OpCodes::OpCallFunc => match ParseInt(&ops[0]) {
+            Ok(funcId) => Instruction::CallFunc { funcId },
+            Err(_) => {
+                pendingRef = Some(PendingRef::Function(ops[0].clone()));
+                Instruction::CallFunc { funcId: 0 }
+            }
+        },
+        OpCodes::OpLoadArray => {
+            let elementType = ParseVarType(&ops[1])?;
+            let elements = if elementType == VarTypes::CharType && ops[3].starts_with('"') {
+                ops[3].strip_prefix('"').and_then(|x| x.strip_suffix('"')).ok_or(format!("Expected a string, found '{}'", ops[3]))?.bytes().map(|x| x as u64).collect()
+            } else {
+                ParseList(&ops[3], |x| ParseConstant(x, elementType))?
+            };
+            Instruction::LoadArray { reg: ParseReg(&ops[0])?, elementType, dimensions: ParseList(&ops[2], ParseInt)?, elements }
+        },
+        OpCodes::OpAccessArray => Instruction::AccessArray { varId: ParseInt(&ops[0])?, dest: ParseReg(&ops[1])?, indexCount: ParseInt(&ops[2])? },
+        OpCodes::OpAccessElementEnd => Instruction::AccessElementEnd { reg: ParseReg(&ops[0])? },
+        OpCodes::OpCopyVarToNewMemoryBlock => Instruction::CopyVarToNewMemoryBlock { varType: ParseVarType(&ops[0])?, varId: ParseInt(&ops[1])? },
+        OpCodes::OpReturn => Instruction::Return { returnType: ParseVarType(&ops[0])?, reg: ParseReg(&ops[1])? },
+        OpCodes::OpLoadArrayDimensions => Instruction::LoadArrayDimensions { dimensions: ParseList(&ops[0], ParseInt)? },
+        OpCodes::OpMakeVariant => {
+            let fields = ParseList(&ops[3], |x| {
+                let (reg, varType) = x.trim().split_once(' ').ok_or(format!("Expected a register and a type, found '{}'", x.trim()))?;
+                Ok((ParseReg(reg)?, ParseVarType(varType)?))
+            })?;
+            Instruction::MakeVariant { reg: ParseReg(&ops[0])?, varType: ParseVarType(&ops[1])?, tag: ParseInt(&ops[2])?, fields }
+        },
+        OpCodes::OpLoadElement => {
+            let (listReg, index) = ParseElement(&ops[2])?;
+            Instruction::LoadElement { reg: ParseReg(&ops[0])?, varType: ParseVarType(&ops[1])?, listReg, index: ParseInt(&index)? }
+        },
+        OpCodes::OpTestTag => Instruction::TestTag { reg: ParseReg(&ops[0])?, tag: ParseInt(&ops[1])? },
+        OpCodes::OpTestLength => Instruction::TestLength { reg: ParseReg(&ops[0])?, length: ParseInt(&ops[1])? },
+        OpCodes::OpTestEqual => Instruction::TestEqual { reg1: ParseReg(&ops[0])?, varType: ParseVarType(&ops[1])?, reg2: ParseReg(&ops[2])? },
+        OpCodes::OpJump | OpCodes::OpJumpIfFalse => {
+            let target = match ParseInt(&ops[0]) {
+                Ok(target) => target,
+                Err(_) => {
+                    pendingRef = Some(PendingRef::Label(ops[0].clone()));
+                    0
+                }
+            };
+            if opCode == OpCodes::OpJump { Instruction::Jump { target } } else { Instruction::JumpIfFalse { target } }
+        },
+        OpCodes::OpDropVars => Instruction::DropVars { count: ParseInt(&ops[0])? },
+        OpCodes::OpLoadIndexed => {
+            let (listReg, indexReg) = ParseElement(&ops[2])?;
+            Instruction::LoadIndexed { reg: ParseReg(&ops[0])?, varType: ParseVarType(&ops[1])?, listReg, indexReg: ParseReg(&indexReg)? }
+        },
+        OpCodes::OpUnwrap => Instruction::Unwrap { reg: ParseReg(&ops[0])?, varType: ParseVarType(&ops[1])?, optionalReg: ParseReg(&ops[2])? },
+        OpCodes::OpSpill => Instruction::Spill { reg: ParseReg(&ops[0])?, slot: ParseInt(&ops[1])? },
+        OpCodes::OpReload => Instruction::Reload { reg: ParseReg(&ops[0])?, slot: ParseInt(&ops[1])? },
+        OpCodes::OpLoadConstant => {
+            let constant = ParsePoolConstant(&ops[1])?;
+            let constId = match constants.iter().position(|x| *x == constant) {
+                Some(constId) => constId,
+                None => {
+                    constants.push(constant);
+                    constants.len() - 1
+                }
+            };
+            Instruction::LoadConstant { reg: ParseReg(&ops[0])?, constId: constId as u64 }
+        },
+        OpCodes::OpEndFunc => Instruction::EndFunc,
+        OpCodes::OpEndParamLoad => Instruction::EndParamLoad,
+        OpCodes::OpEndArray => Instruction::EndArray,
+        OpCodes::OpPrint => Instruction::Print,
+        OpCodes::OpAccessElementBegin => Instruction::AccessElementBegin,
+        OpCodes::OpLoadMultiDimensionalArrayElement => Instruction::LoadMultiDimensionalArrayElement,
+        OpCodes::OpEndMultiDimensionalArrayElement => Instruction::EndMultiDimensionalArrayElement,
+        OpCodes::OpEndArrayDimensions => Instruction::EndArrayDimensions,
+    };
+    return Ok((instruction, pendingRef, funcName));
+}
+
+/// Code of the top level or of one function, with the labels declared in it
+#[derive(Default)]
+struct Region {
+    /// List of (Instruction, Target to work out, Line number)
+    instructions: Vec<(Instruction, Option<PendingRef>, usize)>,
+    /// Key is label, Value is the index of the instruction it comes before
+    labels: HashMap<String, usize>
+}
+
+impl Region {
+    /// Encodes the region, filling in jump targets (relative to the start of the region) and function ids
+    fn Encode(self, funcIds: &HashMap<String, u64>) -> Result<Vec<u8>, String> {
+        // Offset of each instruction, with one more for the end of the region
+        let mut offsets = vec![0];
+        for (instruction, _, _) in &self.instructions {
+            let mut bytes = Vec::<u8>::new();
+            instruction.Encode(&mut bytes);
+            offsets.push(offsets.last().unwrap() + bytes.len());
+        }
+        let mut code = Vec::<u8>::new();
+        for (mut instruction, pendingRef, lineNum) in self.instructions {
+            match pendingRef {
+                Some(PendingRef::Label(label)) => {
+                    let target = offsets[*self.labels.get(&label).ok_or(format!("line {}: Unknown label '{}'", lineNum, label))?] as u64;
+                    match &mut instruction {
+                        Instruction::Jump { target: x } | Instruction::JumpIfFalse { target: x } => *x = target,
+                        _ => unreachable!()
+                    }
+                },
+                Some(PendingRef::Function(name)) => {
+                    let funcId = *funcIds.get(&name).ok_or(format!("line {}: Unknown function '{}'", lineNum, name))?;
+                    instruction = Instruction::CallFunc { funcId };
+                },
+                None => {}
+            }
+            instruction.Encode(&mut code);
+        }
+        return Ok(code);
+    }
+}
+
+/// Assembles a program written one instruction per line (e.g 'loadscalar r1, float, 32.0' or 'callfunc foo').
+/// Values of loadconstant (e.g 'loadconstant r0, "hi"') go into the constant pool of the module.
+///
+/// Each startfunc ... endfunc block goes into the function table, and the other lines make up the top level code.
+/// 'startfunc foo(float) -> float' names a function so callfunc can call it by name. Lines can start with a label ('loop:')
+/// that jumps in the same function (or in the top level code) can target by name. Text after ';' is a comment, and a leading
+/// offset is skipped, so disassembler listings can be assembled again. Errors give the line number of the line that could not be assembled
+pub fn Assemble(source: &str) -> Result<Module, String> {
+    let mut constants = Vec::<Constant>::new();
+    let mut topLevel = Region::default();
+    // List of (Function name, Function id, Body)
+    let mut functions = Vec::<(String, u64, Region)>::new();
+    // Line of the startfunc of the function being read
+    let mut openFunction: Option<usize> = None;
+    let mut funcIds = HashMap::<String, u64>::new();
+    funcIds.insert("print".to_string(), 0);
+    let mut nextFuncId: u64 = 1;
+    for (lineNum, line) in source.lines().enumerate() {
+        let mut line = StripComment(line).trim();
+        // Skips the offset column of a disassembler listing
+        if let Some((first, rest)) = line.split_once(char::is_whitespace) {
+            if first.chars().all(|c| c.is_ascii_digit()) {
+                line = rest.trim();
+            }
+        }
+        let region = match openFunction {
+            Some(_) => &mut functions.last_mut().unwrap().2,
+            None => &mut topLevel
+        };
+        if let Some(label) = line.strip_suffix(':') {
+            if region.labels.insert(label.trim().to_string(), region.instructions.len()).is_some() {
+                return Err(format!("line {}: Label '{}' is already declared", lineNum + 1, label.trim()));
+            }
+            continue;
+        }
+        if line.is_empty() {
+            continue;
+        }
+        let (mnemonic, operands) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let opCode = ParseOpCode(mnemonic).map_err(|x| format!("line {}: {}", lineNum + 1, x))?;
+        let isStartFunc = opCode == OpCodes::OpStartFunc;
+        let isEndFunc = opCode == OpCodes::OpEndFunc;
+        let (instruction, pendingRef, funcName) = ParseInstruction(opCode, operands, &mut constants).map_err(|x| format!("line {}: {}", lineNum + 1, x))?;
+        if isStartFunc {
+            if openFunction.is_some() {
+                return Err(format!("line {}: Functions can not be nested", lineNum + 1));
+            }
+            let name = funcName.unwrap_or_default();
+            if !name.is_empty() {
+                funcIds.insert(name.clone(), nextFuncId);
+            }
+            functions.push((name, nextFuncId, Region::default()));
+            nextFuncId += 1;
+            openFunction = Some(lineNum + 1);
+        } else if isEndFunc && openFunction.is_none() {
+            return Err(format!("line {}: OpEndFunc is not in a function", lineNum + 1));
+        }
+        let region = match openFunction {
+            Some(_) => &mut functions.last_mut().unwrap().2,
+            None => &mut topLevel
+        };
+        region.instructions.push((instruction, pendingRef, lineNum + 1));
+        if isEndFunc {
+            openFunction = None;
+        }
+    }
+    if let Some(startLine) = openFunction {
+        return Err(format!("line {}: Function has no OpEndFunc", startLine));
+    }
+
+    let mut functionTable = Vec::<FunctionEntry>::new();
+    let mut functionCode = Vec::<u8>::new();
+    for (name, funcId, body) in functions {
+        let body = body.Encode(&funcIds)?;
+        functionTable.push(FunctionEntry { name, funcId, offset: functionCode.len() as u64, length: body.len() as u64 });
+        functionCode.extend(body);
+    }
+    let code = topLevel.Encode(&funcIds)?;
+    return Ok(Module { constants, functions: functionTable, functionCode, code, debugInfo: None });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::Assemble;
+    use crate::bytecode::Instruction;
+    use crate::codegen::{ASTConverter, VMCore, VarTypes, regResult};
+    use crate::disassembler::Disassemble;
+    use crate::module::{Constant, FunctionEntry, Module};
+    use crate::parser::Parser;
+
+    #[test]
+    fn assembleAndRunFunctionCall(){
+        let module = Assemble("
+            startfunc foo(float) -> float
+                loadvar r0, float, var 0
+                loadconstant r1, 100.0
+                mul r0, r1
+                return float, r0
+            endfunc
+            callfunc foo
+                loadscalar r1, float, 32.0   ; parameter
+                newvar r1, float
+            endparamload
+        ").unwrap();
+        assert_eq!(module.constants, [Constant::Number(100.0)].to_vec());
+        assert_eq!(module.functions, [FunctionEntry { name: "foo".to_string(), funcId: 1, offset: 0, length: module.functionCode.len() as u64 }].to_vec());
+        let mut toast_vm = VMCore::new();
+        toast_vm.processProgram(&module).unwrap();
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 3200.0);
+    }
+
+    #[test]
+    fn assembleLabels(){
+        let module = Assemble("
+            loadscalar r0, char, ';'
+            jump end
+            loadscalar r0, char, 'b'
+        end:
+            loadreg r0, r1
+        ").unwrap();
+        assert_eq!(Instruction::DecodeProgram(&module.code), [
+            Instruction::LoadScalar { reg: 0, varType: VarTypes::CharType, value: ';' as u64 },
+            Instruction::Jump { target: 17 },
+            Instruction::LoadScalar { reg: 0, varType: VarTypes::CharType, value: 'b' as u64 },
+            Instruction::LoadReg { src: 0, dst: 1 },
+        ].to_vec());
+        let mut toast_vm = VMCore::new();
+        toast_vm.processProgram(&module).unwrap();
+        assert_eq!(toast_vm.registers[1], (VarTypes::CharType, ';' as u64));
+    }
+
+    #[test]
+    fn assembleDisassemblerListing(){
+        let source = "type Shape = Circle(number) | Rect(number, number)\ndef area(s: Shape) -> number:\nmatch s with\n| Circle(r) -> r * r\n| Rect(w, h) -> w * h\nendmatch\nend\nlet xs: number[][] = [[1, 2], [3, 4]]\nprint(area(Rect(3, xs[1][0])))\nprint(\"hi\")";
+        let mut parser = Parser::new(source);
+        let mut ast_converter = ASTConverter::new();
+        for ast in &parser.parse().unwrap() {
+            ast_converter.ConvertExprToByteCode(ast.to_owned());
+        }
+        let compiled = Module::FromConverter(&ast_converter, None);
+        let listing = Disassemble(&compiled);
+        let module = Assemble(&listing).unwrap();
+        assert_eq!(module, compiled);
+        assert_eq!(Disassemble(&module), listing);
+    }
+
+    #[test]
+    fn assembleErrors(){
+        assert_eq!(Assemble("loadscalar r0, float, 1.0\nfoo r1"), Err("line 2: Unknown instruction 'foo'".to_string()));
+        assert_eq!(Assemble("add r0"), Err("line 1: OpAdd takes 2 operands, found 1".to_string()));
+        assert_eq!(Assemble("jump nowhere"), Err("line 1: Unknown label 'nowhere'".to_string()));
+        assert_eq!(Assemble("callfunc bar"), Err("line 1: Unknown function 'bar'".to_string()));
+        assert_eq!(Assemble("newvar x0, float"), Err("line 1: Expected a register, found 'x0'".to_string()));
+        assert_eq!(Assemble("startfunc foo() -> null\nstartfunc bar() -> null"), Err("line 2: Functions can not be nested".to_string()));
+        assert_eq!(Assemble("startfunc foo() -> null\nprint"), Err("line 1: Function has no OpEndFunc".to_string()));
+        assert_eq!(Assemble("endfunc"), Err("line 1: OpEndFunc is not in a function".to_string()));
+    }
+}
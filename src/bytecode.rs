@@ -0,0 +1,480 @@
+#![allow(non_snake_case)]
+use crate::codegen::{OpCodes, VarTypes};
+
+/// A single instruction of a program. Every OpCode has a matching variant, laid out as described on OpCodes
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+    LoadReg { src: u8, dst: u8 },
+    /// Number values take 64 bits, char values take 8 bits
+    LoadScalar { reg: u8, varType: VarTypes, value: u64 },
+    Add { reg1: u8, reg2: u8 },
+    Sub { reg1: u8, reg2: u8 },
+    Mul { reg1: u8, reg2: u8 },
+    Div { reg1: u8, reg2: u8 },
+    NewVar { reg: u8, varType: VarTypes },
+    LoadVar { reg: u8, varType: VarTypes, varId: u64 },
+    /// Each parameter type is laid out as an AddFuncParameter instruction
+    StartFunc { paramTypes: Vec<VarTypes>, returnType: VarTypes },
+    AddFuncParameter { varType: VarTypes },
+    EndFunc,
+    CallFunc { funcId: u64 },
+    EndParamLoad,
+    /// Holds the dimensions and the elements of a list of numbers or chars. Lists of lists hold no elements,
+    /// each inner list follows as a LoadMultiDimensionalArrayElement block instead
+    LoadArray { reg: u8, elementType: VarTypes, dimensions: Vec<u8>, elements: Vec<u64> },
+    EndArray,
+    Print,
+    AccessArray { varId: u64, dest: u8, indexCount: u8 },
+    AccessElementBegin,
+    AccessElementEnd { reg: u8 },
+    CopyVarToNewMemoryBlock { varType: VarTypes, varId: u64 },
+    LoadMultiDimensionalArrayElement,
+    EndMultiDimensionalArrayElement,
+    Return { returnType: VarTypes, reg: u8 },
+    LoadArrayDimensions { dimensions: Vec<u8> },
+    EndArrayDimensions,
+    MakeVariant { reg: u8, varType: VarTypes, tag: u8, fields: Vec<(u8, VarTypes)> },
+    LoadElement { reg: u8, varType: VarTypes, listReg: u8, index: u8 },
+    TestTag { reg: u8, tag: u8 },
+    TestLength { reg: u8, length: u8 },
+    TestEqual { reg1: u8, varType: VarTypes, reg2: u8 },
+    Jump { target: u64 },
+    JumpIfFalse { target: u64 },
+    DropVars { count: u8 },
+    LoadIndexed { reg: u8, varType: VarTypes, listReg: u8, indexReg: u8 },
+    Unwrap { reg: u8, varType: VarTypes, optionalReg: u8 },
+    Spill { reg: u8, slot: u8 },
+    Reload { reg: u8, slot: u8 },
+    /// Index into the constant pool of the module
+    LoadConstant { reg: u8, constId: u64 },
+}
+
+/// Reads the operands of an instruction, moving past each one
+struct OperandReader<'a> {
+    program: &'a [u8],
+    pos: usize
+}
+
+impl<'a> OperandReader<'a> {
+    fn Byte(&mut self) -> Result<u8, String> {
+        let byte = *self.program.get(self.pos).ok_or_else(|| format!("Program ends in the middle of an instruction at {}", self.pos))?;
+        self.pos += 1;
+        return Ok(byte);
+    }
+
+    fn VarType(&mut self) -> Result<VarTypes, String> {
+        let byte = self.Byte()?;
+        return num::FromPrimitive::from_u8(byte).ok_or_else(|| format!("Unkown Var Type: {}", byte));
+    }
+
+    /// 64 bit values are stored most significant byte first
+    fn Val64(&mut self) -> Result<u64, String> {
+        let bytes = self.program.get(self.pos..self.pos + 8).ok_or_else(|| format!("Program ends in the middle of an instruction at {}", self.program.len()))?;
+        self.pos += 8;
+        return Ok(u64::from_be_bytes(bytes.try_into().unwrap()));
+    }
+
+    fn Instruction(&mut self) -> Result<Instruction, String> {
+        let (instruction, length) = Instruction::TryDecode(self.program, self.pos)?;
+        self.pos += length;
+        return Ok(instruction);
+    }
+}
+
+impl Instruction {
+    /// Returns the OpCode the instruction starts with
+    pub fn OpCode(&self) -> OpCodes {
+        match self {
+            Instruction::LoadReg { .. } => OpCodes::OpLoadReg,
+            Instruction::LoadScalar { .. } => OpCodes::OpLoadScalar,
+            Instruction::Add { .. } => OpCodes::OpAdd,
+            Instruction::Sub { .. } => OpCodes::OpSub,
+            Instruction::Mul { .. } => OpCodes::OpMul,
+            Instruction::Div { .. } => OpCodes::OpDiv,
+            Instruction::NewVar { .. } => OpCodes::OpNewVar,
+            Instruction::LoadVar { .. } => OpCodes::OpLoadVar,
+            Instruction::StartFunc { .. } => OpCodes::OpStartFunc,
+            Instruction::AddFuncParameter { .. } => OpCodes::OpAddFuncParameter,
+            Instruction::EndFunc => OpCodes::OpEndFunc,
+            Instruction::CallFunc { .. } => OpCodes::OpCallFunc,
+            Instruction::EndParamLoad => OpCodes::OpEndParamLoad,
+            Instruction::LoadArray { .. } => OpCodes::OpLoadArray,
+            Instruction::EndArray => OpCodes::OpEndArray,
+            Instruction::Print => OpCodes::OpPrint,
+            Instruction::AccessArray { .. } => OpCodes::OpAccessArray,
+            Instruction::AccessElementBegin => OpCodes::OpAccessElementBegin,
+            Instruction::AccessElementEnd { .. } => OpCodes::OpAccessElementEnd,
+            Instruction::CopyVarToNewMemoryBlock { .. } => OpCodes::OpCopyVarToNewMemoryBlock,
+            Instruction::LoadMultiDimensionalArrayElement => OpCodes::OpLoadMultiDimensionalArrayElement,
+            Instruction::EndMultiDimensionalArrayElement => OpCodes::OpEndMultiDimensionalArrayElement,
+            Instruction::Return { .. } => OpCodes::OpReturn,
+            Instruction::LoadArrayDimensions { .. } => OpCodes::OpLoadArrayDimensions,
+            Instruction::EndArrayDimensions => OpCodes::OpEndArrayDimensions,
+            Instruction::MakeVariant { .. } => OpCodes::OpMakeVariant,
+            Instruction::LoadElement { .. } => OpCodes::OpLoadElement,
+            Instruction::TestTag { .. } => OpCodes::OpTestTag,
+            Instruction::TestLength { .. } => OpCodes::OpTestLength,
+            Instruction::TestEqual { .. } => OpCodes::OpTestEqual,
+            Instruction::Jump { .. } => OpCodes::OpJump,
+            Instruction::JumpIfFalse { .. } => OpCodes::OpJumpIfFalse,
+            Instruction::DropVars { .. } => OpCodes::OpDropVars,
+            Instruction::LoadIndexed { .. } => OpCodes::OpLoadIndexed,
+            Instruction::Unwrap { .. } => OpCodes::OpUnwrap,
+            Instruction::Spill { .. } => OpCodes::OpSpill,
+            Instruction::Reload { .. } => OpCodes::OpReload,
+            Instruction::LoadConstant { .. } => OpCodes::OpLoadConstant,
+        }
+    }
+
+    /// Appends the bytes of the instruction to the program
+    pub fn Encode(&self, program: &mut Vec<u8>) {
+        let push64BitVal = |program: &mut Vec<u8>, val: u64| program.extend(val.to_be_bytes());
+        program.push(self.OpCode() as u8);
+        match self {
+            Instruction::LoadReg { src, dst } => program.extend([*src, *dst]),
+            Instruction::LoadScalar { reg, varType, value } => {
+                program.extend([*reg, *varType as u8]);
+                if *varType == VarTypes::CharType {
+                    program.push(*value as u8);
+                } else {
+                    push64BitVal(program, *value);
+                }
+            },
+            Instruction::Add { reg1, reg2 } | Instruction::Sub { reg1, reg2 } | Instruction::Mul { reg1, reg2 } | Instruction::Div { reg1, reg2 } => {
+                program.extend([*reg1, *reg2]);
+            },
+            Instruction::NewVar { reg, varType } => program.extend([*reg, *varType as u8]),
+            Instruction::LoadVar { reg, varType, varId } => {
+                program.extend([*reg, *varType as u8]);
+                push64BitVal(program, *varId);
+            },
+            Instruction::StartFunc { paramTypes, returnType } => {
+                program.push(paramTypes.len() as u8);
+                for paramType in paramTypes {
+                    Instruction::AddFuncParameter { varType: *paramType }.Encode(program);
+                }
+                program.push(*returnType as u8);
+            },
+            Instruction::AddFuncParameter { varType } => program.push(*varType as u8),
+            Instruction::CallFunc { funcId } => push64BitVal(program, *funcId),
+            Instruction::LoadArray { reg, elementType, dimensions, elements } => {
+                program.extend([*reg, *elementType as u8]);
+                Instruction::LoadArrayDimensions { dimensions: dimensions.clone() }.Encode(program);
+                Instruction::EndArrayDimensions.Encode(program);
+                push64BitVal(program, elements.len() as u64);
+                for element in elements {
+                    if *elementType == VarTypes::CharType {
+                        program.push(*element as u8);
+                    } else {
+                        push64BitVal(program, *element);
+                    }
+                }
+            },
+            Instruction::AccessArray { varId, dest, indexCount } => {
+                push64BitVal(program, *varId);
+                program.extend([*dest, *indexCount]);
+            },
+            Instruction::AccessElementEnd { reg } => program.push(*reg),
+            Instruction::CopyVarToNewMemoryBlock { varType, varId } => {
+                program.push(*varType as u8);
+                push64BitVal(program, *varId);
+            },
+            Instruction::Return { returnType, reg } => program.extend([*returnType as u8, *reg]),
+            Instruction::LoadArrayDimensions { dimensions } => {
+                program.push(dimensions.len() as u8);
+                program.extend(dimensions);
+            },
+            Instruction::MakeVariant { reg, varType, tag, fields } => {
+                program.extend([*reg, *varType as u8, *tag, fields.len() as u8]);
+                for (fieldReg, fieldType) in fields {
+                    program.extend([*fieldReg, *fieldType as u8]);
+                }
+            },
+            Instruction::LoadElement { reg, varType, listReg, index } => program.extend([*reg, *varType as u8, *listReg, *index]),
+            Instruction::TestTag { reg, tag } => program.extend([*reg, *tag]),
+            Instruction::TestLength { reg, length } => program.extend([*reg, *length]),
+            Instruction::TestEqual { reg1, varType, reg2 } => program.extend([*reg1, *varType as u8, *reg2]),
+            Instruction::Jump { target } | Instruction::JumpIfFalse { target } => push64BitVal(program, *target),
+            Instruction::DropVars { count } => program.push(*count),
+            Instruction::LoadIndexed { reg, varType, listReg, indexReg } => program.extend([*reg, *varType as u8, *listReg, *indexReg]),
+            Instruction::Unwrap { reg, varType, optionalReg } => program.extend([*reg, *varType as u8, *optionalReg]),
+            Instruction::Spill { reg, slot } | Instruction::Reload { reg, slot } => program.extend([*reg, *slot]),
+            Instruction::LoadConstant { reg, constId } => {
+                program.push(*reg);
+                push64BitVal(program, *constId);
+            },
+            Instruction::EndFunc | Instruction::EndParamLoad | Instruction::EndArray | Instruction::Print | Instruction::AccessElementBegin
+                | Instruction::LoadMultiDimensionalArrayElement | Instruction::EndMultiDimensionalArrayElement | Instruction::EndArrayDimensions => {}
+        }
+    }
+
+    /// Reads the instruction that starts at pc. Returns the instruction and the number of bytes it takes
+    pub fn Decode(program: &[u8], pc: usize) -> (Instruction, usize) {
+        return Instruction::TryDecode(program, pc).unwrap_or_else(|x| panic!("{}", x));
+    }
+
+    /// Like Decode, but fails instead of panicking on bytes that are not a valid instruction
+    pub fn TryDecode(program: &[u8], pc: usize) -> Result<(Instruction, usize), String> {
+        let mut reader = OperandReader { program: program, pos: pc };
+        let byte = reader.Byte()?;
+        let opCode: OpCodes = num::FromPrimitive::from_u8(byte).ok_or_else(|| format!("Unkown opcode: {}", byte))?;
+        let instruction = match opCode {
+            OpCodes::OpLoadReg => Instruction::LoadReg { src: reader.Byte()?, dst: reader.Byte()? },
+            OpCodes::OpLoadScalar => {
+                let reg = reader.Byte()?;
+                let varType = reader.VarType()?;
+                let value = if varType == VarTypes::CharType { reader.Byte()? as u64 } else { reader.Val64()? };
+                Instruction::LoadScalar { reg, varType, value }
+            },
+            OpCodes::OpAdd => Instruction::Add { reg1: reader.Byte()?, reg2: reader.Byte()? },
+            OpCodes::OpSub => Instruction::Sub { reg1: reader.Byte()?, reg2: reader.Byte()? },
+            OpCodes::OpMul => Instruction::Mul { reg1: reader.Byte()?, reg2: reader.Byte()? },
+            OpCodes::OpDiv => Instruction::Div { reg1: reader.Byte()?, reg2: reader.Byte()? },
+            OpCodes::OpNewVar => Instruction::NewVar { reg: reader.Byte()?, varType: reader.VarType()? },
+            OpCodes::OpLoadVar => Instruction::LoadVar { reg: reader.Byte()?, varType: reader.VarType()?, varId: reader.Val64()? },
+            OpCodes::OpStartFunc => {
+                let paramCount = reader.Byte()?;
+                let mut paramTypes = Vec::<VarTypes>::new();
+                for _ in 0..paramCount {
+                    match reader.Instruction()? {
+                        Instruction::AddFuncParameter { varType } => paramTypes.push(varType),
+                        other => return Err(format!("Expected a function parameter, found {:?}", other))
+                    }
+                }
+                Instruction::StartFunc { paramTypes, returnType: reader.VarType()? }
+            },
+            OpCodes::OpAddFuncParameter => Instruction::AddFuncParameter { varType: reader.VarType()? },
+            OpCodes::OpEndFunc => Instruction::EndFunc,
+            OpCodes::OpCallFunc => Instruction::CallFunc { funcId: reader.Val64()? },
+            OpCodes::OpEndParamLoad => Instruction::EndParamLoad,
+            OpCodes::OpLoadArray => {
+                let reg = reader.Byte()?;
+                let elementType = reader.VarType()?;
+                let dimensions = match reader.Instruction()? {
+                    Instruction::LoadArrayDimensions { dimensions } => dimensions,
+                    other => return Err(format!("Expected array dimensions, found {:?}", other))
+                };
+                if reader.Instruction()? != Instruction::EndArrayDimensions {
+                    return Err("Expected the end of the array dimensions".to_string());
+                }
+                let elementCount = reader.Val64()?;
+                let elements = (0..elementCount).map(|_| if elementType == VarTypes::CharType { reader.Byte().map(|x| x as u64) } else { reader.Val64() }).collect::<Result<_, _>>()?;
+                Instruction::LoadArray { reg, elementType, dimensions, elements }
+            },
+            OpCodes::OpEndArray => Instruction::EndArray,
+            OpCodes::OpPrint => Instruction::Print,
+            OpCodes::OpAccessArray => Instruction::AccessArray { varId: reader.Val64()?, dest: reader.Byte()?, indexCount: reader.Byte()? },
+            OpCodes::OpAccessElementBegin => Instruction::AccessElementBegin,
+            OpCodes::OpAccessElementEnd => Instruction::AccessElementEnd { reg: reader.Byte()? },
+            OpCodes::OpCopyVarToNewMemoryBlock => Instruction::CopyVarToNewMemoryBlock { varType: reader.VarType()?, varId: reader.Val64()? },
+            OpCodes::OpLoadMultiDimensionalArrayElement => Instruction::LoadMultiDimensionalArrayElement,
+            OpCodes::OpEndMultiDimensionalArrayElement => Instruction::EndMultiDimensionalArrayElement,
+            OpCodes::OpReturn => Instruction::Return { returnType: reader.VarType()?, reg: reader.Byte()? },
+            OpCodes::OpLoadArrayDimensions => {
+                let count = reader.Byte()?;
+                Instruction::LoadArrayDimensions { dimensions: (0..count).map(|_| reader.Byte()).collect::<Result<_, _>>()? }
+            },
+            OpCodes::OpEndArrayDimensions => Instruction::EndArrayDimensions,
+            OpCodes::OpMakeVariant => {
+                let reg = reader.Byte()?;
+                let varType = reader.VarType()?;
+                let tag = reader.Byte()?;
+                let fieldCount = reader.Byte()?;
+                let fields = (0..fieldCount).map(|_| Ok((reader.Byte()?, reader.VarType()?))).collect::<Result<_, String>>()?;
+                Instruction::MakeVariant { reg, varType, tag, fields }
+            },
+            OpCodes::OpLoadElement => Instruction::LoadElement { reg: reader.Byte()?, varType: reader.VarType()?, listReg: reader.Byte()?, index: reader.Byte()? },
+            OpCodes::OpTestTag => Instruction::TestTag { reg: reader.Byte()?, tag: reader.Byte()? },
+            OpCodes::OpTestLength => Instruction::TestLength { reg: reader.Byte()?, length: reader.Byte()? },
+            OpCodes::OpTestEqual => Instruction::TestEqual { reg1: reader.Byte()?, varType: reader.VarType()?, reg2: reader.Byte()? },
+            OpCodes::OpJump => Instruction::Jump { target: reader.Val64()? },
+            OpCodes::OpJumpIfFalse => Instruction::JumpIfFalse { target: reader.Val64()? },
+            OpCodes::OpDropVars => Instruction::DropVars { count: reader.Byte()? },
+            OpCodes::OpLoadIndexed => Instruction::LoadIndexed { reg: reader.Byte()?, varType: reader.VarType()?, listReg: reader.Byte()?, indexReg: reader.Byte()? },
+            OpCodes::OpUnwrap => Instruction::Unwrap { reg: reader.Byte()?, varType: reader.VarType()?, optionalReg: reader.Byte()? },
+            OpCodes::OpSpill => Instruction::Spill { reg: reader.Byte()?, slot: reader.Byte()? },
+            OpCodes::OpReload => Instruction::Reload { reg: reader.Byte()?, slot: reader.Byte()? },
+            OpCodes::OpLoadConstant => Instruction::LoadConstant { reg: reader.Byte()?, constId: reader.Val64()? },
+        };
+        return Ok((instruction, reader.pos - pc));
+    }
+
+    /// Reads every instruction of a program in order
+    pub fn DecodeProgram(program: &[u8]) -> Vec<Instruction> {
+        let mut instructions = Vec::<Instruction>::new();
+        let mut pc = 0;
+        while pc < program.len() {
+            let (instruction, length) = Instruction::Decode(program, pc);
+            instructions.push(instruction);
+            pc += length;
+        }
+        return instructions;
+    }
+}
+
+/// Marks the bytes of DecodedProgram::starts that are inside an instruction
+const noInstruction: u32 = u32::MAX;
+
+/// A program decoded once, so running it does not read its bytes again. The VM still counts pc in bytes,
+/// so jump targets, errors and the debugger see the same pcs as the bytes
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DecodedProgram {
+    /// Every instruction in order with the number of bytes it takes
+    instructions: Vec<(Instruction, usize)>,
+    /// Index in instructions of the instruction that starts at each byte, noInstruction inside an instruction
+    starts: Vec<u32>
+}
+
+impl DecodedProgram {
+    /// Decodes every instruction of a program. Fails with the pc and reason of the first bytes that are not an instruction
+    pub fn Decode(program: &[u8]) -> Result<DecodedProgram, (usize, String)> {
+        let mut decoded = DecodedProgram::default();
+        decoded.Append(program)?;
+        return Ok(decoded);
+    }
+
+    /// Decodes the instructions of a program that grew past the bytes decoded so far. Nothing is added if the new bytes
+    /// are not all instructions
+    pub fn Append(&mut self, program: &[u8]) -> Result<(), (usize, String)> {
+        let decodedLen = self.Len();
+        let mut instructions = Vec::<(Instruction, usize)>::new();
+        let mut starts = vec![noInstruction; program.len().saturating_sub(decodedLen)];
+        let mut pc = decodedLen;
+        while pc < program.len() {
+            let (instruction, length) = Instruction::TryDecode(program, pc).map_err(|x| (pc, x))?;
+            starts[pc - decodedLen] = (self.instructions.len() + instructions.len()) as u32;
+            instructions.push((instruction, length));
+            pc += length;
+        }
+        self.instructions.extend(instructions);
+        self.starts.extend(starts);
+        return Ok(());
+    }
+
+    /// Number of bytes in the program
+    pub fn Len(&self) -> usize {
+        return self.starts.len();
+    }
+
+    /// Returns the instruction that starts at pc and the number of bytes it takes, or None if no instruction starts there
+    #[inline]
+    pub fn At(&self, pc: usize) -> Option<&(Instruction, usize)> {
+        let index = *self.starts.get(pc)?;
+        return self.instructions.get(index as usize);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::{DecodedProgram, Instruction};
+    use crate::codegen::{OpCodes, VarTypes};
+
+    /// One instruction for every OpCode
+    fn everyInstruction() -> Vec<Instruction> {
+        [
+            Instruction::LoadReg { src: 255, dst: 3 },
+            Instruction::LoadScalar { reg: 200, varType: VarTypes::FloatType, value: f64::to_bits(3.75) },
+            Instruction::Add { reg1: 1, reg2: 2 },
+            Instruction::Sub { reg1: 3, reg2: 4 },
+            Instruction::Mul { reg1: 5, reg2: 6 },
+            Instruction::Div { reg1: 7, reg2: 8 },
+            Instruction::NewVar { reg: 9, varType: VarTypes::ArrayType },
+            Instruction::LoadVar { reg: 10, varType: VarTypes::VariantType, varId: 300 },
+            Instruction::StartFunc { paramTypes: [VarTypes::FloatType, VarTypes::VariantType].to_vec(), returnType: VarTypes::FloatType },
+            Instruction::AddFuncParameter { varType: VarTypes::CharType },
+            Instruction::EndFunc,
+            Instruction::CallFunc { funcId: 2 },
+            Instruction::EndParamLoad,
+            Instruction::LoadArray { reg: 11, elementType: VarTypes::FloatType, dimensions: [2].to_vec(), elements: [f64::to_bits(1.0), f64::to_bits(3.75)].to_vec() },
+            Instruction::EndArray,
+            Instruction::Print,
+            Instruction::AccessArray { varId: 4, dest: 12, indexCount: 2 },
+            Instruction::AccessElementBegin,
+            Instruction::AccessElementEnd { reg: 13 },
+            Instruction::CopyVarToNewMemoryBlock { varType: VarTypes::FloatType, varId: 5 },
+            Instruction::LoadMultiDimensionalArrayElement,
+            Instruction::EndMultiDimensionalArrayElement,
+            Instruction::Return { returnType: VarTypes::FloatType, reg: 14 },
+            Instruction::LoadArrayDimensions { dimensions: [2, 3].to_vec() },
+            Instruction::EndArrayDimensions,
+            Instruction::MakeVariant { reg: 15, varType: VarTypes::VariantType, tag: 1, fields: [(16, VarTypes::FloatType), (17, VarTypes::ArrayType)].to_vec() },
+            Instruction::LoadElement { reg: 18, varType: VarTypes::CharType, listReg: 19, index: 2 },
+            Instruction::TestTag { reg: 20, tag: 3 },
+            Instruction::TestLength { reg: 21, length: 4 },
+            Instruction::TestEqual { reg1: 22, varType: VarTypes::ArrayType, reg2: 23 },
+            Instruction::Jump { target: 1000 },
+            Instruction::JumpIfFalse { target: 2000 },
+            Instruction::DropVars { count: 2 },
+            Instruction::LoadIndexed { reg: 24, varType: VarTypes::FloatType, listReg: 25, indexReg: 26 },
+            Instruction::Unwrap { reg: 27, varType: VarTypes::FloatType, optionalReg: 28 },
+            Instruction::Spill { reg: 29, slot: 0 },
+            Instruction::Reload { reg: 29, slot: 0 },
+            Instruction::LoadConstant { reg: 30, constId: 7 },
+        ].to_vec()
+    }
+
+    #[test]
+    fn roundTripEveryInstruction(){
+        let instructions = everyInstruction();
+        let mut program = Vec::<u8>::new();
+        for instruction in &instructions {
+            let start = program.len();
+            instruction.Encode(&mut program);
+            assert_eq!(program[start], instruction.OpCode() as u8);
+            assert_eq!(Instruction::Decode(&program, start), (instruction.clone(), program.len() - start));
+        }
+        assert_eq!(Instruction::DecodeProgram(&program), instructions);
+    }
+
+    #[test]
+    fn everyOpCodeHasAnInstruction(){
+        let opCodes: Vec<u8> = everyInstruction().iter().map(|x| x.OpCode() as u8).collect();
+        assert_eq!(opCodes, (0..=OpCodes::OpLoadConstant as u8).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn roundTripStringArray(){
+        let instruction = Instruction::LoadArray { reg: 0, elementType: VarTypes::CharType, dimensions: [2].to_vec(), elements: ['h' as u64, 'i' as u64].to_vec() };
+        let mut program = Vec::<u8>::new();
+        instruction.Encode(&mut program);
+        assert_eq!(program, [OpCodes::OpLoadArray as u8, 0, VarTypes::CharType as u8, OpCodes::OpLoadArrayDimensions as u8, 1, 2, OpCodes::OpEndArrayDimensions as u8, 0, 0, 0, 0, 0, 0, 0, 2, 'h' as u8, 'i' as u8].to_vec());
+        assert_eq!(Instruction::DecodeProgram(&program), [instruction].to_vec());
+    }
+
+    #[test]
+    #[should_panic(expected = "Program ends in the middle of an instruction")]
+    fn decodeTruncatedInstruction(){
+        Instruction::Decode(&[OpCodes::OpJump as u8, 0, 0], 0);
+    }
+
+    #[test]
+    fn decodedProgramByPc(){
+        let mut program = Vec::<u8>::new();
+        Instruction::LoadScalar { reg: 0, varType: VarTypes::FloatType, value: f64::to_bits(2.0) }.Encode(&mut program);
+        Instruction::Add { reg1: 0, reg2: 0 }.Encode(&mut program);
+        let decoded = DecodedProgram::Decode(&program).unwrap();
+        assert_eq!(decoded.Len(), program.len());
+        assert_eq!(decoded.At(0), Some(&(Instruction::LoadScalar { reg: 0, varType: VarTypes::FloatType, value: f64::to_bits(2.0) }, 11)));
+        assert_eq!(decoded.At(11), Some(&(Instruction::Add { reg1: 0, reg2: 0 }, 3)));
+        // No instruction starts inside another one or past the end
+        assert_eq!((decoded.At(1), decoded.At(12), decoded.At(14)), (None, None, None));
+        program.truncate(program.len() - 1);
+        assert_eq!(DecodedProgram::Decode(&program), Err((11, "Program ends in the middle of an instruction at 13".to_string())));
+    }
+
+    #[test]
+    fn appendToDecodedProgram(){
+        let mut program = Vec::<u8>::new();
+        Instruction::LoadScalar { reg: 0, varType: VarTypes::FloatType, value: f64::to_bits(2.0) }.Encode(&mut program);
+        let mut decoded = DecodedProgram::Decode(&program).unwrap();
+        Instruction::Add { reg1: 0, reg2: 0 }.Encode(&mut program);
+        Instruction::Print.Encode(&mut program);
+        decoded.Append(&program).unwrap();
+        assert_eq!(decoded, DecodedProgram::Decode(&program).unwrap());
+        // Bytes that are not instructions leave the decoded program as it was
+        let before = decoded.clone();
+        program.extend_from_slice(&[OpCodes::OpAdd as u8, 1]);
+        assert_eq!(decoded.Append(&program), Err((15, "Program ends in the middle of an instruction at 17".to_string())));
+        assert_eq!(decoded, before);
+    }
+}
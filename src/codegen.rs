@@ -1,42 +1,54 @@
 #![allow(non_snake_case)]
 #![allow(unused_parens)]
-use std::{array, collections::{HashMap, btree_map::Values}, hash::Hash, u16};
+use std::{array, collections::{HashMap, btree_map::Values}, fmt, hash::Hash, io::Write, rc::Rc, time::Instant, u16};
 
-//TODO: Change how VarType + Reg code is stored in memory (1 byte for VarType, another for Reg)
-//TODO: Add pub constant for bit masking values
-//TODO: Add type aliases
 //TODO: Add Handing of type[] variable declares
 
-use crate::parser::ExprAST;
+use crate::parser::{ExprAST, PatternAST};
 use crate::lexer::Token;
-use num::{self, range};
+use crate::bytecode::{DecodedProgram, Instruction};
+use crate::module::{Constant, FunctionEntry, Module};
+use crate::vmerror::{CallFrame, VmError, VmErrorKind};
+use crate::verifier;
+use crate::heap::{Heap, HeapObject};
+use crate::debugger::{Debugger, SourceMap};
+use crate::output::Sink;
+use crate::profiler::Profiler;
+use crate::optimizer;
+use num;
 use num_derive::{self, FromPrimitive};
 use multimap::MultiMap;
 
 use regex::Regex;
 
-const bitRegMask: u8 = 7;
-const bitTypeValMask: u8 = 31;
-const bitRegShift: u8 = 5;
-const regResult: usize = 8;
-
-type ToastLangList = (VarTypes, Vec<u64>, Vec<u8>);
-type ToastLangVariable = (VarTypes, u64);
-type ToastLangFunction = (usize, Vec<VarTypes>, VarTypes);
+/// Number of registers each function call gets
+pub(crate) const regWindowSize: usize = 256;
+/// Register that holds the result of the last operation. It is shared by every function call
+pub(crate) const regResult: usize = regWindowSize - 1;
+/// Default for VMCore::maxCallDepth. Calls run on the VM's own frame stack, so this only bounds memory: every call takes a 4KB window of registers
+pub const defaultMaxCallDepth: usize = 10000;
+/// Reading the clock is slow, so the deadline is only checked once every this many instructions
+const deadlineCheckInterval: u64 = 256;
+
+type ToastLangList = HeapObject;
+/// A value tagged with its type
+pub type ToastLangValue = (VarTypes, u64);
+type ToastLangVariable = ToastLangValue;
+pub(crate) type ToastLangFunction = (usize, usize, Vec<VarTypes>, VarTypes);
 
 // Holds memory of function
 #[derive(Debug, Clone)]
 pub struct MemoryBlock {
     pub variableLookup: HashMap<u64, ToastLangVariable>,
-    pub listLookup: Vec<ToastLangList>
-    
+    /// Register values saved by the compiler when it runs out of registers
+    pub spillSlots: Vec<ToastLangValue>
 }
 
 impl MemoryBlock{
     pub fn new() -> Self{
         MemoryBlock {
             variableLookup: HashMap::new(),
-            listLookup: Vec::new()
+            spillSlots: Vec::new()
         }
     }
 }
@@ -44,559 +56,812 @@ impl MemoryBlock{
 //Holds  cpu function of core
 #[derive(Debug)]
 pub struct VMCore {
-    pub registers: [u64; 9],
+    /// Each register holds a value tagged with its type. Every function call gets its own window of registers
+    pub registers: Vec<ToastLangValue>,
+    /// Index of the first register of the current window
+    pub regWindowBase: usize,
     pub pc: usize,
     pub cond: u8,
     pub memoryList: Vec<MemoryBlock>,
-    /// Key is function Id, Value is (Start pc value, list of param types, Return Type)
+    /// Lists, strings and variants. Registers and variables hold their ids
+    pub heap: Heap,
+    /// Key is function Id, Value is (Offset in functionCode, Length, list of param types, Return Type). System functions have a length of 0
     pub funcList: MultiMap<usize, ToastLangFunction>,
     pub curMemoryId: usize,
-    pub curFunctionId: usize,
-    pub curType: VarTypes,
+    /// Constant pool of the program being run
+    pub constants: Vec<Constant>,
+    /// Bodies of the functions in funcList. Shared so a body can run while the VM changes
+    pub functionCode: Rc<Vec<u8>>,
+    /// Decoded bodies of the functions in funcList by offset in functionCode. A body is decoded the first time it is called
+    pub functionBodies: HashMap<usize, Rc<DecodedProgram>>,
+    /// Top level code loaded so far, decoded. It only grows until a program is run from pc 0, so processProgram only decodes
+    /// the code added since the last run
+    pub program: Rc<DecodedProgram>,
+    /// Calls that are running, innermost last
+    pub callStack: Vec<CallFrame>,
+    /// Names of the functions in funcList, used to report errors
+    pub funcNames: HashMap<usize, String>,
+    /// Number of instructions the VM may still run. None means no limit
+    pub fuel: Option<u64>,
+    /// Time at which the VM stops running. None means no deadline
+    pub deadline: Option<Instant>,
+    /// Number of instructions run so far
+    pub instructionCount: u64,
+    /// Most calls that can run at once, so runaway recursion stops before it uses up memory
+    pub maxCallDepth: Option<usize>,
+    /// Instructions whose operands are still being worked out by the instructions that follow them, innermost last
+    openInstructions: Vec<OpenInstruction>,
+    /// Checked before every instruction when set, so the VM can pause at breakpoints and step
+    pub debugger: Option<Debugger>,
+    /// Where the program's output goes. Standard output by default
+    pub output: Sink,
+    /// Where messages about what the VM is doing go, such as the result of each arithmetic instruction. None turns them off
+    pub trace: Option<Sink>,
+    /// Counts instructions and times calls when set
+    pub profiler: Option<Profiler>,
 }
 
 impl VMCore {
     pub fn getSystemFunctions() -> MultiMap<usize, ToastLangFunction> {
         let mut systemFunctions : MultiMap<usize, ToastLangFunction> = MultiMap::new();
 
-        systemFunctions.insert(SystemFunctions::printFunction as usize, (0, [VarTypes::FloatType].to_vec(), VarTypes::NullType ));
-        systemFunctions.insert(SystemFunctions::printFunction as usize, (0, [VarTypes::CharType].to_vec() , VarTypes::NullType ));
-        systemFunctions.insert(SystemFunctions::printFunction as usize, (0, [VarTypes::ArrayType].to_vec(), VarTypes::NullType ));
-        systemFunctions.insert(SystemFunctions::printFunction as usize, (0, [VarTypes::ArrayRef].to_vec() , VarTypes::NullType ));
+        systemFunctions.insert(SystemFunctions::printFunction as usize, (0, 0, [VarTypes::FloatType].to_vec(), VarTypes::NullType ));
+        systemFunctions.insert(SystemFunctions::printFunction as usize, (0, 0, [VarTypes::CharType].to_vec() , VarTypes::NullType ));
+        systemFunctions.insert(SystemFunctions::printFunction as usize, (0, 0, [VarTypes::ArrayType].to_vec(), VarTypes::NullType ));
+        systemFunctions.insert(SystemFunctions::printFunction as usize, (0, 0, [VarTypes::ArrayRef].to_vec() , VarTypes::NullType ));
 
         return systemFunctions.clone();
     }
 
-    pub fn get64BitVal(&mut self,  program: &Vec<u8>) -> u64 {
-        let mut num: u64 = 0;
-        //Floats seperated in to 8 8 bit chunks. Currenytly set to 64 bits. Might change if porting to a 32 bit system.
-        for i in range(0, 8){
-            self.pc += 1;
-            num = (num << 8 * (i > 0) as u8 ) | program[self.pc]as u64;
-        }
-        return num;
-    }
-
     pub fn new() -> Self{
         let mut vm = VMCore {
-            registers: [0; 9],
+            registers: vec![(VarTypes::NullType, 0); regWindowSize],
+            regWindowBase: 0,
             pc: 0,
             cond: 0,
             memoryList: Vec::<MemoryBlock>::new(),
+            heap: Heap::new(),
             funcList: VMCore::getSystemFunctions().clone(),
             curMemoryId: 0,
-            curFunctionId: 1,
-            curType: VarTypes::FloatType
+            constants: Vec::new(),
+            functionCode: Rc::new(Vec::new()),
+            functionBodies: HashMap::new(),
+            program: Rc::new(DecodedProgram::default()),
+            callStack: Vec::new(),
+            funcNames: HashMap::from([(SystemFunctions::printFunction as usize, "print".to_string())]),
+            fuel: None,
+            deadline: None,
+            instructionCount: 0,
+            maxCallDepth: Some(defaultMaxCallDepth),
+            openInstructions: Vec::new(),
+            debugger: None,
+            output: Sink::Stdout(),
+            trace: None,
+            profiler: None
         };
         vm.memoryList.push(MemoryBlock::new());
         return vm;
     }
 
-    pub fn processProgram(&mut self, program: &Vec<u8>){
-        let mut byteCode;
-        while self.pc < program.len(){
-            byteCode = program[self.pc];
-            self.ConsumeByteCode(program, byteCode);
-            self.pc = self.pc + 1;
+    /// Runs the top level code of the module from pc, after loading what is new in it with LoadModule. The module is checked by
+    /// verifier::VerifyAdded first. A module run after another one is taken to be that module with more code and functions added,
+    /// as the REPL compiles them, unless pc is 0. On an error the VM is left as it was when the error happened, call Unwind
+    /// before running more code
+    pub fn processProgram(&mut self, module: &Module) -> Result<(), VmError> {
+        // Running from the start, or code shorter than what is loaded, means another program rather than one that grew
+        if self.pc == 0 || module.code.len() < self.program.Len() {
+            self.program = Rc::new(DecodedProgram::default());
+        }
+        // Nothing is loaded or run unless what the module adds is valid. What is loaded already was checked when it was loaded
+        let isLoaded = |funcId: u64| usize::try_from(funcId).is_ok_and(|funcId| self.funcList.contains_key(&funcId));
+        verifier::VerifyAdded(module, isLoaded, &self.program)
+            .map_err(|err| VmError { pc: err.pc, opCode: err.opCode, kind: VmErrorKind::InvalidProgram(err), callStack: Vec::new() })?;
+        self.LoadModule(module)?;
+        // Decoded once, so each step only looks up the instruction at pc
+        let decoded = Rc::make_mut(&mut self.program).Append(&module.code);
+        decoded.map_err(|(pc, err)| self.MakeError(VmErrorKind::InvalidInstruction(err), pc, None))?;
+        if let Some(profiler) = &mut self.profiler {
+            profiler.Start();
+        }
+        let result = self.Run(Rc::clone(&self.program));
+        if let Some(profiler) = &mut self.profiler {
+            profiler.Stop();
         }
+        return result;
     }
 
-    pub fn getArrayInMultiDimensionalArray(&self, memoryId : usize, array_ref: (VarTypes, Vec<u64>, Vec<u8>)) -> Vec<(VarTypes, Vec<u64>, Vec<u8>)>{
-        let mut arr_of_array : Vec<(VarTypes, Vec<u64>, Vec<u8>)> = Vec::new();
-        for array_index in 0..array_ref.1.len() {
-            let x = self.memoryList.get(memoryId).unwrap().listLookup.get(*array_ref.1.get(array_index).unwrap() as usize).unwrap();
-            match &x.0 {
-                VarTypes::ArrayRef => {
-                    arr_of_array = self.getArrayInMultiDimensionalArray(memoryId, x.clone());
+    /// Loads the constants, function bodies and functions of the module that are not loaded yet. The constant pool and
+    /// function code only grow as more code is compiled, so only what is past the loaded ones is new
+    pub(crate) fn LoadModule(&mut self, module: &Module) -> Result<(), VmError> {
+        if module.constants.len() > self.constants.len() {
+            self.constants.extend_from_slice(&module.constants[self.constants.len()..]);
+        }
+        if module.functionCode.len() > self.functionCode.len() {
+            let loadedLen = self.functionCode.len();
+            Rc::make_mut(&mut self.functionCode).extend_from_slice(&module.functionCode[loadedLen..]);
+        }
+        for function in &module.functions {
+            if self.funcList.contains_key(&(function.funcId as usize)) {
+                continue;
+            }
+            let (offset, length) = (function.offset as usize, function.length as usize);
+            let startFunc = self.functionCode.get(offset..offset.saturating_add(length)).map(|body| Instruction::TryDecode(body, 0));
+            match startFunc {
+                Some(Ok((Instruction::StartFunc { paramTypes, returnType }, _))) => {
+                    self.funcList.insert(function.funcId as usize, (offset, length, paramTypes, returnType));
+                    self.funcNames.insert(function.funcId as usize, function.name.clone());
                 },
-                _ => {
-                    arr_of_array.push(x.clone());
-                }
+                _ => return Err(self.MakeError(VmErrorKind::InvalidInstruction(format!("Function {} does not start with OpStartFunc", function.name)), offset, None))
+            }
+        }
+        return Ok(());
+    }
+
+    /// Frees the heap objects that can no longer be reached from the registers in use, the variables or the spill slots.
+    /// Returns the number of freed objects
+    pub fn CollectGarbage(&mut self) -> usize {
+        let registersInUse = std::cmp::min(self.registers.len(), self.regWindowBase + regWindowSize);
+        let roots = self.registers[..registersInUse].iter()
+            .chain(self.memoryList.iter().flat_map(|block| block.variableLookup.values().chain(block.spillSlots.iter())))
+            .copied();
+        return self.heap.Collect(roots.collect::<Vec<ToastLangValue>>());
+    }
+
+    /// Stores an object on the heap. If it would go over a heap limit, the objects that can no longer be reached are collected
+    /// first, so only reachable objects use up the limits. Every live object must be held by a register, variable or spill slot
+    pub fn Alloc(&mut self, object: HeapObject) -> Result<u64, VmErrorKind> {
+        if self.heap.CheckLimits(object.1.len()).is_err() {
+            self.CollectGarbage();
+        }
+        return self.heap.Alloc(object);
+    }
+
+    /// Runs the program from pc to its end. Function bodies run in this loop too while their callers wait on a frame stack,
+    /// so deep recursion does not use up the Rust stack
+    fn Run(&mut self, program: Rc<DecodedProgram>) -> Result<(), VmError> {
+        // (Program, pc of the OpEndParamLoad of the call) of every caller, innermost last
+        let mut frames: Vec<(Rc<DecodedProgram>, usize)> = Vec::new();
+        let mut program = program;
+        loop {
+            // Function bodies end before their OpEndFunc
+            let end = if frames.is_empty() { program.Len() } else { program.Len() - 1 };
+            if self.pc >= end {
+                let Some((caller, returnPc)) = frames.pop() else { return Ok(()) };
+                program = caller;
+                self.pc = returnPc;
+                self.regWindowBase -= regWindowSize;
+                self.EndCall();
+            } else if let Flow::Enter(body) = self.ConsumeByteCode(&program)? {
+                frames.push((std::mem::replace(&mut program, body), self.pc));
+                // Runs from after OpStartFunc
+                self.pc = program.At(0).map_or(0, |x| x.1);
+                continue;
             }
-            arr_of_array.push(array_ref.clone());
+            self.pc += 1;
         }
-        return arr_of_array;
     }
 
+    /// Drops the memory blocks, register windows and calls left behind by an error, so the VM can run more code
+    pub fn Unwind(&mut self) {
+        self.memoryList.truncate(1);
+        self.curMemoryId = 0;
+        self.regWindowBase = 0;
+        self.callStack.clear();
+        self.openInstructions.clear();
+        if let Some(profiler) = &mut self.profiler {
+            profiler.Unwind();
+        }
+    }
 
-    pub fn printScalar(&self, scalarVal: u64, scalarType: VarTypes) {
+    /// Writes a number or char to the output
+    pub fn printScalar(&mut self, scalarVal: u64, scalarType: VarTypes) -> Result<(), VmErrorKind> {
         match scalarType {
             VarTypes::FloatType => {
-                print!("{:?}", f64::from_bits(scalarVal));
+                write!(self.output, "{:?}", f64::from_bits(scalarVal))?;
             },
             VarTypes::CharType => {
-                print!("{:?}", (scalarVal as u8) as char);
+                write!(self.output, "{:?}", (scalarVal as u8) as char)?;
             },
-            _ => println!("Unimplemented type")
+            found => return Err(VmErrorKind::TypeMismatch { expected: [VarTypes::FloatType, VarTypes::CharType].to_vec(), found })
         }
+        return Ok(());
     }
-    
-    pub fn printArray(&self, array_id: usize){
-        let arr = self.memoryList.get(self.curMemoryId).unwrap().listLookup.get( array_id ).unwrap().clone();
+
+    /// Writes a string or list to the output
+    pub fn printArray(&mut self, array_id: usize) -> Result<(), VmErrorKind>{
+        let arr = self.GetList(array_id)?.clone();
         match arr.0 {
             VarTypes::CharType => {
                 let string_vec: Vec<u16> = arr.1.into_iter().map(|x| x as u16).collect();
-                print!("{:?}", String::from_utf16(string_vec.as_slice()).unwrap())
+                write!(self.output, "{:?}", String::from_utf16(string_vec.as_slice()).unwrap())?;
             },
             VarTypes::FloatType => {
-                print!("[");
+                write!(self.output, "[")?;
                 for ele in arr.1 {
-                    self.printScalar(ele, arr.0);
-                    print!(",")
+                    self.printScalar(ele, arr.0)?;
+                    write!(self.output, ",")?;
                 }
-                print!("]");
+                write!(self.output, "]")?;
             },
             VarTypes::ArrayRef => {
-                print!("[");
+                write!(self.output, "[")?;
                 for ele in arr.1 {
-                    self.printArray(ele as usize);
-                    print!(",")
+                    self.printArray(ele as usize)?;
+                    write!(self.output, ",")?;
                 }
-                print!("]");
+                write!(self.output, "]")?;
             }
-            _ => {println!("Unimplemented variable type");}
+            found => return Err(VmErrorKind::TypeMismatch { expected: [VarTypes::CharType, VarTypes::FloatType, VarTypes::ArrayRef].to_vec(), found })
         }
+        return Ok(());
     }
 
-    pub fn ConsumeByteCode(&mut self, program: &Vec<u8>, mut byteCode: u8){
-        let opCode : OpCodes = num::FromPrimitive::from_u8(byteCode).unwrap();
-        match opCode {
-            OpCodes::OpLoadScalar => {
+    /// Writes a message to the trace when tracing is on. A trace that can not be written to is ignored, since it is not part of the program
+    pub fn Trace(&mut self, message: fmt::Arguments) {
+        if let Some(trace) = &mut self.trace {
+            let _ = writeln!(trace, "{}", message);
+        }
+    }
+
+    /// Runs the instruction at pc. pc is left on the last byte of the instruction
+    fn ConsumeByteCode(&mut self, program: &DecodedProgram) -> Result<Flow, VmError> {
+        // Every live object is held by a register, variable or spill slot between instructions
+        if self.heap.NeedsCollect() {
+            self.CollectGarbage();
+        }
+        let instructionPc = self.pc;
+        let (instruction, length) = VMCore::InstructionAt(program, instructionPc).map_err(|kind| self.MakeError(kind, instructionPc, None))?;
+        let opCode = instruction.OpCode();
+        // Markers are part of the instruction that opened them, so they are not counted or paused at on their own
+        let isMarker = matches!(instruction, Instruction::EndParamLoad | Instruction::LoadMultiDimensionalArrayElement | Instruction::EndMultiDimensionalArrayElement
+            | Instruction::AccessElementBegin | Instruction::AccessElementEnd { .. });
+        if !isMarker {
+            if self.debugger.is_some() {
+                self.DebugHook(instructionPc);
+            }
+            // pc is left on the instruction that did not run
+            self.CheckLimits().map_err(|kind| self.MakeError(kind, instructionPc, Some(opCode)))?;
+            self.instructionCount += 1;
+            if let Some(profiler) = &mut self.profiler {
+                profiler.Instruction(opCode);
+            }
+        }
+        self.pc += length - 1;
+        return self.RunInstruction(instruction, instructionPc).map_err(|fault| match fault {
+            Fault::Kind(kind) => self.MakeError(kind, instructionPc, Some(opCode)),
+            Fault::Error(err) => err
+        });
+    }
+
+    /// Lets the debugger pause before the instruction at pc runs. The debugger is taken out of the VM while its pause handler looks at the VM
+    fn DebugHook(&mut self, pc: usize) {
+        let Some(mut debugger) = self.debugger.take() else { return };
+        if let Some(pause) = debugger.Check(self, pc) {
+            debugger.Paused(self, &pause);
+        }
+        self.debugger = Some(debugger);
+    }
+
+    /// Fails if the instruction budget is used up or the deadline has passed. Otherwise uses up one instruction of the budget
+    fn CheckLimits(&mut self) -> Result<(), VmErrorKind> {
+        if self.fuel == Some(0) {
+            return Err(VmErrorKind::OutOfFuel);
+        }
+        if let Some(deadline) = self.deadline {
+            if self.instructionCount.is_multiple_of(deadlineCheckInterval) && Instant::now() >= deadline {
+                return Err(VmErrorKind::DeadlineExceeded);
+            }
+        }
+        if let Some(fuel) = &mut self.fuel {
+            *fuel -= 1;
+        }
+        return Ok(());
+    }
+
+    fn MakeError(&self, kind: VmErrorKind, pc: usize, opCode: Option<OpCodes>) -> VmError {
+        return VmError { kind, pc, opCode, callStack: self.callStack.clone() };
+    }
+
+    /// Returns the instruction that starts at pc and the number of bytes it takes, failing if no instruction starts there
+    #[inline]
+    fn InstructionAt(program: &DecodedProgram, pc: usize) -> Result<&(Instruction, usize), VmErrorKind> {
+        return program.At(pc).ok_or_else(|| match pc < program.Len() {
+            true => VmErrorKind::InvalidInstruction(format!("No instruction starts at {}", pc)),
+            false => VmErrorKind::InvalidInstruction(format!("Program ends at {} in the middle of an instruction", pc))
+        });
+    }
+
+    /// Returns the decoded body of the function at offset in functionCode, decoding it on its first call
+    fn FunctionBody(&mut self, offset: usize, length: usize) -> Result<Rc<DecodedProgram>, VmErrorKind> {
+        if let Some(body) = self.functionBodies.get(&offset) {
+            return Ok(Rc::clone(body));
+        }
+        let bytes = self.functionCode.get(offset..offset + length).ok_or_else(|| VmErrorKind::InvalidInstruction(format!("Function at {} is outside of the function code", offset)))?;
+        let body = Rc::new(DecodedProgram::Decode(bytes).map_err(|(_, err)| VmErrorKind::InvalidInstruction(err))?);
+        self.functionBodies.insert(offset, Rc::clone(&body));
+        return Ok(body);
+    }
+
+    /// Runs a decoded instruction. instructionPc is where the instruction starts
+    fn RunInstruction(&mut self, instruction: &Instruction, instructionPc: usize) -> Result<Flow, Fault> {
+        match *instruction {
+            Instruction::LoadScalar { reg, varType: curType, value } => {
                 // Add check for Scalar Type
                 // Determine action based on scalar type
-                self.pc += 1;
-                byteCode = program[self.pc];
-                // Only grabs the first 5 bits (31 is all first 5 bits as one) of the bytecode since that is where the current value type is
-                let curType: VarTypes = num::FromPrimitive::from_u8((byteCode & bitTypeValMask)).unwrap();
                 match curType {
                     VarTypes::FloatType => {
-                        self.curType = VarTypes::FloatType;
-                        // Shifts byte code by 5 bits to the right. Masks it by 7 (00000111).
-                        let reg = (byteCode >> bitRegShift) & bitRegMask;
-                        let num: u64 = self.get64BitVal(program);
-                        self.registers[reg as usize] = num;
-                        self.registers[regResult] = num;
+                        self.SetRegister(reg, (VarTypes::FloatType, value));
+                        self.registers[regResult] = (VarTypes::FloatType, value);
                         // println!("Float Value: {}", f64::from_bits(self.registers[reg as usize]))
                     },
                     VarTypes::CharType => {
-                        self.curType = VarTypes::CharType;
-                        // Only grabs the first 5 bits (31 is all first 5 bits as one) of the bytecode since that is where the current value type is
-                        let reg = (byteCode >> bitRegShift) & bitRegMask;
-                        self.pc = self.pc + 1;
-                        let charBit = program[self.pc];
-                        self.registers[reg as usize] = charBit as u64;
-                        self.registers[regResult] = charBit as u64;
+                        self.SetRegister(reg, (VarTypes::CharType, value));
+                        self.registers[regResult] = (VarTypes::CharType, value);
                         // println!("Char Value: {:?}", (self.registers[reg as usize] as u8) as char);
                     }
-                    _ => panic!("Unkown Type")
+                    found => return Err(VmErrorKind::TypeMismatch { expected: [VarTypes::FloatType, VarTypes::CharType].to_vec(), found }.into())
                 }
             },
-            OpCodes::OpAdd | OpCodes::OpSub | OpCodes::OpDiv | OpCodes::OpMul => {
-                // Shifts byte code by 9 bits to the right. Masks it by 7 (00000111).
-                self.pc += 1;
-                byteCode = program[self.pc];
-                let reg1 = (byteCode >> bitRegShift) & bitRegMask;
-                match self.curType {
-                    VarTypes::FloatType => {
-                        // Mask bytecode by 7 (00000111)
-                        let reg2 = byteCode & bitRegMask;
-                        match opCode {
-                            OpCodes::OpAdd => {self.registers[reg1 as usize] = f64::to_bits(f64::from_bits(self.registers[reg1 as usize]) + f64::from_bits(self.registers[reg2 as usize]));},
-                            OpCodes::OpSub => {self.registers[reg1 as usize] = f64::to_bits(f64::from_bits(self.registers[reg1 as usize]) - f64::from_bits(self.registers[reg2 as usize]));},
-                            OpCodes::OpMul => {self.registers[reg1 as usize] = f64::to_bits(f64::from_bits(self.registers[reg1 as usize]) * f64::from_bits(self.registers[reg2 as usize]));},
-                            OpCodes::OpDiv => {self.registers[reg1 as usize] = f64::to_bits(f64::from_bits(self.registers[reg1 as usize]) / f64::from_bits(self.registers[reg2 as usize]));},
-                            _ => {print!("Unkown Operation")}
-                        }
-                        println!("Answer: {}", f64::from_bits(self.registers[reg1 as usize]));
-                        self.registers[regResult] = self.registers[reg1 as usize];
+            Instruction::LoadConstant { reg, constId } => {
+                match self.constants.get(constId as usize).ok_or(VmErrorKind::UnknownConstant(constId))?.clone() {
+                    Constant::Number(value) => {
+                        self.SetRegister(reg, (VarTypes::FloatType, f64::to_bits(value)));
+                        self.registers[regResult] = (VarTypes::FloatType, f64::to_bits(value));
+                    },
+                    Constant::String(value) => {
+                        let dimensions = vec![value.len() as u8];
+                        let list_id = self.Alloc((VarTypes::CharType, value.iter().map(|x| *x as u64).collect(), dimensions))?;
+                        self.SetRegister(reg, (VarTypes::ArrayType, list_id));
+                        self.registers[regResult] = (VarTypes::ArrayType, list_id);
+                    }
+                }
+            },
+            Instruction::Add { reg1, reg2 } | Instruction::Sub { reg1, reg2 } | Instruction::Mul { reg1, reg2 } | Instruction::Div { reg1, reg2 } => {
+                let opCode = instruction.OpCode();
+                // The operation is picked from the types of both operands
+                let (type1, val1) = self.GetRegister(reg1);
+                let (type2, val2) = self.GetRegister(reg2);
+                match (type1, type2) {
+                    (VarTypes::FloatType, VarTypes::FloatType) => {
+                        let (num1, num2) = (f64::from_bits(val1), f64::from_bits(val2));
+                        let answer = match opCode {
+                            OpCodes::OpAdd => num1 + num2,
+                            OpCodes::OpSub => num1 - num2,
+                            OpCodes::OpMul => num1 * num2,
+                            _ => num1 / num2
+                        };
+                        self.Trace(format_args!("Answer: {}", answer));
+                        self.SetRegister(reg1, (VarTypes::FloatType, f64::to_bits(answer)));
                     },
-                    VarTypes::StringType => {
-                        let reg2 = byteCode & bitRegMask;
-                        match opCode {
-                            OpCodes::OpAdd => {
-                                let currMemoryList = self.memoryList.get(self.curMemoryId).unwrap();
-                                let right_arr : Vec<u8> = currMemoryList.listLookup.get(self.registers[reg1 as usize] as usize).unwrap().1.clone().into_iter().map(|x| x as u8).collect();
-                                let left_arr : Vec<u8> = currMemoryList.listLookup.get(self.registers[reg2 as usize] as usize).unwrap().1.clone().into_iter().map(|x| x as u8).collect();
-                                let arr: Vec<u8> = [left_arr, right_arr].concat();
-                                println!("{:?}", String::from_utf8(arr).unwrap());
-                            }
-                            _ => {print!("Unkown Operation")}
+                    (VarTypes::ArrayType, VarTypes::ArrayType) if opCode == OpCodes::OpAdd => {
+                        let left_arr = self.GetList(val1 as usize)?;
+                        let right_arr = self.GetList(val2 as usize)?;
+                        if left_arr.0 != VarTypes::CharType || right_arr.0 != VarTypes::CharType {
+                            return Err(VmErrorKind::UnsupportedOperands { left: left_arr.0, right: right_arr.0 }.into());
                         }
+                        let arr: Vec<u64> = [left_arr.1.clone(), right_arr.1.clone()].concat();
+                        let arr_len = arr.len() as u8;
+                        let list_id = self.Alloc((VarTypes::CharType, arr, vec![arr_len]))?;
+                        self.SetRegister(reg1, (VarTypes::ArrayType, list_id));
                     },
-                    _ => {println!("Unkown number type")}
+                    _ => return Err(VmErrorKind::UnsupportedOperands { left: type1, right: type2 }.into())
                 }
+                self.registers[regResult] = self.GetRegister(reg1);
             },
-            OpCodes::OpLoadReg => {
-                let sourceRegNum = (byteCode >> bitRegShift) & 0x0F;
-                let destRegNum = (byteCode) & bitRegMask;
-                self.registers[destRegNum as usize] = self.registers[sourceRegNum as usize];
+            Instruction::LoadReg { src: sourceRegNum, dst: destRegNum } => {
+                self.SetRegister(destRegNum, self.GetRegister(sourceRegNum));
             },
-            OpCodes::OpNewVar => {
-                self.pc += 1;
-                let reg = (program[self.pc] >> bitRegShift) & bitRegMask;
+            Instruction::NewVar { reg, varType: variableType } => {
+                let value = self.GetRegister(reg);
                 let curMemory = self.memoryList.get_mut(self.curMemoryId).unwrap();
-                let variableType: VarTypes = num::FromPrimitive::from_u8((program[self.pc] & 0x0F)).unwrap();
-                curMemory.variableLookup.insert(curMemory.variableLookup.len() as u64, ( variableType, self.registers[reg as usize]));
-
-                // if variableType == VarTypes::ArrayType {
-                //     self.pc += 1;
-                //     let dim_num = program[self.pc] as usize;
-                //     let mut dim_list: Vec<u8> = vec![0; dim_num];
-                //     self.pc += 1;
-                //     for i in 0..dim_num {
-                //         dim_list[i] = program[self.pc+i];
-                //     }
-                //     self.pc += dim_num - 1;
-                //     curMemory.listLookup.get_mut(self.registers[reg as usize] as usize).unwrap().2 = dim_list;
-                // }
-            },
-            OpCodes::OpLoadVar => {
-                self.pc += 1;
-                let reg = (program[self.pc]  >> bitRegShift) & bitRegMask;
-                let typeVal : VarTypes = num::FromPrimitive::from_u8(program[self.pc]  & 31).unwrap();
-                let varId: u64 = self.get64BitVal(program);
-                self.registers[reg as usize] = self.memoryList.get(self.curMemoryId).unwrap().variableLookup.get(&varId).unwrap().1;
-                self.registers[regResult] = self.registers[reg as usize];
-                match typeVal {
-                    VarTypes::FloatType => {
-                        println!("Variable Value: {}", f64::from_bits(self.registers[reg as usize]))
-                    },
-                    VarTypes::CharType => {
-                        println!("Char Value: {:?}", (self.registers[reg as usize] as u8) as char);
-                    },
-                    VarTypes::ArrayType => {
-                        let arr_data = self.memoryList.get(self.curMemoryId).unwrap().listLookup.get(self.registers[reg as usize] as usize).unwrap().clone();
-                        match arr_data.0 {
-                            VarTypes::CharType => {
-                                let string_vec: Vec<u16> = arr_data.1.into_iter().map(|x| x as u16).collect();
-                                println!("String Value: {:?}", String::from_utf16(string_vec.as_slice()).unwrap())
-                            }
-                            _ => {println!("Unimplemented type")}
-                        }
-                    }
-                    _ => {println!("Unkown variable type")}
+                if value.0 != variableType {
+                    return Err(VmErrorKind::TypeMismatch { expected: [variableType].to_vec(), found: value.0 }.into());
                 }
+                curMemory.variableLookup.insert(curMemory.variableLookup.len() as u64, value);
             },
-            OpCodes::OpStartFunc => {
-                //Move from opCode
-                self.pc += 1;
-                //Grab parameter number (Max 255)
-                let param_num = program[self.pc];
-                let mut paramTypes = Vec::<VarTypes>::new();
-                for _ in 0..param_num{
-                    self.pc = self.pc + 1;
-                    if program[self.pc] == OpCodes::OpAddFuncParameter as u8 {
-                        self.pc = self.pc + 1;
-                        let varType : VarTypes = num::FromPrimitive::from_u8(program[self.pc]).unwrap();
-                        paramTypes.push(varType);
-                    }
+            Instruction::LoadVar { reg, varType: _, varId } => {
+                let (typeVal, value) = *self.memoryList.get(self.curMemoryId).unwrap().variableLookup.get(&varId).ok_or(VmErrorKind::UnknownVariable(varId))?;
+                self.SetRegister(reg, (typeVal, value));
+                self.registers[regResult] = (typeVal, value);
+                if self.trace.is_some() {
+                    let text = Debugger::FormatValue(self, (typeVal, value));
+                    self.Trace(format_args!("Variable Value: {}", text));
                 }
-                self.pc += 1;
-                let funcVarType: VarTypes = num::FromPrimitive::from_u8(program[self.pc]).unwrap();
-                self.pc += 1;
-                let startPCval = self.pc;
-                self.funcList.insert(self.curFunctionId, (startPCval, paramTypes.clone(), funcVarType));
-                while program[self.pc] != (OpCodes::OpEndFunc as u8) {
-                    self.pc += 1;
-                }
-                self.curFunctionId += 1;
-
-                // self.pc += 1;
-                
             },
-            OpCodes::OpCallFunc => {
+            Instruction::StartFunc { .. } => {
+                // Function definitions belong in the function table
+                return Err(VmErrorKind::MisplacedInstruction(OpCodes::OpStartFunc).into());
+            },
+            Instruction::CallFunc { funcId: function_id } => {
                 //TODO: Replace 1 with function that counts system functions
-                let function_id = self.get64BitVal(program);
-                let func_data = self.funcList.get_vec(&(function_id as usize)).unwrap_or_else(|| {panic!("Unkown function")}).clone();
-                self.pc += 1;
+                if !self.funcList.contains_key(&(function_id as usize)) {
+                    return Err(VmErrorKind::UnknownFunction(function_id).into());
+                }
+                // Calls made while loading parameters are nested too, so the depth is counted in memory blocks
+                if let Some(maxCallDepth) = self.maxCallDepth.filter(|max| self.memoryList.len() > *max) {
+                    return Err(VmErrorKind::CallDepthLimit(maxCallDepth).into());
+                }
+                // The instructions up to OpEndParamLoad load the parameters into the new memory block
                 self.memoryList.push(MemoryBlock::new());
                 self.curMemoryId += 1;
-                while program[self.pc] != OpCodes::OpEndParamLoad as u8{
-                    self.ConsumeByteCode(program, program[self.pc]);
-                    self.pc += 1;
-                }
-                let oldPC = self.pc;
-                self.pc = func_data[0].0;
-                if func_data[0].0 != 0 {
-                    while program[self.pc]  != (OpCodes::OpEndFunc as u8) {
-                        self.ConsumeByteCode(program, program[self.pc]);
-                        self.pc += 1;
-                    }
-                }else{
-                    let systemFunction : SystemFunctions = num::FromPrimitive::from_usize(func_data[0].0).unwrap();
-                    match systemFunction {
-                        SystemFunctions::printFunction => {
-                            let param_id : u64 = (self.memoryList.get(self.curMemoryId).unwrap().variableLookup.len()-1) as u64;
-                            let firstParam = self.memoryList.get(self.curMemoryId).unwrap().variableLookup.get(&param_id).unwrap();
-                            // self.registers[8 as usize] = firstParam.1;
-                            if func_data.contains( &(0 as usize, [firstParam.0].to_vec(), VarTypes::NullType) ){
-                                if firstParam.0 == VarTypes::ArrayType {
-                                    self.printArray(firstParam.1 as usize);
-                                }else{
-                                    // self.printScalar(firstParam.1, firstParam.0);
-                                    self.printScalar(self.registers[regResult], firstParam.0);
-                                }
-                                print!("\n");
-                            }
-                        }
+                self.openInstructions.push(OpenInstruction::Call { funcId: function_id, callPc: instructionPc });
+            },
+            Instruction::EndParamLoad => {
+                let Some(OpenInstruction::Call { funcId, callPc }) = self.openInstructions.pop() else {
+                    return Err(VmErrorKind::MisplacedInstruction(OpCodes::OpEndParamLoad).into());
+                };
+                // Errors of the call belong to its OpCallFunc
+                return self.EnterCall(funcId, callPc).map_err(|kind| Fault::Error(self.MakeError(kind, callPc, Some(OpCodes::OpCallFunc))));
+            },
+            Instruction::LoadArray { reg, elementType, dimensions: ref dim_arr, ref elements } => {
+                match elementType {
+                    VarTypes::CharType | VarTypes::FloatType => {
+                        let arr_len = elements.len() as u8;
+                        let list_id = self.Alloc((elementType, elements.clone(), vec![arr_len]))?;
+                        self.SetRegister(reg, (VarTypes::ArrayType, list_id));
+                        self.registers[regResult] = (VarTypes::ArrayType, list_id);
+                        self.openInstructions.push(OpenInstruction::List);
+                    },
+                    VarTypes::ArrayType => {
+                        // Each inner list is loaded in an OpLoadMultiDimensionalArrayElement block, and the list is made at OpEndArray
+                        self.openInstructions.push(OpenInstruction::NestedList { reg, dimensions: dim_arr.clone(), elementType: VarTypes::NullType, elements: Vec::new() });
                     }
+                    found => return Err(VmErrorKind::TypeMismatch { expected: [VarTypes::CharType, VarTypes::FloatType, VarTypes::ArrayType].to_vec(), found }.into())
                 }
-                self.pc = oldPC;
-                self.memoryList.pop();
-                self.curMemoryId -= 1;
-
             },
-            OpCodes::OpLoadArray => {
-                self.pc += 1;
-                byteCode = program[self.pc];
-                self.curType = VarTypes::ArrayType;
-                let reg = (byteCode >> 5) & 7;
-                let elementType: VarTypes = num::FromPrimitive::from_u8(byteCode & bitTypeValMask).unwrap();
-                let mut array_vec = Vec::<u8>::new();
-                
-                let mut dim_arr = Vec::<u8>::new();
-                self.pc += 2;
-                let dim_count = program[self.pc];
-                self.pc += 1;
-                for i in 0..dim_count {
-                    dim_arr.push(program[self.pc]);
-                    self.pc += 1;
+            Instruction::EndMultiDimensionalArrayElement => {
+                if !matches!(self.openInstructions.last(), Some(OpenInstruction::NestedList { .. })) {
+                    return Err(VmErrorKind::MisplacedInstruction(OpCodes::OpEndMultiDimensionalArrayElement).into());
                 }
-
-               self.pc += 1;
-                let oldPC = self.pc;
-                while (program[self.pc] != OpCodes::OpEndArray as u8) {
-                    array_vec.push(program[self.pc]);
-                    self.pc += 1;
+                //Instead pushing back list indexes we send back a list of all the elements.f
+                // The element's list is in the result register and becomes garbage once it is flattened
+                let element_id = self.GetRegisterValue(regResult as u8, &[VarTypes::ArrayType])?;
+                let element = self.GetList(element_id as usize)?.clone();
+                if let Some(OpenInstruction::NestedList { elementType, elements, .. }) = self.openInstructions.last_mut() {
+                    elements.extend_from_slice(&element.1);
+                    *elementType = element.0;
                 }
-                
-                match elementType {
-                    VarTypes::CharType => {
-                        self.memoryList.get_mut(self.curMemoryId).unwrap().listLookup.push((elementType, array_vec.clone().into_iter().map(|x| x as u64).collect(), vec![array_vec.clone().len() as u8]));
-                        self.curType = VarTypes::CharType;
-                    },
-                    VarTypes::FloatType => {
-                        self.curType = VarTypes::FloatType;
-                        let mut num: u64 = 0;
-                        let mut new_arr : Vec<u64> = Vec::new();
-                        if (array_vec.len() > 0){
-                            for i in range(0, (array_vec.len())/8 ){
-                                num = 0;
-                                for j in range(0, 8){
-                                    num = (num << 8 * (j > 0) as u64) | array_vec[ (i * 8) + j] as u64;
-                                }
-                                new_arr.push(num);
-                            }
-                        }
-                        self.memoryList.get_mut(self.curMemoryId).unwrap().listLookup.push((elementType, new_arr.clone(), vec![new_arr.clone().len() as u8; 1]));
-                    },
-                    VarTypes::ArrayType => {
-                        self.pc = oldPC;
-                        let mut new_arr : Vec<u64> = Vec::new();
-                        // let mut oldListId = self.memoryList.get(self.curMemoryId).unwrap().listLookup.len() - 1;
-                        let mut arr_type : VarTypes =  VarTypes::NullType;
-                        let mut arr_flattened : Vec<u64> = Vec::new();
-                        while (program[self.pc] != OpCodes::OpEndArray as u8) { 
-                            match num::FromPrimitive::from_u8(program[self.pc]).unwrap() {
-                                OpCodes::OpLoadMultiDimensionalArrayElement => {
-                                    self.pc += 1;
-                                    self.ConsumeByteCode(program, program[self.pc]);
-                                },
-                                OpCodes::OpEndMultiDimensionalArrayElement => {
-                                    //Instead pushing back list indexes we send back a list of all the elements.f
-                                    arr_flattened.append(self.memoryList.get(self.curMemoryId).unwrap().listLookup.last().unwrap().1.clone().as_mut());
-                                    arr_type = self.memoryList.get(self.curMemoryId).unwrap().listLookup.last().unwrap().0;
-                                    // dim_arr_test.push(self.memoryList.get(self.curMemoryId).unwrap().listLookup.last().unwrap().1.len());
-                                    self.memoryList.get_mut(self.curMemoryId).unwrap().listLookup.pop();
-                                    // new_arr.push( (self.memoryList.get_mut(self.curMemoryId).unwrap().listLookup.len()-1) as u64 );
-                                    if (self.pc+1 > program.len()){
-                                        break;
-                                    }
-                                    if (program[self.pc+1] != OpCodes::OpLoadMultiDimensionalArrayElement as u8){
-                                        self.pc += 1;
-                                        break;
-                                    }
-                                }
-                                _ => {panic!("Unimplemented arm for Array match")}
-                            }
-                            self.pc += 1;
-                        }
+            },
+            Instruction::EndArray => {
+                match self.openInstructions.pop() {
+                    Some(OpenInstruction::List) => {},
+                    Some(OpenInstruction::NestedList { reg, dimensions, elementType, elements }) => {
                         //TODO: Rework How Multidimensional array are accessed and created
                         //TODO: Pass through variable type + dimesnion specs
-                        // let newListId = self.memoryList.get(self.curMemoryId).unwrap().listLookup.len() - 1;
-                        
-                        // Recursive loop to get flattened array
-                        self.memoryList.get_mut(self.curMemoryId).unwrap().listLookup.push((arr_type, arr_flattened.clone(), dim_arr.clone()));
-
-                    }
-                    _ => println!("Unkown Element Type")
-                }
-                self.registers[reg as usize] = (self.memoryList.get(self.curMemoryId).unwrap().listLookup.len()-1) as u64;
-            },
-            OpCodes::OpAccessArray => {
-                    println!("{:?}", program);
-                    let array_id: u64 = self.get64BitVal(program);
-                    let mut elements_indexes = Vec::<u64>::new();
-                    self.pc += 1;
-                    loop {
-                        let bb : OpCodes = num::FromPrimitive::from_u8(program[self.pc]).unwrap(); 
-                        if [OpCodes::OpAccessElementBegin as u8, OpCodes::OpAccessElementEnd as u8].contains(&program[self.pc]) == false {
-                            panic!("Expected OpAccessElementBegin or OpAccessElementEnd, found: {}", program[self.pc]);
-                        }
-                        if program[self.pc] == OpCodes::OpAccessElementBegin as u8 {
-                            self.pc += 1;
-                            self.ConsumeByteCode(program, program[self.pc]);
-                            self.pc += 1;
-                            elements_indexes.push(self.registers[program[self.pc] as usize]);
-                            self.pc += 1;
-                           } else if program[self.pc] == OpCodes::OpAccessElementEnd as u8 {
-                            if program.len() <= self.pc + 1 {
-                                break;
-                            }
-                            
-                            if program[self.pc + 1] != OpCodes::OpAccessElementBegin as u8 {
-                                break;
-                            }
-                            self.pc += 1;
-                        }
+                        let list_id = self.Alloc((elementType, elements, dimensions))?;
+                        self.SetRegister(reg, (VarTypes::ArrayType, list_id));
+                        self.registers[regResult] = (VarTypes::ArrayType, list_id);
+                    },
+                    _ => return Err(VmErrorKind::MisplacedInstruction(OpCodes::OpEndArray).into())
+                }
+            },
+            Instruction::AccessArray { varId, dest, indexCount } => {
+                // Each index is worked out in an OpAccessElementBegin block, and the element is loaded at the last OpAccessElementEnd
+                match indexCount {
+                    0 => self.AccessList(varId, dest, &[])?,
+                    _ => self.openInstructions.push(OpenInstruction::Access { varId, dest, indexCount, indexes: Vec::new() })
+                }
+            },
+            Instruction::AccessElementEnd { reg } => {
+                let index = self.GetRegisterValue(reg, &[VarTypes::FloatType])?;
+                let Some(OpenInstruction::Access { indexCount, indexes, .. }) = self.openInstructions.last_mut() else {
+                    return Err(VmErrorKind::MisplacedInstruction(OpCodes::OpAccessElementEnd).into());
+                };
+                indexes.push(index);
+                if indexes.len() == *indexCount as usize {
+                    if let Some(OpenInstruction::Access { varId, dest, indexes, .. }) = self.openInstructions.pop() {
+                        self.AccessList(varId, dest, &indexes)?;
                     }
-                    let arr: &(VarTypes, Vec<u64>, Vec<u8>) = self.memoryList.get(self.curMemoryId).unwrap().listLookup.get(array_id as usize).unwrap();
-
-                    if elements_indexes.len() > 1 {
-                        if elements_indexes.len() == arr.2.len() {
-                            let mut ele_pos: f64 = 0.0;
+                }
+            },
+            Instruction::CopyVarToNewMemoryBlock { varType: _, varId: var_id } => {
+                // Only runs while the parameters of a call are loaded, so there is always a caller block
+                let callerMemoryId = self.curMemoryId.checked_sub(1).ok_or(VmErrorKind::MisplacedInstruction(OpCodes::OpCopyVarToNewMemoryBlock))?;
+                // Lists and variants are passed by reference, since nothing changes them once they are made
+                let varTuple = *self.memoryList.get(callerMemoryId).unwrap().variableLookup.get(&var_id).ok_or(VmErrorKind::UnknownVariable(var_id))?;
+                let curMemory = self.memoryList.get_mut(self.curMemoryId).unwrap();
+                curMemory.variableLookup.insert(curMemory.variableLookup.len() as u64, varTuple);
+            },
+            Instruction::Return { returnType, reg } => {
+                let (valueType, value) = self.GetRegister(reg);
+                if valueType != returnType {
+                    return Err(VmErrorKind::TypeMismatch { expected: [returnType].to_vec(), found: valueType }.into());
+                }
+                // The value stays on the heap, so lists and variants are returned by reference
+                self.registers[regResult] = (valueType, value);
+            }
+            Instruction::MakeVariant { reg, varType: _, tag, fields: ref fieldRegs } => {
+                let mut fields: Vec<u64> = vec![tag as u64];
+                let mut field_types: Vec<u8> = Vec::new();
+                for &(fieldReg, fieldType) in fieldRegs {
+                    fields.push(self.GetRegisterValue(fieldReg, &[fieldType])?);
+                    field_types.push(fieldType as u8);
+                }
+                let value = (VarTypes::VariantType, self.Alloc((VarTypes::VariantType, fields, field_types))?);
+                self.SetRegister(reg, value);
+                self.registers[regResult] = value;
+            },
+            Instruction::LoadElement { reg, varType: _, listReg, index } => {
+                let list_id = self.GetRegisterValue(listReg, &[VarTypes::ArrayType, VarTypes::VariantType])? as usize;
+                let element = self.GetListElement(list_id, index as usize)?;
+                self.SetRegister(reg, element);
+                self.registers[regResult] = element;
+            },
+            Instruction::LoadIndexed { reg, varType: _, listReg, indexReg } => {
+                let list_id = self.GetRegisterValue(listReg, &[VarTypes::ArrayType])? as usize;
+                let index = f64::from_bits(self.GetRegisterValue(indexReg, &[VarTypes::FloatType])?) as usize;
+                let element = self.GetListElement(list_id, index)?;
+                self.SetRegister(reg, element);
+                self.registers[regResult] = element;
+            },
+            Instruction::Spill { reg, slot } => {
+                let slot = slot as usize;
+                let value = self.GetRegister(reg);
+                let curMemory = self.memoryList.get_mut(self.curMemoryId).unwrap();
+                if curMemory.spillSlots.len() <= slot {
+                    curMemory.spillSlots.resize(slot + 1, (VarTypes::NullType, 0));
+                }
+                curMemory.spillSlots[slot] = value;
+            },
+            Instruction::Reload { reg, slot } => {
+                let value = *self.memoryList.get(self.curMemoryId).unwrap().spillSlots.get(slot as usize).ok_or(VmErrorKind::EmptySpillSlot(slot))?;
+                self.SetRegister(reg, value);
+            },
+            Instruction::Unwrap { reg, varType: _, optionalReg } => {
+                let list_id = self.GetRegisterValue(optionalReg, &[VarTypes::VariantType])? as usize;
+                if self.GetListElement(list_id, 0)?.1 == 0 {
+                    return Err(VmErrorKind::UnwrapNone.into());
+                }
+                let value = self.GetListElement(list_id, 1)?;
+                self.SetRegister(reg, value);
+                self.registers[regResult] = value;
+            },
+            Instruction::TestTag { reg, tag } => {
+                let list_id = self.GetRegisterValue(reg, &[VarTypes::VariantType])? as usize;
+                self.cond = (self.GetListElement(list_id, 0)?.1 == tag as u64) as u8;
+            },
+            Instruction::TestLength { reg, length } => {
+                let list_id = self.GetRegisterValue(reg, &[VarTypes::ArrayType])? as usize;
+                self.cond = (self.GetList(list_id)?.1.len() == length as usize) as u8;
+            },
+            Instruction::TestEqual { reg1, varType: valueType, reg2 } => {
+                let val1 = self.GetRegisterValue(reg1, &[valueType])?;
+                let val2 = self.GetRegisterValue(reg2, &[valueType])?;
+                self.cond = match valueType {
+                    VarTypes::FloatType => (f64::from_bits(val1) == f64::from_bits(val2)) as u8,
+                    VarTypes::ArrayType => (self.GetList(val1 as usize)?.1 == self.GetList(val2 as usize)?.1) as u8,
+                    _ => (val1 == val2) as u8
+                };
+            },
+            Instruction::Jump { target } => {
+                let target = target as usize;
+                // pc is moved past the current instruction after it is consumed
+                self.pc = target - 1;
+            },
+            Instruction::JumpIfFalse { target } => {
+                let target = target as usize;
+                if self.cond == 0 {
+                    self.pc = target - 1;
+                }
+            },
+            Instruction::DropVars { count } => {
+                let curMemory = self.memoryList.get_mut(self.curMemoryId).unwrap();
+                for _ in 0..count {
+                    let lastVarId = curMemory.variableLookup.len().checked_sub(1).ok_or(VmErrorKind::UnknownVariable(0))? as u64;
+                    curMemory.variableLookup.remove(&lastVarId);
+                }
+            },
+            // Markers that start the operands of the instruction they belong to
+            Instruction::LoadMultiDimensionalArrayElement | Instruction::AccessElementBegin => {},
+            _ => return Err(VmErrorKind::MisplacedInstruction(instruction.OpCode()).into())
+        }
+        return Ok(Flow::Next);
+    }
 
-                            for i in 0..elements_indexes.len()-1 {
-                                ele_pos += f64::from_bits(*elements_indexes.get(i).unwrap()) * ((*arr.2.get(i).unwrap() as f64) + 1 as f64);
-                            }
+    /// Starts the call whose parameters were just loaded. Function bodies are entered by the dispatch loop, system functions run right away
+    fn EnterCall(&mut self, function_id: u64, callPc: usize) -> Result<Flow, VmErrorKind> {
+        let (offset, length, wrapsParams) = match self.funcList.get(&(function_id as usize)) {
+            Some((offset, length, paramTypes, _)) => (*offset, *length, *length != 0 && paramTypes.contains(&VarTypes::VariantType)),
+            None => return Err(VmErrorKind::UnknownFunction(function_id))
+        };
+        // Only optional parameters are wrapped, so the param types are only copied for them
+        if wrapsParams {
+            let paramTypes = self.funcList.get(&(function_id as usize)).unwrap().2.clone();
+            self.WrapOptionalParams(&paramTypes)?;
+        }
+        let name = self.funcNames.get(&(function_id as usize)).cloned().unwrap_or_default();
+        if let Some(profiler) = &mut self.profiler {
+            profiler.Enter(&name);
+        }
+        self.callStack.push(CallFrame { funcId: function_id, name, callPc });
+        if length != 0 {
+            let body = self.FunctionBody(offset, length)?;
+            // The function body gets its own window of registers
+            self.regWindowBase += regWindowSize;
+            if self.registers.len() < self.regWindowBase + regWindowSize {
+                self.registers.resize(self.regWindowBase + regWindowSize, (VarTypes::NullType, 0));
+            }
+            return Ok(Flow::Enter(body));
+        }
+        let systemFunction : SystemFunctions = num::FromPrimitive::from_u64(function_id).ok_or(VmErrorKind::UnknownFunction(function_id))?;
+        match systemFunction {
+            SystemFunctions::printFunction => {
+                let variables = &self.memoryList.get(self.curMemoryId).unwrap().variableLookup;
+                let param_id : u64 = variables.len().checked_sub(1).ok_or(VmErrorKind::UnknownVariable(0))? as u64;
+                let firstParam = *variables.get(&param_id).ok_or(VmErrorKind::UnknownVariable(param_id))?;
+                let overloads = self.funcList.get_vec(&(function_id as usize)).unwrap();
+                // Variants, such as optional values, have no overload of print
+                if !overloads.contains( &(0 as usize, 0 as usize, [firstParam.0].to_vec(), VarTypes::NullType) ){
+                    return Err(VmErrorKind::TypeMismatch { expected: overloads.iter().flat_map(|x| x.2.clone()).collect(), found: firstParam.0 });
+                }
+                if firstParam.0 == VarTypes::ArrayType {
+                    self.printArray(firstParam.1 as usize)?;
+                }else{
+                    self.printScalar(firstParam.1, firstParam.0)?;
+                }
+                writeln!(self.output)?;
+            }
+        }
+        self.EndCall();
+        return Ok(Flow::Next);
+    }
 
-                            ele_pos += f64::from_bits(*elements_indexes.last().unwrap());
+    /// Drops the call that just returned and its memory block
+    fn EndCall(&mut self) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.Exit();
+        }
+        self.callStack.pop();
+        self.memoryList.pop();
+        self.curMemoryId -= 1;
+    }
 
-                            let num = arr.1.get(ele_pos as usize).unwrap();
-                            self.pc += 1;
-                            println!("Float Value: {}", f64::from_bits(*num));
-                            self.registers[regResult] = *num;
-                            self.registers[program[self.pc] as usize] = *num;                            
-                        }
-                        // let element_index: u64 = *elements_indexes.last().unwrap();
-                        // elements_indexes.pop();
-                        // let mut cur_arr = arr.1.clone();
-                        // for i in 0 .. elements_indexes.len() {
-                        //     let arr_ref_index = f64::from_bits(*elements_indexes.get(i).unwrap());
-                        //     let list_index =    *cur_arr.get(arr_ref_index as usize).unwrap() as usize;
-                        //     let cur_memory = self.memoryList.get(self.curMemoryId).unwrap();
-                        //     let cur_list: Option<&(VarTypes, Vec<u64>, Vec<u8>)> = cur_memory.listLookup.get(list_index);
-                        //     cur_arr = cur_list.unwrap().1.clone();
-                        // }
-                        // let num = cur_arr.get(f64::from_bits(element_index) as usize).unwrap();
-                        // self.pc += 1;
-                        // println!("Float Value: {}", f64::from_bits(*num));
-                        // self.registers[8 as usize] = *num;
-                        // self.registers[program[self.pc] as usize] = *num;
-                    } else {
-                        match arr.0 {
-                            VarTypes::FloatType => {
-                                let index = f64::from_bits(*elements_indexes.first().unwrap());
-                                let num = arr.1.get(index as usize).unwrap();
-                                println!("Float Value: {}", f64::from_bits(*num));
-                                self.registers[regResult] = *num;
-                                self.pc += 1;
-                                let reg_final = program[self.pc];
-                                self.registers[reg_final as usize] = *num;
-                            },
-                            _ => {panic!("Unimplemented array type")}
-                        }
-                    }
-            },
-            OpCodes::OpCopyVarToNewMemoryBlock => {
-                self.pc += 1;
-                let var_id: u64 = self.get64BitVal(program);
+    /// Loads the element of the list in variable varId at the indexes into dest
+    fn AccessList(&mut self, varId: u64, dest: u8, elements_indexes: &[u64]) -> Result<(), VmErrorKind> {
+        let (listType, list_id) = *self.memoryList.get(self.curMemoryId).unwrap().variableLookup.get(&varId).ok_or(VmErrorKind::UnknownVariable(varId))?;
+        if listType != VarTypes::ArrayType {
+            return Err(VmErrorKind::TypeMismatch { expected: [VarTypes::ArrayType].to_vec(), found: listType });
+        }
+        let arr: &ToastLangList = self.GetList(list_id as usize)?;
 
-                let memoryList = &mut self.memoryList;
+        if elements_indexes.len() > 1 {
+            if elements_indexes.len() == arr.2.len() {
+                let mut ele_pos: f64 = 0.0;
 
-                let varTuple = *memoryList.get(self.curMemoryId - 1).unwrap().variableLookup.get(&var_id).unwrap();
-                let mut newVarId = memoryList.get(self.curMemoryId).unwrap().variableLookup.len();
-                if newVarId > 0 {
-                    newVarId -= 1;
+                for i in 0..elements_indexes.len()-1 {
+                    ele_pos += f64::from_bits(*elements_indexes.get(i).unwrap()) * ((*arr.2.get(i).unwrap() as f64) + 1 as f64);
                 }
 
-                match varTuple.0 {
-                    VarTypes::ArrayType => {
-                        let var_list: (VarTypes, Vec<u64>, Vec<u8>) = memoryList.get(self.curMemoryId - 1).unwrap().listLookup.get(varTuple.1 as usize).unwrap().clone();
-                        let mut newListId: usize = memoryList.get(self.curMemoryId).unwrap().listLookup.len();
-                        if newListId > 0 {
-                            newListId -= 1;
-                        }
-                        memoryList.get_mut(self.curMemoryId).unwrap().listLookup.insert(newListId, var_list.clone());
-                        memoryList.get_mut(self.curMemoryId).unwrap().variableLookup.insert(newVarId as u64, (varTuple.0, newListId as u64));
-                    }
-                    _ => {
-                        memoryList.get_mut(self.curMemoryId).unwrap().variableLookup.insert(newVarId as u64, varTuple);
-                    }
-                }
-            },
-            OpCodes::OpReturn => {
-                self.pc += 1;
-                let returnType : VarTypes = num::FromPrimitive::from_u8(program[self.pc]).unwrap();
-                self.curType = returnType;
-                self.pc += 1;
-                let reg = program[self.pc];
-                let mut target_memoryId = 0;
-                if self.curMemoryId == 0 as usize {
-                    target_memoryId = self.curMemoryId;
-                } else {
-                    target_memoryId =  self.curMemoryId - 1;
-                }
-                match self.curType {
-                    VarTypes::ArrayType => {
-                        // if self.curMemoryId - 1 > 0 {
-                            let vec_arr  = self.memoryList.get(self.curMemoryId).unwrap().listLookup.get(self.registers[reg as usize] as usize).unwrap().clone();
-                            match &vec_arr.0 {
-                                VarTypes::ArrayRef => {
-                                println!("list lookup: {:?}", self.memoryList.get(self.curMemoryId).unwrap());
-                                let mut multi_dimenional_array : Vec<(VarTypes, Vec<u64>, Vec<u8>)> = self.getArrayInMultiDimensionalArray(self.curMemoryId, vec_arr.clone());
-                                println!("Multi Dimensional Array: {:?}", &multi_dimenional_array);
-                                let first_element_from_old_memory = *multi_dimenional_array.iter().find(|item| {
-                                    if let VarTypes::ArrayRef = item.0 {
-                                        true
-                                    } else {
-                                        false
-                                    }
-                                }).unwrap().1.first().unwrap();
-                                for i in 0..multi_dimenional_array.len() {
-                                    match  &multi_dimenional_array.get(i).unwrap().0 {
-                                        VarTypes::ArrayRef => {
-                                            let newArray = multi_dimenional_array.get(i).unwrap().1.clone().into_iter().map(|x| x - first_element_from_old_memory + self.memoryList.get(target_memoryId).unwrap().listLookup.len() as u64).collect();
-                                            multi_dimenional_array.get_mut(i).unwrap().1 = newArray;
-                                        },
-                                        _ => {continue}
-                                    }
-                                }
-                                // println!("Multi Dimensional Array: {:?}", &multi_dimenional_array);
-                                self.memoryList.get_mut(target_memoryId).unwrap().listLookup.extend(multi_dimenional_array);
-                                self.registers[8] = (self.memoryList.get(target_memoryId).unwrap().listLookup.len()-1) as u64;
-                                self.curType = VarTypes::ArrayType;
-                            },
-                                _ => {
-                                    self.memoryList.get_mut(target_memoryId).unwrap().listLookup.push(vec_arr);
-                                    self.registers[8] = (self.memoryList.get(target_memoryId).unwrap().listLookup.len()-1) as u64;
-                                }
-                            }
-                        // }
-                    },
-                    _ => {
-                        self.registers[8] = self.registers[reg as usize];
-                    }
-                }
+                ele_pos += f64::from_bits(*elements_indexes.last().unwrap());
+
+                let num = arr.1.get(ele_pos as usize).ok_or(VmErrorKind::IndexOutOfRange { index: ele_pos as usize, length: arr.1.len() })?;
+                let value = (arr.0, *num);
+                self.Trace(format_args!("Float Value: {}", f64::from_bits(value.1)));
+                self.registers[regResult] = value;
+                self.SetRegister(dest, value);
+            }
+        } else {
+            match arr.0 {
+                VarTypes::FloatType => {
+                    let index = f64::from_bits(*elements_indexes.first().ok_or(VmErrorKind::IndexOutOfRange { index: 0, length: 0 })?) as usize;
+                    let num = *arr.1.get(index).ok_or(VmErrorKind::IndexOutOfRange { index, length: arr.1.len() })?;
+                    self.Trace(format_args!("Float Value: {}", f64::from_bits(num)));
+                    self.registers[regResult] = (VarTypes::FloatType, num);
+                    self.SetRegister(dest, (VarTypes::FloatType, num));
+                },
+                found => return Err(VmErrorKind::TypeMismatch { expected: [VarTypes::FloatType].to_vec(), found })
+            }
+        }
+        return Ok(());
+    }
 
+    /// Wraps parameters that were passed as plain values to optional parameters
+    pub fn WrapOptionalParams(&mut self, paramTypes: &[VarTypes]) -> Result<(), VmErrorKind> {
+        for (varId, paramType) in paramTypes.iter().enumerate() {
+            let Some(param) = self.memoryList[self.curMemoryId].variableLookup.get(&(varId as u64)).copied() else { continue };
+            if *paramType == VarTypes::VariantType && param.0 != VarTypes::VariantType {
+                let list_id = self.Alloc((VarTypes::VariantType, [1, param.1].to_vec(), [param.0 as u8].to_vec()))?;
+                self.memoryList[self.curMemoryId].variableLookup.insert(varId as u64, (VarTypes::VariantType, list_id));
             }
-            _ => println!("No implementation for opcode: {:#?}", opCode)
         }
-        
+        return Ok(());
+    }
+
+    /// Maps a register operand to its index in the current register window. The result register is shared by every window
+    pub fn GetRegisterIndex(&self, reg: u8) -> usize {
+        if reg as usize == regResult {
+            return regResult;
+        }
+        return self.regWindowBase + reg as usize;
+    }
+
+    pub fn GetRegister(&self, reg: u8) -> ToastLangValue {
+        return self.registers[self.GetRegisterIndex(reg)];
+    }
+
+    pub fn SetRegister(&mut self, reg: u8, value: ToastLangValue) {
+        let index = self.GetRegisterIndex(reg);
+        self.registers[index] = value;
+    }
+
+    /// Returns the value in a register. Fails if the value is not one of the expected types
+    pub fn GetRegisterValue(&self, reg: u8, expectedTypes: &[VarTypes]) -> Result<u64, VmErrorKind> {
+        let (valueType, value) = self.GetRegister(reg);
+        if !expectedTypes.contains(&valueType) {
+            return Err(VmErrorKind::TypeMismatch { expected: expectedTypes.to_vec(), found: valueType });
+        }
+        return Ok(value);
+    }
+
+    /// Returns a list on the heap
+    pub fn GetList(&self, list_id: usize) -> Result<&ToastLangList, VmErrorKind> {
+        return self.heap.Get(list_id as u64).ok_or(VmErrorKind::UnknownList(list_id as u64));
+    }
+
+    /// Returns an element of a list tagged with its type
+    pub fn GetListElement(&self, list_id: usize, index: usize) -> Result<ToastLangValue, VmErrorKind> {
+        let list = self.GetList(list_id)?;
+        let element = *list.1.get(index).ok_or(VmErrorKind::IndexOutOfRange { index, length: list.1.len() })?;
+        let elementType = match list.0 {
+            // The first element of a variant is its tag, the rest are fields with their own types
+            VarTypes::VariantType if index == 0 => VarTypes::NullType,
+            VarTypes::VariantType => list.2.get(index - 1).and_then(|x| num::FromPrimitive::from_u8(*x)).ok_or(VmErrorKind::InvalidObject(list_id as u64))?,
+            VarTypes::ArrayRef => VarTypes::ArrayType,
+            elementType => elementType
+        };
+        return Ok((elementType, element));
     }
 }
 
-#[derive(FromPrimitive, Debug, PartialEq)]
+/// What the dispatch loop does after an instruction
+enum Flow {
+    /// Goes on with the next instruction
+    Next,
+    /// Runs the function body, then goes on after the call
+    Enter(Rc<DecodedProgram>)
+}
+
+/// An instruction whose operands are worked out by the instructions that follow it, up to the marker that closes it
+#[derive(Debug)]
+enum OpenInstruction {
+    /// OpCallFunc, closed by OpEndParamLoad
+    Call { funcId: u64, callPc: usize },
+    /// OpLoadArray of numbers or chars, which holds its elements. Closed by OpEndArray
+    List,
+    /// OpLoadArray of lists, closed by OpEndArray. The elements of each inner list are added once it is loaded
+    NestedList { reg: u8, dimensions: Vec<u8>, elementType: VarTypes, elements: Vec<u64> },
+    /// OpAccessArray, closed by its last OpAccessElementEnd
+    Access { varId: u64, dest: u8, indexCount: u8, indexes: Vec<u64> }
+}
+
+/// An error raised while running an instruction. Errors of the instruction itself are given its pc and OpCode by ConsumeByteCode,
+/// errors of a call are given the pc of its OpCallFunc
+enum Fault {
+    Kind(VmErrorKind),
+    Error(VmError)
+}
+
+impl From<VmErrorKind> for Fault {
+    fn from(kind: VmErrorKind) -> Self {
+        return Fault::Kind(kind);
+    }
+}
+
+impl From<std::io::Error> for Fault {
+    fn from(err: std::io::Error) -> Self {
+        return Fault::Kind(err.into());
+    }
+}
+
+impl From<VmError> for Fault {
+    fn from(err: VmError) -> Self {
+        return Fault::Error(err);
+    }
+}
+
+/// Every instruction starts with an 8 bit OpCode. Register operands and Var Type operands take 8 bits each,
+/// and 64 bit values are stored most significant byte first
+#[derive(FromPrimitive, Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum OpCodes {
     /// OpLoadReg - Operation Code for copy data from register to another
     /// 
@@ -604,9 +869,9 @@ pub enum OpCodes {
     /// 
     /// ------------------
     /// 
-    /// Next 4 bits - Source 
+    /// Next 8 bits - Source Register
     /// 
-    /// Next 4 bits - Destination
+    /// Next 8 bits - Destination Register
     OpLoadReg = 0,
     //// OpLoadScalar - Operation Code for loading scalar values into a specified register
     /// 
@@ -614,9 +879,9 @@ pub enum OpCodes {
     /// 
     /// --------------
     /// 
-    /// Next 3 bits - Register
+    /// Next 8 bits - Register
     /// 
-    /// Last x bits - VarType
+    /// Next 8 bits - VarType
     OpLoadScalar,
     /// OpAdd - Operation Code for adding two numbers that are either in two registers or in the op-code bytecode
     /// 
@@ -624,11 +889,9 @@ pub enum OpCodes {
     ///
     /// -----------------------
     ///  
-    /// Next 3 bits - First Reg
+    /// Next 8 bits - First Reg
     /// 
-    /// Next bit - Null
-    /// 
-    /// Next 3 bits - Second Reg
+    /// Next 8 bits - Second Reg
     OpAdd,
     /// OpSub- Operation Code for subtracting two numbers that are either in two registers or in the op-code bytecode
     /// 
@@ -636,11 +899,9 @@ pub enum OpCodes {
     /// 
     /// ---------------------
     /// 
-    /// Next 3 bits - First Reg
-    /// 
-    /// Next bit - Null
+    /// Next 8 bits - First Reg
     /// 
-    /// Next 3 bits - Second Reg
+    /// Next 8 bits - Second Reg
     OpSub,
     /// OpMul- Operation Code for multiplying two numbers that are either in two registers or in the op-code bytecode
     /// 
@@ -648,11 +909,9 @@ pub enum OpCodes {
     /// 
     /// ---------------------
     /// 
-    /// Next 3 bits - First Reg
+    /// Next 8 bits - First Reg
     /// 
-    /// Next bit - Null
-    /// 
-    /// Next 3 bits - Second Reg
+    /// Next 8 bits - Second Reg
     OpMul,
     /// OpDiv- Operation Code for dividing two numbers that are either in two registers or in the op-code bytecode
     /// 
@@ -660,11 +919,9 @@ pub enum OpCodes {
     /// 
     /// ---------------------
     /// 
-    /// Next 3 bits - First Reg
-    /// 
-    /// Next bit - Null
+    /// Next 8 bits - First Reg
     /// 
-    /// Next 3 bits - Second Reg
+    /// Next 8 bits - Second Reg
     OpDiv,
     //// OpNewVar - Operation Code for adding a variable
     /// 
@@ -674,9 +931,9 @@ pub enum OpCodes {
     /// 
     /// ---------------------
     /// 
-    /// Next 3 bits - Reg of variable value
+    /// Next 8 bits - Reg of variable value
     /// 
-    /// Last 5 bits - Var Type
+    /// Next 8 bits - Var Type
     OpNewVar,
     //// OpLoadVar - Operation Load Variable To register
     /// 
@@ -684,11 +941,11 @@ pub enum OpCodes {
     /// 
     /// First 8 bits - OpCode
     /// 
-    /// Next 3 bits - Register Num
+    /// Next 8 bits - Register Num
     /// 
-    /// Last 5 bits - Var Type
+    /// Next 8 bits - Var Type
     OpLoadVar,
-    //// OpStartFunc - Operation Code to Start Function Definition
+    //// OpStartFunc - Operation Code to Start Function Definition. Only found at the start of a function body in the function table
     /// 
     /// First 8 bits - OpCode
     /// 
@@ -718,9 +975,15 @@ pub enum OpCodes {
     /// 
     /// First 8 bits - OpCode
     /// 
-    /// Next 3 bits - Register
+    /// Next 8 bits - Register
     /// 
-    /// Last 5 bits - Element Type
+    /// Next 8 bits - Element Type
+    /// 
+    /// Next - OpLoadArrayDimensions and OpEndArrayDimensions instructions
+    /// 
+    /// Next 64 bits - Number of elements
+    /// 
+    /// Next 8 bits per char element or 64 bits per number element - Elements. Lists of lists have no elements, each inner list follows in an OpLoadMultiDimensionalArrayElement block
     OpLoadArray,
     //// OpEndFunc - Operation Code to Declare End of array load
     /// 
@@ -729,10 +992,20 @@ pub enum OpCodes {
     OpPrint,
     /// OpAccessElement - 
     /// 
-    /// First 16 bytes are Variable Ids
-    /// Next 16 bytes are Element Index
+    /// First 8 bits - OpCode
+    /// 
+    /// Next 64 bits - Id of the variable holding the list
+    /// 
+    /// Next 8 bits - Destination Register
+    /// 
+    /// Next 8 bits - Number of indexes. Each index follows as OpAccessElementBegin, the instructions that work out the index, then OpAccessElementEnd
     OpAccessArray,
     OpAccessElementBegin,
+    //// OpAccessElementEnd - Operation Code to end an index of OpAccessArray
+    /// 
+    /// First 8 bits - OpCode
+    /// 
+    /// Next 8 bits - Register holding the index
     OpAccessElementEnd,
     OpCopyVarToNewMemoryBlock,
     OpLoadMultiDimensionalArrayElement,
@@ -740,21 +1013,197 @@ pub enum OpCodes {
     OpReturn,
     OpLoadArrayDimensions,
     OpEndArrayDimensions,
+    //// OpMakeVariant - Operation Code to build a value of a sum type
+    /// 
+    /// First 8 bits - OpCode
+    /// 
+    /// Next 8 bits - Register
+    /// 
+    /// Next 8 bits - Var Type
+    /// 
+    /// Next 8 bits - Variant Tag
+    /// 
+    /// Next 8 bits - Number of fields
+    /// 
+    /// Next 16 bits per field - Field register and field Var Type
+    OpMakeVariant,
+    //// OpLoadElement - Operation Code to load an element of a list (or a field of a variant) into a register
+    /// 
+    /// First 8 bits - OpCode
+    /// 
+    /// Next 8 bits - Destination Register
+    /// 
+    /// Next 8 bits - Element Type
+    /// 
+    /// Next 8 bits - Register holding the list
+    /// 
+    /// Next 8 bits - Element index
+    OpLoadElement,
+    //// OpTestTag - Operation Code to check the tag of a variant. Sets cond
+    /// 
+    /// First 8 bits - OpCode
+    /// 
+    /// Next 8 bits - Register holding the variant
+    /// 
+    /// Next 8 bits - Tag
+    OpTestTag,
+    //// OpTestLength - Operation Code to check the length of a list. Sets cond
+    /// 
+    /// First 8 bits - OpCode
+    /// 
+    /// Next 8 bits - Register holding the list
+    /// 
+    /// Next 8 bits - Length
+    OpTestLength,
+    //// OpTestEqual - Operation Code to check if two values are equal. Sets cond
+    /// 
+    /// First 8 bits - OpCode
+    /// 
+    /// Next 8 bits - First Register
+    /// 
+    /// Next 8 bits - Var Type
+    /// 
+    /// Next 8 bits - Second Register
+    OpTestEqual,
+    //// OpJump - Operation Code to jump to a position in the program
+    /// 
+    /// First 8 bits - OpCode
+    /// 
+    /// Next 64 bits - Target pc
+    OpJump,
+    //// OpJumpIfFalse - Operation Code to jump to a position in the program if cond is not set
+    /// 
+    /// First 8 bits - OpCode
+    /// 
+    /// Next 64 bits - Target pc
+    OpJumpIfFalse,
+    //// OpDropVars - Operation Code to remove the most recently declared variables of the current memory block
+    /// 
+    /// First 8 bits - OpCode
+    /// 
+    /// Next 8 bits - Number of variables
+    OpDropVars,
+    //// OpLoadIndexed - Operation Code to load an element of a list at the index held in a register
+    /// 
+    /// First 8 bits - OpCode
+    /// 
+    /// Next 8 bits - Destination Register
+    /// 
+    /// Next 8 bits - Element Type
+    /// 
+    /// Next 8 bits - Register holding the list
+    /// 
+    /// Next 8 bits - Register holding the index
+    OpLoadIndexed,
+    //// OpUnwrap - Operation Code to load the value out of an optional value. Fails with VmErrorKind::UnwrapNone if the optional value is none
+    /// 
+    /// First 8 bits - OpCode
+    /// 
+    /// Next 8 bits - Destination Register
+    /// 
+    /// Next 8 bits - Value Type
+    /// 
+    /// Next 8 bits - Register holding the optional value
+    OpUnwrap,
+    //// OpSpill - Operation Code to save a register to a spill slot of the current memory block
+    /// 
+    /// First 8 bits - OpCode
+    /// 
+    /// Next 8 bits - Register
+    /// 
+    /// Next 8 bits - Spill slot
+    OpSpill,
+    //// OpReload - Operation Code to load a register back from a spill slot of the current memory block
+    /// 
+    /// First 8 bits - OpCode
+    /// 
+    /// Next 8 bits - Register
+    /// 
+    /// Next 8 bits - Spill slot
+    OpReload,
+    //// OpLoadConstant - Operation Code to load a value from the constant pool. Numbers are loaded into the register, strings are loaded as a new list
+    /// 
+    /// First 8 bits - OpCode
+    /// 
+    /// Next 8 bits - Register
+    /// 
+    /// Next 64 bits - Constant Id
+    OpLoadConstant,
+}
+
+/// Keeps track of which registers hold live values while compiling
+#[derive(Debug, Clone, Default)]
+pub struct RegisterAllocator {
+    /// Registers holding a live value, oldest first
+    pub liveRegs: Vec<u8>,
+    /// Key is register, Value is the stack of spill slots holding older values of that register
+    pub spilledRegs: HashMap<u8, Vec<u8>>,
+    /// Spill slots of the memory block that hold a value
+    pub usedSlots: Vec<u8>,
+    /// List of (Register, Spill slot) saved by SaveLiveRegs
+    pub savedRegs: Vec<(u8, u8)>
+}
+
+impl RegisterAllocator {
+    /// Returns the lowest spill slot that does not hold a value
+    pub fn NewSlot(&mut self) -> u8 {
+        let slot = (0..=u8::MAX).find(|x| !self.usedSlots.contains(x)).expect("Ran out of spill slots");
+        self.usedSlots.push(slot);
+        return slot;
+    }
+
+    pub fn FreeSlot(&mut self, slot: u8) {
+        self.usedSlots.retain(|x| *x != slot);
+    }
 }
 
+#[derive(Clone)]
 pub struct ASTConverter {
     pub funcIdTable: HashMap<String, u64>,
     /// Key is variable name, Value is (Memory Block, VarType, Variable Id)
     pub varLookUp: HashMap<String, (u128, VarTypes, u64)>,
-    /// Key is variable name, Value is (Memory Block, ElementType, Variable Id. list of dimension sizes)
+    /// Key is variable name, Value is (Memory Block, ElementType, Variable Id, list of dimension sizes)
     pub listLookUp: HashMap<String, (u128, VarTypes, u64, Vec<u64>)>,
     pub program: Vec<u8>,
+    /// Numbers and strings used by the program. Each value is stored once
+    pub constants: Vec<Constant>,
+    /// Key is constant, Value is its index in constants
+    pub constantLookUp: HashMap<Constant, u64>,
+    /// Bodies of compiled functions, each from OpStartFunc to OpEndFunc. Jump targets in a body are relative to its OpStartFunc
+    pub functionCode: Vec<u8>,
+    /// Where the body of each compiled function is in functionCode
+    pub functionTable: Vec<FunctionEntry>,
     pub curType: VarTypes,
     pub curMemoryBlock: u128,
     pub curNumVarId: u64,
-    pub curNumListId: u64,
     pub curFuncId: u64,
-    pub free_reg: u8
+    pub regAllocator: RegisterAllocator,
+    /// Key is type name, Value is list of (Variant name, List of field type names)
+    pub typeLookUp: HashMap<String, Vec<(String, Vec<String>)>>,
+    /// Key is variant name, Value is (Type name, Tag, List of field type names)
+    pub constructorLookUp: HashMap<String, (String, u8, Vec<String>)>,
+    /// Key is variable name, Value is the declared type name
+    pub varTypeNameLookUp: HashMap<String, String>,
+    /// Key is function name, Value is the declared return type name
+    pub funcReturnTypeLookUp: HashMap<String, String>,
+    /// Key is function name, Value is the function definition with type parameters
+    pub genericFuncLookUp: HashMap<String, ExprAST>,
+    pub exprDepth: u32,
+    /// Key is function name, Value is the list of parameter type names
+    pub funcParamTypeLookUp: HashMap<String, Vec<String>>,
+    /// Key is alias name, Value is the aliased type name
+    pub typeAliasLookUp: HashMap<String, String>,
+    /// Return type name of the function being compiled
+    pub curFuncReturnTypeName: Option<String>,
+    /// Id of the function whose body is being compiled, None for top level code
+    pub curFuncBody: Option<u64>,
+    /// Lines of the compiled statements and names of the variables, used by the debugger
+    pub sourceMap: SourceMap,
+    /// Folds constant expressions and runs the peephole pass over the code of each statement and function (-O)
+    pub optimize: bool,
+    /// Key is function name, Value is (List of (Parameter name, Type name), Body expression, Return type name) of the functions
+    /// that calls are inlined into when optimizing
+    pub inlineLookUp: HashMap<String, optimizer::InlineFunction>
 }
 
 #[derive(FromPrimitive, Debug, Clone, Copy, PartialEq)]
@@ -764,7 +1213,9 @@ pub enum VarTypes{
     CharType,
     StringType,
     ArrayType,
-    ArrayRef
+    ArrayRef,
+    /// Value of a sum type. Stored as a list of (tag, fields...)
+    VariantType
 }
 
 #[derive(FromPrimitive, Debug, PartialEq)]
@@ -776,18 +1227,43 @@ impl ASTConverter {
     pub fn new() -> Self{
         let mut systemFuncTable: HashMap<String, u64> = HashMap::new();
         systemFuncTable.insert("print".to_string(), SystemFunctions::printFunction as u64);
+        let mut systemFuncReturnTypes: HashMap<String, String> = HashMap::new();
+        systemFuncReturnTypes.insert("print".to_string(), "null".to_string());
+
+        // Optional values are a built in sum type. The field of 'some' takes the type of the optional value
+        let mut typeLookUp: HashMap<String, Vec<(String, Vec<String>)>> = HashMap::new();
+        typeLookUp.insert("?".to_string(), [("none".to_string(), Vec::new()), ("some".to_string(), ["?".to_string()].to_vec())].to_vec());
+        let mut constructorLookUp: HashMap<String, (String, u8, Vec<String>)> = HashMap::new();
+        constructorLookUp.insert("none".to_string(), ("?".to_string(), 0, Vec::new()));
+        constructorLookUp.insert("some".to_string(), ("?".to_string(), 1, ["?".to_string()].to_vec()));
 
         ASTConverter{
             funcIdTable: systemFuncTable.clone(),
             varLookUp: HashMap::new(),
             listLookUp: HashMap::new(),
             program: Vec::<u8>::new(),
+            constants: Vec::new(),
+            constantLookUp: HashMap::new(),
+            functionCode: Vec::new(),
+            functionTable: Vec::new(),
             curType: VarTypes::NullType,
             curMemoryBlock: 0,
             curNumVarId: 0,
-            curNumListId: 0,
             curFuncId: 1,
-            free_reg: 0
+            regAllocator: RegisterAllocator::default(),
+            typeLookUp: typeLookUp,
+            constructorLookUp: constructorLookUp,
+            varTypeNameLookUp: HashMap::new(),
+            funcReturnTypeLookUp: systemFuncReturnTypes,
+            genericFuncLookUp: HashMap::new(),
+            exprDepth: 0,
+            funcParamTypeLookUp: HashMap::new(),
+            typeAliasLookUp: HashMap::new(),
+            curFuncReturnTypeName: None,
+            curFuncBody: None,
+            sourceMap: SourceMap::default(),
+            optimize: false,
+            inlineLookUp: HashMap::new()
         }
     }
 
@@ -796,112 +1272,782 @@ impl ASTConverter {
             "number" => Some(VarTypes::FloatType),
             "string" => Some(VarTypes::CharType),
             "char" => Some(VarTypes::CharType),
+            _ if self.typeLookUp.contains_key(&var_text) || var_text.ends_with('?') => Some(VarTypes::VariantType),
             _ => panic!("Can not compile type")
             }
     }
 
-    pub fn ConvertExprToByteCode(&mut self, expr: ExprAST) -> Option<u8> {
+    /// Checks if a type name is taken by a built in type, a sum type or a type alias
+    pub fn IsTypeDeclared(&self, typeName: &str) -> bool {
+        return ["number", "char", "string"].contains(&typeName) || self.typeLookUp.contains_key(typeName) || self.typeAliasLookUp.contains_key(typeName);
+    }
+
+    /// Returns the type held in a register or variable for a value of the given type name
+    pub fn GetValueTypeFromString(&mut self, type_text: &str) -> VarTypes {
+        if type_text.ends_with('?') {
+            return VarTypes::VariantType;
+        }
+        if type_text.ends_with("[]") || type_text == "string" {
+            return VarTypes::ArrayType;
+        }
+        return self.GetVarTypeFromString(type_text.to_string()).unwrap();
+    }
+
+    /// Returns the type name of the elements of a list type name
+    pub fn GetElementTypeName(&self, type_text: &str) -> Option<String> {
+        if type_text == "string" {
+            return Some("char".to_string());
+        }
+        return type_text.strip_suffix("[]").map(|x| x.to_string());
+    }
+
+    /// Panics if a value of the given type name can not be stored where the target type name is expected
+    pub fn CheckAssignable(&self, valueTypeName: &Option<String>, targetTypeName: &str, target: &str) {
+        match valueTypeName {
+            Some(x) if x == "null" => panic!("Can not use a value from a function that returns nothing for {}", target),
+            Some(x) if (x == "none" || x.ends_with('?')) && !targetTypeName.ends_with('?') => {
+                panic!("Can not use optional value for {} of type {}. Unwrap it first or give a default with '??'", target, targetTypeName)
+            },
+            _ => {}
+        }
+    }
+
+    /// Wraps the value in the given register into an optional value, unless it already is one. Returns the register of the optional value
+    pub fn WrapOptional(&mut self, reg: u8, valueTypeName: &Option<String>) -> u8 {
+        let isOptional = match valueTypeName {
+            Some(x) => x == "none" || x.ends_with('?'),
+            None => self.curType == VarTypes::VariantType
+        };
+        if isOptional {
+            return reg;
+        }
+        let register = self.GetFreeReg();
+        self.Emit(Instruction::MakeVariant { reg: register, varType: VarTypes::VariantType, tag: 1, fields: [(reg, self.curType)].to_vec() });
+        self.FreeReg(reg);
+        self.curType = VarTypes::VariantType;
+        return register;
+    }
+
+    /// Returns the field type names of a variant. Fields of 'some' take the type of the matched optional value
+    pub fn GetVariantFieldTypeNames(&self, variantName: &str, typeName: &Option<String>) -> Vec<String> {
+        let (variantTypeName, _, fieldTypeNames) = self.constructorLookUp.get(variantName).unwrap_or_else(|| panic!("Unknown variant {}", variantName));
+        if variantTypeName != "?" {
+            return fieldTypeNames.clone();
+        }
+        match typeName {
+            Some(x) if x.ends_with('?') => fieldTypeNames.iter().map(|_| x[..x.len() - 1].to_string()).collect(),
+            _ => panic!("Can not match {} on a value that is not optional", variantName)
+        }
+    }
+
+    /// Works out the type name of an expression without compiling it
+    pub fn InferTypeName(&self, expr: &ExprAST) -> Option<String> {
         match expr {
-            ExprAST::NumberExpr(num) => {
-                let mut byteCode: u8 = 0;
-                //Loads Op Code
-                byteCode = byteCode | ((OpCodes::OpLoadScalar as u8) );
-                self.program.push(byteCode);
+            ExprAST::NumberExpr(_) => Some("number".to_string()),
+            ExprAST::CharExpr(_) => Some("char".to_string()),
+            ExprAST::StringExpr(_) => Some("string".to_string()),
+            ExprAST::NoneExpr => Some("none".to_string()),
+            ExprAST::ListExpr(items) => {
+                let elementType = self.InferTypeName(items.first()?)?;
+                Some(elementType + "[]")
+            },
+            ExprAST::VariableExpr(name) => {
+                if let Some(constructor) = self.constructorLookUp.get(name) {
+                    return Some(constructor.0.clone());
+                }
+                self.varTypeNameLookUp.get(name).cloned()
+            },
+            ExprAST::CallExpr { func_name, parameters } => {
+                if func_name == "unwrap" {
+                    return self.InferTypeName(parameters.first()?)?.strip_suffix('?').map(|x| x.to_string());
+                }
+                if func_name == "some" && !self.funcIdTable.contains_key(func_name) {
+                    return Some(self.InferTypeName(parameters.first()?)?.trim_end_matches('?').to_string() + "?");
+                }
+                if let Some(constructor) = self.constructorLookUp.get(func_name) {
+                    return Some(constructor.0.clone());
+                }
+                if let Some(ExprAST::FuncExpr { name: _, type_params: _, args: _, return_type, body: _, bodyLines: _ }) = self.genericFuncLookUp.get(func_name) {
+                    let typeBindings = self.InferTypeParams(func_name, parameters).ok()?;
+                    return return_type.as_ref().map(|x| ASTConverter::SubstituteTypeName(x, &typeBindings));
+                }
+                self.funcReturnTypeLookUp.get(func_name).cloned()
+            },
+            ExprAST::BinaryExpr { op: Token::DefaultOp, lhs, rhs: _, opChar: _ } => self.InferTypeName(lhs)?.strip_suffix('?').map(|x| x.to_string()),
+            ExprAST::BinaryExpr { op: _, lhs, rhs: _, opChar: _ } => self.InferTypeName(lhs),
+            ExprAST::ElementAccess { array_name, element_indexes } => {
+                let mut typeName = self.varTypeNameLookUp.get(array_name)?.clone();
+                for _ in element_indexes {
+                    typeName = self.GetElementTypeName(&typeName)?;
+                }
+                Some(typeName)
+            },
+            _ => None
+        }
+    }
 
-                let mut byteCode: u8 = 0;
-                //Set the register to load into
-                let register : u8  = self.free_reg;
-                self.free_reg = (self.free_reg + 1) % 8;
+    /// Pushes the bytes of an instruction to the program
+    pub fn Emit(&mut self, instruction: Instruction) {
+        instruction.Encode(&mut self.program);
+    }
+
+    /// Returns the index of the constant in the constant pool, adding it if it is new
+    pub fn AddConstant(&mut self, constant: Constant) -> u64 {
+        if let Some(constId) = self.constantLookUp.get(&constant) {
+            return *constId;
+        }
+        let constId = self.constants.len() as u64;
+        self.constants.push(constant.clone());
+        self.constantLookUp.insert(constant, constId);
+        return constId;
+    }
+
+    /// Overwrites a 64 bit value in the program at the given position
+    pub fn Patch64BitVal(&mut self, pos: usize, val: u64) {
+        for i in 0..8 {
+            let shift: u8 = 56 - 8*i;
+            self.program[pos + i as usize] = ((val >> shift) & 0xFF) as u8;
+        }
+    }
+
+    /// Pushes a jump with a placeholder target. Returns the position of the target to patch
+    pub fn PushJump(&mut self, opCode: OpCodes) -> usize {
+        match opCode {
+            OpCodes::OpJump => self.Emit(Instruction::Jump { target: 0 }),
+            OpCodes::OpJumpIfFalse => self.Emit(Instruction::JumpIfFalse { target: 0 }),
+            _ => panic!("{:?} is not a jump", opCode)
+        }
+        return self.program.len() - 8;
+    }
+
+    /// Gets a free register. If every register is live, the oldest one is spilled and handed out instead
+    pub fn GetFreeReg(&mut self) -> u8 {
+        let allocator = &mut self.regAllocator;
+        if let Some(register) = (0..regResult as u8).find(|x| !allocator.liveRegs.contains(x)) {
+            allocator.liveRegs.push(register);
+            return register;
+        }
+        let register = allocator.liveRegs.remove(0);
+        let slot = allocator.NewSlot();
+        allocator.spilledRegs.entry(register).or_default().push(slot);
+        allocator.liveRegs.push(register);
+        self.Emit(Instruction::Spill { reg: register, slot: slot });
+        return register;
+    }
+
+    /// Frees a register once its value is used. A value spilled out of the register is loaded back
+    pub fn FreeReg(&mut self, register: u8) {
+        let allocator = &mut self.regAllocator;
+        if !allocator.liveRegs.contains(&register) {
+            return;
+        }
+        allocator.liveRegs.retain(|x| *x != register);
+        if let Some(slot) = allocator.spilledRegs.get_mut(&register).and_then(|x| x.pop()) {
+            allocator.FreeSlot(slot);
+            allocator.liveRegs.insert(0, register);
+            self.Emit(Instruction::Reload { reg: register, slot: slot });
+        }
+    }
+
+    /// Spills every live register so the code that follows can use all of them. Returns the allocator to give to RestoreLiveRegs
+    pub fn SaveLiveRegs(&mut self) -> RegisterAllocator {
+        let mut saved = self.regAllocator.clone();
+        self.regAllocator = RegisterAllocator { usedSlots: saved.usedSlots.clone(), ..Default::default() };
+        for register in saved.liveRegs.clone() {
+            let slot = self.regAllocator.NewSlot();
+            saved.savedRegs.push((register, slot));
+            self.Emit(Instruction::Spill { reg: register, slot: slot });
+        }
+        return saved;
+    }
+
+    /// Loads back the registers spilled by SaveLiveRegs
+    pub fn RestoreLiveRegs(&mut self, mut saved: RegisterAllocator) {
+        for (register, slot) in saved.savedRegs.drain(..) {
+            self.Emit(Instruction::Reload { reg: register, slot: slot });
+        }
+        self.regAllocator = saved;
+    }
+
+    /// Replaces type parameters in a type name (e.g T[] becomes number[] when T is number)
+    pub fn SubstituteTypeName(typeName: &str, typeBindings: &HashMap<String, String>) -> String {
+        let baseName = typeName.trim_end_matches(['[', ']', '?']);
+        match typeBindings.get(baseName) {
+            Some(boundType) => boundType.clone() + &typeName[baseName.len()..],
+            None => typeName.to_string()
+        }
+    }
+
+    /// Replaces type parameters in every type name of an expression
+    pub fn SubstituteTypeParams(expr: &ExprAST, typeBindings: &HashMap<String, String>) -> ExprAST {
+        let substitute = |x: &ExprAST| Box::new(ASTConverter::SubstituteTypeParams(x, typeBindings));
+        match expr {
+            ExprAST::VariableHeader { name, typeName } => ExprAST::VariableHeader { name: name.clone(), typeName: ASTConverter::SubstituteTypeName(typeName, typeBindings) },
+            ExprAST::VariableAssignExpr { varObject, value } => ExprAST::VariableAssignExpr { varObject: substitute(varObject), value: substitute(value) },
+            ExprAST::BinaryExpr { op, lhs, rhs, opChar } => ExprAST::BinaryExpr { op: *op, lhs: substitute(lhs), rhs: substitute(rhs), opChar: opChar.clone() },
+            ExprAST::CallExpr { func_name, parameters } => ExprAST::CallExpr { func_name: func_name.clone(), parameters: parameters.iter().map(|x| *substitute(x)).collect() },
+            ExprAST::ListExpr(items) => ExprAST::ListExpr(items.iter().map(|x| *substitute(x)).collect()),
+            ExprAST::ReturnExpr(value) => ExprAST::ReturnExpr(substitute(value)),
+            ExprAST::MatchExpr { value, arms } => ExprAST::MatchExpr { value: substitute(value), arms: arms.iter().map(|(pattern, body)| (pattern.clone(), *substitute(body))).collect() },
+            ExprAST::ElementAccess { array_name, element_indexes } => ExprAST::ElementAccess { array_name: array_name.clone(), element_indexes: element_indexes.iter().map(|x| substitute(x)).collect() },
+            ExprAST::TypeDeclExpr { name, variants } => ExprAST::TypeDeclExpr { 
+                name: name.clone(), 
+                variants: variants.iter().map(|(variantName, fields)| (variantName.clone(), fields.iter().map(|x| ASTConverter::SubstituteTypeName(x, typeBindings)).collect())).collect() 
+            },
+            ExprAST::FuncExpr { name, type_params, args, return_type, body, bodyLines } => ExprAST::FuncExpr { 
+                name: name.clone(), 
+                type_params: type_params.clone(), 
+                args: args.iter().map(|x| *substitute(x)).collect(), 
+                return_type: return_type.as_ref().map(|x| ASTConverter::SubstituteTypeName(x, typeBindings)), 
+                body: body.iter().map(|x| *substitute(x)).collect(),
+                bodyLines: bodyLines.clone()
+            },
+            _ => expr.clone()
+        }
+    }
+
+    /// Follows type aliases in a type name until it names a type that is not an alias (e.g Grid? becomes number[][]?)
+    pub fn ResolveTypeName(&self, typeName: &str) -> String {
+        let mut aliasChain: Vec<String> = Vec::new();
+        let mut resolved = typeName.to_string();
+        loop {
+            let baseName = resolved.trim_end_matches(['[', ']', '?']).to_string();
+            let Some(aliasedType) = self.typeAliasLookUp.get(&baseName) else { return resolved };
+            let isCycle = aliasChain.contains(&baseName);
+            aliasChain.push(baseName.clone());
+            if isCycle {
+                panic!("Type aliases form a cycle: {}", aliasChain.join(" -> "));
+            }
+            resolved = aliasedType.clone() + &resolved[baseName.len()..];
+        }
+    }
+
+    /// Replaces type aliases in every type name of an expression
+    pub fn ResolveTypeAliases(&self, expr: &ExprAST) -> ExprAST {
+        let mut aliasBindings: HashMap<String, String> = self.typeAliasLookUp.keys().map(|x| (x.clone(), self.ResolveTypeName(x))).collect();
+        // Type parameters hide aliases with the same name
+        if let ExprAST::FuncExpr { name: _, type_params, args: _, return_type: _, body: _, bodyLines: _ } = expr {
+            for type_param in type_params {
+                aliasBindings.remove(type_param);
+            }
+        }
+        return ASTConverter::SubstituteTypeParams(expr, &aliasBindings);
+    }
+
+    /// Works out the types of the type parameters of a generic function from the call parameters
+    pub fn InferTypeParams(&self, func_name: &str, parameters: &[ExprAST]) -> Result<HashMap<String, String>, String> {
+        let (type_params, args) = match self.genericFuncLookUp.get(func_name) {
+            Some(ExprAST::FuncExpr { name: _, type_params, args, return_type: _, body: _, bodyLines: _ }) => (type_params, args),
+            _ => return Err(format!("{} is not a generic function", func_name))
+        };
+        if args.len() != parameters.len() {
+            return Err(format!("Function {} takes {} parameters, got {}", func_name, args.len(), parameters.len()));
+        }
+        let mut typeBindings: HashMap<String, String> = HashMap::new();
+        for (arg, param) in args.iter().zip(parameters) {
+            let argTypeName = match arg {
+                ExprAST::VariableHeader { name: _, typeName } => typeName,
+                _ => continue
+            };
+            let baseName = argTypeName.trim_end_matches("[]");
+            if !type_params.iter().any(|x| x == baseName) {
+                continue;
+            }
+            let mut paramTypeName = self.InferTypeName(param).ok_or(format!("Can not infer type of parameter for {}", argTypeName))?;
+            for _ in 0..(argTypeName.len() - baseName.len()) / 2 {
+                paramTypeName = self.GetElementTypeName(&paramTypeName).ok_or(format!("Expected an array for {}, got {}", argTypeName, paramTypeName))?;
+            }
+            match typeBindings.get(baseName) {
+                Some(boundType) if *boundType != paramTypeName => {
+                    return Err(format!("Type parameter {} can not be both {} and {}", baseName, boundType, paramTypeName));
+                },
+                _ => {typeBindings.insert(baseName.to_string(), paramTypeName);}
+            }
+        }
+        for type_param in type_params {
+            if !typeBindings.contains_key(type_param) {
+                return Err(format!("Can not infer type parameter {} of {}", type_param, func_name));
+            }
+        }
+        return Ok(typeBindings);
+    }
+
+    /// Compiles the generic functions an expression needs before the expression itself
+    pub fn InstantiateGenericCalls(&mut self, expr: &ExprAST) {
+        let mut instantiations = Vec::<(String, ExprAST)>::new();
+        let mut shadowed = Vec::<(String, Option<String>)>::new();
+        self.CollectGenericCalls(expr, &mut instantiations, &mut shadowed);
+        self.UnbindTypeNames(&mut shadowed, 0);
+        // A generic function that calls itself is compiled once its id is added to funcIdTable
+        let definedName = match expr {
+            ExprAST::FuncExpr { name, type_params: _, args: _, return_type: _, body: _, bodyLines: _ } => Some(name),
+            _ => None
+        };
+        for (mangledName, funcExpr) in instantiations {
+            if Some(&mangledName) != definedName && !self.funcIdTable.contains_key(&mangledName) {
+                self.ConvertExprToByteCode(funcExpr);
+            }
+        }
+    }
 
-                //Load register into bytecode
-                byteCode = byteCode | ((register as u8) << 5) | VarTypes::FloatType as u8;
+    /// Finds the calls to generic functions in an expression and adds the functions they need that are not compiled yet.
+    /// The type names of the variables the expression declares are bound while it is walked, and the type names they
+    /// replace are kept in shadowed
+    fn CollectGenericCalls(&mut self, expr: &ExprAST, instantiations: &mut Vec<(String, ExprAST)>, shadowed: &mut Vec<(String, Option<String>)>) {
+        match expr {
+            ExprAST::ListExpr(items) => {
+                for item in items {
+                    self.CollectGenericCalls(item, instantiations, shadowed);
+                }
+            },
+            ExprAST::VariableAssignExpr { varObject, value } => {
+                self.CollectGenericCalls(value, instantiations, shadowed);
+                match varObject.as_ref() {
+                    ExprAST::VariableHeader { name, typeName } => self.BindTypeName(name, Some(typeName.clone()), shadowed),
+                    varObject => self.CollectGenericCalls(varObject, instantiations, shadowed)
+                }
+            },
+            ExprAST::BinaryExpr { op: _, lhs, rhs, opChar: _ } => {
+                self.CollectGenericCalls(lhs, instantiations, shadowed);
+                self.CollectGenericCalls(rhs, instantiations, shadowed);
+            },
+            ExprAST::CallExpr { func_name, parameters } => {
+                for param in parameters {
+                    self.CollectGenericCalls(param, instantiations, shadowed);
+                }
+                let Some(ExprAST::FuncExpr { name: _, type_params, args: _, return_type: _, body: _, bodyLines: _ }) = self.genericFuncLookUp.get(func_name) else { return };
+                // Calls whose types can not be worked out fail when they are compiled
+                let Ok(typeBindings) = self.InferTypeParams(func_name, parameters) else { return };
+                let typeNames: Vec<String> = type_params.iter().map(|x| typeBindings.get(x).unwrap().clone()).collect();
+                let mangledName = format!("{}<{}>", func_name, typeNames.join(","));
+                if self.funcIdTable.contains_key(&mangledName) || instantiations.iter().any(|x| x.0 == mangledName) {
+                    return;
+                }
+                let mut funcExpr = ASTConverter::SubstituteTypeParams(self.genericFuncLookUp.get(func_name).unwrap(), &typeBindings);
+                if let ExprAST::FuncExpr { ref mut name, ref mut type_params, args: _, return_type: _, body: _, bodyLines: _ } = funcExpr {
+                    *name = mangledName.clone();
+                    type_params.clear();
+                }
+                instantiations.push((mangledName, funcExpr));
+            },
+            ExprAST::IfExpr { cond, Then, Else } => {
+                self.CollectGenericCalls(cond, instantiations, shadowed);
+                self.CollectGenericCalls(Then, instantiations, shadowed);
+                if let Some(Else) = Else {
+                    self.CollectGenericCalls(Else, instantiations, shadowed);
+                }
+            },
+            ExprAST::ForExpr { var: _, start, end, stepFunc, body } => {
+                for part in [start, end, stepFunc, body] {
+                    self.CollectGenericCalls(part, instantiations, shadowed);
+                }
+            },
+            ExprAST::UnaryExpr { Opcode: _, Operand } => self.CollectGenericCalls(Operand, instantiations, shadowed),
+            // A generic function is only compiled once it is called with types for its type parameters
+            ExprAST::FuncExpr { name: _, type_params, args, return_type: _, body, bodyLines: _ } if type_params.is_empty() => {
+                let scope = shadowed.len();
+                for arg in args {
+                    if let ExprAST::VariableHeader { name, typeName } = arg {
+                        self.BindTypeName(name, Some(typeName.clone()), shadowed);
+                    }
+                }
+                for bodyExpr in body {
+                    self.CollectGenericCalls(bodyExpr, instantiations, shadowed);
+                }
+                self.UnbindTypeNames(shadowed, scope);
+            },
+            ExprAST::ElementAccess { array_name: _, element_indexes } => {
+                for index in element_indexes {
+                    self.CollectGenericCalls(index, instantiations, shadowed);
+                }
+            },
+            ExprAST::ReturnExpr(value) => self.CollectGenericCalls(value, instantiations, shadowed),
+            ExprAST::MatchExpr { value, arms } => {
+                self.CollectGenericCalls(value, instantiations, shadowed);
+                let typeName = self.InferTypeName(value);
+                for (pattern, body) in arms {
+                    let scope = shadowed.len();
+                    let pattern = self.ResolvePattern(pattern);
+                    self.BindPatternTypeNames(&pattern, &typeName, shadowed);
+                    self.CollectGenericCalls(body, instantiations, shadowed);
+                    self.UnbindTypeNames(shadowed, scope);
+                }
+            },
+            _ => {}
+        }
+    }
+
+    /// Binds the type names of the variables a pattern declares, the way CompilePatternBindings does
+    fn BindPatternTypeNames(&mut self, pattern: &PatternAST, typeName: &Option<String>, shadowed: &mut Vec<(String, Option<String>)>) {
+        match pattern {
+            PatternAST::Binding(name) => self.BindTypeName(name, typeName.clone(), shadowed),
+            PatternAST::ListPattern(elements) => {
+                let elementTypeName = typeName.as_ref().and_then(|x| self.GetElementTypeName(x));
+                for element in elements {
+                    self.BindPatternTypeNames(element, &elementTypeName, shadowed);
+                }
+            },
+            PatternAST::VariantPattern { name, fields } => {
+                // Patterns that do not fit the value fail when they are compiled
+                let Some((variantTypeName, _, fieldTypeNames)) = self.constructorLookUp.get(name) else { return };
+                let fieldTypeNames = match typeName {
+                    _ if variantTypeName != "?" => fieldTypeNames.clone(),
+                    Some(x) if x.ends_with('?') => fieldTypeNames.iter().map(|_| x[..x.len() - 1].to_string()).collect(),
+                    _ => return
+                };
+                for (field, fieldTypeName) in fields.iter().zip(fieldTypeNames) {
+                    self.BindPatternTypeNames(field, &Some(fieldTypeName), shadowed);
+                }
+            },
+            _ => {}
+        }
+    }
+
+    /// Sets the type name of a variable, keeping the one it replaces in shadowed
+    fn BindTypeName(&mut self, name: &str, typeName: Option<String>, shadowed: &mut Vec<(String, Option<String>)>) {
+        let replaced = match typeName {
+            Some(typeName) => self.varTypeNameLookUp.insert(name.to_string(), typeName),
+            None => self.varTypeNameLookUp.remove(name)
+        };
+        shadowed.push((name.to_string(), replaced));
+    }
+
+    /// Puts back the type names replaced since shadowed had scope entries
+    fn UnbindTypeNames(&mut self, shadowed: &mut Vec<(String, Option<String>)>, scope: usize) {
+        while shadowed.len() > scope {
+            let (name, typeName) = shadowed.pop().unwrap();
+            match typeName {
+                Some(typeName) => {self.varTypeNameLookUp.insert(name, typeName);},
+                None => {self.varTypeNameLookUp.remove(&name);}
+            }
+        }
+    }
+
+    /// Turns bindings that name a variant without fields into variant patterns
+    pub fn ResolvePattern(&self, pattern: &PatternAST) -> PatternAST {
+        match pattern {
+            PatternAST::Binding(name) if self.constructorLookUp.contains_key(name) => {
+                PatternAST::VariantPattern { name: name.clone(), fields: Vec::new() }
+            },
+            PatternAST::ListPattern(elements) => {
+                PatternAST::ListPattern(elements.iter().map(|x| self.ResolvePattern(x)).collect())
+            },
+            PatternAST::VariantPattern { name, fields } => {
+                PatternAST::VariantPattern { name: name.clone(), fields: fields.iter().map(|x| self.ResolvePattern(x)).collect() }
+            },
+            _ => pattern.clone()
+        }
+    }
 
-                // Adds to the program list
-                self.program.push(byteCode);
+    /// Checks if a pattern matches every value
+    pub fn IsIrrefutablePattern(pattern: &PatternAST) -> bool {
+        matches!(pattern, PatternAST::Wildcard | PatternAST::Binding(_))
+    }
 
-                let floatBits = f64::to_bits(num);
-                for i in range(0, 8){
-                    let shift: u8 = 56 - 8*i;
-                    self.program.push( ((floatBits >> shift) & 0xFF) as u8);    
+    /// Specializes a row of patterns for the given constructor pattern. Returns None if the row can not match the constructor
+    pub fn SpecializePatternRow(&self, row: &[PatternAST], constructor: &PatternAST) -> Option<Vec<PatternAST>> {
+        let (head, rest) = row.split_first().unwrap();
+        let mut newRow: Vec<PatternAST> = match (head, constructor) {
+            (PatternAST::Wildcard | PatternAST::Binding(_), PatternAST::VariantPattern { name, fields: _ }) => {
+                vec![PatternAST::Wildcard; self.constructorLookUp.get(name).unwrap().2.len()]
+            },
+            (PatternAST::Wildcard | PatternAST::Binding(_), PatternAST::ListPattern(elements)) => {
+                vec![PatternAST::Wildcard; elements.len()]
+            },
+            (PatternAST::Wildcard | PatternAST::Binding(_), _) => Vec::new(),
+            (PatternAST::VariantPattern { name, fields }, PatternAST::VariantPattern { name: constructorName, fields: _ }) => {
+                if name != constructorName {
+                    return None;
+                }
+                fields.clone()
+            },
+            (PatternAST::ListPattern(elements), PatternAST::ListPattern(constructorElements)) => {
+                if elements.len() != constructorElements.len() {
+                    return None;
+                }
+                elements.clone()
+            },
+            _ => {
+                if head != constructor {
+                    return None;
                 }
+                Vec::new()
+            }
+        };
+        newRow.extend_from_slice(rest);
+        return Some(newRow);
+    }
 
-                self.curType = VarTypes::FloatType;
-                return Some(register); 
+    /// Checks if the pattern row matches a value that none of the rows in the matrix match
+    pub fn IsPatternRowUseful(&self, matrix: &[Vec<PatternAST>], row: &[PatternAST]) -> bool {
+        if row.is_empty() {
+            return matrix.is_empty();
+        }
+        if !ASTConverter::IsIrrefutablePattern(&row[0]) {
+            let specializedMatrix: Vec<Vec<PatternAST>> = matrix.iter().filter_map(|x| self.SpecializePatternRow(x, &row[0])).collect();
+            return self.IsPatternRowUseful(&specializedMatrix, &self.SpecializePatternRow(row, &row[0]).unwrap());
+        }
+
+        // Row starts with a wildcard. Check every variant if the first column covers a whole sum type
+        let mut usedConstructors: Vec<PatternAST> = Vec::new();
+        for matrixRow in matrix {
+            if !ASTConverter::IsIrrefutablePattern(&matrixRow[0]) && !usedConstructors.contains(&matrixRow[0]) {
+                usedConstructors.push(matrixRow[0].clone());
+            }
+        }
+        if let Some(PatternAST::VariantPattern { name, fields: _ }) = usedConstructors.first() {
+            let typeName = &self.constructorLookUp.get(name).unwrap().0;
+            let variants = self.typeLookUp.get(typeName).unwrap();
+            let usedNames: Vec<&String> = usedConstructors.iter().filter_map(|x| if let PatternAST::VariantPattern { name, fields: _ } = x { Some(name) } else { None }).collect();
+            if variants.iter().all(|x| usedNames.contains(&&x.0)) {
+                return variants.iter().any(|(variantName, fields)| {
+                    let constructor = PatternAST::VariantPattern { name: variantName.clone(), fields: vec![PatternAST::Wildcard; fields.len()] };
+                    let specializedMatrix: Vec<Vec<PatternAST>> = matrix.iter().filter_map(|x| self.SpecializePatternRow(x, &constructor)).collect();
+                    self.IsPatternRowUseful(&specializedMatrix, &self.SpecializePatternRow(row, &constructor).unwrap())
+                });
+            }
+        }
+        let defaultMatrix: Vec<Vec<PatternAST>> = matrix.iter().filter(|x| ASTConverter::IsIrrefutablePattern(&x[0])).map(|x| x[1..].to_vec()).collect();
+        return self.IsPatternRowUseful(&defaultMatrix, &row[1..]);
+    }
+
+    /// Panics if the patterns do not cover every possible value
+    pub fn CheckMatchExhaustive(&self, patterns: &[PatternAST]) {
+        let matrix: Vec<Vec<PatternAST>> = patterns.iter().map(|x| [x.clone()].to_vec()).collect();
+        if !self.IsPatternRowUseful(&matrix, &[PatternAST::Wildcard]) {
+            return;
+        }
+        let typeName = patterns.iter().find_map(|x| if let PatternAST::VariantPattern { name, fields: _ } = x { Some(self.constructorLookUp.get(name).unwrap().0.clone()) } else { None });
+        if let Some(typeName) = typeName {
+            let missingVariants: Vec<String> = self.typeLookUp.get(&typeName).unwrap().iter().filter(|(variantName, fields)| {
+                let constructor = PatternAST::VariantPattern { name: variantName.clone(), fields: vec![PatternAST::Wildcard; fields.len()] };
+                let specializedMatrix: Vec<Vec<PatternAST>> = matrix.iter().filter_map(|x| self.SpecializePatternRow(x, &constructor)).collect();
+                self.IsPatternRowUseful(&specializedMatrix, &self.SpecializePatternRow(std::slice::from_ref(&constructor), &constructor).unwrap())
+            }).map(|x| x.0.clone()).collect();
+            panic!("Match is not exhaustive. Missing variants: {}", missingVariants.join(", "));
+        }
+        panic!("Match is not exhaustive. Add a '_' arm");
+    }
+
+    /// Pushes the checks for a pattern on the value in the given register. Every failed check jumps to a position pushed to failJumps
+    pub fn CompilePatternTest(&mut self, pattern: &PatternAST, reg: u8, typeName: &Option<String>, failJumps: &mut Vec<usize>) {
+        match pattern {
+            PatternAST::Wildcard | PatternAST::Binding(_) => {},
+            PatternAST::NumberPattern(_) | PatternAST::CharPattern(_) | PatternAST::StringPattern(_) => {
+                let (literal, literalType) = match pattern {
+                    PatternAST::NumberPattern(val) => (ExprAST::NumberExpr(*val), VarTypes::FloatType),
+                    PatternAST::CharPattern(val) => (ExprAST::CharExpr(val.clone()), VarTypes::CharType),
+                    PatternAST::StringPattern(val) => (ExprAST::StringExpr(val.clone()), VarTypes::ArrayType),
+                    _ => unreachable!()
+                };
+                let literalReg = self.ConvertExprToByteCode(literal).unwrap();
+                self.Emit(Instruction::TestEqual { reg1: reg, varType: literalType, reg2: literalReg });
+                self.FreeReg(literalReg);
+                failJumps.push(self.PushJump(OpCodes::OpJumpIfFalse));
             },
-            ExprAST::CharExpr(val) => {
-                let mut byteCode: u8 = 0;
-                //Loads Op Code
-                byteCode = byteCode | ((OpCodes::OpLoadScalar as u8) );
-                self.program.push(byteCode);
+            PatternAST::ListPattern(elements) => {
+                let elementTypeName = typeName.as_ref().and_then(|x| self.GetElementTypeName(x)).expect("Can not match array pattern on a value that is not an array");
+                let elementType = self.GetValueTypeFromString(&elementTypeName);
+                self.Emit(Instruction::TestLength { reg: reg, length: elements.len() as u8 });
+                failJumps.push(self.PushJump(OpCodes::OpJumpIfFalse));
+                for (i, element) in elements.iter().enumerate() {
+                    if ASTConverter::IsIrrefutablePattern(element) {
+                        continue;
+                    }
+                    let elementReg = self.GetFreeReg();
+                    self.Emit(Instruction::LoadElement { reg: elementReg, varType: elementType, listReg: reg, index: i as u8 });
+                    self.CompilePatternTest(element, elementReg, &Some(elementTypeName.clone()), failJumps);
+                    self.FreeReg(elementReg);
+                }
+            },
+            PatternAST::VariantPattern { name, fields } => {
+                let tag = self.constructorLookUp.get(name).unwrap_or_else(|| panic!("Unknown variant {}", name)).1;
+                let fieldTypeNames = self.GetVariantFieldTypeNames(name, typeName);
+                if fields.len() != fieldTypeNames.len() {
+                    panic!("Variant {} has {} fields, pattern has {}", name, fieldTypeNames.len(), fields.len());
+                }
+                self.Emit(Instruction::TestTag { reg: reg, tag: tag });
+                failJumps.push(self.PushJump(OpCodes::OpJumpIfFalse));
+                for (i, field) in fields.iter().enumerate() {
+                    if ASTConverter::IsIrrefutablePattern(field) {
+                        continue;
+                    }
+                    let fieldType = self.GetValueTypeFromString(&fieldTypeNames[i]);
+                    let fieldReg = self.GetFreeReg();
+                    self.Emit(Instruction::LoadElement { reg: fieldReg, varType: fieldType, listReg: reg, index: (i + 1) as u8 });
+                    self.CompilePatternTest(field, fieldReg, &Some(fieldTypeNames[i].clone()), failJumps);
+                    self.FreeReg(fieldReg);
+                }
+            }
+        }
+    }
+
+    /// Declares the variables bound by a pattern on the value in the given register
+    pub fn CompilePatternBindings(&mut self, pattern: &PatternAST, reg: u8, valueType: VarTypes, typeName: &Option<String>) {
+        match pattern {
+            PatternAST::Binding(name) => {
+                self.Emit(Instruction::NewVar { reg: reg, varType: valueType });
+                self.NameVariable(self.curNumVarId, name);
+                self.varLookUp.insert(name.clone(), (self.curMemoryBlock, valueType, self.curNumVarId));
+                self.curNumVarId += 1;
+                match typeName {
+                    Some(typeName) => {self.varTypeNameLookUp.insert(name.clone(), typeName.clone());},
+                    None => {self.varTypeNameLookUp.remove(name);}
+                }
+            },
+            PatternAST::ListPattern(elements) => {
+                let elementTypeName = typeName.as_ref().and_then(|x| self.GetElementTypeName(x)).unwrap();
+                let elementType = self.GetValueTypeFromString(&elementTypeName);
+                for (i, element) in elements.iter().enumerate() {
+                    if !ASTConverter::PatternHasBindings(element) {
+                        continue;
+                    }
+                    let elementReg = self.GetFreeReg();
+                    self.Emit(Instruction::LoadElement { reg: elementReg, varType: elementType, listReg: reg, index: i as u8 });
+                    self.CompilePatternBindings(element, elementReg, elementType, &Some(elementTypeName.clone()));
+                    self.FreeReg(elementReg);
+                }
+            },
+            PatternAST::VariantPattern { name, fields } => {
+                let fieldTypeNames = self.GetVariantFieldTypeNames(name, typeName);
+                for (i, field) in fields.iter().enumerate() {
+                    if !ASTConverter::PatternHasBindings(field) {
+                        continue;
+                    }
+                    let fieldType = self.GetValueTypeFromString(&fieldTypeNames[i]);
+                    let fieldReg = self.GetFreeReg();
+                    self.Emit(Instruction::LoadElement { reg: fieldReg, varType: fieldType, listReg: reg, index: (i + 1) as u8 });
+                    self.CompilePatternBindings(field, fieldReg, fieldType, &Some(fieldTypeNames[i].clone()));
+                    self.FreeReg(fieldReg);
+                }
+            },
+            _ => {}
+        }
+    }
 
-                let mut byteCode: u8 = 0;
+    /// Checks if a pattern declares any variables
+    pub fn PatternHasBindings(pattern: &PatternAST) -> bool {
+        match pattern {
+            PatternAST::Binding(_) => true,
+            PatternAST::ListPattern(elements) => elements.iter().any(ASTConverter::PatternHasBindings),
+            PatternAST::VariantPattern { name: _, fields } => fields.iter().any(ASTConverter::PatternHasBindings),
+            _ => false
+        }
+    }
+
+    /// Compiles a top level expression that starts on the given source line, so the debugger can map its code back to the line
+    pub fn ConvertStatement(&mut self, expr: ExprAST, line: usize) -> Option<u8> {
+        self.sourceMap.AddLine(self.curFuncBody, self.program.len(), line);
+        return self.ConvertExprToByteCode(expr);
+    }
+
+    /// Returns the body of a function in inlineLookUp with the call's parameters in place of its own, None if the call has to stay a call.
+    /// Parameters that can print or fail are only inlined if the body uses them once and does nothing else that could
+    pub fn InlineCall(&self, func_name: &str, parameters: &[ExprAST]) -> Option<ExprAST> {
+        let (params, body, returnTypeName) = self.inlineLookUp.get(func_name)?;
+        if params.len() != parameters.len() {
+            return None;
+        }
+        let mut bindings: HashMap<String, ExprAST> = HashMap::new();
+        let mut effectCount = 0;
+        for ((paramName, paramTypeName), param) in params.iter().zip(parameters) {
+            if self.InferTypeName(param).as_ref() != Some(paramTypeName) {
+                return None;
+            }
+            let uses = optimizer::CountUses(body, paramName);
+            match param {
+                ExprAST::NumberExpr(_) | ExprAST::CharExpr(_) | ExprAST::StringExpr(_) | ExprAST::VariableExpr(_) => {},
+                // Arithmetic is not worked out twice
+                ExprAST::BinaryExpr { .. } if !optimizer::HasEffects(param) && uses <= 1 => {},
+                ExprAST::BinaryExpr { .. } | ExprAST::CallExpr { .. } | ExprAST::ElementAccess { .. } if uses == 1 => effectCount += 1,
+                _ => return None
+            }
+            bindings.insert(paramName.clone(), param.clone());
+        }
+        if effectCount > 1 || (effectCount == 1 && optimizer::HasEffects(body)) {
+            return None;
+        }
+        let inlined = optimizer::Substitute(body, &bindings);
+        if self.InferTypeName(&inlined).as_ref() != Some(returnTypeName) {
+            return None;
+        }
+        return Some(inlined);
+    }
+
+    /// Records the name of a variable declared at the current pc for the debugger
+    pub fn NameVariable(&mut self, varId: u64, name: &str) {
+        self.sourceMap.AddVariable(self.curFuncBody, self.program.len(), varId, name);
+    }
+
+    pub fn ConvertExprToByteCode(&mut self, mut expr: ExprAST) -> Option<u8> {
+        if self.exprDepth == 0 && self.optimize {
+            expr = optimizer::FoldConstants(expr);
+        }
+        if self.exprDepth == 0 && !self.typeAliasLookUp.is_empty() {
+            expr = self.ResolveTypeAliases(&expr);
+        }
+        if self.exprDepth == 0 && !self.genericFuncLookUp.is_empty() {
+            self.InstantiateGenericCalls(&expr);
+        }
+        let start = self.program.len();
+        self.exprDepth += 1;
+        let result = self.CompileExpr(expr);
+        self.exprDepth -= 1;
+        if self.exprDepth == 0 {
+            // Nothing is live between top level expressions
+            self.regAllocator = RegisterAllocator::default();
+            if self.optimize && self.program.len() > start {
+                let code = self.program.split_off(start);
+                let optimized = self.OptimizeCode(&code, start);
+                self.program.extend(optimized);
+            }
+        }
+        return result;
+    }
+
+    /// Runs the peephole pass over code of the function being compiled (or the top level code) that starts at pc base,
+    /// and moves the pcs the debugger has for it along with the instructions
+    pub fn OptimizeCode(&mut self, code: &[u8], base: usize) -> Vec<u8> {
+        let statementStarts: Vec<usize> = self.sourceMap.lines.get(&self.curFuncBody).map_or(Vec::new(), |lines| lines.iter().map(|x| x.0).collect());
+        let (optimized, pcMap) = optimizer::Peephole(code, base, &statementStarts);
+        self.sourceMap.MovePcs(self.curFuncBody, |pc| pcMap.Map(pc));
+        return optimized;
+    }
+
+    pub fn CompileExpr(&mut self, expr: ExprAST) -> Option<u8> {
+        match expr {
+            ExprAST::NumberExpr(num) => {
                 //Set the register to load into
-                let register : u8  = self.free_reg;
-                self.free_reg = (self.free_reg + 1) % 8;
+                let register : u8  = self.GetFreeReg();
 
-                //Load register into bytecode
-                byteCode = byteCode | ((register as u8) << bitRegShift) | VarTypes::CharType as u8;
+                let constId = self.AddConstant(Constant::Number(num));
+                self.Emit(Instruction::LoadConstant { reg: register, constId: constId });
 
-                // Adds to the program list
-                self.program.push(byteCode);
+                self.curType = VarTypes::FloatType;
+                return Some(register); 
+            },
+            ExprAST::CharExpr(val) => {
+                //Set the register to load into
+                let register : u8  = self.GetFreeReg();
 
-                let charBits = val.as_bytes()[0];
-                self.program.push(charBits);
+                self.Emit(Instruction::LoadScalar { reg: register, varType: VarTypes::CharType, value: val.as_bytes()[0] as u64 });
                 self.curType = VarTypes::CharType;
                 return Some(register); 
             },
             ExprAST::StringExpr(val) => {
-                let mut bytecode: u8 = 0;
-
                 //Set the register to load into
-                let register : u8  = self.free_reg;
-                self.free_reg = (self.free_reg + 1) % 8;
-
-                // bytecode = bytecode | ((OpCodes::OpLoadArray as u16) << 12) | ((register as u16) << 9) | VarTypes::CharType as u16;
-                bytecode = bytecode | (OpCodes::OpLoadArray as u8);
-                self.program.push(bytecode);
-                bytecode = 0;
-                bytecode = bytecode | (register << bitRegShift) | VarTypes::CharType as u8;
-
-                // Adds to the program list
-                self.program.push(bytecode);
-
-                let mut dimensions_arr = [val.len(); 1].to_vec();
-                bytecode = OpCodes::OpLoadArrayDimensions as u8;
-                self.program.push(bytecode);
-                bytecode = dimensions_arr.len() as u8;
-                self.program.push(bytecode);
-                for i in 0..dimensions_arr.len() {
-                    bytecode = *dimensions_arr.get(i).unwrap() as u8;
-                    self.program.push(bytecode);
-                }
-                bytecode = OpCodes::OpEndArrayDimensions as u8;
-                self.program.push(bytecode);
-                
-                let bytes_arr = val.as_bytes();
-                for i in (0..bytes_arr.len()){
-                    self.program.push(bytes_arr[i] as u8);
-                }
+                let register : u8  = self.GetFreeReg();
 
-                bytecode = 0 | (OpCodes::OpEndArray as u8);
-                self.program.push(bytecode);
+                let constId = self.AddConstant(Constant::String(val.into_bytes()));
+                self.Emit(Instruction::LoadConstant { reg: register, constId: constId });
                 self.curType = VarTypes::ArrayType;
 
                 return Some(register)
             },
             ExprAST::ListExpr(listOfExpr) => {
-                let mut bytecode: u8 = 0;
-
                 //Set the register to load into
-                let register : u8  = self.free_reg;
-                self.free_reg = (self.free_reg + 1) % 8;
-
-                // bytecode = bytecode | ((OpCodes::OpLoadArray as u16) << 12) | ((register as u16) << 9) | VarTypes::CharType as u16;
-                bytecode = bytecode | (OpCodes::OpLoadArray as u8);
-                self.program.push(bytecode);
-                bytecode = 0;
-                bytecode = bytecode | (register << bitRegShift); 
-                
+                let register : u8  = self.GetFreeReg();
+
                 let elementType: VarTypes;
                 if (listOfExpr.len() < 1) {
                     elementType = self.curType;
@@ -923,12 +2069,6 @@ impl ASTConverter {
                     }
                 }
 
-                bytecode = bytecode | elementType as u8;
-                
-
-                // Adds to the program list
-                self.program.push(bytecode);
-
                 let mut dimensions_arr = Vec::<usize>::new();
                 let mut loop_element_type = elementType;
                 dimensions_arr.push(listOfExpr.len());
@@ -950,107 +2090,69 @@ impl ASTConverter {
                     }
                 }
 
-                bytecode = OpCodes::OpLoadArrayDimensions as u8;
-                self.program.push(bytecode);
-                bytecode = dimensions_arr.len() as u8;
-                self.program.push(bytecode);
-                for i in 0..dimensions_arr.len() {
-                    bytecode = *dimensions_arr.get(i).unwrap() as u8;
-                    self.program.push(bytecode);
-                }
-                bytecode = OpCodes::OpEndArrayDimensions as u8;
-                self.program.push(bytecode);
-                
-                for i in (0..listOfExpr.len()){
-                    match elementType {
-                        VarTypes::FloatType => {
-                            if let ExprAST::NumberExpr(val) = listOfExpr[i].clone() {
-                                let floatBits = f64::to_bits(val);
-                                for i in range(0, 8){
-                                    let shift: u8 = 56 - 8*i;
-                                    self.program.push( ((floatBits >> shift) & 0xFF) as u8);    
-                                }
-                            }
-                        },
-                        VarTypes::CharType => {
-                            if let ExprAST::CharExpr(val) = listOfExpr[i].clone() {
-                                let charBits = val.as_bytes()[0];
-                                self.program.push(charBits);
-                            }
-                        },
-                        VarTypes::ArrayType => {
-                            self.program.push(OpCodes::OpLoadMultiDimensionalArrayElement as u8);
-                            self.ConvertExprToByteCode(listOfExpr[i].clone());
-                            self.program.push(OpCodes::OpEndMultiDimensionalArrayElement as u8);
-                            // self.curNumListId += 1;
+                // Lists of numbers and chars hold their elements. Each list of a list of lists is loaded on its own
+                let elements: Vec<u64> = listOfExpr.iter().filter_map(|x| match (elementType, x) {
+                    (VarTypes::FloatType, ExprAST::NumberExpr(val)) => Some(f64::to_bits(*val)),
+                    (VarTypes::CharType, ExprAST::CharExpr(val)) => Some(val.as_bytes()[0] as u64),
+                    (VarTypes::FloatType | VarTypes::CharType | VarTypes::ArrayType, _) => None,
+                    _ => panic!("Unimplemented element type")
+                }).collect();
+                self.Emit(Instruction::LoadArray { reg: register, elementType: elementType, dimensions: dimensions_arr.iter().map(|x| *x as u8).collect(), elements: elements });
+
+                if elementType == VarTypes::ArrayType {
+                    for i in (0..listOfExpr.len()){
+                        self.Emit(Instruction::LoadMultiDimensionalArrayElement);
+                        if let Some(elementReg) = self.ConvertExprToByteCode(listOfExpr[i].clone()) {
+                            self.FreeReg(elementReg);
                         }
-                        _ => {panic!("Unimplemented element type")}
+                        self.Emit(Instruction::EndMultiDimensionalArrayElement);
                     }
                 }
 
-                bytecode = 0 | (OpCodes::OpEndArray as u8);
-                self.program.push(bytecode);
+                self.Emit(Instruction::EndArray);
                 self.curType = VarTypes::ArrayType;
 
                 return Some(register)
 
             },
             ExprAST::VariableExpr(name) => {
-                let mut byteCode: u8 = 0;
+                if self.constructorLookUp.contains_key(&name) && !self.varLookUp.contains_key(&name) {
+                    return self.ConvertExprToByteCode(ExprAST::CallExpr { func_name: name, parameters: Vec::new() });
+                }
                 let varIdTuple = self.varLookUp.get(&name).unwrap().clone();
 
                 //Set the register to load into
-                let mut register : u8  = self.free_reg;
-                self.free_reg = (self.free_reg + 1) % 8;
+                let register : u8  = self.GetFreeReg();
 
                 let mut varId: u64 = 0;
 
                 if varIdTuple.0 != self.curMemoryBlock {
                     // panic!("Variable does not exist in memory block");
-                    byteCode = 0 | (OpCodes::OpCopyVarToNewMemoryBlock as u8);
-                    self.program.push(byteCode);
-                    byteCode = 0;
-                    byteCode = byteCode | varIdTuple.1 as u8;
-                    self.program.push(byteCode);
-
-                    for i in range(0, 8){
-                        let shift: u8 = 56 - 8*i;
-                        self.program.push( ((varIdTuple.2 >> shift) & 0xFF) as u8);    
-                    }
-
-                    byteCode = 0;
+                    self.Emit(Instruction::CopyVarToNewMemoryBlock { varType: varIdTuple.1, varId: varIdTuple.2 });
 
                     // register  = self.free_reg;
                     // self.free_reg = (self.free_reg + 1) % 8;
 
                 }
                 varId = varIdTuple.2;
-                byteCode = byteCode | OpCodes::OpLoadVar as u8;
-                self.program.push(byteCode);
-                byteCode = 0;
-
-                byteCode = byteCode | ((register as u8) << bitRegShift) | varIdTuple.1 as u8;
-                self.program.push(byteCode);
-
-                for i in range(0, 8){
-                    let shift: u8 = 56 - 8*i;
-                    self.program.push( ((varId >> shift) & 0xFF) as u8);    
-                }
+                self.Emit(Instruction::LoadVar { reg: register, varType: varIdTuple.1, varId: varId });
 
                 self.curType = varIdTuple.1;
 
                 return Some(register);
             }
             ExprAST::VariableAssignExpr { varObject, value } => {
-                let mut byteCode: u8 = 0;
-                let register_val: u8;
+                let mut register_val: u8;
                 if let ExprAST::VariableHeader { name, typeName } = *varObject.to_owned() {
                     // let re = Regex::new(r"\[(\d+)\]").unwrap();
                     // let dimensions_arr: Vec<u64> = re.captures_iter(&typeName).filter_map(|cap| cap[1].parse::<u64>().ok()).collect();
                     // let typeName_cleaned = re.replace_all(&typeName, "").to_string();
 
-                    let array_dim_count = typeName.split("[]").count() - 1;
-                    let typeName_cleaned = typeName.replace("[]", "");
+                    let valueTypeName = self.InferTypeName(&value);
+                    self.CheckAssignable(&valueTypeName, &typeName, &name);
+                    let isOptional = typeName.ends_with('?');
+                    let array_dim_count = if isOptional { 0 } else { typeName.split("[]").count() - 1 };
+                    let typeName_cleaned = if isOptional { typeName.clone() } else { typeName.replace("[]", "") };
                     // let mut valVarType = match typeName_cleaned.as_str() {
                     //     "number" => VarTypes::FloatType,
                     //     "char" => VarTypes::CharType,
@@ -1062,23 +2164,21 @@ impl ASTConverter {
                     let isArray = (array_dim_count > 0);
                     self.curType = valVarType;
                     register_val = self.ConvertExprToByteCode(*value).expect("Can not compile variable value");
+                    if isOptional {
+                        register_val = self.WrapOptional(register_val, &valueTypeName);
+                    }
                     
                     if isArray || typeName_cleaned.as_str() == "string" {
-                        self.listLookUp.insert(name.clone(), (self.curMemoryBlock, valVarType, self.curNumListId, Vec::<u64>::new()));
-                        self.curNumListId += 1;
+                        self.listLookUp.insert(name.clone(), (self.curMemoryBlock, valVarType, self.curNumVarId, Vec::<u64>::new()));
                         valVarType = VarTypes::ArrayType;
                     }
+                        self.varTypeNameLookUp.insert(name.clone(), typeName.clone());
+                        self.NameVariable(self.curNumVarId, &name);
                         self.varLookUp.insert(name, (self.curMemoryBlock, valVarType, self.curNumVarId));
                         self.curNumVarId += 1;
 
                     //------
-                    byteCode = 0;
-                    byteCode = byteCode | OpCodes::OpNewVar as u8;
-                    self.program.push(byteCode);
-                    byteCode =  0;
-                    byteCode = byteCode | ((register_val as u8) << bitRegShift)  | valVarType as u8;
-                    self.program.push(byteCode);
-                    byteCode = 0;
+                    self.Emit(Instruction::NewVar { reg: register_val, varType: valVarType });
 
                     // if isArray {
                     //     //Pass number of array dimensions
@@ -1100,21 +2200,55 @@ impl ASTConverter {
                 }
                 return None;
             }
-            ExprAST::BinaryExpr { op, lhs, rhs, opChar: _ } => {
+            ExprAST::BinaryExpr { op: Token::DefaultOp, lhs, rhs, opChar: _ } => {
+                let lhsTypeName = self.InferTypeName(&lhs);
+                let innerTypeName = match &lhsTypeName {
+                    Some(x) if x.ends_with('?') => x[..x.len() - 1].to_string(),
+                    _ => panic!("Left hand of '??' must be an optional value")
+                };
+                let innerType = self.GetValueTypeFromString(&innerTypeName);
+                self.CheckAssignable(&self.InferTypeName(&rhs), &innerTypeName, "the default value of '??'");
+                let optionalReg = self.ConvertExprToByteCode(*lhs).unwrap();
+                let resultReg = self.GetFreeReg();
+
+                self.Emit(Instruction::TestTag { reg: optionalReg, tag: 1 });
+                let noneJump = self.PushJump(OpCodes::OpJumpIfFalse);
+                self.Emit(Instruction::LoadElement { reg: resultReg, varType: innerType, listReg: optionalReg, index: 1 });
+                let endJump = self.PushJump(OpCodes::OpJump);
+
+                // Default value is only worked out when the optional value is none
+                let noneTarget = self.program.len() as u64;
+                self.Patch64BitVal(noneJump, noneTarget);
+                let defaultReg = self.ConvertExprToByteCode(*rhs).unwrap();
+                self.Emit(Instruction::LoadReg { src: defaultReg, dst: resultReg });
+                self.FreeReg(defaultReg);
+                let endTarget = self.program.len() as u64;
+                self.Patch64BitVal(endJump, endTarget);
+                self.FreeReg(optionalReg);
+                self.curType = innerType;
+                return Some(resultReg);
+            },
+            ExprAST::BinaryExpr { op, lhs, rhs, opChar } => {
+                for operand in [&lhs, &rhs] {
+                    match self.InferTypeName(operand) {
+                        Some(x) if x == "none" || x.ends_with('?') => panic!("Can not use optional value in '{}'. Unwrap it first or give a default with '??'", opChar),
+                        Some(x) if x == "null" => panic!("Can not use a value from a function that returns nothing in '{}'", opChar),
+                        _ => {}
+                    }
+                }
                 // Gets register for the left hand side
                 let reg1 = self.ConvertExprToByteCode(*lhs).unwrap();
-                let mut byteCode : u8 = 0;
-                // Gets right op code for operation
-                let opCode : u8 = match op {
-                    Token::Plus => OpCodes::OpAdd as u8,
-                    Token::Minus => OpCodes::OpSub as u8,
-                    Token::Multiply => OpCodes::OpMul as u8,
-                    Token::Divide => OpCodes::OpDiv as u8,
-                    _ => 0 as u8
+                // Gets right instruction for operation
+                let opInstruction: fn(u8, u8) -> Instruction = match op {
+                    Token::Plus => |reg1, reg2| Instruction::Add { reg1, reg2 },
+                    Token::Minus => |reg1, reg2| Instruction::Sub { reg1, reg2 },
+                    Token::Multiply => |reg1, reg2| Instruction::Mul { reg1, reg2 },
+                    Token::Divide => |reg1, reg2| Instruction::Div { reg1, reg2 },
+                    _ => panic!("Unkown operator {}", opChar)
                 };
 
                 match *rhs {
-                    ExprAST::NumberExpr(_) | ExprAST::CharExpr(_) | ExprAST::StringExpr(_) | ExprAST::ElementAccess { array_name: _, element_indexes: _ } => {
+                    ExprAST::NumberExpr(_) | ExprAST::CharExpr(_) | ExprAST::StringExpr(_) | ExprAST::VariableExpr(_) | ExprAST::ElementAccess { array_name: _, element_indexes: _ } | ExprAST::CallExpr { func_name: _, parameters: _ } => {
                         let varTypeOpr1 = self.curType;
                         // Gets register for the right hand side
                         let reg2 = self.ConvertExprToByteCode(*rhs).unwrap();
@@ -1122,30 +2256,18 @@ impl ASTConverter {
                         if varTypeOpr1 as u16 != varTypeOpr2 as u16 {
                             panic!("Operands must match type");
                         }
-                        // Loads opCode and register into bytecode
-                        byteCode = byteCode | opCode;
-                        self.program.push(byteCode);
-                        byteCode = 0;
-                        byteCode = ( (reg1 as u8) << 5);
-                        // Loads register to bytecode
-                        byteCode = byteCode | (reg2 as u8);
-                        // Pushed bytecode to program list
-                        self.program.push(byteCode);
+                        // Loads instruction with both registers into bytecode
+                        self.Emit(opInstruction(reg1, reg2));
+                        self.FreeReg(reg2);
                         return Some(reg1);
                     },
                     ExprAST::BinaryExpr { op, lhs, rhs, opChar } => {
                         let binExpr = ExprAST::BinaryExpr { op: op, lhs: lhs, rhs: rhs, opChar: opChar };
                         let binExprReg = self.ConvertExprToByteCode(binExpr).unwrap();
                         
-                        // Loads opCode and register into bytecode
-                        byteCode = byteCode | opCode;
-                        self.program.push(byteCode);
-                        byteCode = 0;
-                        byteCode = ( (reg1 as u8) << bitRegShift);
-                        // Loads register to bytecode
-                        byteCode = byteCode | (binExprReg as u8);
-                        // Pushed bytecode to program list
-                        self.program.push(byteCode);
+                        // Loads instruction with both registers into bytecode
+                        self.Emit(opInstruction(reg1, binExprReg));
+                        self.FreeReg(binExprReg);
 
                         // byteCode = byteCode | binExprReg;
                         // self.program.push(byteCode);
@@ -1156,22 +2278,37 @@ impl ASTConverter {
                     _ => {return None;}
                 }
             },
-            ExprAST::FuncExpr { name, args, return_type, body } => {
-                let mut bytecode: u8 = 0;
+            ExprAST::FuncExpr { name, type_params, args, return_type, body, bodyLines } if !type_params.is_empty() => {
+                // Generic functions are compiled once for each set of types they are called with
+                self.genericFuncLookUp.insert(name.clone(), ExprAST::FuncExpr { name, type_params, args, return_type, body, bodyLines });
+                return None;
+            },
+            ExprAST::FuncExpr { name, type_params: _, args, return_type, body, bodyLines } => {
+                // Inlined bodies call functions by name, so they can no longer be inlined once a function they call is defined again.
+                // The function is only inlined once its body is compiled, so its own calls to the name are not inlined into it
+                self.inlineLookUp.retain(|inlineName, (_, inlineBody, _)| *inlineName != name && !optimizer::CallsFunction(inlineBody, &name));
+                let inlineBody = match self.optimize {
+                    true => optimizer::InlineBody(&args, &return_type, &body, |callee| callee != name && !self.genericFuncLookUp.contains_key(callee)),
+                    false => None
+                };
                 let oldMemoryBlockId = self.curMemoryBlock;
                 let oldNumVarId = self.curNumVarId;
                 let oldVarLookup = self.varLookUp.clone();
+                let oldVarTypeNameLookup = self.varTypeNameLookUp.clone();
+                // The body runs in its own memory block, so it starts with every register free
+                let oldRegAllocator = std::mem::take(&mut self.regAllocator);
                 self.curNumVarId = 0;
                 self.varLookUp = HashMap::new();
                 self.curMemoryBlock = self.curMemoryBlock + 1;
                 // Insert Function Name with funcID
-                self.funcIdTable.insert(name, self.curFuncId);
+                self.funcReturnTypeLookUp.insert(name.clone(), return_type.clone().unwrap_or("null".to_string()));
+                self.funcParamTypeLookUp.insert(name.clone(), args.iter().filter_map(|x| if let ExprAST::VariableHeader { name: _, typeName } = x { Some(typeName.clone()) } else { None }).collect());
+                let oldFuncReturnTypeName = std::mem::replace(&mut self.curFuncReturnTypeName, return_type.clone());
+                let funcId = self.curFuncId;
+                self.funcIdTable.insert(name.clone(), funcId);
                 self.curFuncId = self.curFuncId + 1;
-                let param_count = (args.len() as u8);
-                // bytecode = bytecode  | (OpCodes::OpStartFunc as u16) << 12 | param_count;
-                bytecode = OpCodes::OpStartFunc as u8;
-                self.program.push(bytecode);
-                self.program.push(param_count);
+                let oldFuncBody = self.curFuncBody.replace(funcId);
+                let mut paramTypes = Vec::<VarTypes>::new();
 
                 // Loop through arguments and load them in to the function def
                 for param in args{
@@ -1181,12 +2318,10 @@ impl ASTConverter {
                         //     "string" => VarTypes::CharType,
                         //     _ => panic!("Can not compile type")
                         //     };
-                        let varVaribleType = self.GetVarTypeFromString(typeName.clone()).unwrap();
-                        bytecode = 0; 
-                        bytecode = bytecode | (OpCodes::OpAddFuncParameter as u8);
-                        self.program.push(bytecode);
-                        bytecode = 0 | (varVaribleType as u8);
-                        self.program.push(bytecode);
+                        let varVaribleType = self.GetValueTypeFromString(&typeName);
+                        paramTypes.push(varVaribleType);
+                        self.varTypeNameLookUp.insert(name.clone(), typeName.clone());
+                        self.NameVariable(self.curNumVarId, &name);
                         self.varLookUp.insert(name, (self.curMemoryBlock, varVaribleType, self.curNumVarId));
                         self.curNumVarId += 1;
                     }
@@ -1194,127 +2329,305 @@ impl ASTConverter {
 
                 let mut funcVarType : VarTypes = VarTypes::NullType;
                 if return_type.is_some() {
-                    funcVarType = self.GetVarTypeFromString(return_type.unwrap()).unwrap();
+                    funcVarType = self.GetValueTypeFromString(&return_type.unwrap());
                 }
-                self.program.push(funcVarType as u8);
+                // The body is compiled on its own and moved to the function code, so it is not part of the top level code
+                let outerProgram = std::mem::take(&mut self.program);
+                self.Emit(Instruction::StartFunc { paramTypes: paramTypes, returnType: funcVarType });
 
                 // Parse through body
-                let mut lastReg: u8 = 0;
-                for bodyExpr in body{
-                    lastReg = self.ConvertExprToByteCode(bodyExpr).unwrap();
+                let bodyLines = bodyLines.into_iter().map(Some).chain(std::iter::repeat(None));
+                for (bodyExpr, line) in body.into_iter().zip(bodyLines) {
+                    let bodyReg = match line {
+                        Some(line) => self.ConvertStatement(bodyExpr, line),
+                        None => self.ConvertExprToByteCode(bodyExpr)
+                    };
+                    if let Some(bodyReg) = bodyReg {
+                        self.FreeReg(bodyReg);
+                    }
                 }
                 // Add FuncEnd part
-                bytecode = 0 | (OpCodes::OpEndFunc as u8);
-                self.program.push(bytecode);
+                self.Emit(Instruction::EndFunc);
+                let mut body = std::mem::replace(&mut self.program, outerProgram);
+                if self.optimize {
+                    body = self.OptimizeCode(&body, 0);
+                }
+                if let Some(inlineBody) = inlineBody {
+                    self.inlineLookUp.insert(name.clone(), inlineBody);
+                }
+                self.functionTable.push(FunctionEntry { name: name, funcId: funcId, offset: self.functionCode.len() as u64, length: body.len() as u64 });
+                self.functionCode.extend(body);
                 self.curMemoryBlock = oldMemoryBlockId;
                 self.curNumVarId = oldNumVarId;
                 self.varLookUp = oldVarLookup.clone();
-                return Some(lastReg);
+                self.varTypeNameLookUp = oldVarTypeNameLookup;
+                self.curFuncReturnTypeName = oldFuncReturnTypeName;
+                self.curFuncBody = oldFuncBody;
+                self.regAllocator = oldRegAllocator;
+                return None;
+            },
+            ExprAST::CallExpr { func_name, parameters } if func_name == "some" && !self.funcIdTable.contains_key(&func_name) => {
+                if parameters.len() != 1 {
+                    panic!("some takes 1 parameter, got {}", parameters.len());
+                }
+                let param = parameters.into_iter().next().unwrap();
+                let valueTypeName = self.InferTypeName(&param);
+                let valueReg = self.ConvertExprToByteCode(param).unwrap();
+                return Some(self.WrapOptional(valueReg, &valueTypeName.map(|x| x.trim_end_matches('?').to_string())));
+            },
+            ExprAST::CallExpr { func_name, parameters } if self.constructorLookUp.contains_key(&func_name) => {
+                let (_, tag, fieldTypeNames) = self.constructorLookUp.get(&func_name).unwrap().clone();
+                if parameters.len() != fieldTypeNames.len() {
+                    panic!("Variant {} takes {} fields, got {}", func_name, fieldTypeNames.len(), parameters.len());
+                }
+                let mut fieldRegs = Vec::<(u8, VarTypes)>::new();
+                for (param, fieldTypeName) in parameters.into_iter().zip(fieldTypeNames.iter()) {
+                    let fieldReg = self.ConvertExprToByteCode(param).expect("Can not compile variant field");
+                    let fieldType = self.GetValueTypeFromString(fieldTypeName);
+                    if self.curType != fieldType {
+                        panic!("Variant {} expects a field of type {}", func_name, fieldTypeName);
+                    }
+                    fieldRegs.push((fieldReg, fieldType));
+                }
+                let register = self.GetFreeReg();
+                self.Emit(Instruction::MakeVariant { reg: register, varType: VarTypes::VariantType, tag: tag, fields: fieldRegs.clone() });
+                for (fieldReg, _) in fieldRegs.iter().rev() {
+                    self.FreeReg(*fieldReg);
+                }
+                self.curType = VarTypes::VariantType;
+                return Some(register);
+            },
+            ExprAST::CallExpr { func_name, parameters } if self.genericFuncLookUp.contains_key(&func_name) => {
+                let typeBindings = self.InferTypeParams(&func_name, &parameters).unwrap_or_else(|x| panic!("{}", x));
+                let type_params = match self.genericFuncLookUp.get(&func_name).unwrap() {
+                    ExprAST::FuncExpr { name: _, type_params, args: _, return_type: _, body: _, bodyLines: _ } => type_params.clone(),
+                    _ => unreachable!()
+                };
+                let typeNames: Vec<String> = type_params.iter().map(|x| typeBindings.get(x).unwrap().clone()).collect();
+                let mangledName = format!("{}<{}>", func_name, typeNames.join(","));
+                // InstantiateGenericCalls compiles the functions a statement calls before the statement
+                if !self.funcIdTable.contains_key(&mangledName) {
+                    panic!("Generic function {} was not compiled", mangledName);
+                }
+                return self.CompileExpr(ExprAST::CallExpr { func_name: mangledName, parameters: parameters });
+            },
+            ExprAST::CallExpr { func_name, parameters } if func_name == "unwrap" => {
+                if parameters.len() != 1 {
+                    panic!("unwrap takes 1 parameter, got {}", parameters.len());
+                }
+                let innerTypeName = match self.InferTypeName(&parameters[0]) {
+                    Some(x) if x.ends_with('?') => x[..x.len() - 1].to_string(),
+                    _ => panic!("unwrap expects an optional value")
+                };
+                let innerType = self.GetValueTypeFromString(&innerTypeName);
+                let optionalReg = self.ConvertExprToByteCode(parameters.into_iter().next().unwrap()).unwrap();
+                let register = self.GetFreeReg();
+                self.Emit(Instruction::Unwrap { reg: register, varType: innerType, optionalReg: optionalReg });
+                self.FreeReg(optionalReg);
+                self.curType = innerType;
+                return Some(register);
             },
             ExprAST::CallExpr { func_name, parameters } => {
+                if let Some(inlined) = self.InlineCall(&func_name, &parameters) {
+                    // Literal parameters can make the body constant
+                    return self.CompileExpr(optimizer::FoldConstants(inlined));
+                }
+                if let Some(paramTypeNames) = self.funcParamTypeLookUp.get(&func_name).cloned() {
+                    for (param, paramTypeName) in parameters.iter().zip(paramTypeNames.iter()) {
+                        self.CheckAssignable(&self.InferTypeName(param), paramTypeName, &format!("parameter of {}", func_name));
+                    }
+                }
                 //Grabs function Id and loads it
                 let funcIdOption = self.funcIdTable.get(&func_name);
-                let mut bytecode: u8;
                 if funcIdOption.is_none() {
                     println!("Function {:#?} Not found", func_name.as_str());
                 }
-                bytecode = 0 | (OpCodes::OpCallFunc as u8);
-                self.program.push(bytecode);
                 let funcId = (*funcIdOption.unwrap());
-                
-                for i in range(0, 8){
-                    let shift: u8 = 56 - 8*i;
-                    self.program.push( ((funcId >> shift) & 0xFF) as u8);    
+                // Parameters are computed before the call, while the caller's variables are the ones in use
+                let mut paramRegs = Vec::<(u8, VarTypes)>::new();
+                for param in parameters {
+                    let param_reg = self.ConvertExprToByteCode(param).expect("Can not compile parameter");
+                    paramRegs.push((param_reg, self.curType));
                 }
+                self.Emit(Instruction::CallFunc { funcId: funcId });
 
                 self.curMemoryBlock += 1;
                 //Loads function paramters
-                let mut param_reg :Option<u8> = Some(self.free_reg);
-                for param in parameters {
-                    // Add check for variable
-                    // If variable, copy variable and append to memory block
-                    match param {
-                        ExprAST::VariableExpr(var) => {
-                            bytecode = 0 | (OpCodes::OpCopyVarToNewMemoryBlock as u8);
-                            self.program.push(bytecode);
-                            let varIdTuple = self.varLookUp.get(&var).unwrap().clone();
-                            let varId = varIdTuple.2;
-                            bytecode = 0;
-                            bytecode = bytecode | varIdTuple.1 as u8;
-                            self.program.push(bytecode);
-            
-                            for i in range(0, 8){
-                                let shift: u8 = 56 - 8*i;
-                                self.program.push( ((varId >> shift) & 0xFF) as u8);    
-                            }
-
-                        },
-                        _ => {
-                            param_reg = self.ConvertExprToByteCode(param);
-                            bytecode = 0 | (OpCodes::OpNewVar as u8);
-                            self.program.push(bytecode);
-                            bytecode = 0 | (param_reg.unwrap()) << bitRegShift | (self.curType as u8);
-                            self.program.push(bytecode);
-                        }
-                    }
+                for (param_reg, paramType) in paramRegs {
+                    self.Emit(Instruction::NewVar { reg: param_reg, varType: paramType });
+                    self.FreeReg(param_reg);
                 }
-                bytecode = 0 |  (OpCodes::OpEndParamLoad as u8);
-                self.program.push(bytecode);
+                self.Emit(Instruction::EndParamLoad);
                 self.curMemoryBlock -= 1;
-                return param_reg;
+                // Functions leave their result in the result register
+                let register = self.GetFreeReg();
+                self.Emit(Instruction::LoadReg { src: regResult as u8, dst: register });
+                if let Some(returnTypeName) = self.funcReturnTypeLookUp.get(&func_name).filter(|x| *x != "null").cloned() {
+                    self.curType = self.GetValueTypeFromString(&returnTypeName);
+                }
+                return Some(register);
+            },
+            ExprAST::ElementAccess { array_name, element_indexes: element_index } if !self.listLookUp.contains_key(&array_name) => {
+                // Arrays passed in as parameters do not have a known list id, so the list is loaded from the variable
+                if element_index.len() != 1 {
+                    panic!("Only one index is supported for array {}", array_name);
+                }
+                let elementTypeName = self.varTypeNameLookUp.get(&array_name).and_then(|x| self.GetElementTypeName(x)).unwrap_or_else(|| panic!("{} is not an array", array_name));
+                let elementType = self.GetValueTypeFromString(&elementTypeName);
+                let listReg = self.CompileExpr(ExprAST::VariableExpr(array_name)).unwrap();
+                let indexReg = self.CompileExpr(*element_index.into_iter().next().unwrap()).unwrap();
+                let register = self.GetFreeReg();
+                self.Emit(Instruction::LoadIndexed { reg: register, varType: elementType, listReg: listReg, indexReg: indexReg });
+                self.FreeReg(indexReg);
+                self.FreeReg(listReg);
+                self.curType = elementType;
+                return Some(register);
             },
             ExprAST::ElementAccess { array_name, element_indexes: element_index } => {
                 let array_obj = self.listLookUp.get(&array_name).unwrap();
                 self.curType = array_obj.1;
-                let array_id = array_obj.2;
-                let mut param_reg :Option<u8> = None;
-
-                self.program.push(0 | OpCodes::OpAccessArray as u8);
-
-                for i in range(0, 8){
-                    let shift: u8 = 56 - 8*i;
-                    self.program.push((( array_id >> shift) & 0xFF) as u8);
-                }
+                // The VM finds the list through the variable holding it
+                let var_id = array_obj.2;
+                let mut indexRegs = Vec::<u8>::new();
+                // Picked before the instruction starts so a spill is not emitted inside it
+                let register : u8  = self.GetFreeReg();
 
+                self.Emit(Instruction::AccessArray { varId: var_id, dest: register, indexCount: element_index.len() as u8 });
 
                 for ele_index in element_index {
-                    self.program.push( 0 | OpCodes::OpAccessElementBegin as u8 );
-                    param_reg = self.ConvertExprToByteCode(*ele_index);
-                    self.program.push(param_reg.unwrap());
-                    self.program.push( 0 | OpCodes::OpAccessElementEnd as u8 );
+                    self.Emit(Instruction::AccessElementBegin);
+                    let param_reg = self.ConvertExprToByteCode(*ele_index).unwrap();
+                    indexRegs.push(param_reg);
+                    self.Emit(Instruction::AccessElementEnd { reg: param_reg });
                 }
 
-                let register : u8  = self.free_reg;
-                self.free_reg = (self.free_reg + 1) % 8;
-                self.program.push(register);
+                for indexReg in indexRegs.into_iter().rev() {
+                    self.FreeReg(indexReg);
+                }
                 
                 return Some(register);
             },
             // Add Variable Type With the Return Data
             ExprAST::ReturnExpr(return_val) => {
-                let param_reg = self.ConvertExprToByteCode(*return_val);
-                let mut byteCode: u8 = 0;
+                let valueTypeName = self.InferTypeName(&return_val);
+                let returnTypeName = self.curFuncReturnTypeName.clone();
+                if let Some(returnTypeName) = &returnTypeName {
+                    self.CheckAssignable(&valueTypeName, returnTypeName, "the return value");
+                }
+                let mut param_reg = self.ConvertExprToByteCode(*return_val);
+                if returnTypeName.is_some_and(|x| x.ends_with('?')) {
+                    param_reg = Some(self.WrapOptional(param_reg.unwrap(), &valueTypeName));
+                }
 
                 if param_reg.is_some() {
-                    byteCode = 0 | (OpCodes::OpReturn as u8);
-                    self.program.push(byteCode);
-                    byteCode = 0 | (self.curType as u8);
-                    self.program.push(byteCode);
-                    byteCode = 0 | param_reg.unwrap();
-                    self.program.push(byteCode);
+                    self.Emit(Instruction::Return { returnType: self.curType, reg: param_reg.unwrap() });
                     return param_reg;
                 }
                 panic!("Error processing return value");
             },
+            ExprAST::NoneExpr => {
+                let register = self.GetFreeReg();
+                self.Emit(Instruction::MakeVariant { reg: register, varType: VarTypes::VariantType, tag: 0, fields: Vec::new() });
+                self.curType = VarTypes::VariantType;
+                return Some(register);
+            },
+            ExprAST::TypeDeclExpr { name, variants } => {
+                if self.IsTypeDeclared(&name) {
+                    panic!("Type {} is already declared", name);
+                }
+                self.typeLookUp.insert(name.clone(), variants.clone());
+                for (tag, (variantName, fields)) in variants.into_iter().enumerate() {
+                    if self.constructorLookUp.contains_key(&variantName) {
+                        panic!("Variant {} is already declared", variantName);
+                    }
+                    self.constructorLookUp.insert(variantName, (name.clone(), tag as u8, fields));
+                }
+                return None;
+            },
+            ExprAST::TypeAliasExpr { name, typeName } => {
+                if self.IsTypeDeclared(&name) {
+                    panic!("Type {} is already declared", name);
+                }
+                self.typeAliasLookUp.insert(name.clone(), typeName);
+                self.ResolveTypeName(&name);
+                return None;
+            },
+            ExprAST::MatchExpr { value, arms } => {
+                let typeName = self.InferTypeName(&value);
+                let valueReg = self.ConvertExprToByteCode(*value).expect("Can not compile matched value");
+                let valueType = self.curType;
+                let patterns: Vec<PatternAST> = arms.iter().map(|x| self.ResolvePattern(&x.0)).collect();
+                self.CheckMatchExhaustive(&patterns);
+
+                // Keeps matched value in a variable so every arm can reload it
+                let matchVarId = self.curNumVarId;
+                self.curNumVarId += 1;
+                self.Emit(Instruction::NewVar { reg: valueReg, varType: valueType });
+                self.FreeReg(valueReg);
+
+                // Every arm starts with all registers free. Each arm leaves its value in the result register
+                let savedRegs = self.SaveLiveRegs();
+                let mut endJumps = Vec::<usize>::new();
+                for (pattern, (_, body)) in patterns.iter().zip(arms) {
+                    let mut failJumps = Vec::<usize>::new();
+                    let testReg = self.GetFreeReg();
+                    self.Emit(Instruction::LoadVar { reg: testReg, varType: valueType, varId: matchVarId });
+                    self.CompilePatternTest(pattern, testReg, &typeName, &mut failJumps);
+                    self.FreeReg(testReg);
+
+                    let oldVarLookup = self.varLookUp.clone();
+                    let oldVarTypeNameLookup = self.varTypeNameLookUp.clone();
+                    let oldNumVarId = self.curNumVarId;
+                    if ASTConverter::PatternHasBindings(pattern) {
+                        let bindReg = self.GetFreeReg();
+                        self.Emit(Instruction::LoadVar { reg: bindReg, varType: valueType, varId: matchVarId });
+                        self.CompilePatternBindings(pattern, bindReg, valueType, &typeName);
+                        self.FreeReg(bindReg);
+                    }
+                    let bodyReg = self.ConvertExprToByteCode(body).expect("Can not compile match arm");
+                    self.Emit(Instruction::LoadReg { src: bodyReg, dst: regResult as u8 });
+                    self.FreeReg(bodyReg);
+                    if self.curNumVarId > oldNumVarId {
+                        self.Emit(Instruction::DropVars { count: (self.curNumVarId - oldNumVarId) as u8 });
+                    }
+                    self.varLookUp = oldVarLookup;
+                    self.varTypeNameLookUp = oldVarTypeNameLookup;
+                    self.curNumVarId = oldNumVarId;
+                    endJumps.push(self.PushJump(OpCodes::OpJump));
+
+                    let nextArm = self.program.len() as u64;
+                    for failJump in failJumps {
+                        self.Patch64BitVal(failJump, nextArm);
+                    }
+                }
+                let matchEnd = self.program.len() as u64;
+                for endJump in endJumps {
+                    self.Patch64BitVal(endJump, matchEnd);
+                }
+                self.Emit(Instruction::DropVars { count: 1 });
+                self.curNumVarId -= 1;
+                self.RestoreLiveRegs(savedRegs);
+                let resultReg = self.GetFreeReg();
+                self.Emit(Instruction::LoadReg { src: regResult as u8, dst: resultReg });
+                return Some(resultReg);
+            },
             _ => {println!("Could not convert expression to bytecode"); return None;}
         }
     }
 
 }
 
+#[cfg(test)]
 mod tests {
     use crate::parser::{ExprAST, Parser};
-    use crate::codegen::{ASTConverter, VMCore, VarTypes};
+    use crate::codegen::{ASTConverter, OpCodes, VMCore, VarTypes, regResult, defaultMaxCallDepth};
+    use crate::bytecode::{DecodedProgram, Instruction};
+    use crate::assembler::Assemble;
+    use crate::heap::Heap;
+    use crate::module::{Constant, FunctionEntry, Module};
+    use crate::vmerror::{CallFrame, VmErrorKind};
+    use crate::output::{CaptureBuffer, Sink};
+    use crate::testsupport::{compile, compileModule, runModule, runSource};
 
     #[test]
     fn compileBasicEquation(){
@@ -1325,8 +2638,13 @@ mod tests {
         for ast in &ast_nodes.unwrap() {
             ast_converter.ConvertExprToByteCode(ast.to_owned());
         }
-        let true_val: Vec<u8> = [1, 1, 63, 240, 0, 0, 0, 0, 0, 0, 1, 33, 64, 0, 0, 0, 0, 0, 0, 0, 2, 1].to_vec();
-        assert_eq!(ast_converter.program, true_val);
+        let true_val: Vec<Instruction> = [
+            Instruction::LoadConstant { reg: 0, constId: 0 },
+            Instruction::LoadConstant { reg: 1, constId: 1 },
+            Instruction::Add { reg1: 0, reg2: 1 },
+        ].to_vec();
+        assert_eq!(Instruction::DecodeProgram(&ast_converter.program), true_val);
+        assert_eq!(ast_converter.constants, [Constant::Number(1.0), Constant::Number(2.0)].to_vec());
     }
 
     #[test]
@@ -1339,8 +2657,8 @@ mod tests {
             ast_converter.ConvertExprToByteCode(ast.to_owned());
         }
         let mut toast_vm = VMCore::new();
-        toast_vm.processProgram(&ast_converter.program);
-        assert_eq!(f64::from_bits(toast_vm.registers[8 as usize]), (3 as f64));
+        toast_vm.processProgram(&Module::FromConverter(&ast_converter, None)).unwrap();
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), (3 as f64));
 
     }
 
@@ -1353,9 +2671,12 @@ mod tests {
         for ast in &ast_nodes.unwrap() {
             ast_converter.ConvertExprToByteCode(ast.to_owned());
         }
-        let true_val: Vec<u8> = [13, 2, 23, 1, 11, 24, 72, 101, 108, 108, 111, 32, 87, 111, 114, 108, 100, 14].to_vec();
-        assert_eq!(ast_converter.program, true_val);
-        //assert_eq!(f64::from_bits(toast_vm.registers[7 as usize]), (3 as f64));
+        let true_val: Vec<Instruction> = [
+            Instruction::LoadConstant { reg: 0, constId: 0 },
+        ].to_vec();
+        assert_eq!(Instruction::DecodeProgram(&ast_converter.program), true_val);
+        assert_eq!(ast_converter.constants, [Constant::String(b"Hello World".to_vec())].to_vec());
+        //assert_eq!(f64::from_bits(toast_vm.registers[7 as usize].1), (3 as f64));
     }
 
     #[test]
@@ -1368,10 +2689,10 @@ mod tests {
             ast_converter.ConvertExprToByteCode(ast.to_owned());
         }
         let mut toast_vm = VMCore::new();
-        toast_vm.processProgram(&ast_converter.program);
+        toast_vm.processProgram(&Module::FromConverter(&ast_converter, None)).unwrap();
         let curMemoryBlock = toast_vm.memoryList.first();
         assert_eq!(curMemoryBlock.is_some(), true);
-        let string_u8_vec: Vec<u8> = curMemoryBlock.unwrap().listLookup.first().unwrap().1.clone().into_iter().map(|x| x as u8).collect();
+        let string_u8_vec: Vec<u8> = toast_vm.heap.Get(toast_vm.registers[regResult].1).unwrap().1.clone().into_iter().map(|x| x as u8).collect();
         assert_eq!(String::from_utf8(string_u8_vec).unwrap(), "Hello World".to_string());
     }
 
@@ -1384,8 +2705,12 @@ mod tests {
         for ast in &ast_nodes.unwrap() {
             ast_converter.ConvertExprToByteCode(ast.to_owned());
         }
-        let true_val: Vec<u8> = [13, 1, 23, 1, 4, 24, 63, 240, 0, 0, 0, 0, 0, 0, 64, 0, 0, 0, 0, 0, 0, 0, 64, 8, 0, 0, 0, 0, 0, 0, 64, 16, 0, 0, 0, 0, 0, 0, 14, 6, 4].to_vec();
-        assert_eq!(ast_converter.program, true_val);
+        let true_val: Vec<Instruction> = [
+            Instruction::LoadArray { reg: 0, elementType: VarTypes::FloatType, dimensions: [4].to_vec(), elements: [1.0, 2.0, 3.0, 4.0].map(f64::to_bits).to_vec() },
+            Instruction::EndArray,
+            Instruction::NewVar { reg: 0, varType: VarTypes::ArrayType },
+        ].to_vec();
+        assert_eq!(Instruction::DecodeProgram(&ast_converter.program), true_val);
     }
 
     #[test]
@@ -1398,12 +2723,13 @@ mod tests {
             ast_converter.ConvertExprToByteCode(ast.to_owned());
         }
         let mut toast_vm = VMCore::new();
-        toast_vm.processProgram(&ast_converter.program);
+        toast_vm.processProgram(&Module::FromConverter(&ast_converter, None)).unwrap();
         let curMemoryBlock = toast_vm.memoryList.first();
         assert_eq!(curMemoryBlock.is_some(), true);
         assert_eq!(curMemoryBlock.unwrap().variableLookup.get(&(0 as u64)).unwrap().0, VarTypes::ArrayType);
-        assert_eq!(curMemoryBlock.unwrap().listLookup.first().unwrap().0, VarTypes::FloatType);
-        let float_vec: Vec<f64> = curMemoryBlock.unwrap().listLookup.first().unwrap().1.clone().into_iter().map(|x| f64::from_bits(x)).collect();
+        let list = toast_vm.heap.Get(curMemoryBlock.unwrap().variableLookup.get(&0).unwrap().1).unwrap();
+        assert_eq!(list.0, VarTypes::FloatType);
+        let float_vec: Vec<f64> = list.1.clone().into_iter().map(|x| f64::from_bits(x)).collect();
         assert_eq!(float_vec, [1.0,2.0,3.0,4.0].to_vec());
     }
 
@@ -1416,8 +2742,17 @@ mod tests {
         for ast in &ast_nodes.unwrap() {
             ast_converter.ConvertExprToByteCode(ast.to_owned());
         }
-        let true_val: Vec<u8> = [8, 1, 9, 1, 0, 7, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 33, 64, 89, 0, 0, 0, 0, 0, 0, 4, 1, 10].to_vec();
-        assert_eq!(ast_converter.program, true_val);
+        let true_val: Vec<Instruction> = [
+            Instruction::StartFunc { paramTypes: [VarTypes::FloatType].to_vec(), returnType: VarTypes::NullType },
+            Instruction::LoadVar { reg: 0, varType: VarTypes::FloatType, varId: 0 },
+            Instruction::LoadConstant { reg: 1, constId: 0 },
+            Instruction::Mul { reg1: 0, reg2: 1 },
+            Instruction::EndFunc,
+        ].to_vec();
+        // The body goes into the function table, so the top level code is empty
+        assert_eq!(Instruction::DecodeProgram(&ast_converter.functionCode), true_val);
+        assert!(ast_converter.program.is_empty());
+        assert_eq!(ast_converter.functionTable, [FunctionEntry { name: "foo".to_string(), funcId: 1, offset: 0, length: ast_converter.functionCode.len() as u64 }].to_vec());
     }
 
     #[test]
@@ -1430,10 +2765,10 @@ mod tests {
             ast_converter.ConvertExprToByteCode(ast.to_owned());
         }
         let mut toast_vm = VMCore::new();
-        toast_vm.processProgram(&ast_converter.program);
+        toast_vm.processProgram(&Module::FromConverter(&ast_converter, None)).unwrap();
         println!("{:?}", toast_vm);
         let func_len = toast_vm.funcList.keys().len();
-        assert_eq!(toast_vm.funcList.get(&(func_len-1)).unwrap().1, [VarTypes::FloatType].to_vec());
+        assert_eq!(toast_vm.funcList.get(&(func_len-1)).unwrap().2, [VarTypes::FloatType].to_vec());
     }
 
 
@@ -1446,8 +2781,14 @@ mod tests {
         for ast in &ast_nodes.unwrap() {
             ast_converter.ConvertExprToByteCode(ast.to_owned());
         }
-        let true_val: Vec<u8> = [8, 1, 9, 1, 0, 7, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 33, 64, 89, 0, 0, 0, 0, 0, 0, 4, 1, 10, 11, 0, 0, 0, 0, 0, 0, 0, 1, 1, 65, 64, 64, 0, 0, 0, 0, 0, 0, 6, 65, 12].to_vec();
-        assert_eq!(ast_converter.program, true_val);
+        let true_val: Vec<Instruction> = [
+            Instruction::LoadConstant { reg: 0, constId: 1 },
+            Instruction::CallFunc { funcId: 1 },
+            Instruction::NewVar { reg: 0, varType: VarTypes::FloatType },
+            Instruction::EndParamLoad,
+            Instruction::LoadReg { src: regResult as u8, dst: 0 },
+        ].to_vec();
+        assert_eq!(Instruction::DecodeProgram(&ast_converter.program), true_val);
     }
 
     #[test]
@@ -1460,10 +2801,27 @@ mod tests {
             ast_converter.ConvertExprToByteCode(ast.to_owned());
         }
         let mut toast_vm = VMCore::new();
-        toast_vm.processProgram(&ast_converter.program);
+        toast_vm.processProgram(&Module::FromConverter(&ast_converter, None)).unwrap();
         let func_len = toast_vm.funcList.keys().len();
-        assert_eq!(toast_vm.funcList.get(&(func_len-1)).unwrap().1, [VarTypes::FloatType].to_vec());
-        assert_eq!(f64::from_bits(toast_vm.registers[8]), 3200 as f64);
+        assert_eq!(toast_vm.funcList.get(&(func_len-1)).unwrap().2, [VarTypes::FloatType].to_vec());
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 3200 as f64);
+    }
+
+    #[test]
+    fn compileAndRunFunctionWithEndFuncOperand(){
+        // 11 is the eleventh constant, so its constant id is the same byte as OpEndFunc
+        let source = "1 + 2 + 3 + 4 + 5 + 6 + 7 + 8 + 9 + 10\ndef foo(a: number) -> number:\na*11\nend\nfoo(2)";
+        let mut parser = Parser::new(source);
+        let ast_nodes = parser.parse();
+        let mut ast_converter = ASTConverter::new();
+        for ast in &ast_nodes.unwrap() {
+            ast_converter.ConvertExprToByteCode(ast.to_owned());
+        }
+        let body = &ast_converter.functionCode;
+        assert!(body[..body.len() - 1].contains(&(OpCodes::OpEndFunc as u8)));
+        let mut toast_vm = VMCore::new();
+        toast_vm.processProgram(&Module::FromConverter(&ast_converter, None)).unwrap();
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 22 as f64);
     }
 
 
@@ -1477,8 +2835,11 @@ mod tests {
             ast_converter.ConvertExprToByteCode(ast.to_owned());
         }
         println!("{:?}", ast_converter.program);
-        let true_val: Vec<u8> = [1, 1, 64, 80, 192, 0, 0, 0, 0, 0, 6, 1].to_vec();
-        assert_eq!(ast_converter.program, true_val);
+        let true_val: Vec<Instruction> = [
+            Instruction::LoadConstant { reg: 0, constId: 0 },
+            Instruction::NewVar { reg: 0, varType: VarTypes::FloatType },
+        ].to_vec();
+        assert_eq!(Instruction::DecodeProgram(&ast_converter.program), true_val);
     }
 
     #[test]
@@ -1491,7 +2852,7 @@ mod tests {
             ast_converter.ConvertExprToByteCode(ast.to_owned());
         }
         let mut toast_vm = VMCore::new();
-        toast_vm.processProgram(&ast_converter.program);
+        toast_vm.processProgram(&Module::FromConverter(&ast_converter, None)).unwrap();
         let toast_vm_var = toast_vm.memoryList.first().unwrap().variableLookup.get(&(0 as u64)).unwrap();
         println!("{:?}", toast_vm);
         assert_eq!(toast_vm_var.0, VarTypes::FloatType);
@@ -1507,8 +2868,16 @@ mod tests {
         for ast in &ast_nodes.unwrap() {
             ast_converter.ConvertExprToByteCode(ast.to_owned());
         }
-        let true_val: Vec<u8> = [13, 1, 23, 1, 3, 24, 63, 240, 0, 0, 0, 0, 0, 0, 64, 0, 0, 0, 0, 0, 0, 0, 64, 8, 0, 0, 0, 0, 0, 0, 14, 6, 4, 16, 0, 0, 0, 0, 0, 0, 0, 0, 17, 1, 33, 0, 0, 0, 0, 0, 0, 0, 0, 1, 18, 2].to_vec();
-        assert_eq!(ast_converter.program, true_val);
+        let true_val: Vec<Instruction> = [
+            Instruction::LoadArray { reg: 0, elementType: VarTypes::FloatType, dimensions: [3].to_vec(), elements: [1.0, 2.0, 3.0].map(f64::to_bits).to_vec() },
+            Instruction::EndArray,
+            Instruction::NewVar { reg: 0, varType: VarTypes::ArrayType },
+            Instruction::AccessArray { varId: 0, dest: 0, indexCount: 1 },
+            Instruction::AccessElementBegin,
+            Instruction::LoadConstant { reg: 1, constId: 0 },
+            Instruction::AccessElementEnd { reg: 1 },
+        ].to_vec();
+        assert_eq!(Instruction::DecodeProgram(&ast_converter.program), true_val);
     }
 
     #[test]
@@ -1521,13 +2890,13 @@ mod tests {
             ast_converter.ConvertExprToByteCode(ast.to_owned());
         }
         let mut toast_vm = VMCore::new();
-        toast_vm.processProgram(&ast_converter.program);
+        toast_vm.processProgram(&Module::FromConverter(&ast_converter, None)).unwrap();
         println!("{:?}", toast_vm);
         let curMemoryBlock = toast_vm.memoryList.first();
         assert_eq!(curMemoryBlock.is_some(), true);
-        let listLookup = curMemoryBlock.unwrap().listLookup.first();
-        assert_eq!(listLookup.unwrap().0, VarTypes::FloatType);
-        assert_eq!(f64::from_bits(toast_vm.registers[8 as usize]), 1 as f64 ); 
+        let list = toast_vm.heap.Get(curMemoryBlock.unwrap().variableLookup.get(&0).unwrap().1);
+        assert_eq!(list.unwrap().0, VarTypes::FloatType);
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 1 as f64 ); 
     }
 
     #[test]
@@ -1539,8 +2908,29 @@ mod tests {
         for ast in &ast_nodes.unwrap() {
             ast_converter.ConvertExprToByteCode(ast.to_owned());
         }
-        let true_val: Vec<u8> = [13, 4, 23, 2, 2, 3, 24, 20, 13, 33, 23, 1, 3, 24, 63, 240, 0, 0, 0, 0, 0, 0, 64, 0, 0, 0, 0, 0, 0, 0, 64, 8, 0, 0, 0, 0, 0, 0, 14, 21, 20, 13, 65, 23, 1, 3, 24, 64, 16, 0, 0, 0, 0, 0, 0, 64, 20, 0, 0, 0, 0, 0, 0, 64, 24, 0, 0, 0, 0, 0, 0, 14, 21, 14, 6, 4, 16, 0, 0, 0, 0, 0, 0, 0, 0, 17, 1, 97, 63, 240, 0, 0, 0, 0, 0, 0, 3, 18, 17, 1, 129, 63, 240, 0, 0, 0, 0, 0, 0, 4, 18, 5].to_vec();
-        assert_eq!(ast_converter.program, true_val);
+        let true_val: Vec<Instruction> = [
+            Instruction::LoadArray { reg: 0, elementType: VarTypes::ArrayType, dimensions: [2, 3].to_vec(), elements: Vec::new() },
+            Instruction::LoadMultiDimensionalArrayElement,
+            Instruction::LoadArray { reg: 1, elementType: VarTypes::FloatType, dimensions: [3].to_vec(), elements: [1.0, 2.0, 3.0].map(f64::to_bits).to_vec() },
+            Instruction::EndArray,
+            Instruction::EndMultiDimensionalArrayElement,
+            Instruction::LoadMultiDimensionalArrayElement,
+            Instruction::LoadArray { reg: 1, elementType: VarTypes::FloatType, dimensions: [3].to_vec(), elements: [4.0, 5.0, 6.0].map(f64::to_bits).to_vec() },
+            Instruction::EndArray,
+            Instruction::EndMultiDimensionalArrayElement,
+            Instruction::EndArray,
+            Instruction::NewVar { reg: 0, varType: VarTypes::ArrayType },
+            Instruction::AccessArray { varId: 0, dest: 0, indexCount: 2 },
+            Instruction::AccessElementBegin,
+            Instruction::LoadConstant { reg: 1, constId: 0 },
+            Instruction::AccessElementEnd { reg: 1 },
+            Instruction::AccessElementBegin,
+            Instruction::LoadConstant { reg: 2, constId: 0 },
+            Instruction::AccessElementEnd { reg: 2 },
+        ].to_vec();
+        assert_eq!(Instruction::DecodeProgram(&ast_converter.program), true_val);
+        // Both indexes share one constant
+        assert_eq!(ast_converter.constants, [Constant::Number(1.0)].to_vec());
     }
 
     #[test]
@@ -1553,11 +2943,436 @@ mod tests {
             ast_converter.ConvertExprToByteCode(ast.to_owned());
         }
         let mut toast_vm = VMCore::new();
-        toast_vm.processProgram(&ast_converter.program);
+        toast_vm.processProgram(&Module::FromConverter(&ast_converter, None)).unwrap();
         let curMemoryBlock = toast_vm.memoryList.first();
         assert_eq!(curMemoryBlock.is_some(), true);
-        let listLookup = curMemoryBlock.unwrap().listLookup.first();
-        assert_eq!(listLookup.unwrap().0, VarTypes::FloatType);
-        assert_eq!(f64::from_bits(toast_vm.registers[8 as usize]), 5 as f64 ); 
+        let list = toast_vm.heap.Get(curMemoryBlock.unwrap().variableLookup.get(&0).unwrap().1);
+        assert_eq!(list.unwrap().0, VarTypes::FloatType);
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 5 as f64 ); 
+    }
+
+    #[test]
+    fn compileAndRunMatchVariant(){
+        let source = "type Shape = Circle(number) | Rect(number, number)\nlet s: Shape = Rect(3, 4)\nmatch s with\n| Circle(r) -> r * r\n| Rect(w, h) -> w * h\nendmatch";
+        let toast_vm = runSource(source);
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 12 as f64 );
+        // Match bindings are dropped after the arm
+        assert_eq!(toast_vm.memoryList.first().unwrap().variableLookup.len(), 1);
+    }
+
+    #[test]
+    fn compileAndRunMatchInFunction(){
+        let source = "type Shape = Circle(number) | Rect(number, number)\ndef area(s: Shape) -> number:\nmatch s with\n| Circle(r) -> r * r\n| Rect(w, h) -> w * h\nendmatch\nend\narea(Circle(5))";
+        let toast_vm = runSource(source);
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 25 as f64 );
+    }
+
+    #[test]
+    fn compileAndRunMatchNestedVariant(){
+        let source = "type List = Nil | Cons(number, List)\nlet l: List = Cons(1, Cons(2, Nil))\nmatch l with\n| Nil -> 0\n| Cons(x, Nil) -> x\n| Cons(x, Cons(y, _)) -> y\nendmatch";
+        let toast_vm = runSource(source);
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 2 as f64 );
+    }
+
+    #[test]
+    fn compileAndRunMatchLiteralAndArray(){
+        let source = "let arr: number[] = [4,5]\nmatch arr with\n| [] -> 0\n| [4, x] -> x\n| _ -> 1\nendmatch";
+        let toast_vm = runSource(source);
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 5 as f64 );
+    }
+
+    #[test]
+    #[should_panic(expected = "Missing variants: Rect")]
+    fn compileNonExhaustiveMatch(){
+        let source = "type Shape = Circle(number) | Rect(number, number)\nlet s: Shape = Rect(3, 4)\nmatch s with\n| Circle(r) -> r\n| Rect(1, h) -> h\nendmatch";
+        compile(source, false);
+    }
+
+    #[test]
+    fn compileAndRunGenericFunction(){
+        let source = "def first<T>(xs: T[]) -> T:\nreturn xs[0]\nend\nlet nums: number[] = [4,5]\nlet word: string = \"hi\"\nfirst(nums)";
+        let mut ast_converter = compile(source, false);
+        assert_eq!(ast_converter.funcIdTable.get("first<number>"), Some(&1));
+        let mut toast_vm = VMCore::new();
+        toast_vm.processProgram(&Module::FromConverter(&ast_converter, None)).unwrap();
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 4 as f64);
+
+        let mut parser = Parser::new("first(word)");
+        for ast in &parser.parse().unwrap() {
+            ast_converter.ConvertExprToByteCode(ast.to_owned());
+        }
+        assert_eq!(ast_converter.funcIdTable.get("first<char>"), Some(&2));
+        toast_vm.processProgram(&Module::FromConverter(&ast_converter, None)).unwrap();
+        assert_eq!((toast_vm.registers[regResult].1 as u8) as char, 'h');
+    }
+
+    #[test]
+    #[should_panic(expected = "Can not infer type parameter U of pick")]
+    fn compileGenericFunctionWithUnusedTypeParam(){
+        let source = "def pick<T, U>(x: T) -> T:\nreturn x\nend\npick(1)";
+        compile(source, false);
+    }
+
+    #[test]
+    fn getElementOfBadVariant(){
+        let mut toast_vm = VMCore::new();
+        toast_vm.heap = Heap::FromObjects(vec![Some((VarTypes::VariantType, vec![0, 5], Vec::new()))]);
+        assert_eq!(toast_vm.GetListElement(0, 0), Ok((VarTypes::NullType, 0)));
+        assert_eq!(toast_vm.GetListElement(0, 1), Err(VmErrorKind::InvalidObject(0)));
+    }
+
+    #[test]
+    fn compileAndRunRecursiveGenericFunction(){
+        let source = "type List = Nil | Cons(number, List)\ndef size<T>(x: T, l: List) -> number:\nmatch l with\n| Nil -> 0\n| Cons(_, rest) -> size(x, rest) + 1\nendmatch\nend\nmatch Cons(1, Cons(2, Nil)) with\n| Cons(v, rest) -> print(size(v, rest))\n| Nil -> print(0)\nendmatch";
+        let ast_converter = compile(source, false);
+        assert_eq!(ast_converter.funcIdTable.get("size<number>"), Some(&1));
+        let (_, output) = runModule(&Module::FromConverter(&ast_converter, None));
+        assert_eq!(output, "1.0\n");
+    }
+
+    #[test]
+    fn compileAndRunOptionalDefault(){
+        let toast_vm = runSource("let x: number? = none\nlet y: number = x ?? 1\ny");
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 1 as f64);
+        let toast_vm = runSource("let x: number? = 5\nlet y: number = x ?? 1\ny");
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 5 as f64);
+    }
+
+    #[test]
+    fn compileAndRunOptionalUnwrapAndMatch(){
+        let toast_vm = runSource("let y: number? = some(5)\nlet z: number = unwrap(y)\nz");
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 5 as f64);
+        let toast_vm = runSource("let y: number? = 5\nlet z: number = match y with\n| none -> 0\n| some(v) -> v + 1\nendmatch\nz");
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 6 as f64);
+    }
+
+    #[test]
+    fn compileAndRunOptionalParameter(){
+        let toast_vm = runSource("def orZero(x: number?) -> number:\nreturn x ?? 0\nend\norZero(7)");
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 7 as f64);
+    }
+
+    #[test]
+    fn runUnwrapNone(){
+        let mut toast_vm = VMCore::new();
+        let err = toast_vm.processProgram(&compileModule("let x: number? = none\nunwrap(x)")).unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::UnwrapNone);
+        assert_eq!(err.opCode, Some(OpCodes::OpUnwrap));
+    }
+
+    #[test]
+    #[should_panic(expected = "Can not use optional value in '+'")]
+    fn compileArithmeticOnOptional(){
+        runSource("let x: number? = 5\nx + 1");
+    }
+
+    #[test]
+    #[should_panic(expected = "Can not use optional value for y of type number")]
+    fn compileAssignOptionalToPlainVariable(){
+        runSource("let x: number? = 5\nlet y: number = x");
+    }
+
+    #[test]
+    fn compileAndRunTypeAlias(){
+        let toast_vm = runSource("type Num = number\ntype Nums = Num[]\ntype MaybeNum = Num?\nlet xs: Nums = [1,2]\nlet m: MaybeNum = none\nlet y: Num = m ?? xs[1]\ny");
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 2 as f64);
+    }
+
+    #[test]
+    fn compileAndRunTypeAliasInFunction(){
+        let toast_vm = runSource("type Nums = number[]\ndef second(xs: Nums) -> number:\nreturn xs[1]\nend\nlet xs: Nums = [3,4]\nsecond(xs)");
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 4 as f64);
+    }
+
+    #[test]
+    #[should_panic(expected = "Type aliases form a cycle: B -> A -> B")]
+    fn compileCyclicTypeAlias(){
+        runSource("type A = B\ntype B = A");
+    }
+
+    #[test]
+    fn runTaggedRegisters(){
+        let toast_vm = runSource("let c: char = 'a'\nlet x: number = 2 + 3\nc");
+        assert_eq!(toast_vm.registers[regResult], (VarTypes::CharType, 'a' as u64));
+        assert_eq!(toast_vm.memoryList[0].variableLookup.get(&1), Some(&(VarTypes::FloatType, f64::to_bits(5.0))));
+    }
+
+    #[test]
+    fn runPrintVariant(){
+        let printTypes = vec![VarTypes::FloatType, VarTypes::CharType, VarTypes::ArrayType, VarTypes::ArrayRef];
+        for source in ["type Shape = Circle(number) | Rect(number, number)\nprint(Circle(2))", "let y: number? = 5\nprint(y)"] {
+            let mut toast_vm = VMCore::new();
+            let err = toast_vm.processProgram(&compileModule(source)).unwrap_err();
+            assert_eq!(err.kind, VmErrorKind::TypeMismatch { expected: printTypes.clone(), found: VarTypes::VariantType });
+            assert_eq!(err.opCode, Some(OpCodes::OpCallFunc));
+        }
+    }
+
+    #[test]
+    fn runAddMixedTypes(){
+        // Loads a char and a number by hand, since the compiler rejects mixed operands
+        let module = Assemble("loadscalar r0, char, 'a'\nloadconstant r1, 1.0\nadd r0, r1").unwrap();
+        let mut toast_vm = VMCore::new();
+        let err = toast_vm.processProgram(&module).unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::UnsupportedOperands { left: VarTypes::CharType, right: VarTypes::FloatType });
+        assert_eq!((err.pc, err.opCode), (14, Some(OpCodes::OpAdd)));
+        assert!(err.callStack.is_empty());
+    }
+
+    #[test]
+    fn runListsSharedByReference(){
+        let toast_vm = runSource("def second(xs: number[]) -> number:\nreturn xs[1]\nend\nlet xs: number[] = [3,4]\nsecond(xs)\nsecond(xs)");
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 4 as f64);
+        // Calls use the caller's list instead of a copy of it
+        assert_eq!(toast_vm.heap.Len(), 1);
+    }
+
+    #[test]
+    fn runReturnListFromFunction(){
+        let toast_vm = runSource("def pair() -> number[]:\nlet xs: number[] = [5,6]\nreturn xs\nend\nlet ys: number[] = pair()\nys[1]");
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 6 as f64);
+    }
+
+    #[test]
+    fn runGarbageCollection(){
+        let mut toast_vm = runSource("def first() -> number:\nlet xs: number[] = [1,2,3]\nreturn xs[0]\nend\nlet kept: number[] = [4]\nfirst()\nfirst()\nfirst()");
+        assert_eq!(toast_vm.heap.Len(), 4);
+        // The lists made by the calls can not be reached once the calls return
+        assert_eq!(toast_vm.CollectGarbage(), 3);
+        assert_eq!(toast_vm.heap.Len(), 1);
+        let kept = toast_vm.memoryList[0].variableLookup.get(&0).unwrap().1;
+        assert_eq!(toast_vm.heap.Get(kept).unwrap().1, [f64::to_bits(4.0)].to_vec());
+    }
+
+    #[test]
+    fn runWithOutputSink(){
+        let module = compileModule("let a: number = 2 + 3\nprint(a)\nprint(\"hi\")\nlet xs: number[] = [1, 2]\nprint(xs)");
+        let (output, trace) = (CaptureBuffer::new(), CaptureBuffer::new());
+        let mut toast_vm = VMCore::new();
+        toast_vm.output = Sink::new(output.clone());
+        toast_vm.processProgram(&module).unwrap();
+        assert_eq!(output.Take(), "5.0\n\"hi\"\n[1.0,2.0,]\n");
+
+        // Tracing is off unless a trace sink is given, and never mixes with the output
+        let mut toast_vm = VMCore::new();
+        toast_vm.output = Sink::new(output.clone());
+        toast_vm.trace = Some(Sink::new(trace.clone()));
+        toast_vm.processProgram(&module).unwrap();
+        assert_eq!(output.Contents(), "5.0\n\"hi\"\n[1.0,2.0,]\n");
+        assert!(trace.Contents().starts_with("Answer: 5\n"));
+    }
+
+    struct BrokenPipe;
+
+    impl std::io::Write for BrokenPipe {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            return Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "pipe closed"));
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    #[test]
+    fn runOutputFailure(){
+        let mut toast_vm = VMCore::new();
+        toast_vm.output = Sink::new(BrokenPipe);
+        let err = toast_vm.processProgram(&compileModule("print(1)")).unwrap_err();
+        assert_eq!((err.kind, err.opCode), (VmErrorKind::OutputFailed("pipe closed".to_string()), Some(OpCodes::OpCallFunc)));
+    }
+
+    #[test]
+    fn runOutOfFuel(){
+        let module = Assemble("loadconstant r0, 1.0\nloop:\nadd r0, r0\njump loop").unwrap();
+        let mut toast_vm = VMCore::new();
+        toast_vm.fuel = Some(100);
+        let err = toast_vm.processProgram(&module).unwrap_err();
+        assert_eq!((err.kind, err.pc, err.opCode), (VmErrorKind::OutOfFuel, 13, Some(OpCodes::OpJump)));
+        // The VM stops before the instruction that would go over the budget
+        assert_eq!((toast_vm.instructionCount, toast_vm.fuel, toast_vm.pc), (100, Some(0), 13));
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 2f64.powi(50));
+    }
+
+    #[test]
+    fn runCallDepthLimit(){
+        let module = Assemble("startfunc f() -> null\ncallfunc f\nendparamload\nendfunc\ncallfunc f\nendparamload").unwrap();
+        let mut toast_vm = VMCore::new();
+        let err = toast_vm.processProgram(&module).unwrap_err();
+        assert_eq!((err.kind, err.opCode), (VmErrorKind::CallDepthLimit(defaultMaxCallDepth), Some(OpCodes::OpCallFunc)));
+        assert_eq!((err.callStack.len(), toast_vm.memoryList.len()), (defaultMaxCallDepth, defaultMaxCallDepth + 1));
+        toast_vm.Unwind();
+        toast_vm.pc = 0;
+        toast_vm.maxCallDepth = Some(5);
+        assert_eq!(toast_vm.processProgram(&module).unwrap_err().callStack.len(), 5);
+    }
+
+    #[test]
+    fn runDeepRecursion(){
+        // Calls do not run on the Rust stack, so recursion is only bounded by maxCallDepth
+        let toast_vm = runSource("def count(n: number) -> number:\nmatch n with\n| 0 -> 0\n| _ -> count(n - 1) + 1\nendmatch\nend\ncount(500)");
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 500 as f64);
+        assert_eq!((toast_vm.memoryList.len(), toast_vm.callStack.len(), toast_vm.regWindowBase), (1, 0, 0));
+    }
+
+    #[test]
+    fn runGrowingProgram(){
+        let mut parser = Parser::new("let a: number = 2\nlet b: number = a * 3\nb + 1");
+        let mut ast_converter = ASTConverter::new();
+        let mut toast_vm = VMCore::new();
+        for ast in parser.parse().unwrap() {
+            ast_converter.ConvertExprToByteCode(ast);
+            toast_vm.processProgram(&Module::FromConverter(&ast_converter, None)).unwrap();
+        }
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 7.0);
+        // The decoded program grew with each statement
+        assert_eq!(*toast_vm.program, DecodedProgram::Decode(&ast_converter.program).unwrap());
+    }
+
+    #[test]
+    fn runHeapLimits(){
+        let module = Assemble("loadconstant r0, \"ab\"\nloop:\nadd r0, r0\njump loop").unwrap();
+        let mut toast_vm = VMCore::new();
+        toast_vm.heap.maxElements = Some(1000);
+        let err = toast_vm.processProgram(&module).unwrap_err();
+        assert_eq!((err.kind, err.pc, err.opCode), (VmErrorKind::HeapSizeLimit(1000), 10, Some(OpCodes::OpAdd)));
+        // The strings that were replaced were collected on the way
+        assert_eq!(toast_vm.heap.ElementCount(), 512);
+
+        // Objects that can no longer be reached do not count towards the limit
+        let module = Assemble("loadconstant r0, 1.0\nloop:\nloadconstant r0, \"a\"\njump loop").unwrap();
+        let mut toast_vm = VMCore::new();
+        toast_vm.heap.maxObjects = Some(10);
+        toast_vm.fuel = Some(1000);
+        assert_eq!(toast_vm.processProgram(&module).unwrap_err().kind, VmErrorKind::OutOfFuel);
+        assert!(toast_vm.heap.Len() <= 10);
+    }
+
+    #[test]
+    fn allocCollectsBeforeLimit(){
+        let mut toast_vm = VMCore::new();
+        toast_vm.heap.maxObjects = Some(4);
+        toast_vm.registers[0] = (VarTypes::ArrayType, toast_vm.Alloc((VarTypes::FloatType, [1].to_vec(), [1].to_vec())).unwrap());
+        // Nothing holds the strings, so each one is garbage once the next is allocated
+        for _ in 0..100 {
+            toast_vm.Alloc((VarTypes::CharType, [97].to_vec(), [1].to_vec())).unwrap();
+        }
+        assert!(toast_vm.heap.Len() <= 4);
+        assert!(toast_vm.heap.Get(toast_vm.registers[0].1).is_some());
+
+        // Objects that can still be reached are kept, so the limit is still reached
+        for reg in 1..4 {
+            toast_vm.registers[reg] = (VarTypes::ArrayType, toast_vm.Alloc((VarTypes::CharType, [97].to_vec(), [1].to_vec())).unwrap());
+        }
+        assert_eq!(toast_vm.Alloc((VarTypes::CharType, Vec::new(), [0].to_vec())), Err(VmErrorKind::HeapObjectLimit(4)));
+
+        // A large list fits once the garbage before it is collected, even though the heap was under half of the limit
+        let module = Assemble(&format!("loadconstant r0, \"{}\"\nloadconstant r0, 1.0\nloadarray r1, char, [70], \"{}\"\nendarray", "a".repeat(40), "b".repeat(70))).unwrap();
+        let mut toast_vm = VMCore::new();
+        toast_vm.heap.maxElements = Some(100);
+        toast_vm.processProgram(&module).unwrap();
+        assert_eq!(toast_vm.heap.ElementCount(), 70);
+    }
+
+    #[test]
+    fn runPastDeadline(){
+        let module = Assemble("startfunc spin() -> null\nloadconstant r0, 1.0\nloop:\njump loop\nendfunc\ncallfunc spin\nendparamload").unwrap();
+        let mut toast_vm = VMCore::new();
+        toast_vm.deadline = Some(std::time::Instant::now() + std::time::Duration::from_millis(20));
+        let err = toast_vm.processProgram(&module).unwrap_err();
+        assert_eq!((err.kind, err.opCode), (VmErrorKind::DeadlineExceeded, Some(OpCodes::OpJump)));
+        assert_eq!(err.callStack, [CallFrame { funcId: 1, name: "spin".to_string(), callPc: 0 }].to_vec());
+        assert!(toast_vm.instructionCount > 0);
+    }
+
+    #[test]
+    fn runErrorInsideFunction(){
+        let module = Assemble("
+            startfunc inner() -> null
+                reload r0, slot 3
+            endfunc
+            startfunc outer() -> null
+                callfunc inner
+                endparamload
+            endfunc
+            callfunc outer
+            endparamload
+        ").unwrap();
+        let mut toast_vm = VMCore::new();
+        let err = toast_vm.processProgram(&module).unwrap_err();
+        assert_eq!((err.kind, err.pc, err.opCode), (VmErrorKind::EmptySpillSlot(3), 3, Some(OpCodes::OpReload)));
+        assert_eq!(err.callStack, [
+            CallFrame { funcId: 2, name: "outer".to_string(), callPc: 0 },
+            CallFrame { funcId: 1, name: "inner".to_string(), callPc: 3 },
+        ].to_vec());
+        // The VM keeps the state of the failed calls until it is unwound
+        assert_eq!(toast_vm.memoryList.len(), 3);
+        toast_vm.Unwind();
+        assert_eq!((toast_vm.memoryList.len(), toast_vm.regWindowBase, toast_vm.callStack.len()), (1, 0, 0));
+        toast_vm.pc = 0;
+        toast_vm.processProgram(&Assemble("loadconstant r0, 1.0").unwrap()).unwrap();
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 1.0);
+    }
+
+    #[test]
+    fn runInvalidBytecode(){
+        let mut toast_vm = VMCore::new();
+        let module = Module { constants: Vec::new(), functions: Vec::new(), functionCode: Vec::new(), code: [250].to_vec(), debugInfo: None };
+        let err = toast_vm.processProgram(&module).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid program: Unkown opcode: 250 at pc 0");
+        // Rejected programs are not run at all
+        let err = toast_vm.processProgram(&Assemble("loadconstant r0, 1.0\ncallfunc 9\nendparamload").unwrap()).unwrap_err();
+        assert_eq!((err.to_string(), err.opCode), ("Invalid program: Unkown function: 9 at pc 10 (OpCallFunc)".to_string(), Some(OpCodes::OpCallFunc)));
+        assert_eq!((toast_vm.pc, toast_vm.constants.len()), (0, 0));
+        let err = VMCore::new().processProgram(&Assemble("loadvar r0, float, var 4").unwrap()).unwrap_err();
+        assert_eq!(err.to_string(), "Unkown variable: 4 at pc 0 (OpLoadVar)");
+    }
+
+    #[test]
+    fn compileAndRunWideExpression(){
+        let source = "let x: number = 1 + (2 + (3 + (4 + (5 + (6 + (7 + (8 + (9 + 10))))))))\nx";
+        let mut parser = Parser::new(source);
+        let ast_nodes = parser.parse();
+        let mut ast_converter = ASTConverter::new();
+        for ast in &ast_nodes.unwrap() {
+            ast_converter.ConvertExprToByteCode(ast.to_owned());
+        }
+        let mut toast_vm = VMCore::new();
+        toast_vm.processProgram(&Module::FromConverter(&ast_converter, None)).unwrap();
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 55 as f64);
+    }
+
+    #[test]
+    fn spillWhenOutOfRegisters(){
+        let mut ast_converter = ASTConverter::new();
+        let registers: Vec<u8> = (0..regResult + 1).map(|_| ast_converter.GetFreeReg()).collect();
+        // The oldest register is spilled once every register of the window is live
+        assert_eq!(registers[regResult - 1], (regResult - 1) as u8);
+        assert_eq!(registers[regResult], 0);
+        assert_eq!(Instruction::DecodeProgram(&ast_converter.program), [Instruction::Spill { reg: 0, slot: 0 }].to_vec());
+        ast_converter.FreeReg(0);
+        assert_eq!(Instruction::DecodeProgram(&ast_converter.program), [Instruction::Spill { reg: 0, slot: 0 }, Instruction::Reload { reg: 0, slot: 0 }].to_vec());
+    }
+
+    #[test]
+    fn compileAndRunCallInsideExpression(){
+        let toast_vm = runSource("def foo(a: number) -> number:\nreturn a * 100\nend\nlet x: number = 1 + foo(2)\nx");
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 201 as f64);
+        let toast_vm = runSource("def foo(a: number) -> number:\nreturn a * 100\nend\nlet x: number = foo(2) - foo(1)\nx");
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 100 as f64);
+    }
+
+    #[test]
+    fn compileAndRunNestedCalls(){
+        // Arguments are computed with the caller's variables, before the callee's memory block is made
+        let toast_vm = runSource("def fib(n: number) -> number:\nmatch n with\n| 0 -> 0\n| 1 -> 1\n| _ -> fib(n - 1) + fib(n - 2)\nendmatch\nend\nfib(15)");
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 610 as f64);
+        let output = CaptureBuffer::new();
+        let mut toast_vm = VMCore::new();
+        toast_vm.output = Sink::new(output.clone());
+        toast_vm.processProgram(&compileModule("def sq(a: number) -> number:\nreturn a * a\nend\nlet b: number = 3\nprint(sq(b))\nprint(sq(sq(b)))")).unwrap();
+        assert_eq!(output.Take(), "9.0\n81.0\n");
+        // Bodies are decoded once however often they are called
+        assert_eq!(toast_vm.functionBodies.len(), 1);
     }
 }
@@ -0,0 +1,335 @@
+#![allow(non_snake_case)]
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::codegen::{regResult, ToastLangValue, VMCore, VarTypes};
+
+/// Maps compiled code back to the source. Filled in by ASTConverter as it compiles
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceMap {
+    /// Key is function id, None for top level code. Value is list of (pc, line) of each statement in order of pc.
+    /// Inside a function pc is relative to the function's OpStartFunc
+    pub lines: HashMap<Option<u64>, Vec<(usize, usize)>>,
+    /// Key is function id, None for top level code. Value is list of (pc, Variable Id, Variable name) in order of declaration
+    pub varNames: HashMap<Option<u64>, Vec<(usize, u64, String)>>
+}
+
+impl SourceMap {
+    /// Records that the statement on the given line starts at pc. A statement that compiled to nothing is replaced by the next one
+    pub fn AddLine(&mut self, function: Option<u64>, pc: usize, line: usize) {
+        let lines = self.lines.entry(function).or_default();
+        if lines.last().is_some_and(|x| x.0 == pc) {
+            lines.pop();
+        }
+        lines.push((pc, line));
+    }
+
+    /// Moves the pcs of the given code to where newPc says their instructions went
+    pub fn MovePcs(&mut self, function: Option<u64>, newPc: impl Fn(usize) -> usize) {
+        for line in self.lines.get_mut(&function).into_iter().flatten() {
+            line.0 = newPc(line.0);
+        }
+        for variable in self.varNames.get_mut(&function).into_iter().flatten() {
+            variable.0 = newPc(variable.0);
+        }
+    }
+
+    /// Records the name of a variable declared at pc
+    pub fn AddVariable(&mut self, function: Option<u64>, pc: usize, varId: u64, name: &str) {
+        self.varNames.entry(function).or_default().push((pc, varId, name.to_string()));
+    }
+
+    /// Returns true if the code has any statements with a line
+    pub fn HasLines(&self, function: Option<u64>) -> bool {
+        return self.lines.get(&function).is_some_and(|x| !x.is_empty());
+    }
+
+    /// Returns the line of the statement the instruction at pc belongs to
+    pub fn Line(&self, function: Option<u64>, pc: usize) -> Option<usize> {
+        let lines = self.lines.get(&function)?;
+        let index = lines.partition_point(|x| x.0 <= pc).checked_sub(1)?;
+        return Some(lines[index].1);
+    }
+
+    /// Returns the line of the statement that starts at pc, None if pc is in the middle of a statement
+    pub fn StatementAt(&self, function: Option<u64>, pc: usize) -> Option<usize> {
+        let lines = self.lines.get(&function)?;
+        return lines.binary_search_by_key(&pc, |x| x.0).ok().map(|index| lines[index].1);
+    }
+
+    /// Returns the name of a variable at pc. Variable ids are reused once a variable goes out of scope, so the latest declaration before pc wins
+    pub fn VarName(&self, function: Option<u64>, pc: usize, varId: u64) -> Option<&str> {
+        return self.varNames.get(&function)?.iter().rev()
+            .find(|x| x.1 == varId && x.0 <= pc)
+            .map(|x| x.2.as_str());
+    }
+}
+
+/// Where the VM stops for the debugger
+#[derive(Debug, Clone, PartialEq)]
+pub enum Breakpoint {
+    /// Stops at the start of each statement on the line
+    Line(usize),
+    /// Stops at the first instruction of each call to the function
+    Function(String)
+}
+
+/// How the VM runs after it paused
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepMode {
+    /// Runs until a breakpoint
+    Continue,
+    /// Stops at the next statement, entering calls
+    Into,
+    /// Stops at the next statement that is not inside a call made from the paused one
+    Over,
+    /// Stops once the paused call returns
+    Out
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PauseReason {
+    /// Index of the breakpoint in Debugger::breakpoints
+    Breakpoint(usize),
+    Step
+}
+
+/// Where the VM paused. The instruction at pc has not run yet
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pause {
+    pub reason: PauseReason,
+    /// Function being run, None for top level code
+    pub function: Option<u64>,
+    /// Inside a function pc is relative to the function's OpStartFunc
+    pub pc: usize,
+    pub line: Option<usize>
+}
+
+/// Called when the VM pauses. It can inspect the VM and change the breakpoints, and sets Debugger::mode to pick how the VM goes on
+pub type PauseHandler = Box<dyn FnMut(&VMCore, &mut Debugger, &Pause)>;
+
+/// Breakpoints and stepping for VMCore. The VM checks the debugger before every instruction it runs (see VMCore::debugger).
+/// Code without lines in the source map, such as assembled code, is stepped one instruction at a time
+pub struct Debugger {
+    pub breakpoints: Vec<Breakpoint>,
+    pub sourceMap: SourceMap,
+    pub mode: StepMode,
+    /// Number of calls running when the VM last paused
+    pauseDepth: usize,
+    /// Number of calls running at the last instruction
+    lastDepth: usize,
+    handler: Option<PauseHandler>
+}
+
+impl fmt::Debug for Debugger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return f.debug_struct("Debugger").field("breakpoints", &self.breakpoints).field("mode", &self.mode).finish();
+    }
+}
+
+impl Debugger {
+    pub fn new(sourceMap: SourceMap, handler: PauseHandler) -> Self {
+        Debugger {
+            breakpoints: Vec::new(),
+            sourceMap,
+            mode: StepMode::Continue,
+            pauseDepth: 0,
+            lastDepth: 0,
+            handler: Some(handler)
+        }
+    }
+
+    /// Checks if the VM should pause before running the instruction at pc. Called by the VM before every instruction
+    pub fn Check(&mut self, vm: &VMCore, pc: usize) -> Option<Pause> {
+        let function = vm.callStack.last().map(|x| x.funcId);
+        let depth = vm.callStack.len();
+        let entered = depth > self.lastDepth;
+        self.lastDepth = depth;
+        let statementLine = self.sourceMap.StatementAt(function, pc);
+        let isStatement = statementLine.is_some() || !self.sourceMap.HasLines(function);
+        let breakpoint = self.breakpoints.iter().position(|breakpoint| match breakpoint {
+            Breakpoint::Line(line) => statementLine == Some(*line),
+            Breakpoint::Function(name) => entered && vm.callStack.last().is_some_and(|x| x.name == *name)
+        });
+        // Every step stops right after the paused call returns, even in the middle of the caller's statement
+        let returned = depth < self.pauseDepth;
+        let step = match self.mode {
+            StepMode::Continue => false,
+            StepMode::Into => isStatement || returned,
+            StepMode::Over => (isStatement && depth <= self.pauseDepth) || returned,
+            StepMode::Out => returned
+        };
+        let reason = match (breakpoint, step) {
+            (Some(index), _) => PauseReason::Breakpoint(index),
+            (None, true) => PauseReason::Step,
+            (None, false) => return None
+        };
+        self.pauseDepth = depth;
+        return Some(Pause { reason, function, pc, line: self.sourceMap.Line(function, pc) });
+    }
+
+    /// Runs the pause handler
+    pub fn Paused(&mut self, vm: &VMCore, pause: &Pause) {
+        if let Some(mut handler) = self.handler.take() {
+            handler(vm, self, pause);
+            self.handler = Some(handler);
+        }
+    }
+
+    /// Lists the registers of the current call that hold a value
+    pub fn Registers(vm: &VMCore) -> Vec<(usize, ToastLangValue)> {
+        let window = &vm.registers[vm.regWindowBase..vm.regWindowBase + regResult];
+        return window.iter().enumerate()
+            .chain([(regResult, &vm.registers[regResult])])
+            .filter(|x| x.1.0 != VarTypes::NullType)
+            .map(|(reg, value)| (reg, *value))
+            .collect();
+    }
+
+    /// Lists the variables of the current call as (Variable Id, Variable name, Value)
+    pub fn Locals(&self, vm: &VMCore, pause: &Pause) -> Vec<(u64, Option<String>, ToastLangValue)> {
+        let Some(block) = vm.memoryList.get(vm.curMemoryId) else { return Vec::new() };
+        let mut locals: Vec<(u64, Option<String>, ToastLangValue)> = block.variableLookup.iter()
+            .map(|(varId, value)| (*varId, self.sourceMap.VarName(pause.function, pause.pc, *varId).map(|x| x.to_string()), *value))
+            .collect();
+        locals.sort_by_key(|x| x.0);
+        return locals;
+    }
+
+    /// Describes the running calls, innermost first, with the line each one is at
+    pub fn CallStack(&self, vm: &VMCore, pause: &Pause) -> Vec<String> {
+        let describe = |function: Option<u64>, pc: usize| {
+            let name = match function {
+                Some(funcId) => vm.funcNames.get(&(funcId as usize)).cloned().unwrap_or(format!("function {}", funcId)),
+                None => "top level".to_string()
+            };
+            match self.sourceMap.Line(function, pc) {
+                Some(line) => format!("{} at line {} (pc {})", name, line, pc),
+                None => format!("{} at pc {}", name, pc)
+            }
+        };
+        let mut frames = [describe(pause.function, pause.pc)].to_vec();
+        for (index, frame) in vm.callStack.iter().enumerate().rev() {
+            let caller = index.checked_sub(1).map(|x| vm.callStack[x].funcId);
+            frames.push(describe(caller, frame.callPc));
+        }
+        return frames;
+    }
+
+    /// Shows a value the way it would be written in source, following lists and variants on the heap
+    pub fn FormatValue(vm: &VMCore, value: ToastLangValue) -> String {
+        let (valueType, bits) = value;
+        match valueType {
+            VarTypes::NullType => return "null".to_string(),
+            VarTypes::FloatType => return format!("{}", f64::from_bits(bits)),
+            VarTypes::CharType => return format!("{:?}", (bits as u8) as char),
+            _ => {}
+        }
+        let Some(object) = vm.heap.Get(bits) else { return format!("<freed object {}>", bits) };
+        match object.0 {
+            VarTypes::CharType => {
+                let text: String = object.1.iter().map(|x| (*x as u8) as char).collect();
+                return format!("{:?}", text);
+            },
+            VarTypes::VariantType => {
+                let fields: Vec<String> = object.2.iter().zip(object.1.iter().skip(1))
+                    .map(|(fieldType, field)| Debugger::FormatValue(vm, (num::FromPrimitive::from_u8(*fieldType).unwrap_or(VarTypes::NullType), *field)))
+                    .collect();
+                return format!("variant {}({})", object.1.first().copied().unwrap_or(0), fields.join(", "));
+            },
+            elementType => {
+                let elementType = if elementType == VarTypes::ArrayRef { VarTypes::ArrayType } else { elementType };
+                let elements: Vec<String> = object.1.iter().map(|x| Debugger::FormatValue(vm, (elementType, *x))).collect();
+                return format!("[{}]", elements.join(", "));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::codegen::{VMCore, VarTypes};
+    use crate::debugger::{Breakpoint, Debugger, Pause, PauseReason, SourceMap, StepMode};
+    use crate::module::Module;
+    use crate::testsupport::compile;
+
+    /// (line, call depth, locals as (name, value)) at a pause
+    type Paused = (Option<usize>, usize, Vec<(Option<String>, String)>);
+
+    /// Runs the program, answering each pause with the next step mode
+    fn debug(source: &str, breakpoints: Vec<Breakpoint>, steps: Vec<StepMode>) -> Vec<Paused> {
+        let ast_converter = compile(source, false);
+        let pauses = Rc::new(RefCell::new(Vec::new()));
+        let seen = Rc::clone(&pauses);
+        let mut steps = steps.into_iter();
+        let mut debugger = Debugger::new(ast_converter.sourceMap.clone(), Box::new(move |vm: &VMCore, debugger: &mut Debugger, pause: &Pause| {
+            let locals = debugger.Locals(vm, pause).into_iter().map(|x| (x.1, Debugger::FormatValue(vm, x.2))).collect();
+            seen.borrow_mut().push((pause.line, vm.callStack.len(), locals));
+            debugger.mode = steps.next().unwrap_or(StepMode::Continue);
+        }));
+        debugger.breakpoints = breakpoints;
+        let mut toast_vm = VMCore::new();
+        toast_vm.debugger = Some(debugger);
+        toast_vm.processProgram(&Module::FromConverter(&ast_converter, None)).unwrap();
+        return pauses.take();
+    }
+
+    #[test]
+    fn mapPcToLines(){
+        let ast_converter = compile("let a: number = 1\n\ndef foo(x: number) -> number:\nlet y: number = x + 1\nreturn y\nend\nfoo(a)", false);
+        let sourceMap = &ast_converter.sourceMap;
+        let funcId = ast_converter.funcIdTable["foo"];
+        assert_eq!(sourceMap.lines[&None].iter().map(|x| x.1).collect::<Vec<usize>>(), [1, 7].to_vec());
+        assert_eq!(sourceMap.lines[&Some(funcId)].iter().map(|x| x.1).collect::<Vec<usize>>(), [4, 5].to_vec());
+        let (returnPc, _) = sourceMap.lines[&Some(funcId)][1];
+        assert_eq!((sourceMap.StatementAt(Some(funcId), returnPc), sourceMap.StatementAt(Some(funcId), returnPc + 1)), (Some(5), None));
+        assert_eq!(sourceMap.Line(Some(funcId), returnPc + 1), Some(5));
+        assert_eq!((sourceMap.VarName(Some(funcId), returnPc, 0), sourceMap.VarName(Some(funcId), returnPc, 1)), (Some("x"), Some("y")));
+        let (callPc, _) = sourceMap.lines[&None][1];
+        assert_eq!((sourceMap.VarName(None, 0, 0), sourceMap.VarName(None, callPc, 0)), (None, Some("a")));
+    }
+
+    #[test]
+    fn breakOnLinesAndFunctions(){
+        let source = "def foo(x: number) -> number:\nlet y: number = x * 2\nreturn y\nend\nlet a: number = foo(1)\nlet b: number = foo(a)";
+        let pauses = debug(source, [Breakpoint::Line(3)].to_vec(), Vec::new());
+        assert_eq!(pauses.iter().map(|x| x.0).collect::<Vec<Option<usize>>>(), [Some(3), Some(3)].to_vec());
+        assert_eq!(pauses[1].2, [(Some("x".to_string()), "2".to_string()), (Some("y".to_string()), "4".to_string())].to_vec());
+        let pauses = debug(source, [Breakpoint::Function("foo".to_string())].to_vec(), Vec::new());
+        assert_eq!(pauses.iter().map(|x| (x.0, x.1)).collect::<Vec<(Option<usize>, usize)>>(), [(Some(2), 1), (Some(2), 1)].to_vec());
+    }
+
+    #[test]
+    fn stepIntoOverAndOut(){
+        let source = "def foo(x: number) -> number:\nlet y: number = x * 2\nreturn y\nend\nlet a: number = 1\nlet b: number = foo(a)\nlet c: string = \"hi\"";
+        let lines = |pauses: Vec<Paused>| pauses.iter().map(|x| x.0.unwrap()).collect::<Vec<usize>>();
+        let breakAtStart = [Breakpoint::Line(5)].to_vec();
+        assert_eq!(lines(debug(source, breakAtStart.clone(), [StepMode::Into; 5].to_vec())), [5, 6, 2, 3, 6, 7].to_vec());
+        assert_eq!(lines(debug(source, breakAtStart.clone(), [StepMode::Over; 5].to_vec())), [5, 6, 7].to_vec());
+        assert_eq!(lines(debug(source, breakAtStart, [StepMode::Into, StepMode::Into, StepMode::Out].to_vec())), [5, 6, 2, 6].to_vec());
+        // Line 7 has not run yet when the VM pauses on it
+        let pauses = debug(source, [Breakpoint::Line(7)].to_vec(), Vec::new());
+        assert_eq!(pauses[0].2, [(Some("a".to_string()), "1".to_string()), (Some("b".to_string()), "2".to_string())].to_vec());
+    }
+
+    #[test]
+    fn stepInstructionsWithoutLines(){
+        let module = crate::assembler::Assemble("loadconstant r0, 1.0\nloadconstant r1, 2.0\nadd r0, r1").unwrap();
+        let pcs = Rc::new(RefCell::new(Vec::new()));
+        let seen = Rc::clone(&pcs);
+        let mut debugger = Debugger::new(SourceMap::default(), Box::new(move |vm: &VMCore, debugger: &mut Debugger, pause: &Pause| {
+            seen.borrow_mut().push((pause.pc, pause.reason.clone(), Debugger::Registers(vm).len()));
+            debugger.mode = StepMode::Into;
+        }));
+        debugger.mode = StepMode::Into;
+        let mut toast_vm = VMCore::new();
+        toast_vm.debugger = Some(debugger);
+        toast_vm.processProgram(&module).unwrap();
+        assert_eq!(pcs.take(), [(0, PauseReason::Step, 0), (10, PauseReason::Step, 2), (20, PauseReason::Step, 3)].to_vec());
+        assert_eq!(Debugger::FormatValue(&toast_vm, toast_vm.registers[0]), "3");
+        assert_eq!(Debugger::FormatValue(&toast_vm, (VarTypes::CharType, 'a' as u64)), "'a'");
+    }
+}
@@ -0,0 +1,152 @@
+#![allow(non_snake_case)]
+use std::collections::HashMap;
+
+use crate::bytecode::Instruction;
+use crate::codegen::{SystemFunctions, VarTypes};
+use crate::module::{Constant, FunctionEntry, Module};
+
+/// Formats a list of registers and Var Types (e.g [r1 FloatType, r2 ArrayType])
+fn FormatTypedRegs(fields: &[(u8, VarTypes)]) -> String {
+    let fields: Vec<String> = fields.iter().map(|(reg, varType)| format!("r{} {:?}", reg, varType)).collect();
+    return format!("[{}]", fields.join(", "));
+}
+
+/// Formats a constant of the given type. Numbers are printed as floats and chars are quoted
+fn FormatConstant(value: u64, varType: VarTypes) -> String {
+    match varType {
+        VarTypes::FloatType => format!("{:?}", f64::from_bits(value)),
+        VarTypes::CharType => format!("{:?}", (value as u8) as char),
+        _ => format!("{}", value)
+    }
+}
+
+/// Formats the operands of an instruction. constants is the constant pool and funcNames maps function ids to function names
+pub fn FormatOperands(instruction: &Instruction, constants: &[Constant], funcNames: &HashMap<u64, String>) -> String {
+    match instruction {
+        Instruction::LoadReg { src, dst } => format!("r{}, r{}", src, dst),
+        Instruction::LoadScalar { reg, varType, value } => format!("r{}, {:?}, {}", reg, varType, FormatConstant(*value, *varType)),
+        Instruction::Add { reg1, reg2 } | Instruction::Sub { reg1, reg2 } | Instruction::Mul { reg1, reg2 } | Instruction::Div { reg1, reg2 } => {
+            format!("r{}, r{}", reg1, reg2)
+        },
+        Instruction::NewVar { reg, varType } => format!("r{}, {:?}", reg, varType),
+        Instruction::LoadVar { reg, varType, varId } => format!("r{}, {:?}, var {}", reg, varType, varId),
+        Instruction::StartFunc { paramTypes, returnType } => {
+            let paramTypes: Vec<String> = paramTypes.iter().map(|x| format!("{:?}", x)).collect();
+            format!("({}) -> {:?}", paramTypes.join(", "), returnType)
+        },
+        Instruction::AddFuncParameter { varType } => format!("{:?}", varType),
+        Instruction::CallFunc { funcId } => match funcNames.get(funcId) {
+            Some(name) => format!("{} ; {}", funcId, name),
+            None => format!("{}", funcId)
+        },
+        Instruction::LoadArray { reg, elementType, dimensions, elements } => {
+            let elementsText = if *elementType == VarTypes::CharType {
+                format!("{:?}", elements.iter().map(|x| (*x as u8) as char).collect::<String>())
+            } else {
+                format!("[{}]", elements.iter().map(|x| FormatConstant(*x, *elementType)).collect::<Vec<String>>().join(", "))
+            };
+            format!("r{}, {:?}, {:?}, {}", reg, elementType, dimensions, elementsText)
+        },
+        Instruction::AccessArray { varId, dest, indexCount } => format!("var {}, r{}, {}", varId, dest, indexCount),
+        Instruction::AccessElementEnd { reg } => format!("r{}", reg),
+        Instruction::CopyVarToNewMemoryBlock { varType, varId } => format!("{:?}, var {}", varType, varId),
+        Instruction::Return { returnType, reg } => format!("{:?}, r{}", returnType, reg),
+        Instruction::LoadArrayDimensions { dimensions } => format!("{:?}", dimensions),
+        Instruction::MakeVariant { reg, varType, tag, fields } => format!("r{}, {:?}, tag {}, {}", reg, varType, tag, FormatTypedRegs(fields)),
+        Instruction::LoadElement { reg, varType, listReg, index } => format!("r{}, {:?}, r{}[{}]", reg, varType, listReg, index),
+        Instruction::TestTag { reg, tag } => format!("r{}, tag {}", reg, tag),
+        Instruction::TestLength { reg, length } => format!("r{}, {}", reg, length),
+        Instruction::TestEqual { reg1, varType, reg2 } => format!("r{}, {:?}, r{}", reg1, varType, reg2),
+        Instruction::Jump { target } | Instruction::JumpIfFalse { target } => format!("{}", target),
+        Instruction::DropVars { count } => format!("{}", count),
+        Instruction::LoadIndexed { reg, varType, listReg, indexReg } => format!("r{}, {:?}, r{}[r{}]", reg, varType, listReg, indexReg),
+        Instruction::Unwrap { reg, varType, optionalReg } => format!("r{}, {:?}, r{}", reg, varType, optionalReg),
+        Instruction::Spill { reg, slot } | Instruction::Reload { reg, slot } => format!("r{}, slot {}", reg, slot),
+        // Constants are printed by value, so the listing can be assembled again
+        Instruction::LoadConstant { reg, constId } => match constants.get(*constId as usize) {
+            Some(Constant::Number(value)) => format!("r{}, {:?}", reg, value),
+            Some(Constant::String(value)) => format!("r{}, {:?}", reg, value.iter().map(|x| *x as char).collect::<String>()),
+            None => format!("r{}, const {}", reg, constId)
+        },
+        Instruction::EndFunc | Instruction::EndParamLoad | Instruction::EndArray | Instruction::Print | Instruction::AccessElementBegin
+            | Instruction::LoadMultiDimensionalArrayElement | Instruction::EndMultiDimensionalArrayElement | Instruction::EndArrayDimensions => String::new()
+    }
+}
+
+/// Adds the listing of one block of code. Offsets are relative to the start of the block. function is the function table entry of the block, if it is a function body
+fn DisassembleCode(listing: &mut String, code: &[u8], constants: &[Constant], funcNames: &HashMap<u64, String>, function: Option<&FunctionEntry>) {
+    let mut depth = 0;
+    let mut pc = 0;
+    while pc < code.len() {
+        let (instruction, length) = Instruction::Decode(code, pc);
+        if matches!(instruction, Instruction::EndFunc | Instruction::EndMultiDimensionalArrayElement | Instruction::AccessElementEnd { .. }) {
+            depth -= 1;
+        }
+        let mut line = format!("{:>6}  {}{:?}", pc, "    ".repeat(depth), instruction.OpCode());
+        let operands = FormatOperands(&instruction, constants, funcNames);
+        if let (Instruction::StartFunc { .. }, Some(function)) = (&instruction, function) {
+            // The name goes before the parameter types, as the assembler expects
+            line += &format!(" {}{} ; function {}", function.name, operands, function.funcId);
+        } else if !operands.is_empty() {
+            line += &format!(" {}", operands);
+        }
+        listing.push_str(line.trim_end());
+        listing.push('\n');
+        if matches!(instruction, Instruction::StartFunc { .. } | Instruction::LoadMultiDimensionalArrayElement | Instruction::AccessElementBegin) {
+            depth += 1;
+        }
+        pc += length;
+    }
+}
+
+/// Turns a module into a listing with one instruction per line: offset, opcode name and operands.
+/// Each function in the function table is listed first, followed by a blank line, then the top level code.
+/// Function bodies, nested lists and array indexes are indented
+pub fn Disassemble(module: &Module) -> String {
+    let mut funcNames: HashMap<u64, String> = module.functions.iter().map(|x| (x.funcId, x.name.clone())).collect();
+    funcNames.insert(SystemFunctions::printFunction as u64, "print".to_string());
+    let mut listing = String::new();
+    for function in &module.functions {
+        let body = &module.functionCode[function.offset as usize..(function.offset + function.length) as usize];
+        DisassembleCode(&mut listing, body, &module.constants, &funcNames, Some(function));
+        listing.push('\n');
+    }
+    DisassembleCode(&mut listing, &module.code, &module.constants, &funcNames, None);
+    return listing;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disassembler::Disassemble;
+    use crate::testsupport::compileModule;
+
+    #[test]
+    fn disassembleBasicEquation(){
+        let listing = Disassemble(&compileModule("1 + 2"));
+        assert_eq!(listing, "     0  OpLoadConstant r0, 1.0\n    10  OpLoadConstant r1, 2.0\n    20  OpAdd r0, r1\n");
+    }
+
+    #[test]
+    fn disassembleFunctionCall(){
+        let listing = Disassemble(&compileModule("def foo(a: number):\na*100\nend\nfoo(32)\nprint(1)"));
+        // Drops the offset column
+        let lines: Vec<&str> = listing.lines().map(|x| x.get(8..).unwrap_or("")).collect();
+        assert_eq!(lines[0], "OpStartFunc foo(FloatType) -> NullType ; function 1");
+        assert_eq!(lines[1], "    OpLoadVar r0, FloatType, var 0");
+        assert_eq!(lines[4], "OpEndFunc");
+        // Top level code follows the functions, starting again from offset 0
+        assert_eq!(lines[5], "");
+        assert_eq!(lines[6], "OpLoadConstant r0, 32.0");
+        assert_eq!(lines[7], "OpCallFunc 1 ; foo");
+        assert!(listing.lines().nth(6).unwrap().starts_with("     0"));
+        assert!(lines.contains(&"OpCallFunc 0 ; print"));
+    }
+
+    #[test]
+    fn disassembleStringAndMatch(){
+        let listing = Disassemble(&compileModule("let s: string = \"hi\"\nmatch s with\n| \"hi\" -> 1\n| _ -> 2\nendmatch"));
+        assert!(listing.contains("OpLoadConstant r0, \"hi\""));
+        assert!(listing.contains("OpTestEqual r0, ArrayType, r1"));
+        assert!(listing.contains("OpJumpIfFalse "));
+    }
+}
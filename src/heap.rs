@@ -0,0 +1,212 @@
+#![allow(non_snake_case)]
+use crate::codegen::VarTypes;
+use crate::vmerror::VmErrorKind;
+
+/// A list, string or variant: (element type, elements, dimensions). The dimensions of a variant are the types of its fields
+pub type HeapObject = (VarTypes, Vec<u64>, Vec<u8>);
+
+/// Collections run no earlier than once this many objects are live
+const minCollectAt: usize = 1024;
+/// Collections run no earlier than once the live objects hold this many elements
+const minCollectElementsAt: usize = 1 << 16;
+
+/// Lists, strings and variants shared by every function call. Registers and variables hold the id of an object,
+/// so values are passed to and returned from functions by reference. Objects that can no longer be reached are freed by Collect
+#[derive(Debug, Clone)]
+pub struct Heap {
+    objects: Vec<Option<HeapObject>>,
+    /// Ids of freed objects, reused by Alloc
+    freeIds: Vec<usize>,
+    liveCount: usize,
+    /// Number of elements held by the live objects
+    elementCount: usize,
+    /// Number of live objects at which NeedsCollect asks for a collection
+    collectAt: usize,
+    /// Number of elements at which NeedsCollect asks for a collection
+    collectElementsAt: usize,
+    /// True if objects were allocated since the last collection
+    allocatedSinceCollect: bool,
+    /// Most objects that can be live at once. None means no limit
+    pub maxObjects: Option<usize>,
+    /// Most elements the live objects can hold together. None means no limit
+    pub maxElements: Option<usize>
+}
+
+/// Returns true if values of this type hold the id of a heap object
+pub fn IsReference(varType: VarTypes) -> bool {
+    return matches!(varType, VarTypes::ArrayType | VarTypes::ArrayRef | VarTypes::VariantType);
+}
+
+/// Ids of the objects an object refers to: the elements of a list of lists and the list fields of a variant
+pub fn Children(object: &HeapObject) -> Vec<u64> {
+    match object.0 {
+        VarTypes::ArrayRef => object.1.clone(),
+        VarTypes::VariantType => object.2.iter().enumerate()
+            .filter(|(_, fieldType)| num::FromPrimitive::from_u8(**fieldType).is_some_and(IsReference))
+            .filter_map(|(i, _)| object.1.get(i + 1).copied())
+            .collect(),
+        _ => Vec::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap {
+            objects: Vec::new(),
+            freeIds: Vec::new(),
+            liveCount: 0,
+            elementCount: 0,
+            collectAt: minCollectAt,
+            collectElementsAt: minCollectElementsAt,
+            allocatedSinceCollect: false,
+            maxObjects: None,
+            maxElements: None
+        }
+    }
+
+    /// Builds a heap holding the objects at the same ids, e.g. to restore a snapshot. None marks a freed id
+    pub fn FromObjects(objects: Vec<Option<HeapObject>>) -> Self {
+        let liveCount = objects.iter().flatten().count();
+        let elementCount = objects.iter().flatten().map(|x| x.1.len()).sum();
+        Heap {
+            freeIds: (0..objects.len()).rev().filter(|id| objects[*id].is_none()).collect(),
+            objects,
+            liveCount,
+            elementCount,
+            collectAt: std::cmp::max(minCollectAt, liveCount * 2),
+            collectElementsAt: std::cmp::max(minCollectElementsAt, elementCount * 2),
+            allocatedSinceCollect: false,
+            maxObjects: None,
+            maxElements: None
+        }
+    }
+
+    /// Every object by id, None for freed ids
+    pub fn Objects(&self) -> &[Option<HeapObject>] {
+        return &self.objects;
+    }
+
+    /// Fails if one more object holding this many elements would take the heap over maxObjects or maxElements
+    pub fn CheckLimits(&self, elementCount: usize) -> Result<(), VmErrorKind> {
+        if let Some(maxObjects) = self.maxObjects.filter(|max| self.liveCount >= *max) {
+            return Err(VmErrorKind::HeapObjectLimit(maxObjects));
+        }
+        if let Some(maxElements) = self.maxElements.filter(|max| self.elementCount + elementCount > *max) {
+            return Err(VmErrorKind::HeapSizeLimit(maxElements));
+        }
+        return Ok(());
+    }
+
+    /// Stores an object and returns its id. Fails if the object would take the heap over maxObjects or maxElements.
+    /// Objects that can no longer be reached count until they are collected, so the VM allocates through VMCore::Alloc,
+    /// which collects before giving up
+    pub fn Alloc(&mut self, object: HeapObject) -> Result<u64, VmErrorKind> {
+        self.CheckLimits(object.1.len())?;
+        self.liveCount += 1;
+        self.elementCount += object.1.len();
+        self.allocatedSinceCollect = true;
+        if let Some(id) = self.freeIds.pop() {
+            self.objects[id] = Some(object);
+            return Ok(id as u64);
+        }
+        self.objects.push(Some(object));
+        return Ok((self.objects.len() - 1) as u64);
+    }
+
+    /// Returns the object with the id, or None if there is no such object or it has been freed
+    pub fn Get(&self, id: u64) -> Option<&HeapObject> {
+        return self.objects.get(usize::try_from(id).ok()?).and_then(|x| x.as_ref());
+    }
+
+    /// Number of live objects
+    pub fn Len(&self) -> usize {
+        return self.liveCount;
+    }
+
+    /// Number of elements held by the live objects
+    pub fn ElementCount(&self) -> usize {
+        return self.elementCount;
+    }
+
+    /// Returns true once enough objects were allocated since the last collection. Past half of a limit every allocation
+    /// asks for a collection, so objects that can no longer be reached do not use up the limit
+    pub fn NeedsCollect(&self) -> bool {
+        let collectAt = std::cmp::min(self.collectAt, self.maxObjects.map_or(usize::MAX, |max| max / 2));
+        let collectElementsAt = std::cmp::min(self.collectElementsAt, self.maxElements.map_or(usize::MAX, |max| max / 2));
+        return self.allocatedSinceCollect && (self.liveCount >= collectAt || self.elementCount >= collectElementsAt);
+    }
+
+    /// Frees every object that can not be reached from the root values. Returns the number of freed objects
+    pub fn Collect(&mut self, roots: impl IntoIterator<Item = (VarTypes, u64)>) -> usize {
+        let mut marked = vec![false; self.objects.len()];
+        let mut pending: Vec<u64> = roots.into_iter().filter(|x| IsReference(x.0)).map(|x| x.1).collect();
+        while let Some(id) = pending.pop() {
+            let Some(object) = self.Get(id) else { continue };
+            if marked[id as usize] {
+                continue;
+            }
+            marked[id as usize] = true;
+            pending.extend(Children(object));
+        }
+        let mut freed = 0;
+        for (id, object) in self.objects.iter_mut().enumerate() {
+            if let Some((_, elements, _)) = object.as_ref().filter(|_| !marked[id]) {
+                self.elementCount -= elements.len();
+                *object = None;
+                self.freeIds.push(id);
+                freed += 1;
+            }
+        }
+        self.liveCount -= freed;
+        self.allocatedSinceCollect = false;
+        self.collectAt = std::cmp::max(minCollectAt, self.liveCount * 2);
+        self.collectElementsAt = std::cmp::max(minCollectElementsAt, self.elementCount * 2);
+        return freed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codegen::VarTypes;
+    use crate::heap::Heap;
+    use crate::vmerror::VmErrorKind;
+
+    #[test]
+    fn collectUnreachableObjects(){
+        let mut heap = Heap::new();
+        let kept = heap.Alloc((VarTypes::FloatType, [1].to_vec(), [1].to_vec())).unwrap();
+        let dropped = heap.Alloc((VarTypes::CharType, [104, 105].to_vec(), [2].to_vec())).unwrap();
+        assert_eq!(heap.Collect([(VarTypes::ArrayType, kept), (VarTypes::FloatType, dropped)]), 1);
+        assert_eq!((heap.Len(), heap.Get(kept).is_some(), heap.Get(dropped)), (1, true, None));
+        // Freed ids are reused
+        assert_eq!(heap.Alloc((VarTypes::FloatType, Vec::new(), [0].to_vec())), Ok(dropped));
+    }
+
+    #[test]
+    fn collectKeepsObjectsReachableThroughOthers(){
+        let mut heap = Heap::new();
+        let inner = heap.Alloc((VarTypes::FloatType, [1, 2].to_vec(), [2].to_vec())).unwrap();
+        let field = heap.Alloc((VarTypes::CharType, [97].to_vec(), [1].to_vec())).unwrap();
+        let list = heap.Alloc((VarTypes::ArrayRef, [inner].to_vec(), [1].to_vec())).unwrap();
+        let variant = heap.Alloc((VarTypes::VariantType, [0, field, list].to_vec(), [VarTypes::ArrayType as u8, VarTypes::ArrayType as u8].to_vec())).unwrap();
+        assert_eq!(heap.Collect([(VarTypes::VariantType, variant)]), 0);
+        assert_eq!(heap.Collect([]), 4);
+        assert_eq!(heap.Len(), 0);
+    }
+
+    #[test]
+    fn allocWithinLimits(){
+        let mut heap = Heap::new();
+        heap.maxObjects = Some(2);
+        heap.maxElements = Some(3);
+        let first = heap.Alloc((VarTypes::FloatType, [1, 2].to_vec(), [2].to_vec())).unwrap();
+        assert_eq!(heap.Alloc((VarTypes::FloatType, [1, 2].to_vec(), [2].to_vec())), Err(VmErrorKind::HeapSizeLimit(3)));
+        heap.Alloc((VarTypes::CharType, [97].to_vec(), [1].to_vec())).unwrap();
+        assert_eq!(heap.Alloc((VarTypes::CharType, Vec::new(), [0].to_vec())), Err(VmErrorKind::HeapObjectLimit(2)));
+        assert!(heap.NeedsCollect());
+        // Collected objects no longer count
+        heap.Collect([(VarTypes::ArrayType, first)]);
+        assert_eq!((heap.Len(), heap.ElementCount(), heap.NeedsCollect()), (1, 2, false));
+        assert!(heap.Alloc((VarTypes::CharType, [97].to_vec(), [1].to_vec())).is_ok());
+    }
+}
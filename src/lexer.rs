@@ -1,7 +1,8 @@
 use logos::Logos;
+use num_derive::FromPrimitive;
 
 
-#[derive(PartialEq, Clone, Copy, Debug, Logos)]
+#[derive(PartialEq, Clone, Copy, Debug, Logos, FromPrimitive)]
 pub enum Token {
     ///Token for 'def' keyword
     #[token("def")]
@@ -23,6 +24,18 @@ pub enum Token {
     EndIf,    
     #[token("return")]
     Return,    
+    ///Token for 'type' keyword
+    #[token("type")]
+    TypeDeclare,
+    ///Token for 'match' keyword
+    #[token("match")]
+    Match,
+    ///Token for 'with' keyword
+    #[token("with")]
+    With,
+    ///Token for 'endmatch' keyword
+    #[token("endmatch")]
+    EndMatch,
     #[token("(")]
     OpeningParenthesis,
     #[token(")")]
@@ -80,6 +93,19 @@ pub enum Token {
     OpenSquareBracket,
     #[token("]")]
     CloseSquareBracket,
+    #[token("|")]
+    Pipe,
+    ///Token for the '?' after optional type names
+    #[token("?")]
+    QuestionMark,
+    ///Token for the '??' default value operator
+    #[token("??")]
+    DefaultOp,
+    ///Token for 'none' keyword
+    #[token("none")]
+    NoneValue,
+    #[token("_")]
+    Underscore,
     // #[regex(r"[a-zA-Z]+\d*\[")]
     // ArrayElementAcces
 }
@@ -261,6 +287,33 @@ mod tests {
     }
 
 
+    #[test]
+    fn lex_match(){
+        {
+            let lex = Token::lexer("match s with | _ -> 0 endmatch");
+            let tokens: Vec<Token> = lex.filter(|x| *x != Token::WhiteSpace).collect();
+            assert_eq!(tokens, [Token::Match, Token::Ident, Token::With, Token::Pipe, Token::Underscore, Token::PointTo, Token::Number, Token::EndMatch].to_vec());
+        };
+    }
+
+    #[test]
+    fn lex_optional(){
+        {
+            let lex = Token::lexer("number? none ??");
+            let tokens: Vec<Token> = lex.filter(|x| *x != Token::WhiteSpace).collect();
+            assert_eq!(tokens, [Token::Ident, Token::QuestionMark, Token::NoneValue, Token::DefaultOp].to_vec());
+        };
+    }
+
+    #[test]
+    fn lex_type_declare(){
+        {
+            let correct_token = Token::TypeDeclare;
+            let mut lex = Token::lexer("type");
+            assert_eq!(lex.next(), Some(correct_token));
+        };
+    }
+
     #[test]
     fn lex_string(){
         {
@@ -0,0 +1,18 @@
+#![allow(non_snake_case, non_upper_case_globals)]
+pub mod parser;
+pub mod lexer;
+pub mod codegen;
+pub mod bytecode;
+pub mod disassembler;
+pub mod assembler;
+pub mod module;
+pub mod vmerror;
+pub mod verifier;
+pub mod heap;
+pub mod debugger;
+pub mod output;
+pub mod profiler;
+pub mod snapshot;
+pub mod optimizer;
+#[cfg(test)]
+mod testsupport;
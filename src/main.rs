@@ -3,9 +3,12 @@ use std::{io::{self, Read, Write}, env, fs};
 
 // use crate::codegen::{ToastVM, ExprConverter};
 
-mod parser;
-mod lexer;
-mod codegen;
+use ToastLang::{parser, codegen, disassembler, module, optimizer};
+use ToastLang::vmerror::VmErrorKind;
+use ToastLang::debugger::{Breakpoint, Debugger, Pause, PauseReason, StepMode};
+use ToastLang::output::Sink;
+use ToastLang::profiler::Profiler;
+use ToastLang::snapshot;
 
 // macro used to print & flush without printing a new line
 macro_rules! print_flush {
@@ -16,35 +19,262 @@ macro_rules! print_flush {
     };
 }
 
+// Parses and compiles a source file without running it
+fn compile_file(path: &str, optimize: bool) -> codegen::ASTConverter {
+    let contents = fs::read_to_string(path).expect("Expected file here");
+    let mut ast_converter = codegen::ASTConverter::new();
+    ast_converter.optimize = optimize;
+    let mut parser = parser::Parser::new(&contents);
+    if let Some(parsed_list) = parser.parse() {
+        for (ast, line) in parsed_list.into_iter().zip(parser.statementLines.clone()) {
+            ast_converter.ConvertStatement(ast, line);
+        }
+    }
+    return ast_converter;
+}
+
+// Makes the module of a whole compiled file. When optimizing, functions the top level code never calls are left out
+fn build_module(ast_converter: &codegen::ASTConverter, source_name: Option<&str>) -> module::Module {
+    let mut compiled = module::Module::FromConverter(ast_converter, source_name);
+    if ast_converter.optimize {
+        optimizer::DropDeadFunctions(&mut compiled);
+    }
+    return compiled;
+}
+
+// Writes what the profiler measured: a report to standard error and, if a path is given, folded stacks for flamegraph tools
+fn write_profile(toast_vm: &codegen::VMCore, folded_path: &Option<String>) {
+    let Some(profiler) = &toast_vm.profiler else { return };
+    eprint!("{}", profiler.Report());
+    if let Some(path) = folded_path {
+        fs::write(path, profiler.FoldedStacks()).expect("Could not write folded stacks");
+    }
+}
+
+// Runs :save or :load, which save the REPL session to a file and restore it. Returns false for other commands
+fn session_command(command: &str, toast_vm: &mut codegen::VMCore, ast_converter: &mut codegen::ASTConverter) -> bool {
+    let mut parts = command.split_whitespace();
+    match (parts.next().unwrap_or(""), parts.next()) {
+        (":save", Some(path)) => {
+            match snapshot::SaveSession(toast_vm, ast_converter).and_then(|bytes| fs::write(path, bytes).map_err(|err| err.to_string())) {
+                Ok(()) => println!("Saved session to {}", path),
+                Err(err) => eprintln!("Could not save session: {}", err)
+            }
+        },
+        (":load", Some(path)) => {
+            match fs::read(path).map_err(|err| err.to_string()).and_then(|bytes| snapshot::LoadSession(&bytes, toast_vm, ast_converter)) {
+                Ok(()) => println!("Loaded session from {}", path),
+                Err(err) => eprintln!("Could not load session: {}", err)
+            }
+        },
+        _ => return false
+    }
+    return true;
+}
+
+// Runs a debugger command. Returns how to go on for commands that resume the program
+fn debug_command(command: &str, vm: &codegen::VMCore, debugger: &mut Debugger, pause: &Pause) -> Option<StepMode> {
+    let mut parts = command.split_whitespace();
+    match (parts.next().unwrap_or(""), parts.next()) {
+        (":break", Some(target)) => {
+            // A number is a line, anything else is a function name
+            let breakpoint = match target.parse::<usize>() {
+                Ok(line) => Breakpoint::Line(line),
+                Err(_) => Breakpoint::Function(target.to_string())
+            };
+            println!("Breakpoint {}: {:?}", debugger.breakpoints.len(), breakpoint);
+            debugger.breakpoints.push(breakpoint);
+        },
+        (":clear", None) => debugger.breakpoints.clear(),
+        (":step", None) => return Some(StepMode::Into),
+        (":next", None) => return Some(StepMode::Over),
+        (":out", None) => return Some(StepMode::Out),
+        (":continue", None) => return Some(StepMode::Continue),
+        (":locals", None) => {
+            for (var_id, name, value) in debugger.Locals(vm, pause) {
+                println!("{} = {}", name.unwrap_or(format!("var {}", var_id)), Debugger::FormatValue(vm, value));
+            }
+        },
+        (":regs", None) => {
+            for (reg, value) in Debugger::Registers(vm) {
+                println!("r{} = {}", reg, Debugger::FormatValue(vm, value));
+            }
+        },
+        (":stack", None) => {
+            for frame in debugger.CallStack(vm, pause) {
+                println!("    {}", frame);
+            }
+        },
+        _ => println!("Commands: :break <line or function>, :clear, :step, :next, :out, :continue, :locals, :regs, :stack, :save <file>, :load <file>")
+    }
+    return None;
+}
+
+// Reads debugger commands while the program is paused, until one resumes it
+fn debug_prompt(vm: &codegen::VMCore, debugger: &mut Debugger, pause: &Pause) {
+    if let PauseReason::Breakpoint(index) = pause.reason {
+        print!("Breakpoint {}, ", index);
+    }
+    match pause.line {
+        Some(line) => println!("paused at line {}", line),
+        None => println!("paused at pc {}", pause.pc)
+    }
+    loop {
+        print_flush!("(debug) ");
+        let mut command = String::new();
+        if io::stdin().read_line(&mut command).unwrap_or(0) == 0 {
+            debugger.mode = StepMode::Continue;
+            return;
+        }
+        if let Some(mode) = debug_command(command.trim(), vm, debugger, pause) {
+            debugger.mode = mode;
+            return;
+        }
+    }
+}
+
 fn main() {
     env::set_var("RUST_BACKTRACE", "1");
     let mut buffer = "".to_string();
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
     let mut toast_vm = codegen::VMCore::new();
+    // --trace writes what the VM and the REPL are doing to standard error, so standard output only has the program's output
+    let trace = args.iter().any(|x| x == "--trace");
+    args.retain(|x| x != "--trace");
+    if trace {
+        toast_vm.trace = Some(Sink::Stderr());
+    }
+    // --profile prints a profile of the program when it finishes, --profile-folded=FILE also writes folded stacks to FILE
+    let folded_path = args.iter().find_map(|x| x.strip_prefix("--profile-folded=")).map(|x| x.to_string());
+    if args.iter().any(|x| x == "--profile") || folded_path.is_some() {
+        toast_vm.profiler = Some(Profiler::new());
+    }
+    args.retain(|x| !x.starts_with("--profile"));
+    // -O folds constant expressions and runs the peephole pass over the compiled code
+    let optimize = args.iter().any(|x| x == "-O");
+    args.retain(|x| x != "-O");
     let mut ast_converter = codegen::ASTConverter::new();
+    ast_converter.optimize = optimize;
     // let mut cpu: ToastVM = ToastVM::new();
     // let mut converter: ExprConverter = ExprConverter::new();
-    println!("{:?}", args);
+    if args.len() == 3 && args[1] == "disasm" {
+        // Compiles the file without running it and prints the bytecode
+        let ast_converter = compile_file(&args[2], optimize);
+        print!("{}", disassembler::Disassemble(&build_module(&ast_converter, None)));
+        return;
+    }
+    if (args.len() == 3 || (args.len() == 5 && args[3] == "-o")) && args[1] == "compile" {
+        // Writes the compiled module next to the source file unless -o is given
+        let output = if args.len() == 5 { args[4].clone() } else { std::path::Path::new(&args[2]).with_extension("toastc").to_string_lossy().to_string() };
+        let ast_converter = compile_file(&args[2], optimize);
+        let compiled = build_module(&ast_converter, Some(&args[2]));
+        fs::write(&output, compiled.Encode()).expect("Could not write compiled module");
+        return;
+    }
+    if args.len() == 3 && args[1] == "debug" {
+        // Runs a source file in the debugger, paused before its first statement
+        let ast_converter = compile_file(&args[2], optimize);
+        let mut debugger = Debugger::new(ast_converter.sourceMap.clone(), Box::new(debug_prompt));
+        debugger.mode = StepMode::Into;
+        toast_vm.debugger = Some(debugger);
+        if let Err(err) = toast_vm.processProgram(&build_module(&ast_converter, None)) {
+            eprintln!("Runtime error: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.len() == 3 && args[1] == "run" {
+        // Runs a compiled module, or compiles and runs a source file
+        let contents = fs::read(&args[2]).expect("Expected file here");
+        let compiled = if contents.starts_with(&module::moduleMagic) {
+            match module::Module::Decode(&contents) {
+                Ok(compiled) => compiled,
+                Err(err) => {
+                    eprintln!("Can not run {}: {}", args[2], err);
+                    std::process::exit(1);
+                }
+            }
+        } else {
+            build_module(&compile_file(&args[2], optimize), None)
+        };
+        // processProgram verifies the module before running any of it
+        let result = toast_vm.processProgram(&compiled);
+        if let Err(VmErrorKind::InvalidProgram(err)) = result.as_ref().map_err(|x| &x.kind) {
+            eprintln!("Can not run {}: {} at pc {}", args[2], err, err.pc);
+            std::process::exit(1);
+        }
+        write_profile(&toast_vm, &folded_path);
+        if let Err(err) = result {
+            eprintln!("Runtime error: {}", err);
+            std::process::exit(1);
+        }
+        if trace {
+            eprintln!("ToastVM: {:?}", toast_vm);
+        }
+        return;
+    }
+    if trace {
+        eprintln!("{:?}", args);
+    }
     match args.len() {
         1 => {
-
+            // Lines are counted across the whole session, so breakpoints can name lines of earlier input
+            let mut line_offset = 0;
             loop{
                 print_flush!("> ");
             //io::stdout().write_all(b"> ");
             io::stdin().read_to_string(&mut buffer);
             if buffer.trim() == "exit" {
+                write_profile(&toast_vm, &folded_path);
                 break;
             }
-            let mut parser = parser::Parser::new(&buffer);
+            // Lines starting with ':' are debugger commands. They are blanked out so the source keeps its line numbers
+            let pause = Pause { reason: PauseReason::Step, function: None, pc: toast_vm.pc, line: None };
+            // :save runs after the code of the input, so it is part of the saved session
+            for command in buffer.lines().filter(|x| x.trim_start().starts_with(':') && !x.trim_start().starts_with(":save")) {
+                if session_command(command.trim(), &mut toast_vm, &mut ast_converter) {
+                    // A loaded session goes on after the last line it compiled
+                    if let Some(debugger) = toast_vm.debugger.as_mut() {
+                        debugger.sourceMap = ast_converter.sourceMap.clone();
+                    }
+                    line_offset = ast_converter.sourceMap.lines.values().flatten().map(|x| x.1).max().unwrap_or(0);
+                    continue;
+                }
+                // The debugger is only attached once a debugger command is run, so code runs without checking it until then
+                let mut debugger = toast_vm.debugger.take().unwrap_or_else(|| Debugger::new(ast_converter.sourceMap.clone(), Box::new(debug_prompt)));
+                if let Some(mode) = debug_command(command.trim(), &toast_vm, &mut debugger, &pause) {
+                    debugger.mode = mode;
+                }
+                toast_vm.debugger = Some(debugger);
+            }
+            let source: Vec<&str> = buffer.lines().map(|x| if x.trim_start().starts_with(':') { "" } else { x }).collect();
+            let source = source.join("\n");
+            let mut parser = parser::Parser::new(&source);
             let ast_nodes = parser.parse();
 
-            println!("Parser: {:?}", &ast_nodes);
-            for ast in &ast_nodes.unwrap() {
-                ast_converter.ConvertExprToByteCode(ast.to_owned());
-                toast_vm.processProgram(&ast_converter.program);
-                println!("ToastVM: {:?}", toast_vm);
+            if trace {
+                eprintln!("Parser: {:?}", &ast_nodes);
+            }
+            for (ast, line) in ast_nodes.unwrap().into_iter().zip(parser.statementLines.clone()) {
+                ast_converter.ConvertStatement(ast, line_offset + line);
+                if let Some(debugger) = toast_vm.debugger.as_mut() {
+                    debugger.sourceMap = ast_converter.sourceMap.clone();
+                }
+                if let Err(err) = toast_vm.processProgram(&module::Module::FromConverter(&ast_converter, None)) {
+                    // Skips the rest of the failed code so the session can go on
+                    eprintln!("Runtime error: {}", err);
+                    toast_vm.Unwind();
+                    toast_vm.pc = ast_converter.program.len();
+                }
+                if trace {
+                    eprintln!("ToastVM: {:?}", toast_vm);
+                }
             }
 
+            for command in buffer.lines().filter(|x| x.trim_start().starts_with(":save")) {
+                session_command(command.trim(), &mut toast_vm, &mut ast_converter);
+            }
+            line_offset += buffer.lines().count();
             buffer = "".to_string();
             }
         },
@@ -55,10 +285,16 @@ fn main() {
             if !test.is_none() {
                 let parsed_list = test.unwrap();
                 // println!("-> Parsed: {:?}", parsed_list);
-                for ast in &parsed_list {
-                    ast_converter.ConvertExprToByteCode(ast.to_owned());
-                    toast_vm.processProgram(&ast_converter.program);
-                    println!("ToastVM: {:?}", toast_vm);
+                for (ast, line) in parsed_list.into_iter().zip(parser.statementLines.clone()) {
+                    ast_converter.ConvertStatement(ast, line);
+                    if let Err(err) = toast_vm.processProgram(&module::Module::FromConverter(&ast_converter, None)) {
+                        write_profile(&toast_vm, &folded_path);
+                        eprintln!("Runtime error: {}", err);
+                        std::process::exit(1);
+                    }
+                    if trace {
+                        eprintln!("ToastVM: {:?}", toast_vm);
+                    }
                 }
             }
         },
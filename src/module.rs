@@ -0,0 +1,325 @@
+#![allow(non_snake_case)]
+use std::hash::{Hash, Hasher};
+
+use crate::codegen::ASTConverter;
+
+/// Bytes every .toastc file starts with
+pub const moduleMagic: [u8; 4] = *b"TSTC";
+/// Version of the .toastc format. Files with any other version are rejected
+pub const moduleVersion: u16 = 3;
+
+/// A value in the constant pool of a module
+#[derive(Debug, Clone)]
+pub enum Constant {
+    Number(f64),
+    String(Vec<u8>)
+}
+
+// Numbers are compared by their bits, so each number (including -0.0 and NaN) gets exactly one entry in the pool
+impl PartialEq for Constant {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Constant::Number(a), Constant::Number(b)) => a.to_bits() == b.to_bits(),
+            (Constant::String(a), Constant::String(b)) => a == b,
+            _ => false
+        }
+    }
+}
+
+impl Eq for Constant {}
+
+impl Hash for Constant {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Constant::Number(value) => value.to_bits().hash(state),
+            Constant::String(value) => value.hash(state)
+        }
+    }
+}
+
+/// A function in the function table of a module
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionEntry {
+    pub name: String,
+    pub funcId: u64,
+    /// Position of the function's OpStartFunc in the function code
+    pub offset: u64,
+    /// Number of bytes from OpStartFunc up to and including OpEndFunc
+    pub length: u64
+}
+
+/// Information that is only needed to report on a module, not to run it
+#[derive(Debug, Clone, PartialEq)]
+pub struct DebugInfo {
+    /// Path of the source file the module was compiled from
+    pub sourceName: String
+}
+
+/// A compiled program, as stored in a .toastc file
+///
+/// Layout (numbers are stored most significant byte first):
+/// [magic: 4 bytes][version: 2 bytes]
+/// [number of constants: 8 bytes]([tag: 1 byte][constant])*. Numbers are 8 bytes, strings are [length: 8 bytes][bytes]
+/// [number of functions: 8 bytes]([name length: 8 bytes][name][function id: 8 bytes][offset: 8 bytes][length: 8 bytes])*
+/// [function code length: 8 bytes][function code]
+/// [code length: 8 bytes][code]
+/// [has debug info: 1 byte]([source name length: 8 bytes][source name])?
+#[derive(Debug, Clone, PartialEq)]
+pub struct Module {
+    pub constants: Vec<Constant>,
+    pub functions: Vec<FunctionEntry>,
+    /// Bodies of the functions in the function table. Jump targets in a body are relative to its OpStartFunc
+    pub functionCode: Vec<u8>,
+    /// Top level code, run from the start
+    pub code: Vec<u8>,
+    pub debugInfo: Option<DebugInfo>
+}
+
+/// Reads the parts of a module or snapshot, failing if the bytes end early
+pub(crate) struct ByteReader<'a> {
+    bytes: &'a [u8],
+    pub(crate) pos: usize,
+    /// What is being read, used in error messages (e.g "Module")
+    what: &'static str
+}
+
+impl<'a> ByteReader<'a> {
+    pub(crate) fn new(bytes: &'a [u8], what: &'static str) -> Self {
+        return ByteReader { bytes, pos: 0, what };
+    }
+
+    pub(crate) fn Bytes(&mut self, length: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() - self.pos < length {
+            return Err(format!("{} ends early at byte {}", self.what, self.bytes.len()));
+        }
+        self.pos += length;
+        return Ok(&self.bytes[self.pos - length..self.pos]);
+    }
+
+    pub(crate) fn Byte(&mut self) -> Result<u8, String> {
+        return Ok(self.Bytes(1)?[0]);
+    }
+
+    pub(crate) fn Val64(&mut self) -> Result<u64, String> {
+        return Ok(u64::from_be_bytes(self.Bytes(8)?.try_into().unwrap()));
+    }
+
+    /// Reads a length followed by that many bytes
+    pub(crate) fn Blob(&mut self) -> Result<&'a [u8], String> {
+        let length = self.Val64()?;
+        return self.Bytes(usize::try_from(length).map_err(|_| format!("Length {} is too large", length))?);
+    }
+
+    pub(crate) fn Text(&mut self) -> Result<String, String> {
+        return String::from_utf8(self.Blob()?.to_vec()).map_err(|_| "Name is not valid UTF-8".to_string());
+    }
+
+    /// Reads the magic and version every file starts with
+    pub(crate) fn Header(&mut self, magic: [u8; 4], version: u16) -> Result<(), String> {
+        if self.Bytes(4).ok() != Some(&magic[..]) {
+            return Err(format!("Not a {}", self.what));
+        }
+        let fileVersion = u16::from_be_bytes(self.Bytes(2)?.try_into().unwrap());
+        if fileVersion != version {
+            return Err(format!("{} has format version {}, but this version of ToastLang reads version {}", self.what, fileVersion, version));
+        }
+        return Ok(());
+    }
+
+    /// Fails if there are bytes left
+    pub(crate) fn End(&self) -> Result<(), String> {
+        if self.pos != self.bytes.len() {
+            return Err(format!("Unexpected bytes after the end of the {} at byte {}", self.what.to_lowercase(), self.pos));
+        }
+        return Ok(());
+    }
+
+    /// Reads a number of items followed by each item
+    pub(crate) fn List<T>(&mut self, mut readItem: impl FnMut(&mut Self) -> Result<T, String>) -> Result<Vec<T>, String> {
+        let mut items = Vec::<T>::new();
+        for _ in 0..self.Val64()? {
+            items.push(readItem(self)?);
+        }
+        return Ok(items);
+    }
+
+    /// Reads a flag followed by the value if the flag is 1
+    pub(crate) fn Optional<T>(&mut self, readValue: impl FnOnce(&mut Self) -> Result<T, String>) -> Result<Option<T>, String> {
+        match self.Byte()? {
+            0 => return Ok(None),
+            1 => return Ok(Some(readValue(self)?)),
+            flag => return Err(format!("Unknown flag: {}", flag))
+        }
+    }
+
+    pub(crate) fn Constants(&mut self) -> Result<Vec<Constant>, String> {
+        return self.List(|reader| match reader.Byte()? {
+            0 => Ok(Constant::Number(f64::from_bits(reader.Val64()?))),
+            1 => Ok(Constant::String(reader.Blob()?.to_vec())),
+            tag => Err(format!("Unknown constant tag: {}", tag))
+        });
+    }
+
+    pub(crate) fn Functions(&mut self) -> Result<Vec<FunctionEntry>, String> {
+        return self.List(|reader| Ok(FunctionEntry { name: reader.Text()?, funcId: reader.Val64()?, offset: reader.Val64()?, length: reader.Val64()? }));
+    }
+}
+
+/// Writes the parts of a module or snapshot in the layout ByteReader reads
+pub(crate) struct ByteWriter {
+    pub(crate) bytes: Vec<u8>
+}
+
+impl ByteWriter {
+    /// Starts a file with its magic and version
+    pub(crate) fn new(magic: [u8; 4], version: u16) -> Self {
+        let mut writer = ByteWriter { bytes: magic.to_vec() };
+        writer.Bytes(&version.to_be_bytes());
+        return writer;
+    }
+
+    pub(crate) fn Bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub(crate) fn Byte(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub(crate) fn Val64(&mut self, value: u64) {
+        self.Bytes(&value.to_be_bytes());
+    }
+
+    /// Writes the length followed by the bytes
+    pub(crate) fn Blob(&mut self, blob: &[u8]) {
+        self.Val64(blob.len() as u64);
+        self.Bytes(blob);
+    }
+
+    pub(crate) fn Text(&mut self, text: &str) {
+        self.Blob(text.as_bytes());
+    }
+
+    /// Writes the number of items followed by each item
+    pub(crate) fn List<T>(&mut self, items: &[T], mut writeItem: impl FnMut(&mut Self, &T)) {
+        self.Val64(items.len() as u64);
+        for item in items {
+            writeItem(self, item);
+        }
+    }
+
+    /// Writes 1 followed by the value, or 0 for None
+    pub(crate) fn Optional<T>(&mut self, value: Option<T>, writeValue: impl FnOnce(&mut Self, T)) {
+        match value {
+            Some(value) => {
+                self.Byte(1);
+                writeValue(self, value);
+            },
+            None => self.Byte(0)
+        }
+    }
+
+    pub(crate) fn Constants(&mut self, constants: &[Constant]) {
+        self.List(constants, |writer, constant| match constant {
+            Constant::Number(value) => {
+                writer.Byte(0);
+                writer.Val64(value.to_bits());
+            },
+            Constant::String(value) => {
+                writer.Byte(1);
+                writer.Blob(value);
+            }
+        });
+    }
+
+    pub(crate) fn Functions(&mut self, functions: &[FunctionEntry]) {
+        self.List(functions, |writer, function| {
+            writer.Text(&function.name);
+            writer.Val64(function.funcId);
+            writer.Val64(function.offset);
+            writer.Val64(function.length);
+        });
+    }
+}
+
+impl Module {
+    /// Builds a module from the program, constant pool and function table of an ASTConverter
+    pub fn FromConverter(converter: &ASTConverter, sourceName: Option<&str>) -> Module {
+        return Module {
+            constants: converter.constants.clone(),
+            functions: converter.functionTable.clone(),
+            functionCode: converter.functionCode.clone(),
+            code: converter.program.clone(),
+            debugInfo: sourceName.map(|x| DebugInfo { sourceName: x.to_string() })
+        };
+    }
+
+    pub fn Encode(&self) -> Vec<u8> {
+        let mut writer = ByteWriter::new(moduleMagic, moduleVersion);
+        writer.Constants(&self.constants);
+        writer.Functions(&self.functions);
+        writer.Blob(&self.functionCode);
+        writer.Blob(&self.code);
+        writer.Optional(self.debugInfo.as_ref(), |writer, debugInfo| writer.Text(&debugInfo.sourceName));
+        return writer.bytes;
+    }
+
+    /// Reads a module. Fails if the bytes are not a .toastc file, were written by another version of the format, or are cut short
+    pub fn Decode(bytes: &[u8]) -> Result<Module, String> {
+        let mut reader = ByteReader::new(bytes, "Module");
+        // Modules of other versions are not converted, they are compiled again
+        reader.Header(moduleMagic, moduleVersion).map_err(|err| match bytes.starts_with(&moduleMagic) {
+            false => "Not a compiled ToastLang module".to_string(),
+            true if bytes.len() >= moduleMagic.len() + 2 => format!("{}. Recompile it from source", err),
+            true => err
+        })?;
+        let constants = reader.Constants()?;
+        let functions = reader.Functions()?;
+        let functionCode = reader.Blob()?.to_vec();
+        for function in &functions {
+            if function.offset.checked_add(function.length).map_or(true, |end| end > functionCode.len() as u64) {
+                return Err(format!("Function {} is outside of the function code", function.name));
+            }
+        }
+        let code = reader.Blob()?.to_vec();
+        let debugInfo = reader.Optional(|reader| Ok(DebugInfo { sourceName: reader.Text()? }))?;
+        reader.End()?;
+        return Ok(Module { constants, functions, functionCode, code, debugInfo });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codegen::regResult;
+    use crate::module::{Constant, FunctionEntry, Module, moduleVersion};
+    use crate::testsupport::{compile, compileModule, runModule};
+
+    #[test]
+    fn moduleRoundTrip(){
+        let module = Module::FromConverter(&compile("def foo(a: number) -> number:\na*100\nend\nfoo(32)\nprint(\"hi\")", false), Some("test.toast"));
+        assert_eq!(Module::Decode(&module.Encode()), Ok(module.clone()));
+        assert_eq!(module.constants, [Constant::Number(100.0), Constant::Number(32.0), Constant::String(b"hi".to_vec())].to_vec());
+        assert_eq!(module.functions, [FunctionEntry { name: "foo".to_string(), funcId: 1, offset: 0, length: module.functionCode.len() as u64 }].to_vec());
+    }
+
+    #[test]
+    fn runDecodedModule(){
+        let module = Module::Decode(&compileModule("def foo(a: number) -> number:\na*100\nend\nfoo(32)").Encode()).unwrap();
+        let (toast_vm, _) = runModule(&module);
+        assert_eq!(f64::from_bits(toast_vm.registers[regResult].1), 3200.0);
+    }
+
+    #[test]
+    fn rejectOtherVersions(){
+        let mut bytes = compileModule("1 + 2").Encode();
+        bytes[5] = (moduleVersion + 1) as u8;
+        assert_eq!(Module::Decode(&bytes), Err(format!("Module has format version {}, but this version of ToastLang reads version {}. Recompile it from source", moduleVersion + 1, moduleVersion)));
+    }
+
+    #[test]
+    fn rejectBadModules(){
+        let bytes = compileModule("1 + 2").Encode();
+        assert_eq!(Module::Decode(b"1 + 2"), Err("Not a compiled ToastLang module".to_string()));
+        assert!(Module::Decode(&bytes[..bytes.len() - 1]).unwrap_err().starts_with("Module ends early"));
+    }
+}
@@ -0,0 +1,471 @@
+#![allow(non_snake_case)]
+use std::collections::{HashMap, HashSet};
+
+use crate::bytecode::Instruction;
+use crate::lexer::Token;
+use crate::module::{FunctionEntry, Module};
+use crate::parser::ExprAST;
+
+/// Register the VM keeps the result of the last load or operation in (regResult)
+const resultReg: u8 = u8::MAX;
+/// Most AST nodes the body of a function can have for calls to it to be inlined
+const inlineSizeLimit: usize = 16;
+
+/// Replaces arithmetic on number literals and '+' on string literals with its result. List literals are left as they are written,
+/// since the compiler only puts literal elements in a list
+pub fn FoldConstants(expr: ExprAST) -> ExprAST {
+    let fold = |x: Box<ExprAST>| Box::new(FoldConstants(*x));
+    match expr {
+        ExprAST::BinaryExpr { op, lhs, rhs, opChar } => {
+            let (lhs, rhs) = (FoldConstants(*lhs), FoldConstants(*rhs));
+            // Worked out the way the VM works them out, so the result is the same to the bit
+            return match (op, &lhs, &rhs) {
+                (Token::Plus, ExprAST::NumberExpr(a), ExprAST::NumberExpr(b)) => ExprAST::NumberExpr(a + b),
+                (Token::Minus, ExprAST::NumberExpr(a), ExprAST::NumberExpr(b)) => ExprAST::NumberExpr(a - b),
+                (Token::Multiply, ExprAST::NumberExpr(a), ExprAST::NumberExpr(b)) => ExprAST::NumberExpr(a * b),
+                (Token::Divide, ExprAST::NumberExpr(a), ExprAST::NumberExpr(b)) => ExprAST::NumberExpr(a / b),
+                (Token::Plus, ExprAST::StringExpr(a), ExprAST::StringExpr(b)) => ExprAST::StringExpr(a.clone() + b),
+                _ => ExprAST::BinaryExpr { op, lhs: Box::new(lhs), rhs: Box::new(rhs), opChar }
+            };
+        },
+        ExprAST::VariableAssignExpr { varObject, value } => ExprAST::VariableAssignExpr { varObject, value: fold(value) },
+        ExprAST::CallExpr { func_name, parameters } => ExprAST::CallExpr { func_name, parameters: parameters.into_iter().map(FoldConstants).collect() },
+        ExprAST::ReturnExpr(value) => ExprAST::ReturnExpr(fold(value)),
+        ExprAST::MatchExpr { value, arms } => ExprAST::MatchExpr { value: fold(value), arms: arms.into_iter().map(|(pattern, body)| (pattern, FoldConstants(body))).collect() },
+        ExprAST::ElementAccess { array_name, element_indexes } => ExprAST::ElementAccess { array_name, element_indexes: element_indexes.into_iter().map(fold).collect() },
+        ExprAST::FuncExpr { name, type_params, args, return_type, body, bodyLines } => ExprAST::FuncExpr {
+            name,
+            type_params,
+            args,
+            return_type,
+            body: body.into_iter().map(FoldConstants).collect(),
+            bodyLines
+        },
+        _ => expr
+    }
+}
+
+/// (List of (Parameter name, Type name), Body expression, Return type name) of a function calls can be replaced with
+pub type InlineFunction = (Vec<(String, String)>, ExprAST, String);
+
+/// Returns the InlineFunction of a function that is small enough to inline:
+/// its body is a single expression of literals, parameters, arithmetic and calls to functions isCallable allows, and it takes and
+/// returns values that are not optional. None if calls to it have to stay calls
+pub fn InlineBody(args: &[ExprAST], return_type: &Option<String>, body: &[ExprAST], isCallable: impl Fn(&str) -> bool) -> Option<InlineFunction> {
+    let returnTypeName = return_type.as_ref().filter(|x| !x.ends_with('?'))?;
+    let mut params = Vec::<(String, String)>::new();
+    for arg in args {
+        match arg {
+            // Optional parameters are wrapped by the call
+            ExprAST::VariableHeader { name, typeName } if !typeName.ends_with('?') => params.push((name.clone(), typeName.clone())),
+            _ => return None
+        }
+    }
+    let expr = match body {
+        [ExprAST::ReturnExpr(value)] => value.as_ref(),
+        [expr] => expr,
+        _ => return None
+    };
+    let paramNames: Vec<&str> = params.iter().map(|x| x.0.as_str()).collect();
+    if !IsInlinable(expr, &paramNames, &isCallable) || Size(expr) > inlineSizeLimit {
+        return None;
+    }
+    return Some((params, expr.clone(), returnTypeName.clone()));
+}
+
+/// Checks if an expression is made only of literals, the given parameters, arithmetic and calls to functions isCallable allows
+fn IsInlinable(expr: &ExprAST, paramNames: &[&str], isCallable: &impl Fn(&str) -> bool) -> bool {
+    match expr {
+        ExprAST::NumberExpr(_) | ExprAST::CharExpr(_) | ExprAST::StringExpr(_) => true,
+        ExprAST::VariableExpr(name) => paramNames.contains(&name.as_str()),
+        ExprAST::BinaryExpr { op: Token::Plus | Token::Minus | Token::Multiply | Token::Divide, lhs, rhs, opChar: _ } => {
+            IsInlinable(lhs, paramNames, isCallable) && IsInlinable(rhs, paramNames, isCallable)
+        },
+        ExprAST::CallExpr { func_name, parameters } => isCallable(func_name) && parameters.iter().all(|x| IsInlinable(x, paramNames, isCallable)),
+        _ => false
+    }
+}
+
+/// Number of AST nodes in an expression made of the nodes IsInlinable allows
+fn Size(expr: &ExprAST) -> usize {
+    match expr {
+        ExprAST::BinaryExpr { op: _, lhs, rhs, opChar: _ } => 1 + Size(lhs) + Size(rhs),
+        ExprAST::CallExpr { func_name: _, parameters } => 1 + parameters.iter().map(Size).sum::<usize>(),
+        _ => 1
+    }
+}
+
+/// Checks if working out an expression can print or fail, so it can not be repeated, dropped or moved past other code
+pub fn HasEffects(expr: &ExprAST) -> bool {
+    match expr {
+        ExprAST::NumberExpr(_) | ExprAST::CharExpr(_) | ExprAST::StringExpr(_) | ExprAST::VariableExpr(_) => false,
+        ExprAST::BinaryExpr { op: Token::Plus | Token::Minus | Token::Multiply | Token::Divide, lhs, rhs, opChar: _ } => HasEffects(lhs) || HasEffects(rhs),
+        _ => true
+    }
+}
+
+/// Checks if an expression calls the named function
+pub fn CallsFunction(expr: &ExprAST, name: &str) -> bool {
+    match expr {
+        ExprAST::BinaryExpr { op: _, lhs, rhs, opChar: _ } => CallsFunction(lhs, name) || CallsFunction(rhs, name),
+        ExprAST::CallExpr { func_name, parameters } => func_name == name || parameters.iter().any(|x| CallsFunction(x, name)),
+        _ => false
+    }
+}
+
+/// Number of times an expression made of the nodes IsInlinable allows reads the named variable
+pub fn CountUses(expr: &ExprAST, name: &str) -> usize {
+    match expr {
+        ExprAST::VariableExpr(varName) => (varName == name) as usize,
+        ExprAST::BinaryExpr { op: _, lhs, rhs, opChar: _ } => CountUses(lhs, name) + CountUses(rhs, name),
+        ExprAST::CallExpr { func_name: _, parameters } => parameters.iter().map(|x| CountUses(x, name)).sum(),
+        _ => 0
+    }
+}
+
+/// Replaces the variables of an expression made of the nodes IsInlinable allows with the expressions bound to them
+pub fn Substitute(expr: &ExprAST, bindings: &HashMap<String, ExprAST>) -> ExprAST {
+    match expr {
+        ExprAST::VariableExpr(name) => bindings.get(name).cloned().unwrap_or_else(|| expr.clone()),
+        ExprAST::BinaryExpr { op, lhs, rhs, opChar } => ExprAST::BinaryExpr {
+            op: *op,
+            lhs: Box::new(Substitute(lhs, bindings)),
+            rhs: Box::new(Substitute(rhs, bindings)),
+            opChar: opChar.clone()
+        },
+        ExprAST::CallExpr { func_name, parameters } => ExprAST::CallExpr { func_name: func_name.clone(), parameters: parameters.iter().map(|x| Substitute(x, bindings)).collect() },
+        _ => expr.clone()
+    }
+}
+
+/// Leaves out the functions that the top level code can not call, directly or through other functions. Function ids stay the same,
+/// so only the function table and function code change. Returns the number of functions left out
+pub fn DropDeadFunctions(module: &mut Module) -> usize {
+    let bodies: HashMap<u64, &[u8]> = module.functions.iter()
+        .map(|x| (x.funcId, &module.functionCode[x.offset as usize..(x.offset + x.length) as usize]))
+        .collect();
+    let mut reached = HashSet::<u64>::new();
+    let mut pending: Vec<&[u8]> = vec![&module.code];
+    while let Some(code) = pending.pop() {
+        for instruction in Instruction::DecodeProgram(code) {
+            if let Instruction::CallFunc { funcId } = instruction {
+                if reached.insert(funcId) {
+                    pending.extend(bodies.get(&funcId));
+                }
+            }
+        }
+    }
+    let mut functions = Vec::<FunctionEntry>::new();
+    let mut functionCode = Vec::<u8>::new();
+    for function in &module.functions {
+        if !reached.contains(&function.funcId) {
+            continue;
+        }
+        functions.push(FunctionEntry { offset: functionCode.len() as u64, ..function.clone() });
+        functionCode.extend_from_slice(bodies[&function.funcId]);
+    }
+    let dropped = module.functions.len() - functions.len();
+    module.functions = functions;
+    module.functionCode = functionCode;
+    return dropped;
+}
+
+/// What an instruction does to the registers of its window
+struct RegisterEffect {
+    reads: Vec<u8>,
+    /// Registers given the same new value
+    writes: Vec<u8>,
+    /// Register whose value is copied to the written registers
+    copyOf: Option<u8>,
+    /// True if the instruction does nothing but write its registers, so it can go once nothing reads them
+    isLoad: bool
+}
+
+impl RegisterEffect {
+    fn new(reads: Vec<u8>, writes: Vec<u8>) -> Self {
+        return RegisterEffect { reads, writes, copyOf: None, isLoad: false };
+    }
+}
+
+/// Returns what an instruction does to the registers. None for instructions that run other instructions or that the pass does not look into,
+/// which may read or write any register
+fn Effect(instruction: &Instruction) -> Option<RegisterEffect> {
+    let effect = match *instruction {
+        Instruction::LoadScalar { reg, .. } => RegisterEffect { isLoad: true, ..RegisterEffect::new(Vec::new(), vec![reg, resultReg]) },
+        // Fail on a variable that does not exist or a heap limit, so they are kept even when the value is not read
+        Instruction::LoadConstant { reg, .. } | Instruction::LoadVar { reg, .. } => RegisterEffect::new(Vec::new(), vec![reg, resultReg]),
+        Instruction::LoadReg { src, dst } => RegisterEffect { reads: vec![src], writes: vec![dst], copyOf: Some(src), isLoad: true },
+        Instruction::Add { reg1, reg2 } | Instruction::Sub { reg1, reg2 } | Instruction::Mul { reg1, reg2 } | Instruction::Div { reg1, reg2 } => {
+            RegisterEffect::new(vec![reg1, reg2], vec![reg1, resultReg])
+        },
+        Instruction::NewVar { reg, .. } | Instruction::TestTag { reg, .. } | Instruction::TestLength { reg, .. } | Instruction::Spill { reg, .. } => {
+            RegisterEffect::new(vec![reg], Vec::new())
+        },
+        Instruction::TestEqual { reg1, reg2, .. } => RegisterEffect::new(vec![reg1, reg2], Vec::new()),
+        Instruction::LoadElement { reg, listReg, .. } => RegisterEffect::new(vec![listReg], vec![reg, resultReg]),
+        Instruction::LoadIndexed { reg, listReg, indexReg, .. } => RegisterEffect::new(vec![listReg, indexReg], vec![reg, resultReg]),
+        Instruction::Unwrap { reg, optionalReg, .. } => RegisterEffect::new(vec![optionalReg], vec![reg, resultReg]),
+        Instruction::MakeVariant { reg, ref fields, .. } => RegisterEffect::new(fields.iter().map(|x| x.0).collect(), vec![reg, resultReg]),
+        Instruction::Reload { reg, .. } => RegisterEffect::new(Vec::new(), vec![reg]),
+        // Checks the type of the value, so it is kept even when the result is not read
+        Instruction::Return { reg, .. } => RegisterEffect { copyOf: Some(reg), ..RegisterEffect::new(vec![reg], vec![resultReg]) },
+        Instruction::DropVars { .. } | Instruction::Jump { .. } | Instruction::JumpIfFalse { .. } => RegisterEffect::new(Vec::new(), Vec::new()),
+        _ => return None
+    };
+    return Some(effect);
+}
+
+/// Where each pc of a block of code went after the peephole pass
+#[derive(Debug, Clone)]
+pub struct PcMap {
+    base: usize,
+    /// New offset from base of each byte of the old code, and of its end
+    newOffsets: Vec<usize>
+}
+
+impl PcMap {
+    /// Returns the new pc of the instruction at pc. The pc of a removed instruction goes to the instruction that followed it
+    pub fn Map(&self, pc: usize) -> usize {
+        if pc < self.base {
+            return pc;
+        }
+        let offset = std::cmp::min(pc - self.base, self.newOffsets.len() - 1);
+        return self.base + self.newOffsets[offset];
+    }
+}
+
+/// Removes register moves that copy a value the register already holds and scalar loads into registers that are written again before they are read.
+/// code is a function body (base 0) or top level code that starts at pc base, and jump targets in it are pcs. Each block of code between
+/// jumps, jump targets and statementStarts is looked at on its own, and every register is taken to be read once a block ends
+pub fn Peephole(code: &[u8], base: usize, statementStarts: &[usize]) -> (Vec<u8>, PcMap) {
+    let mut instructions = Vec::<(usize, Instruction, usize)>::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let (instruction, length) = Instruction::Decode(code, offset);
+        instructions.push((offset, instruction, length));
+        offset += length;
+    }
+
+    // Blocks start at the first instruction, jump targets, statements and after jumps
+    let mut leaders: HashSet<usize> = statementStarts.iter().filter_map(|pc| pc.checked_sub(base)).collect();
+    leaders.insert(0);
+    for (offset, instruction, length) in &instructions {
+        if let Instruction::Jump { target } | Instruction::JumpIfFalse { target } = *instruction {
+            leaders.insert((target as usize).wrapping_sub(base));
+            leaders.insert(offset + length);
+        }
+    }
+    let mut blocks = Vec::<(usize, usize)>::new();
+    for (index, (offset, _, _)) in instructions.iter().enumerate() {
+        if leaders.contains(offset) && index != 0 {
+            blocks.push((blocks.last().map_or(0, |x| x.1), index));
+        }
+    }
+    blocks.push((blocks.last().map_or(0, |x| x.1), instructions.len()));
+
+    let mut keep = vec![true; instructions.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &(start, end) in &blocks {
+            changed |= RemoveRedundantMoves(&instructions[start..end], &mut keep[start..end]);
+            changed |= RemoveDeadLoads(&instructions[start..end], &mut keep[start..end]);
+        }
+    }
+
+    let mut newOffsets = Vec::<usize>::with_capacity(code.len() + 1);
+    let mut newOffset = 0;
+    for (index, (_, _, length)) in instructions.iter().enumerate() {
+        newOffsets.extend(std::iter::repeat_n(newOffset, *length));
+        if keep[index] {
+            newOffset += length;
+        }
+    }
+    newOffsets.push(newOffset);
+    let pcMap = PcMap { base, newOffsets };
+
+    let mut optimized = Vec::<u8>::with_capacity(newOffset);
+    for (index, (_, instruction, _)) in instructions.into_iter().enumerate() {
+        if !keep[index] {
+            continue;
+        }
+        match instruction {
+            Instruction::Jump { target } => Instruction::Jump { target: pcMap.Map(target as usize) as u64 }.Encode(&mut optimized),
+            Instruction::JumpIfFalse { target } => Instruction::JumpIfFalse { target: pcMap.Map(target as usize) as u64 }.Encode(&mut optimized),
+            instruction => instruction.Encode(&mut optimized)
+        }
+    }
+    return (optimized, pcMap);
+}
+
+/// Numbers the values held by registers through a block and removes OpLoadReg moves to a register that already holds the value.
+/// Returns true if anything was removed
+fn RemoveRedundantMoves(block: &[(usize, Instruction, usize)], keep: &mut [bool]) -> bool {
+    // Key is register, Value is the number of the value it holds. Registers get a number the first time they are looked at
+    let mut values = HashMap::<u8, u32>::new();
+    let mut nextValue = 0;
+    let mut changed = false;
+    for (index, (_, instruction, _)) in block.iter().enumerate() {
+        if !keep[index] {
+            continue;
+        }
+        let Some(effect) = Effect(instruction) else {
+            values.clear();
+            continue;
+        };
+        let value = match effect.copyOf {
+            Some(src) => *values.entry(src).or_insert_with(|| { nextValue += 1; nextValue }),
+            None => { nextValue += 1; nextValue }
+        };
+        if effect.isLoad && effect.copyOf.is_some() && effect.writes.iter().all(|x| values.get(x) == Some(&value)) {
+            keep[index] = false;
+            changed = true;
+            continue;
+        }
+        for reg in effect.writes {
+            values.insert(reg, value);
+        }
+    }
+    return changed;
+}
+
+/// Removes scalar loads and moves into registers that are written again before anything reads them. Returns true if anything was removed
+fn RemoveDeadLoads(block: &[(usize, Instruction, usize)], keep: &mut [bool]) -> bool {
+    let mut live = [true; 256];
+    let mut changed = false;
+    for (index, (_, instruction, _)) in block.iter().enumerate().rev() {
+        if !keep[index] {
+            continue;
+        }
+        let Some(effect) = Effect(instruction) else {
+            live = [true; 256];
+            continue;
+        };
+        if effect.isLoad && effect.writes.iter().all(|x| !live[*x as usize]) {
+            keep[index] = false;
+            changed = true;
+            continue;
+        }
+        for reg in effect.writes {
+            live[reg as usize] = false;
+        }
+        for reg in effect.reads {
+            live[reg as usize] = true;
+        }
+    }
+    return changed;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::Assemble;
+    use crate::bytecode::Instruction;
+    use crate::codegen::{VarTypes, regResult};
+    use crate::disassembler::Disassemble;
+    use crate::module::Module;
+    use crate::optimizer::{DropDeadFunctions, FoldConstants, Peephole};
+    use crate::parser::{ExprAST, Parser};
+    use crate::testsupport::{self, runModule};
+
+    fn parseOne(source: &str) -> ExprAST {
+        return Parser::new(source).parse().unwrap().remove(0);
+    }
+
+    fn compile(source: &str, optimize: bool) -> Module {
+        return Module::FromConverter(&testsupport::compile(source, optimize), None);
+    }
+
+    /// Runs a module and returns what it printed, the result register and the number of instructions run
+    fn run(module: &Module) -> (String, u64, u64) {
+        let (toast_vm, output) = runModule(module);
+        return (output, toast_vm.registers[regResult].1, toast_vm.instructionCount);
+    }
+
+    #[test]
+    fn foldConstantExpressions(){
+        assert_eq!(FoldConstants(parseOne("1 + 2 * 3")), ExprAST::NumberExpr(7.0));
+        assert_eq!(FoldConstants(parseOne("\"ab\" + \"c\"")), ExprAST::StringExpr("abc".to_string()));
+        assert_eq!(FoldConstants(parseOne("print(8 / 2)")), ExprAST::CallExpr { func_name: "print".to_string(), parameters: [ExprAST::NumberExpr(4.0)].to_vec() });
+        // Only literals are folded
+        let expr = parseOne("x + 2 * 3");
+        let ExprAST::BinaryExpr { rhs, .. } = FoldConstants(expr) else { panic!("Expected a binary expression") };
+        assert_eq!(*rhs, ExprAST::NumberExpr(6.0));
+        assert_eq!(FoldConstants(parseOne("[1, 2]")), parseOne("[1, 2]"));
+    }
+
+    #[test]
+    fn removeRedundantMovesAndDeadLoads(){
+        let module = Assemble("loadconstant r0, 1.0\nloadreg r0, r255\nloadscalar r1, float, 5.0\nloadvar r1, float, var 0\nloadvar r1, float, var 1\nnewvar r1, float").unwrap();
+        let (code, pcMap) = Peephole(&module.code, 0, &[]);
+        // r255 already holds the constant, and the scalar in r1 is overwritten before it is read.
+        // The first load of a variable is overwritten too, but is kept since it fails if the variable does not exist
+        assert_eq!(Instruction::DecodeProgram(&code), [
+            Instruction::LoadConstant { reg: 0, constId: 0 },
+            Instruction::LoadVar { reg: 1, varType: VarTypes::FloatType, varId: 0 },
+            Instruction::LoadVar { reg: 1, varType: VarTypes::FloatType, varId: 1 },
+            Instruction::NewVar { reg: 1, varType: VarTypes::FloatType }
+        ].to_vec());
+        assert_eq!((pcMap.Map(10), pcMap.Map(13), pcMap.Map(24), pcMap.Map(35), pcMap.Map(46)), (10, 10, 10, 21, 32));
+    }
+
+    #[test]
+    fn keepLoadsAcrossBlocks(){
+        // r0 is loaded again after the jump target, but code that jumps there does not run the load before it
+        let module = Assemble("loadconstant r0, 1.0\njumpiffalse next\nloadconstant r0, 2.0\nnext:\nloadconstant r0, 3.0\nnewvar r0, float").unwrap();
+        let (code, pcMap) = Peephole(&module.code, 0, &[]);
+        assert_eq!(code, module.code);
+        assert_eq!(pcMap.Map(29), 29);
+    }
+
+    #[test]
+    fn optimizeWithoutChangingBehavior(){
+        let source = "def fib(n: number) -> number:\nmatch n with\n| 0 -> 0\n| 1 -> 1\n| _ -> fib(n - 1) + fib(n - 2)\nendmatch\nend\nlet a: number = 1 + 2 * 3\nlet b: number? = none\nlet c: number = b ?? 4\nprint(fib(10) + a)\nprint(\"to\" + \"ast\")\nprint(c * (10 - 2))\nc";
+        let (plain, optimized) = (compile(source, false), compile(source, true));
+        let (plainOutput, plainResult, plainCount) = run(&plain);
+        let (output, result, count) = run(&optimized);
+        assert_eq!((output.as_str(), result), (plainOutput.as_str(), plainResult));
+        assert_eq!(output, "62.0\n\"toast\"\n32.0\n");
+        assert!(count < plainCount);
+        assert!(optimized.code.len() < plain.code.len() && optimized.functionCode.len() < plain.functionCode.len());
+        assert!(Disassemble(&optimized).contains("OpLoadConstant r0, 7.0"));
+    }
+
+    #[test]
+    fn inlineSmallFunctions(){
+        let source = "def sq(a: number) -> number:\nreturn a * a\nend\ndef cube(a: number) -> number:\nreturn sq(a) * a\nend\ndef shout(a: number) -> number:\nprint(a)\nreturn a\nend\ndef fib(n: number) -> number:\nmatch n with\n| 0 -> 0\n| 1 -> 1\n| _ -> fib(n - 1) + fib(n - 2)\nendmatch\nend\nlet b: number = 3\nprint(sq(b))\nprint(cube(b + 1))\nprint(sq(shout(2)))\nprint(fib(cube(2)))";
+        let (plain, optimized) = (compile(source, false), compile(source, true));
+        let (output, _, count) = run(&optimized);
+        assert_eq!(output, run(&plain).0);
+        assert_eq!(output, "9.0\n64.0\n2.0\n4.0\n21.0\n");
+        assert!(count < run(&plain).2);
+        let disassembly = Disassemble(&optimized);
+        let topLevel = &disassembly[disassembly.rfind("OpEndFunc").unwrap()..];
+        // cube uses its parameter twice, so b + 1 is not worked out twice. shout prints, so its result is not used twice
+        assert_eq!(topLevel.matches("OpCallFunc 1 ; sq").count(), 1);
+        assert_eq!(topLevel.matches("OpCallFunc 2 ; cube").count(), 1);
+        // cube(2) is inlined with sq(2) folded, and recursive functions stay calls
+        assert!(topLevel.contains("OpLoadConstant r0, 4.0\n") && topLevel.contains("OpCallFunc 4 ; fib"));
+        assert!(disassembly.contains("OpCallFunc 4 ; fib\n") && disassembly.contains("cube(FloatType)"));
+    }
+
+    #[test]
+    fn stopInliningRedefinedCalls(){
+        // twice calls the first inc by id, so it can not be inlined once inc means something else
+        let source = "def inc(a: number) -> number:\nreturn a + 1\nend\ndef twice(a: number) -> number:\nreturn inc(a) * 2\nend\ndef inc(a: number) -> number:\nreturn a + 100\nend\nprint(twice(1))\nprint(inc(1))";
+        assert_eq!(run(&compile(source, true)).0, "4.0\n101.0\n");
+        assert_eq!(run(&compile(source, false)).0, "4.0\n101.0\n");
+    }
+
+    #[test]
+    fn dropUnreachableFunctions(){
+        let mut module = compile("def a(x: number) -> number:\nreturn x\nend\ndef b(x: number) -> number:\nprint(x)\nreturn a(x)\nend\ndef c(x: number) -> number:\nprint(x)\nreturn x\nend\nb(1)", false);
+        let names = |module: &Module| module.functions.iter().map(|x| x.name.clone()).collect::<Vec<String>>();
+        assert_eq!(names(&module), ["a", "b", "c"]);
+        let expected = run(&module);
+        // a is only called by b
+        assert_eq!(DropDeadFunctions(&mut module), 1);
+        assert_eq!(names(&module), ["a", "b"]);
+        assert_eq!(module.functions[1].offset, module.functions[0].length);
+        assert_eq!(run(&module), expected);
+    }
+}
@@ -0,0 +1,72 @@
+#![allow(non_snake_case)]
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+/// Somewhere VMCore writes to, such as the program output or the trace of what the VM is doing
+pub struct Sink(Box<dyn Write>);
+
+impl Sink {
+    pub fn new(writer: impl Write + 'static) -> Self {
+        return Sink(Box::new(writer));
+    }
+
+    pub fn Stdout() -> Self {
+        return Sink::new(io::stdout());
+    }
+
+    pub fn Stderr() -> Self {
+        return Sink::new(io::stderr());
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        return self.0.write(buf);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.0.flush();
+    }
+}
+
+impl fmt::Debug for Sink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "Sink");
+    }
+}
+
+/// Keeps everything written to it so a host can read it back, e.g. to capture what a program printed.
+/// Clones share the same buffer, so one clone can be given to a Sink and another kept to read from
+#[derive(Debug, Clone, Default)]
+pub struct CaptureBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl CaptureBuffer {
+    pub fn new() -> Self {
+        return CaptureBuffer::default();
+    }
+
+    /// Returns everything written so far
+    pub fn Contents(&self) -> String {
+        return String::from_utf8_lossy(&self.0.borrow()).to_string();
+    }
+
+    /// Returns everything written so far and empties the buffer
+    pub fn Take(&self) -> String {
+        let contents = self.Contents();
+        self.0.borrow_mut().clear();
+        return contents;
+    }
+}
+
+impl Write for CaptureBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
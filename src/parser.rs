@@ -72,18 +72,66 @@ pub enum ExprAST {
     FuncExpr {
         ///Name of function
         name: String,
+        ///List of type parameters (e.g T in def first<T>(xs: T[]) -> T)
+        type_params: Vec<String>,
         ///List of Arugments
         args: Vec<ExprAST>,
         return_type: Option<String>,
         ///Body of Functions
         // body: Option<Box<ExprAST>>
-        body: Vec<ExprAST>
+        body: Vec<ExprAST>,
+        ///Source line each expression of the body starts on
+        bodyLines: Vec<usize>
     },
     ElementAccess{
         array_name: String,
         element_indexes: Vec<Box<ExprAST>>
     },
-    ReturnExpr(Box<ExprAST>)
+    ReturnExpr(Box<ExprAST>),
+    ///Represents a sum type declaration
+    TypeDeclExpr {
+        ///Name of the type
+        name: String,
+        ///List of variants as (Variant name, List of field type names)
+        variants: Vec<(String, Vec<String>)>
+    },
+    ///Represents a type alias declaration
+    TypeAliasExpr {
+        ///Name of the alias
+        name: String,
+        ///Name of the aliased type
+        typeName: String
+    },
+    ///Represents a match expression
+    MatchExpr {
+        ///Value being matched on
+        value: Box<ExprAST>,
+        ///List of arms as (Pattern, Arm body)
+        arms: Vec<(PatternAST, ExprAST)>
+    },
+    ///Represents the 'none' value of optional types
+    NoneExpr
+}
+
+///Pattern AST node used by match arms
+#[derive(PartialEq, Clone, Debug)]
+pub enum PatternAST {
+    ///Represents '_', matches anything
+    Wildcard,
+    ///Represents a bare identifier. Binds the value unless the name is a variant without fields
+    Binding(String),
+    NumberPattern(f64),
+    CharPattern(String),
+    StringPattern(String),
+    ///Represents an array pattern of fixed length
+    ListPattern(Vec<PatternAST>),
+    ///Represents a variant pattern
+    VariantPattern {
+        ///Name of variant
+        name: String,
+        ///Patterns for each of the variant fields
+        fields: Vec<PatternAST>
+    }
 }
 
 // impl<T: PartialEq, U: PartialEq> PartialEq for ExprAST {
@@ -114,7 +162,9 @@ pub struct Parser<'a>{
     ///List of tokens to skip over
     pub TokensToSkip: Vec<Token>,
     pub line_num: usize,
-    pub col_num: usize
+    pub col_num: usize,
+    ///Source line each expression returned by parse starts on
+    pub statementLines: Vec<usize>
 }
 
 impl<'a> Parser <'a>{
@@ -129,6 +179,7 @@ impl<'a> Parser <'a>{
         BinOp.insert("/".to_string(), 30);
         BinOp.insert("=".to_string(), 10);
         BinOp.insert("[".to_string(), 50);
+        BinOp.insert("??".to_string(), 15);
 
         let skipToken = [Token::WhiteSpace].to_vec();
         Parser {
@@ -139,6 +190,7 @@ impl<'a> Parser <'a>{
             ,TokensToSkip: skipToken.clone()
             ,line_num: 0
             ,col_num: 0
+            ,statementLines: Vec::new()
         }
     }
 
@@ -158,6 +210,11 @@ impl<'a> Parser <'a>{
         self.col_num += (self.lexer.span().end - self.lexer.span().start)
     }
 
+    /// Returns the source line the current token starts on, counting from 1
+    pub fn CurrentLine(&self) -> usize {
+        return self.lexer.source()[..self.lexer.span().start].matches('\n').count() + 1;
+    }
+
     pub fn LogError(&mut self, error : &str) -> Option<ExprAST>{
         println!("(Line Num {}, Col {}): Error: {} Col", self.line_num, self.col_num, error);
         return None;
@@ -180,14 +237,17 @@ impl<'a> Parser <'a>{
                 break;
             }
 
+            let line = self.CurrentLine();
             let result = match self.current_token.unwrap() {
                 Token::Def => self.ParseDef(),
+                Token::TypeDeclare => self.ParseTypeDecl(),
                 _ => self.ParseExpr()
             };
             if result.is_none() {
                 return  None;
             }
             program.push(result.clone().unwrap());
+            self.statementLines.push(line);
         }
         return Some(program);
     }
@@ -232,7 +292,9 @@ impl<'a> Parser <'a>{
         //Add Possible Type Cast
 
         let mut funcBody = Vec::<ExprAST>::new();
+        let mut funcBodyLines = Vec::<usize>::new();
         while self.current_token.unwrap() != Token::FuncEnd{
+            funcBodyLines.push(self.CurrentLine());
             let curExpr = self.ParseExpr();
             if curExpr.is_none() {
                 return self.LogError(("Could not parse body"));
@@ -244,8 +306,9 @@ impl<'a> Parser <'a>{
         }
         self.getNewToken(); //Consume End
 
-        if let ExprAST::FuncExpr { name: _, args: _, return_type: _, ref mut body } = prototype {
+        if let ExprAST::FuncExpr { name: _, type_params: _, args: _, return_type: _, ref mut body, ref mut bodyLines } = prototype {
             *body = funcBody.clone();
+            *bodyLines = funcBodyLines;
         }
 
         return Some(prototype);
@@ -262,6 +325,22 @@ impl<'a> Parser <'a>{
             _ => {return self.LogError("Expected function name here")}
         }
         
+        let mut typeParams: Vec<String> = Vec::new();
+        if self.current_token == Some(Token::LessThan) {
+            self.getNewToken(); //Consume '<'
+            while self.current_token == Some(Token::Ident) {
+                typeParams.push(self.lexer.slice().to_owned());
+                self.getNewToken(); //Consume type parameter
+                if self.current_token != Some(Token::Comma) {
+                    break;
+                }
+                self.getNewToken(); //Consume ','
+            }
+            if self.current_token != Some(Token::GreaterThan) {
+                return self.LogError("Expected a '>' here");
+            }
+            self.getNewToken(); //Consume '>'
+        }
 
         if self.current_token.unwrap() != Token::OpeningParenthesis {
             return self.LogError("Expected a '(' here");
@@ -294,11 +373,10 @@ impl<'a> Parser <'a>{
             if self.current_token.unwrap() != Token::Ident {
                 self.LogError("No function return type here");
             }
-            return_type = Some(self.lexer.slice().to_owned());
-            self.getNewToken(); // Consume Type
+            return_type = self.ParseTypeName(); // Consume Type
         }
 
-        let funcExpression : ExprAST = ExprAST::FuncExpr { name: prototypeName, args: newArgs, return_type: return_type, body: Vec::<ExprAST>::new() };
+        let funcExpression : ExprAST = ExprAST::FuncExpr { name: prototypeName, type_params: typeParams, args: newArgs, return_type: return_type, body: Vec::<ExprAST>::new(), bodyLines: Vec::new() };
         return Some(funcExpression);
         
     }
@@ -360,6 +438,11 @@ impl<'a> Parser <'a>{
             Token::Comment => self.ParseSingleLineComment(),
             Token::MultilineCommentBegin => self.ParseMultiLineComment(),
             Token::VarDeclare => self.ParseVarDeclar(),
+            Token::Match => self.ParseMatchExpr(),
+            Token::NoneValue => {
+                self.getNewToken(); // Consume 'none'
+                return Some(ExprAST::NoneExpr);
+            },
             Token::Return => {
                 self.getNewToken(); // Consume 'return'
                 let val = self.ParseExpr().expect("Could not parse parameter");
@@ -436,7 +519,7 @@ impl<'a> Parser <'a>{
             // consumes type
             self.getNewToken();
 
-            while [Token::OpenSquareBracket, Token::CloseSquareBracket].contains(&self.current_token.unwrap()) {
+            while self.current_token.is_some() && [Token::OpenSquareBracket, Token::CloseSquareBracket, Token::QuestionMark].contains(&self.current_token.unwrap()) {
                 TypeName.push_str(self.lexer.slice());
                 self.getNewToken();
             }
@@ -491,7 +574,7 @@ impl<'a> Parser <'a>{
         }
 
         match self.lexer.slice() {
-            "+" | "-" | "/" | "*" | "<"| ">" | "=" | "[" => {
+            "+" | "-" | "/" | "*" | "<"| ">" | "=" | "[" | "??" => {
                 BinOp = self.current_token;
             }
             _ => {
@@ -572,12 +655,205 @@ impl<'a> Parser <'a>{
         } else {return self.LogError("Error caused by wrong Expr variant");}
     }
 
+    /// Parses type name (e.g number, number[][])
+    pub fn ParseTypeName(&mut self) -> Option<String>{
+        if self.current_token != Some(Token::Ident) {
+            self.LogError("Expected a type name here");
+            return None;
+        }
+        let mut TypeName = self.lexer.slice().to_owned();
+        self.getNewToken(); // consumes type
+        while self.current_token.is_some() && [Token::OpenSquareBracket, Token::CloseSquareBracket, Token::QuestionMark].contains(&self.current_token.unwrap()) {
+            TypeName.push_str(self.lexer.slice());
+            self.getNewToken();
+        }
+        return Some(TypeName);
+    }
+
+    /// Parses type declaration (e.g type Shape = Circle(number) | Rect(number, number)) or type alias (e.g type Grid = number[][])
+    pub fn ParseTypeDecl(&mut self) -> Option<ExprAST>{
+        self.getNewToken(); // consume 'type'
+        if self.current_token != Some(Token::Ident) {
+            return self.LogError("Expected type name here");
+        }
+        let typeName = self.lexer.slice().to_owned();
+        self.getNewToken(); // consume type name
+        if self.current_token != Some(Token::Equals) {
+            return self.LogError("Expected a '=' here");
+        }
+        self.getNewToken(); // consume '='
+        let mut firstVariant: Option<String> = None;
+        if self.current_token == Some(Token::Pipe) {
+            self.getNewToken(); // consume optional leading '|'
+        } else {
+            // A single type name is an alias (e.g type Grid = number[][]). A one variant sum type needs the leading '|'
+            let aliasedType = self.ParseTypeName()?;
+            if aliasedType.ends_with(['[', ']', '?']) || ![Some(Token::OpeningParenthesis), Some(Token::Pipe)].contains(&self.current_token) {
+                return Some(ExprAST::TypeAliasExpr { name: typeName, typeName: aliasedType });
+            }
+            firstVariant = Some(aliasedType);
+        }
+
+        let mut variants: Vec<(String, Vec<String>)> = Vec::new();
+        loop {
+            let variantName = match firstVariant.take() {
+                Some(variantName) => variantName,
+                None => {
+                    if self.current_token != Some(Token::Ident) {
+                        return self.LogError("Expected variant name here");
+                    }
+                    let variantName = self.lexer.slice().to_owned();
+                    self.getNewToken(); // consume variant name
+                    variantName
+                }
+            };
+            let mut fields: Vec<String> = Vec::new();
+            if self.current_token == Some(Token::OpeningParenthesis) {
+                self.getNewToken(); // consume '('
+                while self.current_token != Some(Token::ClosingParenthesis) {
+                    fields.push(self.ParseTypeName()?);
+                    if self.current_token != Some(Token::Comma) {
+                        break;
+                    }
+                    self.getNewToken(); // consume ','
+                }
+                if self.current_token != Some(Token::ClosingParenthesis) {
+                    return self.LogError("Expected a ')' here");
+                }
+                self.getNewToken(); // consume ')'
+            }
+            variants.push((variantName, fields));
+            if self.current_token != Some(Token::Pipe) {
+                break;
+            }
+            self.getNewToken(); // consume '|'
+        }
+        return Some(ExprAST::TypeDeclExpr { name: typeName, variants: variants });
+    }
+
+    /// Parses match expression (e.g match s with | Circle(r) -> r * r | _ -> 0 endmatch)
+    pub fn ParseMatchExpr(&mut self) -> Option<ExprAST>{
+        self.getNewToken(); // consume 'match'
+        let value = self.ParseExpr()?;
+        if self.current_token != Some(Token::With) {
+            return self.LogError("Expected a 'with' here");
+        }
+        self.getNewToken(); // consume 'with'
+
+        let mut arms: Vec<(PatternAST, ExprAST)> = Vec::new();
+        loop {
+            match self.current_token {
+                None => return self.LogError("Expected a 'endmatch' here"),
+                Some(Token::EndMatch) => {
+                    self.getNewToken(); // consume 'endmatch'
+                    break;
+                },
+                Some(Token::Pipe) => {
+                    self.getNewToken(); // consume '|'
+                    continue;
+                },
+                _ => {}
+            }
+            let pattern = self.ParsePattern()?;
+            if self.current_token != Some(Token::PointTo) {
+                return self.LogError("Expected a '->' here");
+            }
+            self.getNewToken(); // consume '->'
+            let body = self.ParseExpr()?;
+            arms.push((pattern, body));
+        }
+        return Some(ExprAST::MatchExpr { value: Box::new(value), arms: arms });
+    }
+
+    /// Parses a pattern of a match arm
+    pub fn ParsePattern(&mut self) -> Option<PatternAST>{
+        match self.current_token {
+            Some(Token::Underscore) => {
+                self.getNewToken(); // consume '_'
+                return Some(PatternAST::Wildcard);
+            },
+            Some(Token::NoneValue) => {
+                self.getNewToken(); // consume 'none'
+                return Some(PatternAST::VariantPattern { name: "none".to_string(), fields: Vec::new() });
+            },
+            Some(Token::Number) => {
+                let result = PatternAST::NumberPattern(self.lexer.slice().parse::<f64>().unwrap());
+                self.getNewToken();
+                return Some(result);
+            },
+            Some(Token::Minus) => {
+                self.getNewToken(); // consume '-'
+                if self.current_token != Some(Token::Number) {
+                    self.LogError("Expected a number here");
+                    return None;
+                }
+                let result = PatternAST::NumberPattern(-self.lexer.slice().parse::<f64>().unwrap());
+                self.getNewToken();
+                return Some(result);
+            },
+            Some(Token::Char) => {
+                let result = PatternAST::CharPattern(self.lexer.slice().replace("\'", ""));
+                self.getNewToken();
+                return Some(result);
+            },
+            Some(Token::String) => {
+                let result = PatternAST::StringPattern(self.lexer.slice().replace("\"", ""));
+                self.getNewToken();
+                return Some(result);
+            },
+            Some(Token::OpenSquareBracket) => {
+                self.getNewToken(); // consume '['
+                let mut elements: Vec<PatternAST> = Vec::new();
+                while self.current_token != Some(Token::CloseSquareBracket) {
+                    elements.push(self.ParsePattern()?);
+                    if self.current_token != Some(Token::Comma) {
+                        break;
+                    }
+                    self.getNewToken(); // consume ','
+                }
+                if self.current_token != Some(Token::CloseSquareBracket) {
+                    self.LogError("Expected a ']' here");
+                    return None;
+                }
+                self.getNewToken(); // consume ']'
+                return Some(PatternAST::ListPattern(elements));
+            },
+            Some(Token::Ident) => {
+                let name = self.lexer.slice().to_owned();
+                self.getNewToken(); // consume identifier
+                if self.current_token != Some(Token::OpeningParenthesis) {
+                    return Some(PatternAST::Binding(name));
+                }
+                self.getNewToken(); // consume '('
+                let mut fields: Vec<PatternAST> = Vec::new();
+                while self.current_token != Some(Token::ClosingParenthesis) {
+                    fields.push(self.ParsePattern()?);
+                    if self.current_token != Some(Token::Comma) {
+                        break;
+                    }
+                    self.getNewToken(); // consume ','
+                }
+                if self.current_token != Some(Token::ClosingParenthesis) {
+                    self.LogError("Expected a ')' here");
+                    return None;
+                }
+                self.getNewToken(); // consume ')'
+                return Some(PatternAST::VariantPattern { name: name, fields: fields });
+            },
+            _ => {
+                self.LogError("Expected a pattern here");
+                return None;
+            }
+        }
+    }
+
     // pub fn ParseReturn(&mut self)
 
 }
 
+#[cfg(test)]
 mod tests {
-    use crate::parser::{ExprAST, Parser};
+    use crate::parser::{ExprAST, Parser, PatternAST};
     
     
     #[test]
@@ -605,18 +881,23 @@ mod tests {
         let true_val = [ 
             ExprAST::FuncExpr { 
                 name: "foo".to_string(), 
+                type_params: Vec::new(),
                 return_type: None,
                 args: [ExprAST::VariableHeader { name: "a".to_string(), typeName: "number".to_string() }, ExprAST::VariableHeader { name: "b".to_string(), typeName: "number".to_string() }].to_vec(), 
-                body: [ExprAST::BinaryExpr { op: (crate::lexer::Token::Minus), lhs: Box::new(ExprAST::VariableExpr("a".to_string())), rhs: Box::new(ExprAST::VariableExpr("b".to_string())), opChar: "-".to_string() }].to_vec()
+                body: [ExprAST::BinaryExpr { op: (crate::lexer::Token::Minus), lhs: Box::new(ExprAST::VariableExpr("a".to_string())), rhs: Box::new(ExprAST::VariableExpr("b".to_string())), opChar: "-".to_string() }].to_vec(),
+                bodyLines: [2].to_vec()
             },
             ExprAST::FuncExpr { 
                 name: "boo".to_string(), 
+                type_params: Vec::new(),
                 return_type: None,
                 args: [ExprAST::VariableHeader { name: "a".to_string(), typeName: "number".to_string() }, ExprAST::VariableHeader { name: "b".to_string(), typeName: "number".to_string() }].to_vec(), 
-                body: [ExprAST::BinaryExpr { op: (crate::lexer::Token::Plus), lhs: Box::new(ExprAST::VariableExpr("a".to_string())), rhs: Box::new(ExprAST::VariableExpr("b".to_string())), opChar: "+".to_string() }].to_vec()
+                body: [ExprAST::BinaryExpr { op: (crate::lexer::Token::Plus), lhs: Box::new(ExprAST::VariableExpr("a".to_string())), rhs: Box::new(ExprAST::VariableExpr("b".to_string())), opChar: "+".to_string() }].to_vec(),
+                bodyLines: [4].to_vec()
             },
          ];
         assert_eq!(test.unwrap(), true_val.to_vec());
+        assert_eq!(parser.statementLines, [1, 4].to_vec());
     }
 
     #[test]
@@ -707,4 +988,86 @@ mod tests {
         let true_val = ExprAST::ReturnExpr(Box::new(ExprAST::VariableExpr("a".to_string())));
         assert_eq!(test.unwrap().first().unwrap().to_owned(), true_val );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn parseTypeDecl(){
+        let source = "type Shape = Circle(number) | Rect(number, number) | Empty";
+        let mut parser = Parser::new(source);
+        let test = parser.parse();
+        println!("{:?}", test);
+        let true_val = ExprAST::TypeDeclExpr { 
+            name: "Shape".to_string(), 
+            variants: [
+                ("Circle".to_string(), ["number".to_string()].to_vec()),
+                ("Rect".to_string(), ["number".to_string(), "number".to_string()].to_vec()),
+                ("Empty".to_string(), Vec::new())
+            ].to_vec()
+        };
+        assert_eq!(test.unwrap().first().unwrap().to_owned(), true_val );
+    }
+
+    #[test]
+    fn parseTypeAlias(){
+        let source = "type Grid = number[][] type Num = number type Unit = | Unit";
+        let mut parser = Parser::new(source);
+        let test = parser.parse();
+        println!("{:?}", test);
+        let true_val = [
+            ExprAST::TypeAliasExpr { name: "Grid".to_string(), typeName: "number[][]".to_string() },
+            ExprAST::TypeAliasExpr { name: "Num".to_string(), typeName: "number".to_string() },
+            ExprAST::TypeDeclExpr { name: "Unit".to_string(), variants: [("Unit".to_string(), Vec::new())].to_vec() }
+        ];
+        assert_eq!(test.unwrap(), true_val.to_vec());
+    }
+
+    #[test]
+    fn parseMatch(){
+        let source = "match s with | Circle(r) -> r | [a, _] -> a | 3 -> 1 | _ -> 0 endmatch";
+        let mut parser = Parser::new(source);
+        let test = parser.parse();
+        println!("{:?}", test);
+        let true_val = ExprAST::MatchExpr { 
+            value: Box::new(ExprAST::VariableExpr("s".to_string())), 
+            arms: [
+                (PatternAST::VariantPattern { name: "Circle".to_string(), fields: [PatternAST::Binding("r".to_string())].to_vec() }, ExprAST::VariableExpr("r".to_string())),
+                (PatternAST::ListPattern([PatternAST::Binding("a".to_string()), PatternAST::Wildcard].to_vec()), ExprAST::VariableExpr("a".to_string())),
+                (PatternAST::NumberPattern(3.0), ExprAST::NumberExpr(1.0)),
+                (PatternAST::Wildcard, ExprAST::NumberExpr(0.0))
+            ].to_vec()
+        };
+        assert_eq!(test.unwrap().first().unwrap().to_owned(), true_val );
+    }
+
+    #[test]
+    fn parseGenericFunction(){
+        let source = "def first<T>(xs: T[]) -> T: xs[0] end";
+        let mut parser = Parser::new(source);
+        let test = parser.parse();
+        println!("{:?}", test);
+        let true_val = ExprAST::FuncExpr { 
+            name: "first".to_string(), 
+            type_params: ["T".to_string()].to_vec(),
+            args: [ExprAST::VariableHeader { name: "xs".to_string(), typeName: "T[]".to_string() }].to_vec(), 
+            return_type: Some("T".to_string()),
+            body: [ExprAST::ElementAccess { array_name: "xs".to_string(), element_indexes: [Box::new(ExprAST::NumberExpr(0 as f64))].to_vec() }].to_vec(),
+            bodyLines: [1].to_vec()
+        };
+        assert_eq!(test.unwrap().first().unwrap().to_owned(), true_val );
+    }
+
+    #[test]
+    fn parseOptional(){
+        let source = "let a: number? = none a ?? 1";
+        let mut parser = Parser::new(source);
+        let test = parser.parse();
+        println!("{:?}", test);
+        let true_val = [
+            ExprAST::VariableAssignExpr { 
+                varObject: Box::new(ExprAST::VariableHeader { name: "a".to_string(), typeName: "number?".to_string() }), 
+                value: Box::new(ExprAST::NoneExpr) 
+            },
+            ExprAST::BinaryExpr { op: crate::lexer::Token::DefaultOp, lhs: Box::new(ExprAST::VariableExpr("a".to_string())), rhs: Box::new(ExprAST::NumberExpr(1 as f64)), opChar: "??".to_string() }
+        ];
+        assert_eq!(test.unwrap(), true_val.to_vec());
+    }
+}
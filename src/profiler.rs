@@ -0,0 +1,184 @@
+#![allow(non_snake_case)]
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::codegen::OpCodes;
+
+/// Name the profiler gives to top level code
+pub const topLevelName: &str = "<module>";
+
+/// What the profiler measured for one function
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FunctionStats {
+    pub calls: u64,
+    /// Instructions run in the function itself, not in the functions it called
+    pub instructions: u64,
+    /// Time from call to return. A recursive call is only counted once, by its outermost call
+    pub inclusiveTime: Duration,
+    /// Time spent in the function itself, not in the functions it called
+    pub exclusiveTime: Duration
+}
+
+/// Counts the instructions the VM runs and times function calls. Set VMCore::profiler to turn it on.
+/// Every instruction reads the clock, so programs run slower while they are profiled
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    /// Key is OpCode, Value is the number of times it ran
+    pub opCodeCounts: HashMap<OpCodes, u64>,
+    /// Key is function name, topLevelName for top level code
+    pub functions: HashMap<String, FunctionStats>,
+    /// Key is the names of the running calls from outermost to innermost joined by ';', Value is exclusive time in nanoseconds
+    pub stacks: HashMap<String, u64>,
+    /// Running calls as (name, time of the call)
+    callStack: Vec<(String, Instant)>,
+    /// When the time up to now was last given to the running call. None while the VM is not running
+    lastTick: Option<Instant>,
+    /// When the VM started running the current top level code
+    startedAt: Option<Instant>
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        return Profiler::default();
+    }
+
+    /// Name of the call that is running
+    fn Current(&self) -> &str {
+        return self.callStack.last().map_or(topLevelName, |x| x.0.as_str());
+    }
+
+    /// Gives the time since the last tick to the running call
+    fn Tick(&mut self, now: Instant) {
+        if let Some(lastTick) = self.lastTick.replace(now) {
+            let elapsed = now - lastTick;
+            let current = self.Current().to_string();
+            self.functions.entry(current).or_default().exclusiveTime += elapsed;
+            let stack = [topLevelName].into_iter().chain(self.callStack.iter().map(|x| x.0.as_str())).collect::<Vec<&str>>().join(";");
+            *self.stacks.entry(stack).or_default() += elapsed.as_nanos() as u64;
+        }
+    }
+
+    /// Called when the VM starts running top level code
+    pub fn Start(&mut self) {
+        let now = Instant::now();
+        self.lastTick = Some(now);
+        self.startedAt = Some(now);
+        self.functions.entry(topLevelName.to_string()).or_default().calls += 1;
+    }
+
+    /// Called when the VM stops running top level code, so time between runs is not counted
+    pub fn Stop(&mut self) {
+        let now = Instant::now();
+        self.Tick(now);
+        self.lastTick = None;
+        if let Some(startedAt) = self.startedAt.take() {
+            self.functions.entry(topLevelName.to_string()).or_default().inclusiveTime += now - startedAt;
+        }
+    }
+
+    /// Called before each instruction the VM runs
+    pub fn Instruction(&mut self, opCode: OpCodes) {
+        self.Tick(Instant::now());
+        *self.opCodeCounts.entry(opCode).or_default() += 1;
+        let current = self.Current().to_string();
+        self.functions.entry(current).or_default().instructions += 1;
+    }
+
+    /// Called when a function is called
+    pub fn Enter(&mut self, name: &str) {
+        let now = Instant::now();
+        self.Tick(now);
+        self.functions.entry(name.to_string()).or_default().calls += 1;
+        self.callStack.push((name.to_string(), now));
+    }
+
+    /// Called when a function returns
+    pub fn Exit(&mut self) {
+        let now = Instant::now();
+        self.Tick(now);
+        if let Some((name, calledAt)) = self.callStack.pop() {
+            if !self.callStack.iter().any(|x| x.0 == name) {
+                self.functions.entry(name).or_default().inclusiveTime += now - calledAt;
+            }
+        }
+    }
+
+    /// Forgets the calls that were running when an error stopped the VM
+    pub fn Unwind(&mut self) {
+        self.callStack.clear();
+    }
+
+    /// Table of functions by exclusive time and of OpCodes by the number of times they ran
+    pub fn Report(&self) -> String {
+        let mut functions: Vec<(&String, &FunctionStats)> = self.functions.iter().collect();
+        functions.sort_by(|a, b| b.1.exclusiveTime.cmp(&a.1.exclusiveTime).then(a.0.cmp(b.0)));
+        let mut report = format!("{:<32} {:>10} {:>14} {:>16} {:>16}\n", "Function", "Calls", "Instructions", "Inclusive (ms)", "Exclusive (ms)");
+        for (name, stats) in functions {
+            report += &format!("{:<32} {:>10} {:>14} {:>16.3} {:>16.3}\n", name, stats.calls, stats.instructions,
+                stats.inclusiveTime.as_secs_f64() * 1000.0, stats.exclusiveTime.as_secs_f64() * 1000.0);
+        }
+        let mut opCodes: Vec<(String, u64)> = self.opCodeCounts.iter().map(|(opCode, count)| (format!("{:?}", opCode), *count)).collect();
+        opCodes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        report += &format!("\n{:<32} {:>10}\n", "OpCode", "Count");
+        for (opCode, count) in opCodes {
+            report += &format!("{:<32} {:>10}\n", opCode, count);
+        }
+        return report;
+    }
+
+    /// One line per call stack with its exclusive time in nanoseconds (e.g "<module>;foo;bar 1200"), as read by flamegraph tools
+    pub fn FoldedStacks(&self) -> String {
+        let mut stacks: Vec<(&String, &u64)> = self.stacks.iter().filter(|x| *x.1 > 0).collect();
+        stacks.sort();
+        return stacks.iter().map(|(stack, time)| format!("{} {}\n", stack, time)).collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codegen::{OpCodes, VMCore};
+    use crate::assembler::Assemble;
+    use crate::profiler::{topLevelName, Profiler};
+
+    #[test]
+    fn profileCallsAndInstructions(){
+        let module = Assemble("startfunc inner() -> null\nloadconstant r0, 1.0\nendfunc\nstartfunc outer() -> null\ncallfunc inner\nendparamload\ncallfunc inner\nendparamload\nendfunc\ncallfunc outer\nendparamload").unwrap();
+        let mut toast_vm = VMCore::new();
+        toast_vm.profiler = Some(Profiler::new());
+        toast_vm.processProgram(&module).unwrap();
+        let profiler = toast_vm.profiler.unwrap();
+        assert_eq!(profiler.opCodeCounts[&OpCodes::OpCallFunc], 3);
+        assert_eq!(profiler.opCodeCounts[&OpCodes::OpLoadConstant], 2);
+        let counts = |name: &str| (profiler.functions[name].calls, profiler.functions[name].instructions);
+        assert_eq!((counts(topLevelName), counts("outer"), counts("inner")), ((1, 1), (1, 2), (2, 2)));
+        let outer = &profiler.functions["outer"];
+        assert!(outer.inclusiveTime >= outer.exclusiveTime + profiler.functions["inner"].inclusiveTime);
+        assert!(profiler.functions[topLevelName].inclusiveTime >= outer.inclusiveTime);
+
+        let folded = profiler.FoldedStacks();
+        assert!(folded.lines().all(|x| x.starts_with("<module>") && x.rsplit(' ').next().unwrap().parse::<u64>().is_ok()));
+        assert!(folded.contains("<module>;outer;inner "));
+        let report = profiler.Report();
+        assert!(report.lines().any(|x| x.starts_with("inner") && x.split_whitespace().nth(1) == Some("2")));
+        assert!(report.lines().any(|x| x.starts_with("OpCallFunc") && x.ends_with(" 3")));
+    }
+
+    #[test]
+    fn profileRecursionOnce(){
+        let mut profiler = Profiler::new();
+        profiler.Start();
+        profiler.Enter("f");
+        profiler.Enter("f");
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        profiler.Exit();
+        profiler.Exit();
+        profiler.Stop();
+        let f = &profiler.functions["f"];
+        assert_eq!(f.calls, 2);
+        assert!(f.inclusiveTime >= std::time::Duration::from_millis(20));
+        assert!(f.inclusiveTime >= f.exclusiveTime);
+        // The inner call's time is already part of the outer call's, so counting it again would take f past the whole run
+        assert!(f.inclusiveTime <= profiler.functions[topLevelName].inclusiveTime);
+        assert!(profiler.stacks.contains_key("<module>;f;f"));
+    }
+}
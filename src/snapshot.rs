@@ -0,0 +1,683 @@
+#![allow(non_snake_case)]
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::bytecode::DecodedProgram;
+use crate::codegen::{ASTConverter, MemoryBlock, RegisterAllocator, ToastLangValue, VMCore, VarTypes, regWindowSize};
+use crate::heap::{Children, Heap, HeapObject};
+use crate::lexer::Token;
+use crate::module::{ByteReader, ByteWriter, FunctionEntry, Module};
+use crate::parser::{ExprAST, PatternAST};
+use crate::verifier;
+
+/// Bytes every VM snapshot starts with
+pub const vmSnapshotMagic: [u8; 4] = *b"TSVM";
+/// Bytes every ASTConverter snapshot starts with
+pub const converterSnapshotMagic: [u8; 4] = *b"TSAC";
+/// Bytes every saved session starts with
+pub const sessionMagic: [u8; 4] = *b"TSSN";
+/// Version of the snapshot formats. Snapshots with any other version are rejected
+pub const snapshotVersion: u16 = 2;
+
+/// Entries of a map ordered by key, so the same state is always saved as the same bytes
+fn Sorted<K: Ord, V>(map: &HashMap<K, V>) -> Vec<(&K, &V)> {
+    let mut entries: Vec<(&K, &V)> = map.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    return entries;
+}
+
+fn WriteValue(writer: &mut ByteWriter, value: &ToastLangValue) {
+    writer.Byte(value.0 as u8);
+    writer.Val64(value.1);
+}
+
+fn WriteStrings(writer: &mut ByteWriter, strings: &[String]) {
+    writer.List(strings, |writer, string| writer.Text(string));
+}
+
+fn ReadType(reader: &mut ByteReader) -> Result<VarTypes, String> {
+    let tag = reader.Byte()?;
+    return num::FromPrimitive::from_u8(tag).ok_or(format!("Unknown type: {}", tag));
+}
+
+fn ReadValue(reader: &mut ByteReader) -> Result<ToastLangValue, String> {
+    return Ok((ReadType(reader)?, reader.Val64()?));
+}
+
+fn ReadStrings(reader: &mut ByteReader) -> Result<Vec<String>, String> {
+    return reader.List(|reader| reader.Text());
+}
+
+fn ReadU128(reader: &mut ByteReader) -> Result<u128, String> {
+    return Ok(u128::from_be_bytes(reader.Bytes(16)?.try_into().unwrap()));
+}
+
+fn ReadUsize(reader: &mut ByteReader) -> Result<usize, String> {
+    let value = reader.Val64()?;
+    return usize::try_from(value).map_err(|_| format!("Value {} is too large", value));
+}
+
+/// Reads a map saved as a list of entries
+fn ReadMap<K: Eq + std::hash::Hash, V>(reader: &mut ByteReader, readEntry: impl FnMut(&mut ByteReader) -> Result<(K, V), String>) -> Result<HashMap<K, V>, String> {
+    return Ok(reader.List(readEntry)?.into_iter().collect());
+}
+
+/// Reads the module in a snapshot. Its code was run or compiled before it was saved, so it is read as it is
+fn ReadModule(reader: &mut ByteReader) -> Result<Module, String> {
+    return Module::Decode(reader.Blob()?).map_err(|err| format!("Saved code: {}", err));
+}
+
+/// Checks that the heap objects of a snapshot are ones the VM could have made: each variant has a tag and a valid type
+/// for each field, and objects only refer to ids in the heap
+fn CheckObjects(objects: &[Option<HeapObject>]) -> Result<(), String> {
+    for (id, object) in objects.iter().enumerate() {
+        let Some(object) = object else { continue };
+        if object.0 == VarTypes::VariantType {
+            if object.1.is_empty() || object.2.len() != object.1.len() - 1 {
+                return Err(format!("Variant {} does not have a type for each field", id));
+            }
+            if let Some(fieldType) = object.2.iter().find(|x| <VarTypes as num::FromPrimitive>::from_u8(**x).is_none()) {
+                return Err(format!("Variant {} has a field of unknown type {}", id, fieldType));
+            }
+        }
+        if let Some(child) = Children(object).into_iter().find(|child| *child >= objects.len() as u64) {
+            return Err(format!("Object {} refers to object {}, which does not exist", id, child));
+        }
+    }
+    return Ok(());
+}
+
+fn WriteExpr(writer: &mut ByteWriter, expr: &ExprAST) {
+    match expr {
+        ExprAST::NumberExpr(value) => {
+            writer.Byte(0);
+            writer.Val64(value.to_bits());
+        },
+        ExprAST::CharExpr(value) => {
+            writer.Byte(1);
+            writer.Text(value);
+        },
+        ExprAST::StringExpr(value) => {
+            writer.Byte(2);
+            writer.Text(value);
+        },
+        ExprAST::ListExpr(elements) => {
+            writer.Byte(3);
+            writer.List(elements, WriteExpr);
+        },
+        ExprAST::VariableExpr(name) => {
+            writer.Byte(4);
+            writer.Text(name);
+        },
+        ExprAST::VariableAssignExpr { varObject, value } => {
+            writer.Byte(5);
+            WriteExpr(writer, varObject);
+            WriteExpr(writer, value);
+        },
+        ExprAST::VariableHeader { name, typeName } => {
+            writer.Byte(6);
+            writer.Text(name);
+            writer.Text(typeName);
+        },
+        ExprAST::BinaryExpr { op, lhs, rhs, opChar } => {
+            writer.Byte(7);
+            writer.Byte(*op as u8);
+            WriteExpr(writer, lhs);
+            WriteExpr(writer, rhs);
+            writer.Text(opChar);
+        },
+        ExprAST::CallExpr { func_name, parameters } => {
+            writer.Byte(8);
+            writer.Text(func_name);
+            writer.List(parameters, WriteExpr);
+        },
+        ExprAST::IfExpr { cond, Then, Else } => {
+            writer.Byte(9);
+            WriteExpr(writer, cond);
+            WriteExpr(writer, Then);
+            writer.Optional(Else.as_deref(), WriteExpr);
+        },
+        ExprAST::ForExpr { var, start, end, stepFunc, body } => {
+            writer.Byte(10);
+            writer.Text(var);
+            for expr in [start, end, stepFunc, body] {
+                WriteExpr(writer, expr);
+            }
+        },
+        ExprAST::UnaryExpr { Opcode, Operand } => {
+            writer.Byte(11);
+            writer.Text(Opcode);
+            WriteExpr(writer, Operand);
+        },
+        ExprAST::CommentExpr(comment) => {
+            writer.Byte(12);
+            writer.Text(comment);
+        },
+        ExprAST::FuncExpr { name, type_params, args, return_type, body, bodyLines } => {
+            writer.Byte(13);
+            writer.Text(name);
+            WriteStrings(writer, type_params);
+            writer.List(args, WriteExpr);
+            writer.Optional(return_type.as_deref(), ByteWriter::Text);
+            writer.List(body, WriteExpr);
+            writer.List(bodyLines, |writer, line| writer.Val64(*line as u64));
+        },
+        ExprAST::ElementAccess { array_name, element_indexes } => {
+            writer.Byte(14);
+            writer.Text(array_name);
+            writer.List(element_indexes, |writer, index| WriteExpr(writer, index));
+        },
+        ExprAST::ReturnExpr(value) => {
+            writer.Byte(15);
+            WriteExpr(writer, value);
+        },
+        ExprAST::TypeDeclExpr { name, variants } => {
+            writer.Byte(16);
+            writer.Text(name);
+            writer.List(variants, |writer, (variant, fields)| {
+                writer.Text(variant);
+                WriteStrings(writer, fields);
+            });
+        },
+        ExprAST::TypeAliasExpr { name, typeName } => {
+            writer.Byte(17);
+            writer.Text(name);
+            writer.Text(typeName);
+        },
+        ExprAST::MatchExpr { value, arms } => {
+            writer.Byte(18);
+            WriteExpr(writer, value);
+            writer.List(arms, |writer, (pattern, body)| {
+                WritePattern(writer, pattern);
+                WriteExpr(writer, body);
+            });
+        },
+        ExprAST::NoneExpr => writer.Byte(19)
+    }
+}
+
+fn WritePattern(writer: &mut ByteWriter, pattern: &PatternAST) {
+    match pattern {
+        PatternAST::Wildcard => writer.Byte(0),
+        PatternAST::Binding(name) => {
+            writer.Byte(1);
+            writer.Text(name);
+        },
+        PatternAST::NumberPattern(value) => {
+            writer.Byte(2);
+            writer.Val64(value.to_bits());
+        },
+        PatternAST::CharPattern(value) => {
+            writer.Byte(3);
+            writer.Text(value);
+        },
+        PatternAST::StringPattern(value) => {
+            writer.Byte(4);
+            writer.Text(value);
+        },
+        PatternAST::ListPattern(elements) => {
+            writer.Byte(5);
+            writer.List(elements, WritePattern);
+        },
+        PatternAST::VariantPattern { name, fields } => {
+            writer.Byte(6);
+            writer.Text(name);
+            writer.List(fields, WritePattern);
+        }
+    }
+}
+
+fn ReadExpr(reader: &mut ByteReader) -> Result<ExprAST, String> {
+    let boxed = |reader: &mut ByteReader| Ok(Box::new(ReadExpr(reader)?));
+    let expr = match reader.Byte()? {
+        0 => ExprAST::NumberExpr(f64::from_bits(reader.Val64()?)),
+        1 => ExprAST::CharExpr(reader.Text()?),
+        2 => ExprAST::StringExpr(reader.Text()?),
+        3 => ExprAST::ListExpr(reader.List(ReadExpr)?),
+        4 => ExprAST::VariableExpr(reader.Text()?),
+        5 => ExprAST::VariableAssignExpr { varObject: boxed(reader)?, value: boxed(reader)? },
+        6 => ExprAST::VariableHeader { name: reader.Text()?, typeName: reader.Text()? },
+        7 => {
+            let tag = reader.Byte()?;
+            let op: Token = num::FromPrimitive::from_u8(tag).ok_or(format!("Unknown token: {}", tag))?;
+            ExprAST::BinaryExpr { op, lhs: boxed(reader)?, rhs: boxed(reader)?, opChar: reader.Text()? }
+        },
+        8 => ExprAST::CallExpr { func_name: reader.Text()?, parameters: reader.List(ReadExpr)? },
+        9 => ExprAST::IfExpr { cond: boxed(reader)?, Then: boxed(reader)?, Else: reader.Optional(boxed)? },
+        10 => ExprAST::ForExpr { var: reader.Text()?, start: boxed(reader)?, end: boxed(reader)?, stepFunc: boxed(reader)?, body: boxed(reader)? },
+        11 => ExprAST::UnaryExpr { Opcode: reader.Text()?, Operand: boxed(reader)? },
+        12 => ExprAST::CommentExpr(reader.Text()?),
+        13 => ExprAST::FuncExpr { name: reader.Text()?, type_params: ReadStrings(reader)?, args: reader.List(ReadExpr)?,
+            return_type: reader.Optional(ByteReader::Text)?, body: reader.List(ReadExpr)?, bodyLines: reader.List(ReadUsize)? },
+        14 => ExprAST::ElementAccess { array_name: reader.Text()?, element_indexes: reader.List(boxed)? },
+        15 => ExprAST::ReturnExpr(boxed(reader)?),
+        16 => ExprAST::TypeDeclExpr { name: reader.Text()?, variants: reader.List(|reader| Ok((reader.Text()?, ReadStrings(reader)?)))? },
+        17 => ExprAST::TypeAliasExpr { name: reader.Text()?, typeName: reader.Text()? },
+        18 => ExprAST::MatchExpr { value: boxed(reader)?, arms: reader.List(|reader| Ok((ReadPattern(reader)?, ReadExpr(reader)?)))? },
+        19 => ExprAST::NoneExpr,
+        tag => return Err(format!("Unknown expression tag: {}", tag))
+    };
+    return Ok(expr);
+}
+
+fn ReadPattern(reader: &mut ByteReader) -> Result<PatternAST, String> {
+    let pattern = match reader.Byte()? {
+        0 => PatternAST::Wildcard,
+        1 => PatternAST::Binding(reader.Text()?),
+        2 => PatternAST::NumberPattern(f64::from_bits(reader.Val64()?)),
+        3 => PatternAST::CharPattern(reader.Text()?),
+        4 => PatternAST::StringPattern(reader.Text()?),
+        5 => PatternAST::ListPattern(reader.List(ReadPattern)?),
+        6 => PatternAST::VariantPattern { name: reader.Text()?, fields: reader.List(ReadPattern)? },
+        tag => return Err(format!("Unknown pattern tag: {}", tag))
+    };
+    return Ok(pattern);
+}
+
+/// Saves the state of the VM between runs of top level code, so it can be restored later or on another machine
+///
+/// Layout (numbers are stored most significant byte first, a value is [type: 1 byte][value: 8 bytes] and lists are
+/// [number of items: 8 bytes][item]*):
+/// [magic: 4 bytes][version: 2 bytes]
+/// [pc: 8 bytes][cond: 1 byte][instruction count: 8 bytes][current memory block: 8 bytes]
+/// [registers of the top level window: 256 values]
+/// [memory blocks: list of ([variables: list of ([variable id: 8 bytes][value])][spill slots: list of values])]
+/// [heap: list of ([is live: 1 byte]([element type: 1 byte][elements: list of 8 bytes][dimensions length: 8 bytes][dimensions])?)]
+/// [loaded code length: 8 bytes][loaded code: a .toastc module of the constants, functions and function code, with no top level code]
+impl VMCore {
+    /// Fails while a function is running, since only the top level memory block and register window are saved.
+    /// The limits, sinks, debugger and profiler belong to the host and are not saved
+    pub fn Snapshot(&self) -> Result<Vec<u8>, String> {
+        if !self.callStack.is_empty() || self.memoryList.len() != 1 {
+            return Err("Can not save the VM while a function is running".to_string());
+        }
+        let mut writer = ByteWriter::new(vmSnapshotMagic, snapshotVersion);
+        writer.Val64(self.pc as u64);
+        writer.Byte(self.cond);
+        writer.Val64(self.instructionCount);
+        writer.Val64(self.curMemoryId as u64);
+        for register in &self.registers[..regWindowSize] {
+            WriteValue(&mut writer, register);
+        }
+        writer.List(&self.memoryList, |writer, memoryBlock| {
+            writer.List(&Sorted(&memoryBlock.variableLookup), |writer, (varId, value)| {
+                writer.Val64(**varId);
+                WriteValue(writer, value);
+            });
+            writer.List(&memoryBlock.spillSlots, WriteValue);
+        });
+        writer.List(self.heap.Objects(), |writer, object| writer.Optional(object.as_ref(), |writer, (elementType, elements, dimensions)| {
+            writer.Byte(*elementType as u8);
+            writer.List(elements, |writer, element| writer.Val64(*element));
+            writer.Blob(dimensions);
+        }));
+        writer.Blob(&self.LoadedCode().Encode());
+        return Ok(writer.bytes);
+    }
+
+    /// The constants, functions and function code the VM has loaded. System functions are built in, so they are left out
+    fn LoadedCode(&self) -> Module {
+        let mut functions: Vec<FunctionEntry> = self.funcList.iter()
+            .filter(|(_, (_, length, _, _))| *length != 0)
+            .map(|(funcId, (offset, length, _, _))| FunctionEntry {
+                name: self.funcNames.get(funcId).cloned().unwrap_or_default(),
+                funcId: *funcId as u64,
+                offset: *offset as u64,
+                length: *length as u64
+            })
+            .collect();
+        functions.sort_by_key(|x| x.funcId);
+        return Module { constants: self.constants.clone(), functions, functionCode: self.functionCode.to_vec(), code: Vec::new(), debugInfo: None };
+    }
+
+    /// Replaces the state of the VM with a snapshot made by Snapshot. The VM is left as it was if the snapshot can not be read,
+    /// or holds heap objects or function code that fail CheckObjects or verifier::Verify
+    pub fn Restore(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let mut reader = ByteReader::new(bytes, "VM snapshot");
+        reader.Header(vmSnapshotMagic, snapshotVersion)?;
+        let pc = ReadUsize(&mut reader)?;
+        let cond = reader.Byte()?;
+        let instructionCount = reader.Val64()?;
+        let curMemoryId = ReadUsize(&mut reader)?;
+        let mut registers = Vec::<ToastLangValue>::new();
+        for _ in 0..regWindowSize {
+            registers.push(ReadValue(&mut reader)?);
+        }
+        let memoryList = reader.List(|reader| {
+            let mut memoryBlock = MemoryBlock::new();
+            memoryBlock.variableLookup = ReadMap(reader, |reader| Ok((reader.Val64()?, ReadValue(reader)?)))?;
+            memoryBlock.spillSlots = reader.List(ReadValue)?;
+            return Ok(memoryBlock);
+        })?;
+        if curMemoryId >= memoryList.len() {
+            return Err(format!("Memory block {} does not exist", curMemoryId));
+        }
+        let objects = reader.List(|reader| reader.Optional(|reader| Ok((ReadType(reader)?, reader.List(|reader| reader.Val64())?, reader.Blob()?.to_vec()))))?;
+        CheckObjects(&objects)?;
+        let code = ReadModule(&mut reader)?;
+        reader.End()?;
+        verifier::Verify(&code).map_err(|err| format!("Saved code: {}", err))?;
+        // The functions are loaded the way processProgram loads them, into a VM that has only the system functions
+        let mut loaded = VMCore::new();
+        loaded.LoadModule(&code).map_err(|err| format!("Saved code: {}", err))?;
+
+        let mut heap = Heap::FromObjects(objects);
+        heap.maxObjects = self.heap.maxObjects;
+        heap.maxElements = self.heap.maxElements;
+        // Drops the state of a run that failed, such as its calls
+        self.Unwind();
+        self.registers = registers;
+        self.pc = pc;
+        self.cond = cond;
+        self.memoryList = memoryList;
+        self.heap = heap;
+        self.funcList = loaded.funcList;
+        self.curMemoryId = curMemoryId;
+        self.constants = loaded.constants;
+        self.functionCode = loaded.functionCode;
+        // Bodies are decoded again from the restored function code, and the top level code from the code run next
+        self.functionBodies.clear();
+        self.program = Rc::new(DecodedProgram::default());
+        self.funcNames = loaded.funcNames;
+        self.instructionCount = instructionCount;
+        return Ok(());
+    }
+}
+
+/// Saves the symbol tables and compiled code of the converter between statements, so more code can be compiled after it is restored
+///
+/// Layout (numbers are stored most significant byte first, memory blocks are 16 bytes, lists are [number of items: 8 bytes][item]*,
+/// maps are lists of [key][value] in order of key and strings are [length: 8 bytes][bytes]):
+/// [magic: 4 bytes][version: 2 bytes]
+/// [compiled code length: 8 bytes][compiled code: a .toastc module of the program, constants, function table and function code]
+/// [function ids: map of name to id]
+/// [variables: map of name to (memory block, type: 1 byte, variable id)]
+/// [lists: map of name to (memory block, element type: 1 byte, variable id, list of dimension sizes)]
+/// [current type: 1 byte][current memory block][next variable id: 8 bytes][next function id: 8 bytes]
+/// [types: map of name to list of (variant name, list of field type names)]
+/// [constructors: map of variant name to (type name, tag: 1 byte, list of field type names)]
+/// [variable type names, function return type names: maps of name to type name]
+/// [generic functions: map of name to function definition][function parameter type names: map of name to list of type names]
+/// [type aliases: map of name to type name]
+/// [source lines: map of function id to list of (pc, line)][variable names: map of function id to list of (pc, variable id, name)].
+/// Function ids are [is function: 1 byte]([function id: 8 bytes])?
+impl ASTConverter {
+    /// Fails while a function is being compiled
+    pub fn Snapshot(&self) -> Result<Vec<u8>, String> {
+        if self.curFuncBody.is_some() || self.exprDepth != 0 {
+            return Err("Can not save the compiler while it is compiling a statement".to_string());
+        }
+        let mut writer = ByteWriter::new(converterSnapshotMagic, snapshotVersion);
+        writer.Blob(&Module::FromConverter(self, None).Encode());
+        writer.List(&Sorted(&self.funcIdTable), |writer, (name, funcId)| {
+            writer.Text(name);
+            writer.Val64(**funcId);
+        });
+        writer.List(&Sorted(&self.varLookUp), |writer, (name, (memoryBlock, varType, varId))| {
+            writer.Text(name);
+            writer.Bytes(&memoryBlock.to_be_bytes());
+            writer.Byte(*varType as u8);
+            writer.Val64(*varId);
+        });
+        writer.List(&Sorted(&self.listLookUp), |writer, (name, (memoryBlock, elementType, varId, dimensions))| {
+            writer.Text(name);
+            writer.Bytes(&memoryBlock.to_be_bytes());
+            writer.Byte(*elementType as u8);
+            writer.Val64(*varId);
+            writer.List(dimensions, |writer, dimension| writer.Val64(*dimension));
+        });
+        writer.Byte(self.curType as u8);
+        writer.Bytes(&self.curMemoryBlock.to_be_bytes());
+        writer.Val64(self.curNumVarId);
+        writer.Val64(self.curFuncId);
+        writer.List(&Sorted(&self.typeLookUp), |writer, (name, variants)| {
+            writer.Text(name);
+            writer.List(variants, |writer, (variant, fields)| {
+                writer.Text(variant);
+                WriteStrings(writer, fields);
+            });
+        });
+        writer.List(&Sorted(&self.constructorLookUp), |writer, (name, (typeName, tag, fields))| {
+            writer.Text(name);
+            writer.Text(typeName);
+            writer.Byte(*tag);
+            WriteStrings(writer, fields);
+        });
+        for typeNames in [&self.varTypeNameLookUp, &self.funcReturnTypeLookUp] {
+            writer.List(&Sorted(typeNames), |writer, (name, typeName)| {
+                writer.Text(name);
+                writer.Text(typeName);
+            });
+        }
+        writer.List(&Sorted(&self.genericFuncLookUp), |writer, (name, definition)| {
+            writer.Text(name);
+            WriteExpr(writer, definition);
+        });
+        writer.List(&Sorted(&self.funcParamTypeLookUp), |writer, (name, typeNames)| {
+            writer.Text(name);
+            WriteStrings(writer, typeNames);
+        });
+        writer.List(&Sorted(&self.typeAliasLookUp), |writer, (name, typeName)| {
+            writer.Text(name);
+            writer.Text(typeName);
+        });
+        writer.List(&Sorted(&self.sourceMap.lines), |writer, (function, lines)| {
+            writer.Optional(**function, ByteWriter::Val64);
+            writer.List(lines, |writer, (pc, line)| {
+                writer.Val64(*pc as u64);
+                writer.Val64(*line as u64);
+            });
+        });
+        writer.List(&Sorted(&self.sourceMap.varNames), |writer, (function, names)| {
+            writer.Optional(**function, ByteWriter::Val64);
+            writer.List(names, |writer, (pc, varId, name)| {
+                writer.Val64(*pc as u64);
+                writer.Val64(*varId);
+                writer.Text(name);
+            });
+        });
+        return Ok(writer.bytes);
+    }
+
+    /// Replaces the state of the converter with a snapshot made by Snapshot. The converter is left as it was if the snapshot can not be read
+    pub fn Restore(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let mut reader = ByteReader::new(bytes, "Compiler snapshot");
+        reader.Header(converterSnapshotMagic, snapshotVersion)?;
+        let code = ReadModule(&mut reader)?;
+        let funcIdTable = ReadMap(&mut reader, |reader| Ok((reader.Text()?, reader.Val64()?)))?;
+        let varLookUp = ReadMap(&mut reader, |reader| Ok((reader.Text()?, (ReadU128(reader)?, ReadType(reader)?, reader.Val64()?))))?;
+        let listLookUp = ReadMap(&mut reader, |reader| Ok((reader.Text()?, (ReadU128(reader)?, ReadType(reader)?, reader.Val64()?, reader.List(|reader| reader.Val64())?))))?;
+        let curType = ReadType(&mut reader)?;
+        let curMemoryBlock = ReadU128(&mut reader)?;
+        let curNumVarId = reader.Val64()?;
+        let curFuncId = reader.Val64()?;
+        let typeLookUp = ReadMap(&mut reader, |reader| Ok((reader.Text()?, reader.List(|reader| Ok((reader.Text()?, ReadStrings(reader)?)))?)))?;
+        let constructorLookUp = ReadMap(&mut reader, |reader| Ok((reader.Text()?, (reader.Text()?, reader.Byte()?, ReadStrings(reader)?))))?;
+        let varTypeNameLookUp = ReadMap(&mut reader, |reader| Ok((reader.Text()?, reader.Text()?)))?;
+        let funcReturnTypeLookUp = ReadMap(&mut reader, |reader| Ok((reader.Text()?, reader.Text()?)))?;
+        let genericFuncLookUp = ReadMap(&mut reader, |reader| Ok((reader.Text()?, ReadExpr(reader)?)))?;
+        let funcParamTypeLookUp = ReadMap(&mut reader, |reader| Ok((reader.Text()?, ReadStrings(reader)?)))?;
+        let typeAliasLookUp = ReadMap(&mut reader, |reader| Ok((reader.Text()?, reader.Text()?)))?;
+        let lines = ReadMap(&mut reader, |reader| Ok((reader.Optional(|reader| reader.Val64())?, reader.List(|reader| Ok((ReadUsize(reader)?, ReadUsize(reader)?)))?)))?;
+        let varNames = ReadMap(&mut reader, |reader| Ok((reader.Optional(|reader| reader.Val64())?, reader.List(|reader| Ok((ReadUsize(reader)?, reader.Val64()?, reader.Text()?)))?)))?;
+        reader.End()?;
+
+        self.funcIdTable = funcIdTable;
+        self.varLookUp = varLookUp;
+        self.listLookUp = listLookUp;
+        self.program = code.code;
+        self.constantLookUp = code.constants.iter().cloned().enumerate().map(|(i, constant)| (constant, i as u64)).collect();
+        self.constants = code.constants;
+        self.functionCode = code.functionCode;
+        self.functionTable = code.functions;
+        self.curType = curType;
+        self.curMemoryBlock = curMemoryBlock;
+        self.curNumVarId = curNumVarId;
+        self.curFuncId = curFuncId;
+        self.regAllocator = RegisterAllocator::default();
+        self.typeLookUp = typeLookUp;
+        self.constructorLookUp = constructorLookUp;
+        self.varTypeNameLookUp = varTypeNameLookUp;
+        self.funcReturnTypeLookUp = funcReturnTypeLookUp;
+        self.genericFuncLookUp = genericFuncLookUp;
+        // Inlining only makes code faster, so the restored functions are called instead of inlined
+        self.inlineLookUp = HashMap::new();
+        self.exprDepth = 0;
+        self.funcParamTypeLookUp = funcParamTypeLookUp;
+        self.typeAliasLookUp = typeAliasLookUp;
+        self.curFuncReturnTypeName = None;
+        self.curFuncBody = None;
+        self.sourceMap.lines = lines;
+        self.sourceMap.varNames = varNames;
+        return Ok(());
+    }
+}
+
+/// Saves a session: a VM and the converter that compiles the code it runs.
+/// Layout: [magic: 4 bytes][version: 2 bytes][VM snapshot length: 8 bytes][VM snapshot][converter snapshot length: 8 bytes][converter snapshot]
+pub fn SaveSession(vm: &VMCore, converter: &ASTConverter) -> Result<Vec<u8>, String> {
+    let mut writer = ByteWriter::new(sessionMagic, snapshotVersion);
+    writer.Blob(&vm.Snapshot()?);
+    writer.Blob(&converter.Snapshot()?);
+    return Ok(writer.bytes);
+}
+
+/// Restores a session saved by SaveSession. Neither the VM nor the converter is changed if the session can not be read
+pub fn LoadSession(bytes: &[u8], vm: &mut VMCore, converter: &mut ASTConverter) -> Result<(), String> {
+    let mut reader = ByteReader::new(bytes, "Session");
+    reader.Header(sessionMagic, snapshotVersion)?;
+    let (vmBytes, converterBytes) = (reader.Blob()?, reader.Blob()?);
+    reader.End()?;
+    let mut restoredConverter = ASTConverter::new();
+    // Whether code is optimized is chosen by the host, like the limits of the VM, so it is not saved
+    restoredConverter.optimize = converter.optimize;
+    restoredConverter.Restore(converterBytes)?;
+    vm.Restore(vmBytes)?;
+    *converter = restoredConverter;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::parser::Parser;
+    use crate::codegen::{ASTConverter, MemoryBlock, OpCodes, VMCore, VarTypes};
+    use crate::heap::Heap;
+    use crate::module::Module;
+    use crate::output::{CaptureBuffer, Sink};
+    use crate::snapshot::{LoadSession, SaveSession, snapshotVersion};
+
+    // Compiles and runs each statement of the source the way the REPL does
+    fn runStatements(source: &str, toast_vm: &mut VMCore, ast_converter: &mut ASTConverter) {
+        let mut parser = Parser::new(source);
+        for (ast, line) in parser.parse().unwrap().into_iter().zip(parser.statementLines.clone()) {
+            ast_converter.ConvertStatement(ast, line);
+            toast_vm.processProgram(&Module::FromConverter(ast_converter, None)).unwrap();
+        }
+    }
+
+    #[test]
+    fn restoreSessionAndGoOn(){
+        let source = "type Shape = Circle(number) | Rect(number, number)\ndef area(s: Shape) -> number:\nmatch s with\n| Circle(r) -> r * r\n| Rect(w, h) -> w * h\nendmatch\nend\n\
+            def first<T>(xs: T[]) -> T:\nreturn xs[0]\nend\nlet nums: number[] = [7, 8]\nlet word: string = \"hi\"\nprint(first([1, 2]))";
+        let output = CaptureBuffer::new();
+        let (mut toast_vm, mut ast_converter) = (VMCore::new(), ASTConverter::new());
+        toast_vm.output = Sink::new(output.clone());
+        runStatements(source, &mut toast_vm, &mut ast_converter);
+        assert_eq!(output.Take(), "1.0\n");
+        let session = SaveSession(&toast_vm, &ast_converter).unwrap();
+
+        let (mut restored_vm, mut restored_converter) = (VMCore::new(), ASTConverter::new());
+        restored_vm.output = Sink::new(output.clone());
+        LoadSession(&session, &mut restored_vm, &mut restored_converter).unwrap();
+        assert_eq!(restored_converter.genericFuncLookUp, ast_converter.genericFuncLookUp);
+        assert_eq!(restored_converter.sourceMap, ast_converter.sourceMap);
+        // The same state is always saved as the same bytes
+        assert_eq!(SaveSession(&restored_vm, &restored_converter).unwrap(), session);
+
+        runStatements("print(area(Rect(3, 4)))\nprint(word)\nprint(nums)\nprint(area(Circle(first([7, 8]))))\nlet total: number = nums[0] + nums[1]\nprint(total)", &mut restored_vm, &mut restored_converter);
+        assert_eq!(output.Take(), "12.0\n\"hi\"\n[7.0,8.0,]\n49.0\n15.0\n");
+    }
+
+    #[test]
+    fn continueRunAfterLoad(){
+        let output = CaptureBuffer::new();
+        let (mut toast_vm, mut ast_converter) = (VMCore::new(), ASTConverter::new());
+        toast_vm.output = Sink::new(output.clone());
+        runStatements("def count(i: number, acc: number) -> number:\nmatch i with\n| 0 -> acc\n| _ -> count(i - 1, acc + i)\nendmatch\nend\n\
+            let nums: number[] = [1, 2, 3]\nlet start: number = count(10, 0)", &mut toast_vm, &mut ast_converter);
+        // Saved and read back through a file, as :save and :load do
+        let path = std::env::temp_dir().join(format!("toast-session-{}.bin", std::process::id()));
+        std::fs::write(&path, SaveSession(&toast_vm, &ast_converter).unwrap()).unwrap();
+        let session = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let (mut restored_vm, mut restored_converter) = (VMCore::new(), ASTConverter::new());
+        restored_vm.output = Sink::new(output.clone());
+        LoadSession(&session, &mut restored_vm, &mut restored_converter).unwrap();
+        let more = "print(start + count(nums[2], 0))\nlet next: number[] = [4, 5]\nprint(next[1] + nums[1])";
+        runStatements(more, &mut toast_vm, &mut ast_converter);
+        let expected = output.Take();
+        runStatements(more, &mut restored_vm, &mut restored_converter);
+        assert_eq!(output.Take(), expected);
+        assert_eq!(expected, "61.0\n7.0\n");
+        assert_eq!(restored_vm.instructionCount, toast_vm.instructionCount);
+    }
+
+    #[test]
+    fn keepOptimizeOnLoad(){
+        let (mut toast_vm, mut ast_converter) = (VMCore::new(), ASTConverter::new());
+        runStatements("let a: number = 5", &mut toast_vm, &mut ast_converter);
+        let session = SaveSession(&toast_vm, &ast_converter).unwrap();
+
+        let (mut restored_vm, mut restored_converter) = (VMCore::new(), ASTConverter::new());
+        restored_converter.optimize = true;
+        LoadSession(&session, &mut restored_vm, &mut restored_converter).unwrap();
+        assert!(restored_converter.optimize);
+        // Constants are folded in the code compiled after the load
+        let start = restored_converter.program.len();
+        runStatements("let b: number = 2 * 3", &mut restored_vm, &mut restored_converter);
+        let (mut plain_vm, mut plain_converter) = (VMCore::new(), ASTConverter::new());
+        runStatements("let a: number = 5\nlet b: number = 2 * 3", &mut plain_vm, &mut plain_converter);
+        assert!(restored_converter.program.len() - start < plain_converter.program.len() - start);
+    }
+
+    #[test]
+    fn rejectBadSnapshots(){
+        let (mut toast_vm, mut ast_converter) = (VMCore::new(), ASTConverter::new());
+        runStatements("let a: number = 5", &mut toast_vm, &mut ast_converter);
+        let session = SaveSession(&toast_vm, &ast_converter).unwrap();
+
+        let (mut other_vm, mut other_converter) = (VMCore::new(), ASTConverter::new());
+        assert_eq!(LoadSession(b"let a: number = 5", &mut other_vm, &mut other_converter), Err("Not a Session".to_string()));
+        assert!(LoadSession(&session[..session.len() - 1], &mut other_vm, &mut other_converter).unwrap_err().starts_with("Session ends early"));
+        let mut newer = session.clone();
+        newer[5] = (snapshotVersion + 1) as u8;
+        assert_eq!(LoadSession(&newer, &mut other_vm, &mut other_converter),
+            Err(format!("Session has format version {}, but this version of ToastLang reads version {}", snapshotVersion + 1, snapshotVersion)));
+        // Nothing is restored from a session that can not be read
+        assert!(other_converter.varLookUp.is_empty() && other_vm.pc == 0);
+
+        // Objects and function code the VM could not have made are rejected
+        let snapshot = toast_vm.Snapshot().unwrap();
+        let mut bad_vm = VMCore::new();
+        bad_vm.heap = Heap::FromObjects(vec![Some((VarTypes::VariantType, vec![0, 5], Vec::new()))]);
+        assert_eq!(other_vm.Restore(&bad_vm.Snapshot().unwrap()), Err("Variant 0 does not have a type for each field".to_string()));
+        bad_vm.heap = Heap::FromObjects(vec![Some((VarTypes::VariantType, vec![0, 5], vec![42]))]);
+        assert_eq!(other_vm.Restore(&bad_vm.Snapshot().unwrap()), Err("Variant 0 has a field of unknown type 42".to_string()));
+        bad_vm.heap = Heap::FromObjects(vec![Some((VarTypes::ArrayRef, vec![3], vec![1]))]);
+        assert_eq!(other_vm.Restore(&bad_vm.Snapshot().unwrap()), Err("Object 0 refers to object 3, which does not exist".to_string()));
+        let mut bad_vm = VMCore::new();
+        runStatements("def double(x: number) -> number:\nreturn x * 2\nend", &mut bad_vm, &mut ASTConverter::new());
+        let end = bad_vm.functionCode.len() - 1;
+        Rc::make_mut(&mut bad_vm.functionCode)[end] = OpCodes::OpStartFunc as u8;
+        assert!(other_vm.Restore(&bad_vm.Snapshot().unwrap()).unwrap_err().starts_with("Saved code: "));
+        // Nothing is restored from a rejected snapshot
+        assert!(other_vm.heap.Objects().is_empty() && other_vm.funcList.len() == VMCore::new().funcList.len());
+        other_vm.Restore(&snapshot).unwrap();
+
+        toast_vm.memoryList.push(MemoryBlock::new());
+        assert_eq!(toast_vm.Snapshot(), Err("Can not save the VM while a function is running".to_string()));
+    }
+}
@@ -0,0 +1,34 @@
+#![allow(non_snake_case)]
+// Compiling and running source code, shared by the tests of every module
+use crate::codegen::{ASTConverter, VMCore};
+use crate::module::Module;
+use crate::output::{CaptureBuffer, Sink};
+use crate::parser::Parser;
+
+/// Compiles each statement of the source with its line, the way `toast run` does
+pub fn compile(source: &str, optimize: bool) -> ASTConverter {
+    let mut parser = Parser::new(source);
+    let mut ast_converter = ASTConverter::new();
+    ast_converter.optimize = optimize;
+    for (ast, line) in parser.parse().unwrap().into_iter().zip(parser.statementLines.clone()) {
+        ast_converter.ConvertStatement(ast, line);
+    }
+    return ast_converter;
+}
+
+pub fn compileModule(source: &str) -> Module {
+    return Module::FromConverter(&compile(source, false), None);
+}
+
+/// Runs a module on a new VM and returns the VM and what the program printed
+pub fn runModule(module: &Module) -> (VMCore, String) {
+    let output = CaptureBuffer::new();
+    let mut toast_vm = VMCore::new();
+    toast_vm.output = Sink::new(output.clone());
+    toast_vm.processProgram(module).unwrap();
+    return (toast_vm, output.Take());
+}
+
+pub fn runSource(source: &str) -> VMCore {
+    return runModule(&compileModule(source)).0;
+}
@@ -0,0 +1,232 @@
+#![allow(non_snake_case)]
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::bytecode::{DecodedProgram, Instruction};
+use crate::codegen::{OpCodes, SystemFunctions, VarTypes};
+use crate::module::Module;
+
+/// Why Verify rejected a module
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyError {
+    /// Function the problem is in, None for top level code
+    pub function: Option<String>,
+    /// Start of the bad instruction. Inside a function it is relative to the function's OpStartFunc
+    pub pc: usize,
+    /// None if the bytes at pc are not a valid instruction
+    pub opCode: Option<OpCodes>,
+    pub reason: String
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "{} in function {}", self.reason, name),
+            None => write!(f, "{}", self.reason)
+        }
+    }
+}
+
+/// Checks a module before it is run, so bad bytecode is rejected instead of reaching the VM. Checks that:
+/// - every instruction decodes, so opcodes and var type operands are valid
+/// - type operands are ones the instruction can work on
+/// - OpStartFunc and OpEndFunc only appear at the ends of function bodies
+/// - OpLoadArray/OpEndArray, OpLoadMultiDimensionalArrayElement/OpEndMultiDimensionalArrayElement, OpAccessElementBegin/OpAccessElementEnd
+///   and OpCallFunc/OpEndParamLoad are balanced
+/// - jumps land on the start of an instruction in the same block of code
+/// - called functions and loaded constants exist
+///
+/// Register operands take 8 bits and every register window has 256 registers, so any register operand is in range
+pub fn Verify(module: &Module) -> Result<(), VerifyError> {
+    return VerifyAdded(module, |_| false, &DecodedProgram::default());
+}
+
+/// Checks what a module adds to code that was checked before, the way Verify checks a whole module: the functions for which
+/// isLoaded is false and the top level code past loadedCode. The new code may call the loaded functions and jump to loadedCode
+pub fn VerifyAdded(module: &Module, isLoaded: impl Fn(u64) -> bool, loadedCode: &DecodedProgram) -> Result<(), VerifyError> {
+    let mut funcIds = HashSet::<u64>::new();
+    for function in &module.functions {
+        let fail = |reason: String| VerifyError { function: Some(function.name.clone()), pc: 0, opCode: None, reason };
+        if IsSystemFunction(function.funcId) {
+            return Err(fail(format!("Function id {} belongs to a system function", function.funcId)));
+        }
+        if !funcIds.insert(function.funcId) {
+            return Err(fail(format!("Function id {} is used more than once", function.funcId)));
+        }
+    }
+    let isFunction = |funcId: u64| funcIds.contains(&funcId) || isLoaded(funcId);
+    for function in module.functions.iter().filter(|function| !isLoaded(function.funcId)) {
+        let body = usize::try_from(function.offset.saturating_add(function.length)).ok()
+            .and_then(|end| module.functionCode.get(function.offset as usize..end))
+            .ok_or_else(|| VerifyError { function: Some(function.name.clone()), pc: 0, opCode: None, reason: "Function is outside of the function code".to_string() })?;
+        VerifyCode(module, &isFunction, body, &DecodedProgram::default(), Some(&function.name))?;
+    }
+    return VerifyCode(module, &isFunction, &module.code, loadedCode, None);
+}
+
+fn IsSystemFunction(funcId: u64) -> bool {
+    let systemFunction: Option<SystemFunctions> = num::FromPrimitive::from_u64(funcId);
+    return systemFunction.is_some();
+}
+
+/// Checks one block of code past the part of it that is already checked: a function body if function is given, otherwise the top level code
+fn VerifyCode(module: &Module, isFunction: &impl Fn(u64) -> bool, code: &[u8], loadedCode: &DecodedProgram, function: Option<&str>) -> Result<(), VerifyError> {
+    let fail = |pc: usize, opCode: Option<OpCodes>, reason: String| VerifyError { function: function.map(|x| x.to_string()), pc, opCode, reason };
+
+    // Decodes every instruction first, so jumps can be checked against where instructions start
+    let mut instructions = Vec::<(usize, Instruction)>::new();
+    let mut starts = HashSet::<usize>::new();
+    let mut pc = loadedCode.Len();
+    while pc < code.len() {
+        let (instruction, length) = Instruction::TryDecode(code, pc).map_err(|x| fail(pc, None, x))?;
+        starts.insert(pc);
+        instructions.push((pc, instruction));
+        pc += length;
+    }
+    if function.is_some() {
+        let startsWithFunc = matches!(instructions.first(), Some((_, Instruction::StartFunc { .. })));
+        let endsWithFunc = instructions.len() > 1 && matches!(instructions.last(), Some((_, Instruction::EndFunc)));
+        if !startsWithFunc || !endsWithFunc {
+            return Err(fail(0, None, "Function body does not start with OpStartFunc and end with OpEndFunc".to_string()));
+        }
+    }
+
+    // Instructions that are waiting for their closing instruction, with the OpCode that closes them
+    let mut open = Vec::<(usize, OpCodes)>::new();
+    for (index, (pc, instruction)) in instructions.iter().enumerate() {
+        let opCode = instruction.OpCode();
+        let fail = |reason: String| fail(*pc, Some(opCode), reason);
+        match instruction {
+            Instruction::StartFunc { .. } if function.is_some() && index == 0 => {},
+            Instruction::EndFunc if function.is_some() && index == instructions.len() - 1 => {},
+            Instruction::StartFunc { .. } | Instruction::EndFunc => {
+                return Err(fail(format!("{:?} is only allowed at the ends of a function body", opCode)));
+            },
+            Instruction::LoadScalar { varType, .. } if ![VarTypes::FloatType, VarTypes::CharType].contains(varType) => {
+                return Err(fail(format!("OpLoadScalar can not load a {:?}", varType)));
+            },
+            Instruction::LoadArray { elementType, .. } => {
+                if ![VarTypes::FloatType, VarTypes::CharType, VarTypes::ArrayType].contains(elementType) {
+                    return Err(fail(format!("OpLoadArray can not hold {:?} elements", elementType)));
+                }
+                open.push((*pc, OpCodes::OpEndArray));
+            },
+            Instruction::LoadMultiDimensionalArrayElement => open.push((*pc, OpCodes::OpEndMultiDimensionalArrayElement)),
+            Instruction::AccessElementBegin => open.push((*pc, OpCodes::OpAccessElementEnd)),
+            Instruction::CallFunc { funcId } => {
+                if !IsSystemFunction(*funcId) && !isFunction(*funcId) {
+                    return Err(fail(format!("Unkown function: {}", funcId)));
+                }
+                open.push((*pc, OpCodes::OpEndParamLoad));
+            },
+            Instruction::EndArray | Instruction::EndMultiDimensionalArrayElement | Instruction::AccessElementEnd { .. } | Instruction::EndParamLoad => {
+                match open.pop() {
+                    Some((_, expected)) if expected == opCode => {},
+                    Some((_, expected)) => return Err(fail(format!("Expected {:?}, found {:?}", expected, opCode))),
+                    None => return Err(fail(format!("{:?} has nothing to close", opCode)))
+                }
+            },
+            Instruction::Jump { target } | Instruction::JumpIfFalse { target } => {
+                // A jump to the end of the code finishes it. The VM can not jump back to the first instruction
+                let target = usize::try_from(*target).unwrap_or(usize::MAX);
+                if target == 0 {
+                    return Err(fail("Jumps can not go back to the first instruction".to_string()));
+                }
+                if target != code.len() && !starts.contains(&target) && loadedCode.At(target).is_none() {
+                    return Err(fail(format!("Jump target {} is not the start of an instruction", target)));
+                }
+            },
+            Instruction::LoadConstant { constId, .. } if *constId >= module.constants.len() as u64 => {
+                return Err(fail(format!("Unkown constant: {}", constId)));
+            },
+            _ => {}
+        }
+    }
+    if let Some((pc, expected)) = open.last() {
+        let opCode = instructions.iter().find(|(start, _)| start == pc).map(|(_, instruction)| instruction.OpCode());
+        return Err(fail(*pc, opCode, format!("{:?} is never closed with {:?}", opCode.unwrap(), expected)));
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::Assemble;
+    use crate::bytecode::{DecodedProgram, Instruction};
+    use crate::codegen::{OpCodes, VarTypes};
+    use crate::module::{FunctionEntry, Module};
+    use crate::testsupport::compileModule;
+    use crate::verifier::{Verify, VerifyAdded, VerifyError};
+
+    fn verifyAssembly(source: &str) -> Result<(), VerifyError> {
+        return Verify(&Assemble(source).unwrap());
+    }
+
+    #[test]
+    fn verifyCompiledProgram(){
+        let source = "type Shape = Circle(number) | Rect(number, number)\ndef area(s: Shape) -> number:\nreturn match s with\n| Circle(r) -> r * r\n| Rect(w, h) -> w * h\nendmatch\nend\nlet xs: number[] = [1, 2, 3]\nlet m: number[][] = [[1, 2], [3, 4]]\nprint(\"hi\")\narea(Rect(2, xs[1]))";
+        assert_eq!(Verify(&compileModule(source)), Ok(()));
+    }
+
+    #[test]
+    fn rejectBadInstructions(){
+        let module = Module { constants: Vec::new(), functions: Vec::new(), functionCode: Vec::new(), code: [OpCodes::OpLoadReg as u8, 1].to_vec(), debugInfo: None };
+        assert_eq!(Verify(&module), Err(VerifyError { function: None, pc: 0, opCode: None, reason: "Program ends in the middle of an instruction at 2".to_string() }));
+        let mut module = Assemble("loadreg r0, r1").unwrap();
+        Instruction::LoadScalar { reg: 0, varType: VarTypes::ArrayType, value: 1 }.Encode(&mut module.code);
+        let err = Verify(&module).unwrap_err();
+        assert_eq!((err.pc, err.opCode, err.reason.as_str()), (3, Some(OpCodes::OpLoadScalar), "OpLoadScalar can not load a ArrayType"));
+        let mut module = Assemble("loadconstant r0, 1.0").unwrap();
+        module.code.clear();
+        Instruction::LoadConstant { reg: 0, constId: 2 }.Encode(&mut module.code);
+        assert_eq!(Verify(&module).unwrap_err().reason, "Unkown constant: 2");
+    }
+
+    #[test]
+    fn rejectUnbalancedBlocks(){
+        assert_eq!(verifyAssembly("endarray").unwrap_err().reason, "OpEndArray has nothing to close");
+        assert_eq!(verifyAssembly("callfunc print\nloadarray r0, char, [1], \"a\"").unwrap_err().reason, "OpLoadArray is never closed with OpEndArray");
+        assert_eq!(verifyAssembly("callfunc print\nloadarray r0, char, [1], \"a\"\nendparamload").unwrap_err().reason, "Expected OpEndArray, found OpEndParamLoad");
+        let mut module = Assemble("startfunc foo() -> null\nendfunc").unwrap();
+        module.code = module.functionCode.clone();
+        assert_eq!(Verify(&module).unwrap_err().reason, "OpStartFunc is only allowed at the ends of a function body");
+    }
+
+    #[test]
+    fn rejectBadJumps(){
+        assert_eq!(verifyAssembly("jump 2\nloadreg r0, r1"), Err(VerifyError { function: None, pc: 0, opCode: Some(OpCodes::OpJump), reason: "Jump target 2 is not the start of an instruction".to_string() }));
+        assert_eq!(verifyAssembly("jump 0").unwrap_err().reason, "Jumps can not go back to the first instruction");
+        assert_eq!(verifyAssembly("jump end\nloadreg r0, r1\nend:"), Ok(()));
+    }
+
+    #[test]
+    fn rejectUnknownFunctions(){
+        assert_eq!(verifyAssembly("callfunc 7\nendparamload").unwrap_err().reason, "Unkown function: 7");
+        let mut module = Assemble("startfunc foo() -> null\nendfunc").unwrap();
+        module.functions.push(FunctionEntry { name: "bar".to_string(), funcId: 2, offset: 1, length: 5 });
+        assert_eq!(Verify(&module).unwrap_err().to_string(), "Function is outside of the function code in function bar");
+        module.functions[1] = FunctionEntry { name: "bar".to_string(), funcId: 2, offset: 0, length: 3 };
+        assert_eq!(Verify(&module).unwrap_err().to_string(), "Function body does not start with OpStartFunc and end with OpEndFunc in function bar");
+    }
+
+    #[test]
+    fn verifyOnlyAddedCode(){
+        let mut module = Assemble("startfunc foo() -> null\nendfunc\nloadreg r0, r1\nloadreg r1, r0").unwrap();
+        let loadedCode = DecodedProgram::Decode(&module.code).unwrap();
+        Instruction::Jump { target: 3 }.Encode(&mut module.code);
+        Instruction::CallFunc { funcId: 1 }.Encode(&mut module.code);
+        Instruction::EndParamLoad.Encode(&mut module.code);
+        assert_eq!(VerifyAdded(&module, |funcId| funcId == 1, &loadedCode), Ok(()));
+        // Loaded functions are not checked again
+        module.functions[0].length = 1;
+        assert_eq!(VerifyAdded(&module, |funcId| funcId == 1, &loadedCode), Ok(()));
+        assert!(VerifyAdded(&module, |_| false, &loadedCode).is_err());
+        // The new code can only jump to the start of a loaded instruction
+        module.functions[0].length = module.functionCode.len() as u64;
+        let jumpPc = loadedCode.Len();
+        module.code.truncate(jumpPc);
+        Instruction::Jump { target: 4 }.Encode(&mut module.code);
+        assert_eq!(VerifyAdded(&module, |funcId| funcId == 1, &loadedCode),
+            Err(VerifyError { function: None, pc: jumpPc, opCode: Some(OpCodes::OpJump), reason: "Jump target 4 is not the start of an instruction".to_string() }));
+    }
+}
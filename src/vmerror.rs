@@ -0,0 +1,108 @@
+#![allow(non_snake_case)]
+use std::fmt;
+
+use crate::codegen::{OpCodes, VarTypes};
+use crate::verifier::VerifyError;
+
+/// What went wrong while running a program
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmErrorKind {
+    /// The module was rejected by the verifier before it was run
+    InvalidProgram(VerifyError),
+    /// The bytes at pc are not a valid instruction
+    InvalidInstruction(String),
+    /// An instruction was found where it can not be run, such as OpStartFunc in top level code
+    MisplacedInstruction(OpCodes),
+    UnknownFunction(u64),
+    UnknownConstant(u64),
+    UnknownVariable(u64),
+    UnknownList(u64),
+    /// A heap object does not hold what its type says, such as a variant with no type for one of its fields
+    InvalidObject(u64),
+    IndexOutOfRange { index: usize, length: usize },
+    TypeMismatch { expected: Vec<VarTypes>, found: VarTypes },
+    /// An arithmetic instruction was given operands it can not work on
+    UnsupportedOperands { left: VarTypes, right: VarTypes },
+    EmptySpillSlot(u8),
+    UnwrapNone,
+    /// The instruction budget in VMCore::fuel was used up
+    OutOfFuel,
+    /// VMCore::deadline has passed
+    DeadlineExceeded,
+    /// Heap::maxObjects objects are already live
+    HeapObjectLimit(usize),
+    /// The live objects would hold more than Heap::maxElements elements
+    HeapSizeLimit(usize),
+    /// VMCore::maxCallDepth calls are already running
+    CallDepthLimit(usize),
+    /// The program's output could not be written to VMCore::output
+    OutputFailed(String)
+}
+
+impl fmt::Display for VmErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmErrorKind::InvalidProgram(err) => write!(f, "Invalid program: {}", err),
+            VmErrorKind::InvalidInstruction(reason) => write!(f, "Invalid instruction: {}", reason),
+            VmErrorKind::MisplacedInstruction(opCode) => write!(f, "{:?} can not be run here", opCode),
+            VmErrorKind::UnknownFunction(funcId) => write!(f, "Unkown function: {}", funcId),
+            VmErrorKind::UnknownConstant(constId) => write!(f, "Unkown constant: {}", constId),
+            VmErrorKind::UnknownVariable(varId) => write!(f, "Unkown variable: {}", varId),
+            VmErrorKind::UnknownList(listId) => write!(f, "Unkown list: {}", listId),
+            VmErrorKind::InvalidObject(listId) => write!(f, "List {} is not a valid list, string or variant", listId),
+            VmErrorKind::IndexOutOfRange { index, length } => write!(f, "Index {} is out of range for a list of length {}", index, length),
+            VmErrorKind::TypeMismatch { expected, found } => write!(f, "Expected {:?}, found {:?}", expected, found),
+            VmErrorKind::UnsupportedOperands { left, right } => write!(f, "Can not use {:?} and {:?} together", left, right),
+            VmErrorKind::EmptySpillSlot(slot) => write!(f, "Spill slot {} is empty", slot),
+            VmErrorKind::UnwrapNone => write!(f, "Unwrapped a none value"),
+            VmErrorKind::OutOfFuel => write!(f, "Ran out of instruction budget"),
+            VmErrorKind::DeadlineExceeded => write!(f, "Ran past the deadline"),
+            VmErrorKind::HeapObjectLimit(max) => write!(f, "Can not have more than {} lists, strings and variants at once", max),
+            VmErrorKind::HeapSizeLimit(max) => write!(f, "Lists, strings and variants can not hold more than {} elements together", max),
+            VmErrorKind::CallDepthLimit(max) => write!(f, "Calls can not be nested more than {} deep", max),
+            VmErrorKind::OutputFailed(reason) => write!(f, "Could not write output: {}", reason)
+        }
+    }
+}
+
+impl From<std::io::Error> for VmErrorKind {
+    fn from(err: std::io::Error) -> Self {
+        return VmErrorKind::OutputFailed(err.to_string());
+    }
+}
+
+/// A function call that was running when an error happened
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallFrame {
+    pub funcId: u64,
+    pub name: String,
+    /// pc of the OpCallFunc in the caller
+    pub callPc: usize
+}
+
+/// An error raised by VMCore while running a program
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmError {
+    pub kind: VmErrorKind,
+    /// Start of the faulting instruction. Inside a function it is relative to the function's OpStartFunc
+    pub pc: usize,
+    /// None if the bytes at pc are not a valid instruction
+    pub opCode: Option<OpCodes>,
+    /// Calls that were running, innermost last
+    pub callStack: Vec<CallFrame>
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at pc {}", self.kind, self.pc)?;
+        if let Some(opCode) = &self.opCode {
+            write!(f, " ({:?})", opCode)?;
+        }
+        for frame in self.callStack.iter().rev() {
+            write!(f, "\n    in {} called at pc {}", frame.name, frame.callPc)?;
+        }
+        return Ok(());
+    }
+}
+
+impl std::error::Error for VmError {}